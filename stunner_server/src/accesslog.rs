@@ -0,0 +1,324 @@
+//! Access log of the messages handled, one JSON object per line written to a file rotated by
+//! size or age, for auditing. It's independent from the diagnostic log records.
+
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write as _};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::logging::json_string;
+use crate::server::Transport;
+
+/// Number of entries waiting to be written beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 65536;
+
+/// When the access log file is rotated.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Size in bytes the file is rotated at, if any.
+    pub max_size: Option<u64>,
+    /// Age the file is rotated at, if any.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files kept, the older ones are removed. All are kept when 0.
+    pub keep: usize,
+}
+
+/// A message handled.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub transport: Transport,
+    pub src_addr: SocketAddr,
+    pub method: &'a str,
+    pub class: &'a str,
+    pub outcome: &'a str,
+    /// Size of the message received.
+    pub bytes_in: usize,
+    /// Size of the response sent back, 0 if none.
+    pub bytes_out: usize,
+    /// Time from the reception of the message to its response being encoded.
+    pub latency: Duration,
+    /// Country code of the source, when looked up.
+    pub country: Option<&'a str>,
+    /// Autonomous system number of the source, when looked up.
+    pub asn: Option<u32>,
+}
+
+/// Writes the entries to the file on a thread of its own, so that handling messages never
+/// waits on the disk.
+#[derive(Debug)]
+pub struct AccessLog {
+    tx: SyncSender<String>,
+    /// Entries dropped as the writer couldn't keep up.
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Append the entries to the file at `path`, created if needed, rotated as configured. The
+    /// rotated files are named after it, suffixed with the UNIX time in milliseconds of their
+    /// rotation.
+    pub fn open(path: PathBuf, rotation: Rotation) -> Result<Self> {
+        let writer = Writer::open(path, rotation)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || writer.run(rx))?;
+        Ok(AccessLog {
+            tx,
+            dropped: AtomicU64::default(),
+        })
+    }
+
+    /// Log `entry`, dropping it if too many are waiting to be written.
+    pub fn record(&self, entry: &Entry) {
+        if self
+            .tx
+            .try_send(format_entry(entry, SystemTime::now()))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of entries dropped as the writer couldn't keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// JSON line of `entry`, logged at `time`.
+fn format_entry(entry: &Entry, time: SystemTime) -> String {
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "{{\"timestamp\":\"{}\",\"transport\":\"{}\",\"src_addr\":\"{}\",\"method\":{},\
+         \"class\":{},\"outcome\":{},\"bytes_in\":{},\"bytes_out\":{},\"latency_us\":{}",
+        humantime::format_rfc3339_millis(time),
+        entry.transport.as_str(),
+        entry.src_addr,
+        json_string(entry.method),
+        json_string(entry.class),
+        json_string(entry.outcome),
+        entry.bytes_in,
+        entry.bytes_out,
+        entry.latency.as_micros()
+    );
+    // Only logged when looked up, so that the entries stay the same without GeoIP databases.
+    if let Some(country) = entry.country {
+        let _ = write!(line, ",\"country\":{}", json_string(country));
+    }
+    if let Some(asn) = entry.asn {
+        let _ = write!(line, ",\"asn\":{}", asn);
+    }
+    line.push_str("}\n");
+    line
+}
+
+/// Access log file being written.
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl Writer {
+    fn open(path: PathBuf, rotation: Rotation) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open access log {:?}", path))?;
+        let size = file.metadata()?.len();
+        Ok(Writer {
+            path,
+            rotation,
+            file: BufWriter::new(file),
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    /// Write the lines received until the sender is dropped, flushing whenever none is waiting.
+    fn run(mut self, rx: Receiver<String>) {
+        loop {
+            let line = match rx.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => {
+                    if let Err(err) = self.file.flush() {
+                        log::warn!("could not write access log: {}", err);
+                    }
+                    match rx.recv() {
+                        Ok(line) => line,
+                        Err(_) => return,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            if let Err(err) = self.write(&line) {
+                log::warn!("could not write access log: {}", err);
+            }
+        }
+        let _ = self.file.flush();
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.should_rotate(line.len() as u64) {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the file must be rotated before writing `len` more bytes to it.
+    fn should_rotate(&self, len: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_large =
+            matches!(self.rotation.max_size, Some(max_size) if self.size + len > max_size);
+        let too_old =
+            matches!(self.rotation.max_age, Some(max_age) if self.opened_at.elapsed() >= max_age);
+        too_large || too_old
+    }
+
+    /// Rename the file aside and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", millis));
+        fs::rename(&self.path, &rotated)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.opened_at = Instant::now();
+        if self.rotation.keep > 0 {
+            if let Err(err) = self.remove_old() {
+                log::warn!("could not remove old access logs: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the rotated files beyond the number kept.
+    fn remove_old(&self) -> io::Result<()> {
+        for old in rotated_files(&self.path)?
+            .into_iter()
+            .rev()
+            .skip(self.rotation.keep)
+        {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rotated files of the access log, or of another file rotated the same way, at `path`,
+/// oldest first.
+pub(crate) fn rotated_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(Vec::new()),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut prefix = OsString::from(name);
+    prefix.push(".");
+    let prefix = prefix.to_string_lossy().into_owned();
+    let mut rotated: Vec<(u128, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name();
+            let millis = file_name.to_str()?.strip_prefix(&prefix)?.parse().ok()?;
+            Some((millis, entry.path()))
+        })
+        .collect();
+    rotated.sort();
+    Ok(rotated.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_entry, rotated_files, Entry, Rotation, Writer};
+    use crate::server::Transport;
+
+    #[test]
+    fn formats_entries() {
+        let entry = Entry {
+            transport: Transport::Udp,
+            src_addr: "192.0.2.1:5000".parse().unwrap(),
+            method: "binding",
+            class: "request",
+            outcome: "success",
+            bytes_in: 20,
+            bytes_out: 32,
+            latency: Duration::from_micros(42),
+            country: None,
+            asn: None,
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1700000000);
+        assert_eq!(
+            format_entry(&entry, time),
+            "{\"timestamp\":\"2023-11-14T22:13:20.000Z\",\"transport\":\"udp\",\
+             \"src_addr\":\"192.0.2.1:5000\",\"method\":\"binding\",\"class\":\"request\",\
+             \"outcome\":\"success\",\"bytes_in\":20,\"bytes_out\":32,\"latency_us\":42}\n"
+        );
+
+        // With the origin of the source.
+        let entry = Entry {
+            country: Some("FR"),
+            asn: Some(3215),
+            ..entry
+        };
+        assert!(format_entry(&entry, time).ends_with(",\"country\":\"FR\",\"asn\":3215}\n"));
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_latest_files() {
+        let dir = std::env::temp_dir().join(format!("stunner-access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotation = Rotation {
+            max_size: Some(10),
+            max_age: None,
+            keep: 2,
+        };
+        let mut writer = Writer::open(path.clone(), rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            writer.write(line).unwrap();
+            // Rotated files are named after the time in milliseconds.
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        writer.file.into_inner().unwrap();
+
+        let rotated = rotated_files(&path).unwrap();
+        let contents: Vec<String> = rotated
+            .iter()
+            .map(|path| std::fs::read_to_string(path).unwrap())
+            .collect();
+        assert_eq!(contents, ["second\n", "third\n"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Access control lists of source IP ranges.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+
+/// An IP address range in CIDR notation, e.g. 10.0.0.0/8 or 2001:db8::/32.
+/// A single address without prefix length covers only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is part of the range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address in {:?}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .with_context(|| format!("invalid prefix length in {:?}", s))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            bail!("prefix length of {:?} exceeds {}", s, max_len);
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+/// Whether the first `len` bits of `a` and `b` are equal.
+fn prefix_eq(a: &[u8], b: &[u8], len: u8) -> bool {
+    let (bytes, bits) = ((len / 8) as usize, len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+/// Source IP ranges allowed to use the server and denied from it.
+#[derive(Debug, Default)]
+pub struct Acl {
+    /// When not empty, only sources in these ranges are permitted.
+    allow: Vec<Cidr>,
+    /// Sources in these ranges are never permitted.
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Acl { allow, deny }
+    }
+
+    /// Whether messages from `ip` may be handled, deny entries take precedence.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Whether every source is permitted.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, Cidr};
+
+    fn cidrs(values: &[&str]) -> Vec<Cidr> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/33".parse().unwrap();
+        assert!(cidr.contains("2001:db8:7fff::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db8:8000::1".parse().unwrap()));
+        assert!(!cidr.contains("10.0.0.1".parse().unwrap()));
+
+        let cidr: Cidr = "192.0.2.1".parse().unwrap();
+        assert!(cidr.contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr.contains("192.0.2.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("0.0.0.0/0".parse::<Cidr>().is_ok());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let acl = Acl::new(cidrs(&["10.0.0.0/8"]), cidrs(&["10.0.0.0/24"]));
+        assert!(acl.permits("10.1.0.1".parse().unwrap()));
+        assert!(!acl.permits("10.0.0.1".parse().unwrap()));
+        assert!(!acl.permits("192.0.2.1".parse().unwrap()));
+
+        let acl = Acl::new(vec![], cidrs(&["192.0.2.0/24"]));
+        assert!(acl.permits("10.0.0.1".parse().unwrap()));
+        assert!(!acl.permits("192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,387 @@
+//! TLS certificate obtained and renewed from an ACME certificate authority such as Let's Encrypt,
+//! proving the control of the domains by answering its HTTP-01 challenges,
+//! see https://datatracker.ietf.org/doc/html/rfc8555
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use instant_acme::{
+    Account, AuthorizationStatus, ChallengeType, Identifier, Key, NewAccount, NewOrder,
+    OrderStatus, RetryPolicy,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+use crate::http::{read_head, response};
+use crate::server::Server;
+use crate::tls::Certificate;
+
+/// Path of the HTTP-01 challenges, followed by their token.
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Longest time between two checks of the expiry of the certificate.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Time to wait before trying again when the certificate could not be obtained.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Client of an ACME certificate authority, keeping the account key and the certificate in a
+/// state directory.
+pub struct Acme {
+    domains: Vec<String>,
+    contact: Vec<String>,
+    dir: PathBuf,
+    directory_url: String,
+    /// Key authorizations of the pending challenges, by token.
+    challenges: Mutex<HashMap<String, String>>,
+}
+
+impl Acme {
+    pub fn new(
+        domains: Vec<String>,
+        email: Option<&str>,
+        dir: PathBuf,
+        directory_url: String,
+    ) -> Arc<Self> {
+        Arc::new(Acme {
+            domains,
+            contact: email
+                .map(|email| format!("mailto:{}", email))
+                .into_iter()
+                .collect(),
+            dir,
+            directory_url,
+            challenges: Mutex::default(),
+        })
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join("key.pem")
+    }
+
+    fn account_key_path(&self) -> PathBuf {
+        self.dir.join("account.pem")
+    }
+
+    /// Certificate obtained previously, or a self-signed one until it's obtained. The state
+    /// directory is created if needed.
+    pub fn certificate(&self) -> Result<Arc<Certificate>> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create {:?}", self.dir))?;
+        if self.cert_path().exists() {
+            Certificate::load(&self.cert_path(), &self.key_path(), &[])
+        } else {
+            Certificate::self_signed(&self.cert_path(), &self.key_path(), &self.domains)
+        }
+    }
+
+    /// Answer the challenges on `listener` and obtain a certificate whenever `certificate`
+    /// expires soon, reloading it once written, until the server shuts down.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        certificate: Arc<Certificate>,
+        server: Arc<Server>,
+    ) -> Result<()> {
+        log::info!(
+            "serving ACME challenges over HTTP on addr: {}",
+            listener.local_addr()?
+        );
+        tokio::spawn(self.clone().serve_challenges(listener, server.clone()));
+        loop {
+            let mut wait = match renewal_time(&self.cert_path()) {
+                Ok(time) => time.duration_since(SystemTime::now()).unwrap_or_default(),
+                Err(err) => {
+                    log::debug!("no certificate to renew: {:#}", err);
+                    Duration::ZERO
+                }
+            }
+            .min(CHECK_INTERVAL);
+            if wait.is_zero() {
+                let obtained = tokio::select! {
+                    obtained = self.obtain() => obtained,
+                    _ = server.stopped() => return Ok(()),
+                };
+                self.challenges.lock().unwrap().clear();
+                match obtained.and_then(|()| certificate.reload()) {
+                    Ok(()) => {
+                        log::info!("obtained a certificate for {:?}", self.domains);
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "could not obtain a certificate for {:?}: {:#}",
+                            self.domains,
+                            err
+                        );
+                        wait = RETRY_INTERVAL;
+                    }
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = server.stopped() => return Ok(()),
+            }
+        }
+    }
+
+    /// Order a certificate for the domains and write it along with its private key to the
+    /// state directory.
+    async fn obtain(&self) -> Result<()> {
+        let account = self.account().await?;
+        let identifiers: Vec<_> = self
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("authorization is {:?}", status),
+            }
+            let mut challenge = authorization
+                .challenge(ChallengeType::Http01)
+                .context("no HTTP-01 challenge offered")?;
+            self.challenges.lock().unwrap().insert(
+                challenge.token.clone(),
+                challenge.key_authorization().as_str().to_owned(),
+            );
+            challenge.set_ready().await?;
+        }
+
+        let status = order.poll_ready(&RetryPolicy::default()).await?;
+        ensure!(status == OrderStatus::Ready, "order is {:?}", status);
+        let key = order.finalize().await?;
+        let chain = order.poll_certificate(&RetryPolicy::default()).await?;
+        write_private(&self.key_path(), key.as_bytes())?;
+        std::fs::write(self.cert_path(), chain)
+            .with_context(|| format!("could not write {:?}", self.cert_path()))?;
+        Ok(())
+    }
+
+    /// Account of the server at the certificate authority, created along with its key on first
+    /// use.
+    async fn account(&self) -> Result<Account> {
+        let path = self.account_key_path();
+        if path.exists() {
+            let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&path)?))
+                .with_context(|| format!("could not parse private key from {:?}", path))?;
+            let key = match key {
+                Some(PrivateKeyDer::Pkcs8(key)) => key,
+                _ => bail!("no PKCS#8 private key found in {:?}", path),
+            };
+            let (account, _) = Account::builder()?
+                .from_key(
+                    (Key::from_pkcs8_der(key.clone_key())?, key.into()),
+                    self.directory_url.clone(),
+                )
+                .await?;
+            return Ok(account);
+        }
+
+        let contact: Vec<_> = self.contact.iter().map(String::as_str).collect();
+        let (account, credentials) = Account::builder()?
+            .create(
+                &NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.directory_url.clone(),
+                None,
+            )
+            .await?;
+        log::info!("created ACME account {}", account.id());
+        let der = credentials.private_key().secret_pkcs8_der();
+        write_private(&path, pem("PRIVATE KEY", der).as_bytes())?;
+        Ok(account)
+    }
+
+    /// Answer the HTTP-01 challenges, until the server shuts down.
+    async fn serve_challenges(self: Arc<Self>, listener: TcpListener, server: Arc<Server>) {
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        log::warn!("could not accept ACME challenge request: {}", err);
+                        continue;
+                    }
+                },
+                _ = server.stopped() => return,
+            };
+            let acme = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = acme.answer_challenge(stream).await {
+                    log::debug!(
+                        "ACME challenge request from {:?} failed: {}",
+                        peer_addr,
+                        err
+                    );
+                }
+            });
+        }
+    }
+
+    /// Answer a single HTTP request for a challenge and close the connection.
+    async fn answer_challenge(&self, mut stream: TcpStream) -> Result<()> {
+        let buf = match read_head(&mut stream).await? {
+            Some(buf) => buf,
+            None => return Ok(()),
+        };
+        let key_authorization = buf
+            .strip_prefix(format!("GET {}", CHALLENGE_PATH).as_bytes())
+            .and_then(|rest| rest.split(|&byte| byte == b' ').next())
+            .and_then(|token| {
+                let challenges = self.challenges.lock().unwrap();
+                challenges.get(&*String::from_utf8_lossy(token)).cloned()
+            });
+        let response = match key_authorization {
+            Some(key_authorization) => response("200 OK", "text/plain", &key_authorization),
+            None => response("404 Not Found", "text/plain", ""),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Time from which the PEM encoded certificate at `path` is renewed, once two thirds of its
+/// validity have passed as recommended by Let's Encrypt.
+fn renewal_time(path: &Path) -> Result<SystemTime> {
+    let pem = std::fs::read(path).with_context(|| format!("could not read {:?}", path))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)
+        .with_context(|| format!("could not parse certificate from {:?}", path))?;
+    let cert = pem
+        .parse_x509()
+        .with_context(|| format!("could not parse certificate from {:?}", path))?;
+    let validity = cert.validity();
+    let not_before = validity.not_before.timestamp();
+    let not_after = validity.not_after.timestamp();
+    let renewal = not_after - (not_after - not_before) / 3;
+    Ok(UNIX_EPOCH + Duration::from_secs(renewal.max(0) as u64))
+}
+
+/// PEM encoding of `der` with the given label.
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Write the private key `contents` to `path`, readable by the current user only.
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("could not write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use rcgen::{date_time_ymd, CertificateParams, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{pem, renewal_time, Acme};
+    use crate::server::Server;
+
+    #[test]
+    fn renews_after_two_thirds_of_the_validity() {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_before = date_time_ymd(2024, 1, 1);
+        params.not_after = date_time_ymd(2024, 4, 1);
+        let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+        let path = std::env::temp_dir().join(format!("stunner-acme-{}.pem", std::process::id()));
+        std::fs::write(&path, cert.pem()).unwrap();
+        let renewal = renewal_time(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Two thirds of the 91 days from 2024-01-01 to 2024-04-01.
+        let not_before = UNIX_EPOCH + Duration::from_secs(1704067200);
+        assert_eq!(
+            renewal.duration_since(not_before).unwrap(),
+            Duration::from_secs(91 * 24 * 60 * 60 * 2 / 3)
+        );
+    }
+
+    #[test]
+    fn encodes_pem() {
+        let der: Vec<u8> = (0..100).collect();
+        let encoded = pem("PRIVATE KEY", &der);
+        assert!(encoded.lines().all(|line| line.len() <= 64));
+        let item = rustls_pemfile::read_one(&mut BufReader::new(encoded.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert!(
+            matches!(item, rustls_pemfile::Item::Pkcs8Key(key) if key.secret_pkcs8_der() == der)
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pending_challenges() {
+        let acme = Acme::new(
+            vec!["localhost".to_string()],
+            None,
+            std::env::temp_dir(),
+            String::new(),
+        );
+        acme.challenges
+            .lock()
+            .unwrap()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::default());
+        tokio::spawn(acme.serve_challenges(listener, server.clone()));
+
+        for (path, expected) in [
+            ("/.well-known/acme-challenge/token", "200 OK"),
+            ("/.well-known/acme-challenge/other", "404 Not Found"),
+            ("/token", "404 Not Found"),
+        ] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", expected)));
+            assert_eq!(
+                response.ends_with("\r\n\r\ntoken.thumbprint"),
+                expected == "200 OK"
+            );
+        }
+        server.shutdown();
+    }
+}
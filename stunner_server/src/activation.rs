@@ -0,0 +1,96 @@
+//! systemd socket activation, sockets bound by the service manager are inherited as file
+//! descriptors, see https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use socket2::{Socket, Type};
+
+/// Sockets passed by the service manager and not used yet.
+static INHERITED: Mutex<Vec<Socket>> = Mutex::new(Vec::new());
+
+/// Take over the sockets passed with `LISTEN_PID` and `LISTEN_FDS`, if any, returning how many.
+/// Must be called once, before any socket is bound.
+#[cfg(unix)]
+pub fn inherit() -> usize {
+    use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+    /// First file descriptor passed by the service manager.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let count = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    let mut inherited = INHERITED.lock().unwrap();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd {
+        // SAFETY: the service manager passed the ownership of the file descriptor to this
+        // process, nothing else in the process uses it. It is released below if it turns out
+        // not to be a socket.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        match socket.local_addr().map(|addr| addr.as_socket()) {
+            Ok(Some(_)) => inherited.push(socket),
+            _ => {
+                log::warn!(
+                    "ignoring inherited file descriptor {}, not an IP socket",
+                    fd
+                );
+                // Leave the file descriptor open, it may be used by something else.
+                let _ = socket.into_raw_fd();
+            }
+        }
+    }
+    inherited.len()
+}
+
+#[cfg(not(unix))]
+pub fn inherit() -> usize {
+    0
+}
+
+/// Number of file descriptors passed to this process according to the values of the
+/// `LISTEN_PID` and `LISTEN_FDS` environment variables.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) if listen_pid.parse() == Ok(pid) => {
+            listen_fds.parse().unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Take the inherited socket of the given type bound to `addr`, if any.
+pub fn take(addr: SocketAddr, ty: Type) -> Option<Socket> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let position = inherited.iter().position(|socket| {
+        socket.r#type().ok() == Some(ty)
+            && socket.local_addr().ok().and_then(|local| local.as_socket()) == Some(addr)
+    })?;
+    Some(inherited.remove(position))
+}
+
+/// Addresses of the inherited sockets of the given type not used yet.
+pub fn addrs(ty: Type) -> Vec<SocketAddr> {
+    INHERITED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|socket| socket.r#type().ok() == Some(ty))
+        .filter_map(|socket| socket.local_addr().ok()?.as_socket())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::listen_fds;
+
+    #[test]
+    fn counts_file_descriptors_passed_to_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("two"), 42), 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! Admin API to inspect and control the running server, JSON over HTTP. Requests must carry the
+//! admin token in an `Authorization: Bearer <token>` header.
+//!
+//! - `GET /stats`: message counters, number of allocations and rate limit
+//! - `GET /allocations`: active TURN allocations
+//! - `DELETE /allocations/<relayed address>`: delete an allocation
+//! - `GET /bans`: banned IP addresses and the UNIX time their ban ends
+//! - `PUT /bans/<ip>[/<seconds>]`: ban an IP address, for an hour by default
+//! - `DELETE /bans/<ip>`: lift a ban
+//! - `PUT /rate-limit/<messages per second>`, `DELETE /rate-limit`: change the rate limit per
+//!   source IP
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::http;
+use crate::logging::json_string;
+use crate::server::Server;
+use crate::turn::Direction;
+
+/// How long an IP address is banned when no duration is given.
+const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(3600);
+
+/// Serve the admin API of `server` to the clients with `token`, until it shuts down.
+pub async fn serve(listener: TcpListener, token: String, server: Arc<Server>) -> Result<()> {
+    log::info!("serving the admin API on addr: {}", listener.local_addr()?);
+    let token = Arc::new(token);
+    let connections = Arc::new(Semaphore::new(http::MAX_CONNECTIONS));
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = server.stopped() => return Ok(()),
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            log::debug!(
+                "closing admin connection from {:?}, too many open",
+                peer_addr
+            );
+            continue;
+        };
+        let server = server.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_connection(stream, &token, &server).await {
+                log::debug!("admin request from {:?} failed: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+/// Answer a single request and close the connection.
+async fn handle_connection(mut stream: TcpStream, token: &str, server: &Server) -> Result<()> {
+    let head = match http::read_head(&mut stream).await? {
+        Some(head) => String::from_utf8_lossy(&head).into_owned(),
+        None => return Ok(()),
+    };
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+    let authorized = lines.any(|line| match line.split_once(':') {
+        Some((name, value)) => {
+            name.eq_ignore_ascii_case("authorization")
+                && is_token(value.trim().strip_prefix("Bearer "), token)
+        }
+        None => false,
+    });
+
+    let (status, body) = if authorized {
+        route(method, path, server).await
+    } else {
+        ("401 Unauthorized", String::new())
+    };
+    log::info!("admin request {} {}: {}", method, path, status);
+    let response = http::response(status, "application/json", &body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Whether the bearer token of a request is `token`, compared in constant time.
+fn is_token(given: Option<&str>, token: &str) -> bool {
+    match given {
+        Some(given) if given.len() == token.len() => {
+            given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+/// Status and JSON body of the response to an authorized request.
+async fn route(method: &str, path: &str, server: &Server) -> (&'static str, String) {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["stats"]) => ("200 OK", stats(server)),
+        ("GET", ["allocations"]) => ("200 OK", allocations(server)),
+        ("DELETE", ["allocations", relayed_addr]) => {
+            let relayed_addr = match relayed_addr.parse() {
+                Ok(addr) => addr,
+                Err(_) => return bad_request("invalid relayed address"),
+            };
+            match server.turn() {
+                Some(turn) if turn.release_relayed(relayed_addr) => no_content(),
+                _ => ("404 Not Found", String::new()),
+            }
+        }
+        ("GET", ["bans"]) => ("200 OK", bans(server)),
+        ("PUT", ["bans", ip, duration @ ..]) if duration.len() <= 1 => {
+            let ip = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => return bad_request("invalid IP address"),
+            };
+            let duration = match duration.first().map(|seconds| seconds.parse()) {
+                Some(Ok(seconds)) => Duration::from_secs(seconds),
+                Some(Err(_)) => return bad_request("invalid number of seconds"),
+                None => DEFAULT_BAN_DURATION,
+            };
+            match server.state().ban(ip, duration).await {
+                Ok(()) => no_content(),
+                Err(err) => server_error(err),
+            }
+        }
+        ("DELETE", ["bans", ip]) => {
+            let ip = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => return bad_request("invalid IP address"),
+            };
+            match server.state().unban(ip).await {
+                Ok(true) => no_content(),
+                Ok(false) => ("404 Not Found", String::new()),
+                Err(err) => server_error(err),
+            }
+        }
+        ("PUT", ["rate-limit", per_second]) => match per_second.parse() {
+            Ok(per_second) if per_second > 0 => {
+                server.set_rate_limit(Some(per_second));
+                no_content()
+            }
+            _ => bad_request("invalid number of messages per second"),
+        },
+        ("DELETE", ["rate-limit"]) => {
+            server.set_rate_limit(None);
+            no_content()
+        }
+        _ => ("404 Not Found", String::new()),
+    }
+}
+
+fn stats(server: &Server) -> String {
+    let mut out = String::from("{");
+    for (name, value) in server.stats().counters() {
+        let _ = write!(out, "{}:{},", json_string(name), value);
+    }
+    let allocations = server.turn().map_or(0, |turn| turn.allocation_count());
+    let _ = write!(out, "\"allocations\":{},\"rate_limit\":", allocations);
+    match server.rate_limit() {
+        Some(per_second) => {
+            let _ = write!(out, "{}}}", per_second);
+        }
+        None => out.push_str("null}"),
+    }
+    out
+}
+
+fn allocations(server: &Server) -> String {
+    let allocations = server.turn().map(|turn| turn.allocations());
+    let objects: Vec<String> = allocations
+        .unwrap_or_default()
+        .into_iter()
+        .map(|allocation| {
+            format!(
+                "{{\"client\":{},\"server\":{},\"transport\":{},\"relayed_addr\":{},\
+                 \"relay_transport\":{},\"expires_in\":{},\"bytes_to_peer\":{},\
+                 \"bytes_to_client\":{},\"packets_to_peer\":{},\"packets_to_client\":{}}}",
+                json_string(&allocation.five_tuple.client.to_string()),
+                json_string(&allocation.five_tuple.server.to_string()),
+                json_string(allocation.five_tuple.transport.as_str()),
+                json_string(&allocation.relayed_addr.to_string()),
+                json_string(if allocation.tcp { "tcp" } else { "udp" }),
+                allocation.expires_in.as_secs(),
+                allocation.bytes[Direction::ToPeer as usize],
+                allocation.bytes[Direction::ToClient as usize],
+                allocation.packets[Direction::ToPeer as usize],
+                allocation.packets[Direction::ToClient as usize],
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(","))
+}
+
+fn bans(server: &Server) -> String {
+    let objects: Vec<String> = server
+        .state()
+        .bans()
+        .into_iter()
+        .map(|(ip, ends_at)| {
+            let ends_at = ends_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!(
+                "{{\"ip\":{},\"ends_at\":{}}}",
+                json_string(&ip.to_string()),
+                ends_at.as_secs()
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(","))
+}
+
+fn no_content() -> (&'static str, String) {
+    ("204 No Content", String::new())
+}
+
+fn bad_request(error: &str) -> (&'static str, String) {
+    (
+        "400 Bad Request",
+        format!("{{\"error\":{}}}", json_string(error)),
+    )
+}
+
+fn server_error(err: anyhow::Error) -> (&'static str, String) {
+    log::error!("admin request failed: {:#}", err);
+    let error = json_string(&format!("{:#}", err));
+    (
+        "500 Internal Server Error",
+        format!("{{\"error\":{}}}", error),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::server::Server;
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, token: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\r\n",
+            method, path, token
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn bans_addresses_and_sets_rate_limits() {
+        let server = Arc::new(Server::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, "token".into(), server.clone()));
+
+        let response = request(addr, "GET", "/stats", "wrong").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        let response = request(addr, "GET", "/stats", "token").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\"allocations\":0,\"rate_limit\":null}"));
+        let response = request(addr, "GET", "/allocations", "token").await;
+        assert!(response.ends_with("\r\n\r\n[]"));
+
+        let response = request(addr, "PUT", "/bans/192.0.2.1/60", "token").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(server.state().is_banned("192.0.2.1".parse().unwrap()));
+        let response = request(addr, "GET", "/bans", "token").await;
+        assert!(response.contains("[{\"ip\":\"192.0.2.1\",\"ends_at\":"));
+        let response = request(addr, "DELETE", "/bans/192.0.2.1", "token").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!server.state().is_banned("192.0.2.1".parse().unwrap()));
+        let response = request(addr, "DELETE", "/bans/192.0.2.1", "token").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "PUT", "/bans/example.com", "token").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = request(addr, "PUT", "/rate-limit/50", "token").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(server.rate_limit(), Some(50));
+        request(addr, "DELETE", "/rate-limit", "token").await;
+        assert_eq!(server.rate_limit(), None);
+    }
+}
@@ -0,0 +1,53 @@
+//! CPU affinity of threads on Linux, to pin the worker threads of the runtime each to its own
+//! core on dedicated hosts, sparing them the migrations and cache misses.
+
+use std::io;
+use std::mem;
+
+/// The cores the calling thread may run on, in order.
+pub fn cores() -> io::Result<Vec<usize>> {
+    // SAFETY: all zeros is an empty CPU set.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    // SAFETY: the set is of the given size and outlives the call.
+    let result = unsafe { libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        // SAFETY: the core is within the set.
+        .filter(|core| unsafe { libc::CPU_ISSET(*core, &set) })
+        .collect())
+}
+
+/// Only let the calling thread run on `core`.
+pub fn pin(core: usize) -> io::Result<()> {
+    // SAFETY: all zeros is an empty CPU set.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    // SAFETY: CPU_SET checks the core is within the set.
+    unsafe { libc::CPU_SET(core, &mut set) };
+    // SAFETY: the set is of the given size and outlives the call.
+    let result = unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn pins_threads_to_a_core() {
+        let cores = super::cores().unwrap();
+        assert!(!cores.is_empty());
+        let last = *cores.last().unwrap();
+        let pinned = std::thread::spawn(move || {
+            super::pin(last).unwrap();
+            super::cores().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(pinned, vec![last]);
+        // Other threads are left alone.
+        assert_eq!(super::cores().unwrap(), cores);
+    }
+}
@@ -0,0 +1,145 @@
+//! Handlers of attributes the server doesn't know itself, so that applications can accept
+//! additional attributes in requests and add their own to the responses, e.g. echoing ORIGIN
+//! or vendor attributes, see https://datatracker.ietf.org/doc/html/rfc5389#section-18.2
+
+use anyhow::{bail, Context, Result};
+
+use crate::message::{Attribute, Message};
+
+/// Handler of the attributes of a type in requests.
+pub trait AttributeHandler: Send + Sync {
+    /// Type of the attributes handled, comprehension-required types aren't answered with an
+    /// UNKNOWN-ATTRIBUTES error once handled.
+    fn kind(&self) -> u16;
+
+    /// Handle the attribute with `value` of `request`, adding attributes to its `response` if
+    /// needed.
+    fn handle(&self, value: &[u8], request: &Message, response: &mut Message);
+}
+
+/// Echo the attribute back in the response, as ORIGIN may be.
+pub struct Echo(pub u16);
+
+impl AttributeHandler for Echo {
+    fn kind(&self) -> u16 {
+        self.0
+    }
+
+    fn handle(&self, value: &[u8], _request: &Message, response: &mut Message) {
+        response.attributes.push(Attribute {
+            kind: self.0,
+            value: value.to_vec(),
+        });
+    }
+}
+
+/// Attributes handled on top of the ones the server knows, and attributes added to every
+/// response.
+#[derive(Default)]
+pub struct Attributes {
+    handlers: Vec<Box<dyn AttributeHandler>>,
+    extra: Vec<(u16, Vec<u8>)>,
+}
+
+impl Attributes {
+    /// Handle the attributes of the type of `handler` with it.
+    pub fn register(mut self, handler: Box<dyn AttributeHandler>) -> Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Add an attribute with `kind` and `value` to every response.
+    pub fn append(mut self, kind: u16, value: Vec<u8>) -> Self {
+        self.extra.push((kind, value));
+        self
+    }
+
+    /// Whether no attribute is handled nor added.
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty() && self.extra.is_empty()
+    }
+
+    /// Types of the attributes handled.
+    pub fn known(&self) -> impl Iterator<Item = u16> + '_ {
+        self.handlers.iter().map(|handler| handler.kind())
+    }
+
+    /// Pass the attributes of `request` to their handlers and add the extra attributes to its
+    /// `response`.
+    pub fn apply(&self, request: &Message, response: &mut Message) {
+        for handler in &self.handlers {
+            if let Some(value) = request.get(handler.kind()) {
+                handler.handle(value, request, response);
+            }
+        }
+        for (kind, value) in &self.extra {
+            response.attributes.push(Attribute {
+                kind: *kind,
+                value: value.clone(),
+            });
+        }
+    }
+}
+
+/// Parse an attribute type, in hexadecimal with a leading 0x or in decimal.
+pub fn parse_kind(value: &str) -> Result<u16> {
+    let kind = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    kind.with_context(|| format!("invalid attribute type {:?}", value))
+}
+
+/// Parse an attribute given as `type=value`, with its value in hexadecimal.
+pub fn parse_attribute(value: &str) -> Result<(u16, Vec<u8>)> {
+    let (kind, hex) = match value.split_once('=') {
+        Some(attribute) => attribute,
+        None => bail!("expected type=value, got {:?}", value),
+    };
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        bail!("invalid attribute value {:?}", hex);
+    }
+    let value = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid attribute value {:?}", hex))?;
+    Ok((parse_kind(kind)?, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_attribute, parse_kind, Attributes, Echo};
+    use crate::message::{methods, Class, Message};
+
+    #[test]
+    fn echoes_and_appends_attributes() {
+        let attributes = Attributes::default()
+            .register(Box::new(Echo(0x802F)))
+            .append(0xC001, vec![1, 2]);
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x802F, b"https://example.com".to_vec());
+        let mut response = request.success_response();
+        attributes.apply(&request, &mut response);
+        assert_eq!(response.get(0x802F), Some(&b"https://example.com"[..]));
+        assert_eq!(response.get(0xC001), Some(&[1, 2][..]));
+
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let mut response = request.success_response();
+        attributes.apply(&request, &mut response);
+        assert_eq!(response.get(0x802F), None);
+    }
+
+    #[test]
+    fn parses_attributes() {
+        assert_eq!(parse_kind("0x802F").unwrap(), 0x802F);
+        assert_eq!(parse_kind("32815").unwrap(), 0x802F);
+        assert_eq!(
+            parse_attribute("0xC001=00ff").unwrap(),
+            (0xC001, vec![0, 255])
+        );
+        assert!(parse_attribute("0xC001=0").is_err());
+        assert!(parse_attribute("0xC001").is_err());
+        assert!(parse_kind("origin").is_err());
+    }
+}
@@ -0,0 +1,781 @@
+//! Credential mechanisms, see https://datatracker.ietf.org/doc/html/rfc8489#section-9
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use sha2::Sha256;
+
+#[cfg(feature = "ldap")]
+use crate::ldap::LdapDirectory;
+use crate::message::{attributes, hex, Integrity, Message};
+#[cfg(feature = "sql")]
+use crate::userdb::UserDatabase;
+#[cfg(feature = "webhook")]
+use crate::webhook::AuthWebhook;
+
+/// How long a nonce handed out in a challenge remains valid by default.
+pub const DEFAULT_NONCE_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Number of bytes of the nonce signature kept in the nonce.
+const NONCE_SIGNATURE_SIZE: usize = 8;
+
+/// Prefix of the nonces announcing the security features of RFC 8489 supported, password
+/// algorithms and username anonymity, see https://datatracker.ietf.org/doc/html/rfc8489#section-9.2
+const NONCE_COOKIE: &str = "obMatJos2AAAD";
+
+/// Password algorithms offered in the challenges, in order of preference.
+const PASSWORD_ALGORITHMS: [PasswordAlgorithm; 2] =
+    [PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5];
+
+/// Users allowed to authenticate and their passwords.
+pub type Users = HashMap<String, String>;
+
+/// Credentials a request was authenticated with: its user, the key its response must be signed
+/// with and the integrity attribute to sign it with, the one of the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub key: Vec<u8>,
+    pub integrity: Integrity,
+}
+
+/// Algorithm a long-term credential key is derived from the password with,
+/// see https://datatracker.ietf.org/doc/html/rfc8489#section-18.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl PasswordAlgorithm {
+    fn code(self) -> u16 {
+        match self {
+            PasswordAlgorithm::Md5 => 0x0001,
+            PasswordAlgorithm::Sha256 => 0x0002,
+        }
+    }
+
+    /// Decode a PASSWORD-ALGORITHM attribute, `None` for the algorithms not supported.
+    fn decode(value: &[u8]) -> Option<Self> {
+        match value.get(..2)? {
+            [0x00, 0x01] => Some(PasswordAlgorithm::Md5),
+            [0x00, 0x02] => Some(PasswordAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Encode as a PASSWORD-ALGORITHM attribute or entry of PASSWORD-ALGORITHMS, neither
+    /// algorithm has parameters.
+    fn encode(self) -> [u8; 4] {
+        let [high, low] = self.code().to_be_bytes();
+        [high, low, 0, 0]
+    }
+
+    /// Long-term credential key, the hash of username ":" realm ":" password.
+    pub fn key(self, username: &str, realm: &str, password: &str) -> Vec<u8> {
+        let input = format!("{}:{}:{}", username, realm, password);
+        match self {
+            PasswordAlgorithm::Md5 => Md5::digest(input).to_vec(),
+            PasswordAlgorithm::Sha256 => Sha256::digest(input).to_vec(),
+        }
+    }
+}
+
+/// How requests are authenticated.
+pub enum Auth {
+    LongTerm(Box<LongTermAuth>),
+    ShortTerm(ShortTermAuth),
+}
+
+impl Auth {
+    /// Authenticate a request from `client` given its encoded form `buf`, returning the key its
+    /// response must be signed with, or the error response to send back.
+    pub async fn authenticate(
+        &self,
+        request: &Message,
+        buf: &[u8],
+        client: IpAddr,
+    ) -> Result<Credentials, Message> {
+        match self {
+            Auth::LongTerm(auth) => auth.authenticate(request, buf, client).await,
+            Auth::ShortTerm(auth) => auth.authenticate(request, buf),
+        }
+    }
+
+    /// Realm of long-term credentials, short-term ones have none.
+    pub fn realm(&self) -> Option<&str> {
+        match self {
+            Auth::LongTerm(auth) => Some(&auth.realm),
+            Auth::ShortTerm(_) => None,
+        }
+    }
+}
+
+/// Authenticates requests with a single short-term username and password, as exchanged by ICE
+/// agents for their connectivity checks.
+pub struct ShortTermAuth {
+    username: String,
+    password: String,
+}
+
+impl ShortTermAuth {
+    pub fn new(username: String, password: String) -> Self {
+        ShortTermAuth { username, password }
+    }
+
+    /// Authenticate a request given its encoded form `buf`, returning the key its response must
+    /// be signed with, or the error response to send back,
+    /// see https://datatracker.ietf.org/doc/html/rfc8489#section-9.1.3
+    pub fn authenticate(&self, request: &Message, buf: &[u8]) -> Result<Credentials, Message> {
+        let (username, integrity) = match (
+            request.get_str(attributes::USERNAME),
+            Integrity::of(request),
+        ) {
+            (Some(username), Some(integrity)) => (username, integrity),
+            _ => return Err(request.error_response(400, "Missing credentials")),
+        };
+        let key = self.password.as_bytes();
+        if username != self.username || !integrity.check(buf, key) {
+            return Err(request.error_response(401, "Unauthorized"));
+        }
+        Ok(Credentials {
+            username: username.to_string(),
+            key: key.to_vec(),
+            integrity,
+        })
+    }
+}
+
+/// Authenticates requests with the long-term credentials of the configured users, or with
+/// ephemeral ones derived from a shared secret.
+pub struct LongTermAuth {
+    realm: String,
+    users: Users,
+    /// Configured usernames by their USERHASH, SHA-256(username ":" realm).
+    userhashes: HashMap<Vec<u8>, String>,
+    /// Secret the passwords of ephemeral credentials are derived from, if any.
+    auth_secret: Option<String>,
+    /// Database the users not configured are looked up in, if any.
+    #[cfg(feature = "sql")]
+    database: Option<UserDatabase>,
+    /// HTTP endpoint the users not configured nor in the database are looked up from, if any.
+    #[cfg(feature = "webhook")]
+    webhook: Option<AuthWebhook>,
+    /// LDAP directory the users not found elsewhere are looked up in, if any.
+    #[cfg(feature = "ldap")]
+    directory: Option<LdapDirectory>,
+    /// Key signing the nonces, so that they can be verified without keeping them around.
+    secret: [u8; 16],
+    nonce_lifetime: Duration,
+}
+
+impl LongTermAuth {
+    pub fn new(realm: String, users: Users) -> Self {
+        let userhashes = users
+            .keys()
+            .map(|username| (userhash(username, &realm), username.clone()))
+            .collect();
+        LongTermAuth {
+            realm,
+            users,
+            userhashes,
+            auth_secret: None,
+            #[cfg(feature = "sql")]
+            database: None,
+            #[cfg(feature = "webhook")]
+            webhook: None,
+            #[cfg(feature = "ldap")]
+            directory: None,
+            secret: rand::random(),
+            nonce_lifetime: DEFAULT_NONCE_LIFETIME,
+        }
+    }
+
+    /// Hand out nonces valid for `lifetime`, requests signed with an expired one are answered
+    /// 438 Stale Nonce with a fresh one to retry with.
+    pub fn with_nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.nonce_lifetime = lifetime;
+        self
+    }
+
+    /// Sign the nonces with `secret`, so that the nonces handed out by the other instances
+    /// sharing it are valid too.
+    pub fn with_nonce_secret(mut self, secret: [u8; 16]) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// Also accept the ephemeral credentials of the TURN REST API, minted by applications
+    /// knowing `auth_secret`: the username is an expiry UNIX timestamp optionally followed by
+    /// `:` and a user id, the password base64(HMAC-SHA1(auth_secret, username)), see
+    /// https://datatracker.ietf.org/doc/html/draft-uberti-behave-turn-rest-00#section-2.2
+    pub fn with_auth_secret(mut self, auth_secret: Option<String>) -> Self {
+        self.auth_secret = auth_secret;
+        self
+    }
+
+    /// Also look up users in `database`, after the configured ones.
+    #[cfg(feature = "sql")]
+    pub fn with_database(mut self, database: Option<UserDatabase>) -> Self {
+        self.database = database;
+        self
+    }
+
+    /// Also look up users from `webhook`, after the configured ones and the database.
+    #[cfg(feature = "webhook")]
+    pub fn with_webhook(mut self, webhook: Option<AuthWebhook>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    /// Also look up users in the LDAP `directory`, after every other source.
+    #[cfg(feature = "ldap")]
+    pub fn with_directory(mut self, directory: Option<LdapDirectory>) -> Self {
+        self.directory = directory;
+        self
+    }
+
+    /// Authenticate a request from `client` given its encoded form `buf`, returning the key its
+    /// response must be signed with, or the error response to send back. The username may be
+    /// hidden in a USERHASH for the configured users,
+    /// see https://datatracker.ietf.org/doc/html/rfc8489#section-9.2.4
+    pub async fn authenticate(
+        &self,
+        request: &Message,
+        buf: &[u8],
+        client: IpAddr,
+    ) -> Result<Credentials, Message> {
+        let integrity = match Integrity::of(request) {
+            Some(integrity) => integrity,
+            None => return Err(self.challenge(request, client, 401, "Unauthorized")),
+        };
+        let username = match request.get(attributes::USERHASH) {
+            Some(userhash) => match self.userhashes.get(userhash) {
+                Some(username) => Some(username.as_str()),
+                None => return Err(self.challenge(request, client, 401, "Unauthorized")),
+            },
+            None => request.get_str(attributes::USERNAME),
+        };
+        let (username, realm, nonce) = match (
+            username,
+            request.get_str(attributes::REALM),
+            request.get_str(attributes::NONCE),
+        ) {
+            (Some(username), Some(realm), Some(nonce)) => (username, realm, nonce),
+            _ => return Err(request.error_response(400, "Missing credentials")),
+        };
+        if realm != self.realm || !self.is_valid_nonce(nonce, client) {
+            return Err(self.challenge(request, client, 438, "Stale Nonce"));
+        }
+        let algorithm = match password_algorithm(request) {
+            Some(algorithm) => algorithm,
+            None => return Err(request.error_response(400, "Bad Request")),
+        };
+        let key = match self.key(username, algorithm).await {
+            Ok(Some(key)) => key,
+            Ok(None) => return Err(self.challenge(request, client, 401, "Unauthorized")),
+            Err(err) => {
+                log::error!("could not look up user {:?}: {:#}", username, err);
+                return Err(request.error_response(500, "Server Error"));
+            }
+        };
+        if !integrity.check(buf, &key) {
+            return Err(self.challenge(request, client, 401, "Unauthorized"));
+        }
+        Ok(Credentials {
+            username: username.to_string(),
+            key,
+            integrity,
+        })
+    }
+
+    /// Key derived with `algorithm` of a configured user, of ephemeral credentials that haven't
+    /// expired, or of a user in the database, known to the webhook or in the LDAP directory.
+    async fn key(&self, username: &str, algorithm: PasswordAlgorithm) -> Result<Option<Vec<u8>>> {
+        if let Some(password) = self.password(username) {
+            return Ok(Some(algorithm.key(username, &self.realm, &password)));
+        }
+        #[cfg(feature = "sql")]
+        if let Some(database) = &self.database {
+            if let Some(key) = database.key(username, &self.realm, algorithm).await? {
+                return Ok(Some(key));
+            }
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = &self.webhook {
+            if let Some(key) = webhook.key(username, &self.realm, algorithm).await? {
+                return Ok(Some(key));
+            }
+        }
+        #[cfg(feature = "ldap")]
+        if let Some(directory) = &self.directory {
+            return directory.key(username, &self.realm, algorithm).await;
+        }
+        Ok(None)
+    }
+
+    /// Password of a configured user, or of ephemeral credentials that haven't expired.
+    fn password(&self, username: &str) -> Option<String> {
+        if let Some(password) = self.users.get(username) {
+            return Some(password.clone());
+        }
+        let auth_secret = self.auth_secret.as_ref()?;
+        let expires_at: u64 = username.split(':').next()?.parse().ok()?;
+        if UNIX_EPOCH + Duration::from_secs(expires_at) <= SystemTime::now() {
+            return None;
+        }
+        Some(ephemeral_password(auth_secret, username))
+    }
+
+    /// Error response carrying the realm, a fresh nonce for `client` to authenticate with and
+    /// the password algorithms offered.
+    fn challenge(&self, request: &Message, client: IpAddr, code: u16, reason: &str) -> Message {
+        request
+            .error_response(code, reason)
+            .add_attribute(attributes::REALM, self.realm.clone().into_bytes())
+            .add_attribute(attributes::NONCE, self.nonce(client).into_bytes())
+            .add_attribute(attributes::PASSWORD_ALGORITHMS, password_algorithms())
+    }
+
+    /// A nonce made of the cookie, its expiry time and a signature of both and of the client
+    /// address, so that it is only valid for that client.
+    fn nonce(&self, client: IpAddr) -> String {
+        let expires_at = (SystemTime::now() + self.nonce_lifetime)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let signed = format!("{}{:016x}", NONCE_COOKIE, expires_at);
+        let signature = self.mac(&signed, client).finalize().into_bytes();
+        format!("{}{}", signed, hex(&signature[..NONCE_SIGNATURE_SIZE]))
+    }
+
+    /// Whether `nonce` was handed out to `client` and hasn't expired, its signature being
+    /// checked in constant time.
+    fn is_valid_nonce(&self, nonce: &str, client: IpAddr) -> bool {
+        let signed_len = NONCE_COOKIE.len() + 16;
+        if nonce.len() != signed_len + NONCE_SIGNATURE_SIZE * 2
+            || !nonce.is_ascii()
+            || !nonce.starts_with(NONCE_COOKIE)
+        {
+            return false;
+        }
+        let (signed, signature) = nonce.split_at(signed_len);
+        let mut tag = [0; NONCE_SIGNATURE_SIZE];
+        for (i, byte) in tag.iter_mut().enumerate() {
+            match u8::from_str_radix(&signature[i * 2..i * 2 + 2], 16) {
+                Ok(value) => *byte = value,
+                Err(_) => return false,
+            }
+        }
+        if self
+            .mac(signed, client)
+            .verify_truncated_left(&tag)
+            .is_err()
+        {
+            return false;
+        }
+        match u64::from_str_radix(&signed[NONCE_COOKIE.len()..], 16) {
+            Ok(expires_at) => UNIX_EPOCH + Duration::from_secs(expires_at) > SystemTime::now(),
+            Err(_) => false,
+        }
+    }
+
+    /// HMAC of the `signed` part of a nonce and of the client address.
+    fn mac(&self, signed: &str, client: IpAddr) -> Hmac<Sha1> {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(signed.as_bytes());
+        match client {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac
+    }
+}
+
+/// PASSWORD-ALGORITHMS attribute offered in the challenges.
+fn password_algorithms() -> Vec<u8> {
+    PASSWORD_ALGORITHMS
+        .iter()
+        .flat_map(|algorithm| algorithm.encode())
+        .collect()
+}
+
+/// Algorithm the key of a request is derived with, MD5 unless it picks one, or `None` if the
+/// PASSWORD-ALGORITHMS it echoes don't match the ones offered, as a downgrade attack would.
+fn password_algorithm(request: &Message) -> Option<PasswordAlgorithm> {
+    match (
+        request.get(attributes::PASSWORD_ALGORITHMS),
+        request.get(attributes::PASSWORD_ALGORITHM),
+    ) {
+        (None, None) => Some(PasswordAlgorithm::Md5),
+        (Some(offered), Some(algorithm)) if offered == password_algorithms() => {
+            PasswordAlgorithm::decode(algorithm)
+        }
+        _ => None,
+    }
+}
+
+/// USERHASH of `username`, SHA-256(username ":" realm).
+fn userhash(username: &str, realm: &str) -> Vec<u8> {
+    Sha256::digest(format!("{}:{}", username, realm)).to_vec()
+}
+
+/// Password of the ephemeral credentials of `username`, base64(HMAC-SHA1(auth_secret, username)).
+fn ephemeral_password(auth_secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(auth_secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(username.as_bytes());
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+/// Long-term credential key, MD5(username ":" realm ":" password).
+#[cfg(test)]
+pub fn key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    PasswordAlgorithm::Md5.key(username, realm, password)
+}
+
+/// Parse a `user=password` pair.
+pub fn parse_user(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((user, password)) if !user.is_empty() => Ok((user.into(), password.into())),
+        _ => bail!("expected user=password, got {:?}", value),
+    }
+}
+
+/// Load users from a file of `user=password` lines, blank lines and lines starting with `#`
+/// are ignored.
+pub fn load_users(path: &Path) -> Result<Users> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_user)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{
+        ephemeral_password, key, parse_user, password_algorithms, userhash, Credentials,
+        LongTermAuth, PasswordAlgorithm, ShortTermAuth, NONCE_COOKIE,
+    };
+    use crate::message::{attributes, check_integrity, methods, Class, Integrity, Message};
+
+    fn auth() -> LongTermAuth {
+        LongTermAuth::new(
+            "stunner".into(),
+            [("user".to_string(), "pass".to_string())].into(),
+        )
+    }
+
+    fn request(username: &str, realm: &str, nonce: &str) -> Message {
+        Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERNAME, username.as_bytes().to_vec())
+            .add_attribute(attributes::REALM, realm.as_bytes().to_vec())
+            .add_attribute(attributes::NONCE, nonce.as_bytes().to_vec())
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    async fn authenticate(auth: &LongTermAuth, buf: &[u8]) -> Result<Credentials, Message> {
+        auth.authenticate(&Message::decode(buf).unwrap(), buf, CLIENT)
+            .await
+    }
+
+    #[tokio::test]
+    async fn authenticates_after_challenge() {
+        let auth = auth();
+        let unauthenticated =
+            Message::with_random_transaction_id(methods::BINDING, Class::Request).encode();
+        let challenge = authenticate(&auth, &unauthenticated).await.unwrap_err();
+        assert_eq!(challenge.error_code(), Some(401));
+        assert_eq!(challenge.get_str(attributes::REALM), Some("stunner"));
+        let nonce = challenge.get_str(attributes::NONCE).unwrap();
+
+        let key = key("user", "stunner", "pass");
+        let buf = request("user", "stunner", nonce).encode_with_integrity(&key);
+        assert_eq!(authenticate(&auth, &buf).await.unwrap().key, key);
+
+        let response = Message::decode(&buf)
+            .unwrap()
+            .success_response()
+            .encode_with_integrity(&key);
+        assert!(check_integrity(&response, &key));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_credentials() {
+        let auth = auth();
+        let nonce = auth.nonce(CLIENT);
+
+        let wrong_password = request("user", "stunner", &nonce)
+            .encode_with_integrity(&key("user", "stunner", "wrong"));
+        assert_eq!(
+            authenticate(&auth, &wrong_password)
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(401)
+        );
+
+        let unknown_user = request("other", "stunner", &nonce)
+            .encode_with_integrity(&key("other", "stunner", "pass"));
+        assert_eq!(
+            authenticate(&auth, &unknown_user)
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(401)
+        );
+
+        let key = key("user", "stunner", "pass");
+        let forged_nonce = format!("{:016x}{}", u64::MAX, "0".repeat(16));
+        let stale = request("user", "stunner", &forged_nonce).encode_with_integrity(&key);
+        let response = authenticate(&auth, &stale).await.unwrap_err();
+        assert_eq!(response.error_code(), Some(438));
+        assert!(response.get(attributes::NONCE).is_some());
+
+        let missing_nonce = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERNAME, b"user".to_vec())
+            .encode_with_integrity(&key);
+        assert_eq!(
+            authenticate(&auth, &missing_nonce)
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(400)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_nonces() {
+        let key = key("user", "stunner", "pass");
+        let auth = auth();
+        let other_client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let buf = request("user", "stunner", &auth.nonce(other_client)).encode_with_integrity(&key);
+        let response = authenticate(&auth, &buf).await.unwrap_err();
+        assert_eq!(response.error_code(), Some(438));
+        // The fresh nonce of the challenge is valid for the client.
+        let nonce = response.get_str(attributes::NONCE).unwrap();
+        let buf = request("user", "stunner", nonce).encode_with_integrity(&key);
+        assert!(authenticate(&auth, &buf).await.is_ok());
+
+        // The cookie is required, and signed.
+        let without_cookie = &nonce[NONCE_COOKIE.len()..];
+        let other_cookie = format!("obMatJos2AAAB{}", without_cookie);
+        for nonce in [without_cookie, &other_cookie] {
+            let buf = request("user", "stunner", nonce).encode_with_integrity(&key);
+            assert_eq!(
+                authenticate(&auth, &buf).await.unwrap_err().error_code(),
+                Some(438)
+            );
+        }
+
+        let auth = auth.with_nonce_lifetime(Duration::ZERO);
+        let buf = request("user", "stunner", &auth.nonce(CLIENT)).encode_with_integrity(&key);
+        assert_eq!(
+            authenticate(&auth, &buf).await.unwrap_err().error_code(),
+            Some(438)
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticates_ephemeral_credentials() {
+        let auth = auth().with_auth_secret(Some("secret".into()));
+        let nonce = auth.nonce(CLIENT);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signed = |username: &str, password: &str| {
+            request(username, "stunner", &nonce)
+                .encode_with_integrity(&key(username, "stunner", password))
+        };
+
+        assert_eq!(
+            ephemeral_password("secret", "1700000000:alice"),
+            "d8soP47RbdIKLDUOpnJPVQyq5Ts="
+        );
+        for username in [format!("{}:alice", now + 60), format!("{}", now + 60)] {
+            let password = ephemeral_password("secret", &username);
+            assert!(authenticate(&auth, &signed(&username, &password))
+                .await
+                .is_ok());
+            let wrong = ephemeral_password("other", &username);
+            assert_eq!(
+                authenticate(&auth, &signed(&username, &wrong))
+                    .await
+                    .unwrap_err()
+                    .error_code(),
+                Some(401)
+            );
+        }
+
+        let expired = format!("{}:alice", now - 1);
+        let password = ephemeral_password("secret", &expired);
+        assert_eq!(
+            authenticate(&auth, &signed(&expired, &password))
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(401)
+        );
+        // The configured users still authenticate.
+        assert!(authenticate(&auth, &signed("user", "pass")).await.is_ok());
+    }
+
+    #[test]
+    fn authenticates_short_term_credentials() {
+        let auth = ShortTermAuth::new("remote:local".into(), "pass".into());
+        let request = |username: &str| {
+            Message::with_random_transaction_id(methods::BINDING, Class::Request)
+                .add_attribute(attributes::USERNAME, username.as_bytes().to_vec())
+        };
+        let check = |buf: &[u8]| auth.authenticate(&Message::decode(buf).unwrap(), buf);
+
+        let valid = request("remote:local").encode_with_integrity(b"pass");
+        assert_eq!(check(&valid).unwrap().key, b"pass");
+        let valid = request("remote:local").encode_with_integrity_sha256(b"pass");
+        assert_eq!(
+            check(&valid).unwrap(),
+            Credentials {
+                username: "remote:local".into(),
+                key: b"pass".to_vec(),
+                integrity: Integrity::Sha256
+            }
+        );
+
+        let wrong_password = request("remote:local").encode_with_integrity(b"wrong");
+        assert_eq!(check(&wrong_password).unwrap_err().error_code(), Some(401));
+
+        let wrong_username = request("other:local").encode_with_integrity(b"pass");
+        assert_eq!(check(&wrong_username).unwrap_err().error_code(), Some(401));
+
+        let unsigned = request("remote:local").encode();
+        let response = check(&unsigned).unwrap_err();
+        assert_eq!(response.error_code(), Some(400));
+        assert!(response.get(attributes::NONCE).is_none());
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_sha256() {
+        let auth = auth();
+        let unauthenticated =
+            Message::with_random_transaction_id(methods::BINDING, Class::Request).encode();
+        let challenge = authenticate(&auth, &unauthenticated).await.unwrap_err();
+        let nonce = challenge.get_str(attributes::NONCE).unwrap();
+        assert!(nonce.starts_with(NONCE_COOKIE));
+        let offered = challenge.get(attributes::PASSWORD_ALGORITHMS).unwrap();
+        assert_eq!(offered, [0, 2, 0, 0, 0, 1, 0, 0]);
+
+        let sha256 = PasswordAlgorithm::Sha256;
+        let key = sha256.key("user", "stunner", "pass");
+        let signed = |request: Message, algorithm: PasswordAlgorithm| {
+            request
+                .add_attribute(attributes::PASSWORD_ALGORITHMS, offered.to_vec())
+                .add_attribute(attributes::PASSWORD_ALGORITHM, algorithm.encode().to_vec())
+                .encode_with_integrity_sha256(&key)
+        };
+        let buf = signed(request("user", "stunner", nonce), sha256);
+        assert_eq!(
+            authenticate(&auth, &buf).await.unwrap(),
+            Credentials {
+                username: "user".into(),
+                key: key.clone(),
+                integrity: Integrity::Sha256
+            }
+        );
+
+        // The username can be hidden in a USERHASH.
+        let anonymous = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERHASH, userhash("user", "stunner"))
+            .add_attribute(attributes::REALM, b"stunner".to_vec())
+            .add_attribute(attributes::NONCE, nonce.as_bytes().to_vec());
+        assert!(authenticate(&auth, &signed(anonymous, sha256))
+            .await
+            .is_ok());
+
+        // The key must match the algorithm picked.
+        let buf = signed(request("user", "stunner", nonce), PasswordAlgorithm::Md5);
+        assert_eq!(
+            authenticate(&auth, &buf).await.unwrap_err().error_code(),
+            Some(401)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_password_algorithm_downgrades() {
+        let auth = auth();
+        let nonce = auth.nonce(CLIENT);
+        let md5 = PasswordAlgorithm::Md5;
+        let key = key("user", "stunner", "pass");
+
+        let downgraded = request("user", "stunner", &nonce)
+            .add_attribute(attributes::PASSWORD_ALGORITHMS, md5.encode().to_vec())
+            .add_attribute(attributes::PASSWORD_ALGORITHM, md5.encode().to_vec())
+            .encode_with_integrity(&key);
+        assert_eq!(
+            authenticate(&auth, &downgraded)
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(400)
+        );
+
+        let missing_algorithms = request("user", "stunner", &nonce)
+            .add_attribute(attributes::PASSWORD_ALGORITHM, md5.encode().to_vec())
+            .encode_with_integrity(&key);
+        assert_eq!(
+            authenticate(&auth, &missing_algorithms)
+                .await
+                .unwrap_err()
+                .error_code(),
+            Some(400)
+        );
+
+        let picked_md5 = request("user", "stunner", &nonce)
+            .add_attribute(attributes::PASSWORD_ALGORITHMS, password_algorithms())
+            .add_attribute(attributes::PASSWORD_ALGORITHM, md5.encode().to_vec())
+            .encode_with_integrity(&key);
+        assert!(authenticate(&auth, &picked_md5).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn challenges_unknown_userhashes() {
+        let auth = auth();
+        let nonce = auth.nonce(CLIENT);
+        let buf = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERHASH, userhash("other", "stunner"))
+            .add_attribute(attributes::REALM, b"stunner".to_vec())
+            .add_attribute(attributes::NONCE, nonce.as_bytes().to_vec())
+            .encode_with_integrity(&key("other", "stunner", "pass"));
+        let response = authenticate(&auth, &buf).await.unwrap_err();
+        assert_eq!(response.error_code(), Some(401));
+        assert!(response.get(attributes::NONCE).is_some());
+    }
+
+    #[test]
+    fn parses_users() {
+        assert_eq!(
+            parse_user("user=pa=ss").unwrap(),
+            ("user".to_string(), "pa=ss".to_string())
+        );
+        assert!(parse_user("user").is_err());
+        assert!(parse_user("=pass").is_err());
+    }
+}
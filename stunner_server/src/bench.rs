@@ -0,0 +1,239 @@
+//! Load test of a STUN server, flooding it with Binding requests from many local ports to size
+//! the hardware it runs on. The time each request is sent is carried in its transaction id,
+//! so that the latency of the responses is measured without keeping the requests around.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use tokio::net::UdpSocket;
+use tokio::time::MissedTickBehavior;
+
+use crate::message::{methods, Class, Message, TRANSACTION_ID_SIZE};
+
+/// Options of the `bench` subcommand.
+#[derive(Debug, Clone, Args)]
+pub struct Options {
+    /// Server to load, as host:port
+    #[clap(long)]
+    pub target: String,
+
+    /// Binding requests sent per second, spread over the local ports
+    #[clap(long, default_value = "1000")]
+    pub rate: u32,
+
+    /// How long to send requests for, e.g. 30s or 2m
+    #[clap(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    pub duration: Duration,
+
+    /// Number of local ports the requests are sent from
+    #[clap(long, default_value = "64")]
+    pub ports: usize,
+
+    /// How long to wait for the responses once the last request is sent, the ones arriving
+    /// later are counted as lost
+    #[clap(long, default_value = "1s", parse(try_from_str = humantime::parse_duration))]
+    pub timeout: Duration,
+}
+
+/// Outcome of a load test.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Time requests were sent for.
+    pub duration: Duration,
+    pub sent: u64,
+    /// Requests which couldn't be sent.
+    pub errors: u64,
+    /// Latency of each response received, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Latency below which `percentile` percent of the responses were received.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = (last as f64 * percentile / 100.0).round() as usize;
+        Some(self.latencies[index])
+    }
+
+    fn merge(&mut self, other: Report) {
+        self.sent += other.sent;
+        self.errors += other.errors;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64();
+        let received = self.latencies.len() as u64;
+        writeln!(
+            f,
+            "sent {} requests in {:.1}s, {:.0}/s, {} errors",
+            self.sent,
+            seconds,
+            self.sent as f64 / seconds,
+            self.errors
+        )?;
+        writeln!(
+            f,
+            "received {} responses, {:.2}%, {:.0}/s",
+            received,
+            100.0 * received as f64 / self.sent.max(1) as f64,
+            received as f64 / seconds
+        )?;
+        if let Some(max) = self.latencies.last() {
+            write!(f, "latency")?;
+            for percentile in [50.0, 90.0, 99.0, 99.9] {
+                let latency = self.percentile(percentile).unwrap_or_default();
+                write!(f, " p{} {:?}", percentile, latency)?;
+            }
+            writeln!(f, " max {:?}", max)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send Binding requests to the target of `options` and measure its responses.
+pub async fn run(options: &Options) -> Result<Report> {
+    let target = tokio::net::lookup_host(&options.target)
+        .await
+        .with_context(|| format!("could not resolve {}", options.target))?
+        .next()
+        .with_context(|| format!("no address for {}", options.target))?;
+    let bind_addr = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let ports = options.ports.max(1);
+    // Each port sends its share of the requests.
+    let interval =
+        (Duration::from_secs(1) * ports as u32 / options.rate.max(1)).max(Duration::from_nanos(1));
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(ports);
+    for _ in 0..ports {
+        let sock = UdpSocket::bind(bind_addr)
+            .await
+            .context("could not bind a local port")?;
+        sock.connect(target).await?;
+        tasks.push(tokio::spawn(load(
+            sock,
+            start,
+            interval,
+            options.duration,
+            options.timeout,
+        )));
+    }
+    let mut report = Report {
+        duration: options.duration,
+        ..Report::default()
+    };
+    for task in tasks {
+        report.merge(task.await?);
+    }
+    report.latencies.sort();
+    Ok(report)
+}
+
+/// Send a request on `sock` every `interval` for `duration` from `start`, and receive the
+/// responses until `timeout` after the last one.
+async fn load(
+    sock: UdpSocket,
+    start: Instant,
+    interval: Duration,
+    duration: Duration,
+    timeout: Duration,
+) -> Report {
+    let mut report = Report::default();
+    let mut ticks = tokio::time::interval(interval);
+    // Requests missed while the runtime is busy are sent at once, keeping the rate on average.
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let end = tokio::time::Instant::from_std(start + duration);
+    let deadline = tokio::time::sleep_until(end + timeout);
+    tokio::pin!(deadline);
+    let mut buf = [0; 1500];
+    loop {
+        tokio::select! {
+            _ = ticks.tick(), if tokio::time::Instant::now() < end => {
+                let request = request(start.elapsed());
+                match sock.send(&request.encode()).await {
+                    Ok(_) => report.sent += 1,
+                    Err(_) => report.errors += 1,
+                }
+            }
+            received = sock.recv(&mut buf) => {
+                let now = start.elapsed();
+                if let Some(sent_at) = received.ok().and_then(|len| sent_at(&buf[..len])) {
+                    report.latencies.push(now.saturating_sub(sent_at));
+                }
+            }
+            _ = &mut deadline => return report,
+        }
+    }
+}
+
+/// Binding request sent `sent_at` after the start of the test, carried in the first 8 bytes
+/// of its transaction id.
+fn request(sent_at: Duration) -> Message {
+    let mut transaction_id: [u8; TRANSACTION_ID_SIZE] = rand::random();
+    transaction_id[..8].copy_from_slice(&(sent_at.as_nanos() as u64).to_be_bytes());
+    Message::new(methods::BINDING, Class::Request, transaction_id)
+}
+
+/// Time after the start of the test the request answered by the response in `buf` was sent.
+fn sent_at(buf: &[u8]) -> Option<Duration> {
+    let response = Message::decode(buf).ok()?;
+    if (response.method, response.class) != (methods::BINDING, Class::SuccessResponse) {
+        return None;
+    }
+    let nanos = u64::from_be_bytes(response.transaction_id[..8].try_into().ok()?);
+    Some(Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{run, Options, Report};
+    use crate::server::Server;
+    use crate::transport::{self, Udp};
+
+    #[tokio::test]
+    async fn measures_the_responses_of_a_server() {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let target = sock.local_addr().unwrap();
+        tokio::spawn(transport::serve(
+            Arc::new(Udp::new(sock).unwrap()),
+            Arc::new(Server::default()),
+        ));
+        let options = Options {
+            target: target.to_string(),
+            rate: 200,
+            duration: Duration::from_millis(500),
+            ports: 4,
+            timeout: Duration::from_millis(500),
+        };
+        let report = run(&options).await.unwrap();
+        assert!(report.sent >= 50, "{}", report);
+        assert_eq!(report.latencies.len() as u64, report.sent, "{}", report);
+        assert!(report.percentile(50.0).unwrap() <= report.percentile(99.0).unwrap());
+        assert!(report.to_string().contains("latency p50"));
+    }
+
+    #[test]
+    fn computes_percentiles() {
+        let report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Report::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(Report::default().percentile(50.0), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Source IP ranges denied from a file managed outside of the server, e.g. by a threat feed,
+//! read again as soon as it changes so that additions take effect without a restart.
+//!
+//! The file holds an address or range in CIDR notation per line, optionally followed by a
+//! comment after a `;` as in the Spamhaus DROP list, blank lines and lines starting with `#`
+//! are ignored:
+//!
+//! ```text
+//! # /etc/stunner/deny.txt
+//! 192.0.2.1
+//! 198.51.100.0/24
+//! ```
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+use crate::acl::Cidr;
+
+/// Modification time and size of the file, which change along with its contents.
+type Version = (SystemTime, u64);
+
+/// Denies the sources in the ranges of a file, polled for changes.
+#[derive(Debug)]
+pub struct Blocklist {
+    path: PathBuf,
+    ranges: RwLock<Vec<Cidr>>,
+    /// Version of the file last read, `None` once it couldn't be.
+    version: Mutex<Option<Version>>,
+}
+
+impl Blocklist {
+    /// Deny the sources in the ranges of the file at `path`.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let version = version(&path)?;
+        let ranges = read(&path)?;
+        Ok(Blocklist {
+            path,
+            ranges: RwLock::new(ranges),
+            version: Mutex::new(Some(version)),
+        })
+    }
+
+    /// Whether `ip` is in one of the ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ranges
+            .read()
+            .unwrap()
+            .iter()
+            .any(|cidr| cidr.contains(ip))
+    }
+
+    /// Number of ranges denied.
+    pub fn range_count(&self) -> usize {
+        self.ranges.read().unwrap().len()
+    }
+
+    /// Read the file again if it changed, returning whether it did. The ranges read last are
+    /// kept when it can't be read, which is only reported once until it changes again.
+    pub fn refresh(&self) -> Result<bool> {
+        let mut last = self.version.lock().unwrap();
+        let version = match version(&self.path) {
+            Ok(version) => version,
+            Err(err) if last.take().is_some() => return Err(err),
+            Err(_) => return Ok(false),
+        };
+        if *last == Some(version) {
+            return Ok(false);
+        }
+        *last = Some(version);
+        *self.ranges.write().unwrap() = read(&self.path)?;
+        Ok(true)
+    }
+}
+
+fn version(path: &Path) -> Result<Version> {
+    let metadata = fs::metadata(path).with_context(|| format!("could not stat {:?}", path))?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+fn read(path: &Path) -> Result<Vec<Cidr>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+    parse(&contents).with_context(|| format!("invalid blocklist {:?}", path))
+}
+
+/// Ranges of the lines of a blocklist.
+pub(crate) fn parse(contents: &str) -> Result<Vec<Cidr>> {
+    contents
+        .lines()
+        .map(|line| line.split(';').next().unwrap_or_default().trim())
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| line.parse().with_context(|| format!("line {}", i + 1)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Blocklist};
+
+    #[test]
+    fn parses_ranges_and_skips_comments() {
+        let ranges =
+            parse("# threat feed\n192.0.2.1\n\n  198.51.100.0/24 ; SBL000001\n; end\n").unwrap();
+        assert_eq!(
+            ranges,
+            [
+                "192.0.2.1".parse().unwrap(),
+                "198.51.100.0/24".parse().unwrap()
+            ]
+        );
+        let err = parse("192.0.2.1\n192.0.2/24\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2");
+    }
+
+    #[test]
+    fn reads_the_file_again_once_changed() {
+        let path =
+            std::env::temp_dir().join(format!("stunner-blocklist-{}", rand::random::<u64>()));
+        std::fs::write(&path, "192.0.2.1\n").unwrap();
+        let blocklist = Blocklist::load(path.clone()).unwrap();
+        assert!(blocklist.contains("192.0.2.1".parse().unwrap()));
+        assert!(!blocklist.refresh().unwrap());
+
+        std::fs::write(&path, "192.0.2.1\n198.51.100.0/24\n").unwrap();
+        assert!(blocklist.refresh().unwrap());
+        assert!(blocklist.contains("198.51.100.7".parse().unwrap()));
+        assert_eq!(blocklist.range_count(), 2);
+
+        // Invalid contents are reported, and the ranges read last kept.
+        std::fs::write(&path, "not an address\n").unwrap();
+        assert!(blocklist.refresh().is_err());
+        assert!(blocklist.contains("198.51.100.7".parse().unwrap()));
+
+        // A missing file is reported once.
+        std::fs::remove_file(&path).unwrap();
+        assert!(blocklist.refresh().is_err());
+        assert!(!blocklist.refresh().unwrap());
+        assert!(blocklist.contains("198.51.100.7".parse().unwrap()));
+    }
+}
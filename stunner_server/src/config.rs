@@ -0,0 +1,147 @@
+//! Configuration file, holding command line options so that they can be read again on SIGHUP.
+//!
+//! Each line holds an option as `name = value`, or `name` alone for flags, where `name` is the
+//! long option without the leading dashes. Blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # /etc/stunner/stunner.conf
+//! listen = 0.0.0.0:3478
+//! allow = 10.0.0.0/8
+//! max-rps-per-ip = 50
+//! fingerprint
+//! ```
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use clap::{ArgMatches, Command};
+
+/// Options that don't belong in a configuration file.
+const SKIPPED: &[&str] = &["help", "version", "config", "check-config", "service"];
+
+/// Command line arguments equivalent to the options of the configuration file at `path`.
+pub fn args(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("could not read configuration file {}", path.display()))?;
+    parse(&content).with_context(|| format!("invalid configuration file {}", path.display()))
+}
+
+fn parse(content: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim_end(), Some(value.trim_start())),
+            None => (line, None),
+        };
+        ensure!(
+            !name.is_empty() && !name.starts_with('-'),
+            "invalid option on line {}: {:?}",
+            number + 1,
+            line
+        );
+        args.push(format!("--{}", name));
+        args.extend(value.map(str::to_owned));
+    }
+    Ok(args)
+}
+
+/// Configuration file equivalent to `matches` of `command`, with the default values of the
+/// options not given. The values of the options named in `secrets` are redacted.
+pub fn format(command: &Command, matches: &ArgMatches, secrets: &[&str]) -> String {
+    let mut content = String::new();
+    for arg in command.get_arguments() {
+        let name = match arg.get_long() {
+            Some(name) if !SKIPPED.contains(&name) => name,
+            _ => continue,
+        };
+        if !arg.is_takes_value_set() {
+            if matches.is_present(arg.get_id()) {
+                let _ = writeln!(content, "{}", name);
+            }
+            continue;
+        }
+        for value in matches.get_raw(arg.get_id()).into_iter().flatten() {
+            let value = if secrets.contains(&name) {
+                "<redacted>".into()
+            } else {
+                value.to_string_lossy()
+            };
+            let _ = writeln!(content, "{} = {}", name, value);
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, Command};
+
+    use super::{format, parse};
+
+    #[test]
+    fn parses_options() {
+        let content = "\
+            # comment\n\
+            \n\
+            allow = 10.0.0.0/8\n\
+            users=alice=secret\n\
+            fingerprint\n";
+        assert_eq!(
+            parse(content).unwrap(),
+            [
+                "--allow",
+                "10.0.0.0/8",
+                "--users",
+                "alice=secret",
+                "--fingerprint"
+            ]
+        );
+        assert!(parse("--allow = 10.0.0.0/8").is_err());
+        assert!(parse("= 10.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn formats_options() {
+        let mut command = Command::new("stunner")
+            .arg(Arg::new("config").long("config").takes_value(true))
+            .arg(
+                Arg::new("allow")
+                    .long("allow")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(Arg::new("port").long("port").default_value("3478"))
+            .arg(Arg::new("password").long("password").takes_value(true))
+            .arg(Arg::new("realm").long("realm").takes_value(true))
+            .arg(Arg::new("fingerprint").long("fingerprint"))
+            .arg(Arg::new("sandbox").long("sandbox"));
+        let matches = command
+            .try_get_matches_from_mut([
+                "stunner",
+                "--config",
+                "stunner.conf",
+                "--allow",
+                "10.0.0.0/8",
+                "--allow",
+                "192.0.2.0/24",
+                "--password",
+                "secret",
+                "--fingerprint",
+            ])
+            .unwrap();
+        assert_eq!(
+            format(&command, &matches, &["password"]),
+            "allow = 10.0.0.0/8\n\
+             allow = 192.0.2.0/24\n\
+             port = 3478\n\
+             password = <redacted>\n\
+             fingerprint\n"
+        );
+    }
+}
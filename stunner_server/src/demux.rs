@@ -0,0 +1,257 @@
+//! Demultiplexing of the datagrams received on a port shared with a media server. The
+//! protocol of a datagram is told by its first byte, see
+//! https://datatracker.ietf.org/doc/html/rfc7983#section-7 and
+//! https://datatracker.ietf.org/doc/html/rfc9443#section-5, the datagrams which aren't STUN
+//! are forwarded to the media server and its replies sent back to their source.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::AbortHandle;
+
+use crate::net;
+use crate::server::{Sink, Source};
+
+/// Time after which a flow nothing was forwarded on in either direction is forgotten.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Protocol of a datagram, going by its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Stun,
+    Zrtp,
+    Dtls,
+    /// TURN ChannelData, or a QUIC short header packet when not relaying.
+    ChannelData,
+    Quic,
+    /// RTP or RTCP.
+    Rtp,
+    Unknown,
+}
+
+/// Protocol of the datagram in `buf`.
+pub fn classify(buf: &[u8]) -> Protocol {
+    match buf.first() {
+        Some(0..=3) => Protocol::Stun,
+        Some(16..=19) => Protocol::Zrtp,
+        Some(20..=63) => Protocol::Dtls,
+        Some(64..=79) => Protocol::ChannelData,
+        Some(80..=127) | Some(192..=255) => Protocol::Quic,
+        Some(128..=191) => Protocol::Rtp,
+        _ => Protocol::Unknown,
+    }
+}
+
+/// A source whose datagrams are forwarded.
+struct Flow {
+    /// Socket connected to the media server, to forward the datagrams of the source from.
+    upstream: Arc<UdpSocket>,
+    /// Task sending the replies of the media server back to the source.
+    replies: AbortHandle,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.replies.abort();
+    }
+}
+
+/// Forwards the datagrams which aren't STUN to a media server.
+pub struct Demux {
+    downstream: SocketAddr,
+    /// Flows by address of the source and local address the datagrams were received on.
+    flows: Mutex<HashMap<(SocketAddr, SocketAddr), Flow>>,
+}
+
+impl Demux {
+    /// Forward the datagrams to the media server listening on `downstream`.
+    pub fn new(downstream: SocketAddr) -> Self {
+        Demux {
+            downstream,
+            flows: Default::default(),
+        }
+    }
+
+    /// Whether the server handles the datagram in `buf` rather than forwarding it, ChannelData
+    /// being handled when relaying.
+    pub fn handles(&self, buf: &[u8], relaying: bool) -> bool {
+        match classify(buf) {
+            Protocol::Stun => true,
+            Protocol::ChannelData => relaying,
+            _ => false,
+        }
+    }
+
+    /// Forward the datagram in `buf` received from `source` to the media server.
+    pub async fn forward(&self, buf: &[u8], source: &Source) {
+        let sock = match &source.sink {
+            Sink::Datagram(sock) => sock,
+            Sink::Stream(_) => return,
+        };
+        let upstream = match self.flow(sock, source) {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                log::debug!("could not forward datagram from {:?}: {}", source.addr, err);
+                return;
+            }
+        };
+        if let Err(err) = upstream.send(buf).await {
+            log::debug!(
+                "could not forward datagram from {:?} to {:?}: {}",
+                source.addr,
+                self.downstream,
+                err
+            );
+        }
+    }
+
+    /// Socket forwarding the datagrams of `source`, received on `sock`, set up on its first
+    /// datagram.
+    fn flow(&self, sock: &Arc<UdpSocket>, source: &Source) -> io::Result<Arc<UdpSocket>> {
+        let mut flows = self.flows.lock().unwrap();
+        let key = (source.addr, source.local_addr);
+        if let Some(flow) = flows.get(&key) {
+            *flow.last_active.lock().unwrap() = Instant::now();
+            return Ok(flow.upstream.clone());
+        }
+        let bind_addr = match self.downstream {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let upstream = std::net::UdpSocket::bind(bind_addr)?;
+        upstream.connect(self.downstream)?;
+        upstream.set_nonblocking(true)?;
+        let upstream = Arc::new(UdpSocket::from_std(upstream)?);
+        let last_active = Arc::new(Mutex::new(Instant::now()));
+        let replies = tokio::spawn(relay_replies(
+            upstream.clone(),
+            sock.clone(),
+            source.addr,
+            source.local_addr,
+            last_active.clone(),
+        ));
+        log::debug!(
+            "forwarding datagrams of {:?} to {:?}",
+            source.addr,
+            self.downstream
+        );
+        flows.insert(
+            key,
+            Flow {
+                upstream: upstream.clone(),
+                replies: replies.abort_handle(),
+                last_active,
+            },
+        );
+        Ok(upstream)
+    }
+
+    /// Forget the flows idle for too long.
+    pub fn expire(&self) {
+        self.flows
+            .lock()
+            .unwrap()
+            .retain(|_, flow| flow.last_active.lock().unwrap().elapsed() < FLOW_IDLE_TIMEOUT);
+    }
+
+    /// Number of sources whose datagrams are forwarded.
+    pub fn flow_count(&self) -> usize {
+        self.flows.lock().unwrap().len()
+    }
+}
+
+/// Send the datagrams received on `upstream` to `addr`, from `local_addr` of `sock`.
+async fn relay_replies(
+    upstream: Arc<UdpSocket>,
+    sock: Arc<UdpSocket>,
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    last_active: Arc<Mutex<Instant>>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let len = match upstream.recv(&mut buf).await {
+            Ok(len) => len,
+            // ICMP errors of the media server not listening are reported on the next receive.
+            Err(err) => {
+                log::trace!("could not receive datagram for {:?}: {}", addr, err);
+                continue;
+            }
+        };
+        *last_active.lock().unwrap() = Instant::now();
+        if let Err(err) = net::send_to(&sock, &buf[..len], addr, local_addr).await {
+            log::debug!("could not send datagram to {:?}: {}", addr, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{classify, Demux, Protocol};
+    use crate::message::{methods, Class, Message};
+    use crate::server::Server;
+    use crate::transport::{self, Udp};
+
+    #[test]
+    fn classifies_datagrams_by_first_byte() {
+        assert_eq!(classify(&[0x00, 0x01]), Protocol::Stun);
+        assert_eq!(classify(&[0x16, 0xFE, 0xFD]), Protocol::Dtls);
+        assert_eq!(classify(&[0x80, 0x60]), Protocol::Rtp);
+        assert_eq!(classify(&[0x40, 0x00]), Protocol::ChannelData);
+        assert_eq!(classify(&[0xC3]), Protocol::Quic);
+        assert_eq!(classify(&[0x10]), Protocol::Zrtp);
+        assert_eq!(classify(&[0x08]), Protocol::Unknown);
+        assert_eq!(classify(&[]), Protocol::Unknown);
+    }
+
+    #[tokio::test]
+    async fn forwards_other_protocols_downstream() {
+        let media = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = sock.local_addr().unwrap();
+        let server =
+            Arc::new(Server::default().with_demux(Some(Demux::new(media.local_addr().unwrap()))));
+        tokio::spawn(transport::serve(
+            Arc::new(Udp::new(sock).unwrap()),
+            server.clone(),
+        ));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0; 1500];
+        let timeout = Duration::from_secs(5);
+
+        // RTP goes to the media server, and its replies back to the client.
+        client.send_to(&[0x80, 0x60, 1, 2], addr).await.unwrap();
+        let (len, from) = tokio::time::timeout(timeout, media.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[0x80, 0x60, 1, 2]);
+        media.send_to(&[0x80, 0x61], from).await.unwrap();
+        let (len, from) = tokio::time::timeout(timeout, client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((&buf[..len], from), (&[0x80, 0x61][..], addr));
+
+        // STUN is answered by the server.
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        client.send_to(&request.encode(), addr).await.unwrap();
+        let len = tokio::time::timeout(timeout, client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert_eq!(server.demux().unwrap().flow_count(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+//! NAT behavior discovery, see https://datatracker.ietf.org/doc/html/rfc5780
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use tokio::net::UdpSocket;
+
+use crate::message::{attributes, Message};
+use crate::net::{self, SocketConfig};
+use crate::server::{Source, Transport};
+
+/// CHANGE-REQUEST flag asking for the response to be sent from the alternate IP address.
+const CHANGE_IP: u32 = 0x04;
+
+/// CHANGE-REQUEST flag asking for the response to be sent from the alternate port.
+const CHANGE_PORT: u32 = 0x02;
+
+/// UDP sockets bound on every combination of a primary and an alternate IP address and port,
+/// so that responses can be sent from an address other than the one the request was sent to.
+pub struct Discovery {
+    /// Sockets indexed by `[alternate ip][alternate port]`.
+    sockets: [[Arc<UdpSocket>; 2]; 2],
+}
+
+impl Discovery {
+    /// Bind the sockets with `config`, the port of the alternate address is picked by the
+    /// system when 0.
+    pub fn bind(primary: SocketAddr, alternate: SocketAddr, config: &SocketConfig) -> Result<Self> {
+        ensure!(
+            !primary.ip().is_unspecified() && !alternate.ip().is_unspecified(),
+            "NAT behavior discovery requires specific IP addresses"
+        );
+        ensure!(
+            primary.is_ipv4() == alternate.is_ipv4() && primary.ip() != alternate.ip(),
+            "the alternate IP address must be a different address of the same family"
+        );
+        let primary_primary = net::bind_udp(primary, config)?;
+        let primary = primary_primary.local_addr()?;
+        let primary_alternate =
+            net::bind_udp(SocketAddr::new(primary.ip(), alternate.port()), config)?;
+        let alternate_port = primary_alternate.local_addr()?.port();
+        let alternate_primary =
+            net::bind_udp(SocketAddr::new(alternate.ip(), primary.port()), config)?;
+        let alternate_alternate =
+            net::bind_udp(SocketAddr::new(alternate.ip(), alternate_port), config)?;
+        Ok(Discovery {
+            sockets: [
+                [Arc::new(primary_primary), Arc::new(primary_alternate)],
+                [Arc::new(alternate_primary), Arc::new(alternate_alternate)],
+            ],
+        })
+    }
+
+    /// All the sockets to serve requests on.
+    pub fn sockets(&self) -> impl Iterator<Item = &Arc<UdpSocket>> {
+        self.sockets.iter().flatten()
+    }
+
+    /// Socket bound on the given address.
+    pub fn socket(&self, addr: SocketAddr) -> Option<&Arc<UdpSocket>> {
+        self.position(addr)
+            .map(|(alternate_ip, alternate_port)| &self.sockets[alternate_ip][alternate_port])
+    }
+
+    /// Indexes of the socket bound on the given address.
+    fn position(&self, addr: SocketAddr) -> Option<(usize, usize)> {
+        (0..2)
+            .flat_map(|ip| (0..2).map(move |port| (ip, port)))
+            .find(|&(ip, port)| self.sockets[ip][port].local_addr().ok() == Some(addr))
+    }
+
+    /// Add RESPONSE-ORIGIN and OTHER-ADDRESS to a Binding success response, returning the socket
+    /// it must be sent from when the request asked for a change of address with CHANGE-REQUEST,
+    /// see https://datatracker.ietf.org/doc/html/rfc5780#section-6.1
+    pub fn respond(
+        &self,
+        request: &Message,
+        response: Message,
+        source: &Source,
+    ) -> (Message, Option<Arc<UdpSocket>>) {
+        let (ip, port) = match self.position(source.local_addr) {
+            Some(position) => position,
+            None => return (response, None),
+        };
+        let change = request.get_u32(attributes::CHANGE_REQUEST).unwrap_or(0);
+        if change != 0 && source.transport != Transport::Udp {
+            return (
+                request.error_response(400, "CHANGE-REQUEST is only supported over UDP"),
+                None,
+            );
+        }
+
+        let other = &self.sockets[1 - ip][1 - port];
+        let origin = &self.sockets[ip ^ (change & CHANGE_IP != 0) as usize]
+            [port ^ (change & CHANGE_PORT != 0) as usize];
+        // RFC 3489 clients expect the addresses in SOURCE-ADDRESS and CHANGED-ADDRESS instead.
+        let (origin_attribute, other_attribute) = if request.is_legacy() {
+            (attributes::SOURCE_ADDRESS, attributes::CHANGED_ADDRESS)
+        } else {
+            (attributes::RESPONSE_ORIGIN, attributes::OTHER_ADDRESS)
+        };
+        let mut response = response;
+        if let Ok(addr) = origin.local_addr() {
+            response = response.add_address(origin_attribute, addr);
+        }
+        if let Ok(addr) = other.local_addr() {
+            response = response.add_address(other_attribute, addr);
+        }
+        let origin = (change != 0).then(|| origin.clone());
+        (response, origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{Discovery, CHANGE_IP, CHANGE_PORT};
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::net::SocketConfig;
+    use crate::server::{Server, Sink, Source, Transport};
+
+    #[tokio::test]
+    async fn responds_from_the_requested_address() {
+        let discovery = Discovery::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+            &SocketConfig::default(),
+        )
+        .unwrap();
+        let addrs: Vec<SocketAddr> = discovery
+            .sockets()
+            .map(|sock| sock.local_addr().unwrap())
+            .collect();
+        let primary = discovery.sockets[0][0].clone();
+        let server = Server::default().with_discovery(Some(discovery));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let source = Source {
+            addr: client.local_addr().unwrap(),
+            local_addr: addrs[0],
+            transport: Transport::Udp,
+            sink: Sink::Datagram(primary),
+        };
+
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(
+            response.get_address(attributes::RESPONSE_ORIGIN),
+            Some(addrs[0])
+        );
+        assert_eq!(
+            response.get_address(attributes::OTHER_ADDRESS),
+            Some(addrs[3])
+        );
+
+        for (change, origin) in [
+            (CHANGE_PORT, addrs[1]),
+            (CHANGE_IP, addrs[2]),
+            (CHANGE_IP | CHANGE_PORT, addrs[3]),
+        ] {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+                .add_u32(attributes::CHANGE_REQUEST, change);
+            assert!(server.handle(&request.encode(), &source).await.is_none());
+
+            let mut buf = [0; 1024];
+            let (len, from) =
+                tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(from, origin);
+            let response = Message::decode(&buf[..len]).unwrap();
+            assert_eq!(response.transaction_id, request.transaction_id);
+            assert_eq!(
+                response.get_address(attributes::RESPONSE_ORIGIN),
+                Some(origin)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_change_requests_over_tcp() {
+        let discovery = Discovery::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+            &SocketConfig::default(),
+        )
+        .unwrap();
+        let local_addr = discovery.sockets[0][0].local_addr().unwrap();
+        let server = Server::default().with_discovery(Some(discovery));
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let source = Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr,
+            transport: Transport::Tcp,
+            sink: Sink::Stream(tx),
+        };
+
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_u32(attributes::CHANGE_REQUEST, CHANGE_IP);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        assert_eq!(Message::decode(&response).unwrap().error_code(), Some(400));
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use openssl::ssl::{
+    select_next_proto, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod, SslOptions,
+    SslVerifyMode,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_openssl::SslStream;
+use tracing::Instrument;
+
+use crate::server::{Server, Sink, Source, Transport};
+
+/// Maximum size of a datagram carrying a DTLS record.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// MTU used for DTLS records, small enough to avoid fragmentation on most paths.
+const DTLS_MTU: u32 = 1200;
+
+/// Number of datagrams queued for a session before new ones are dropped.
+const SESSION_QUEUE_SIZE: usize = 32;
+
+/// ALPN protocol identifiers of STUN in wire format, each prefixed with its length,
+/// see https://datatracker.ietf.org/doc/html/rfc7443
+const ALPN_PROTOCOLS: &[u8] = b"\x09stun.turn\x12stun.nat-discovery";
+
+/// Sessions that don't receive any datagram for this long are closed.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Datagrams pending to be read by each DTLS session, keyed by the remote address.
+type Sessions = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Build a DTLS acceptor from a PEM encoded certificate chain and private key, requiring clients
+/// to present a certificate issued by one of the PEM encoded authorities at `client_ca_path` if
+/// given. The clients offering ALPN protocols must offer one of the STUN ones.
+pub fn acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::dtls())?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_alpn_select_callback(|_, offered| {
+        select_next_proto(ALPN_PROTOCOLS, offered).ok_or(AlpnError::ALERT_FATAL)
+    });
+    if let Some(path) = client_ca_path {
+        builder.set_ca_file(path)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    // Sessions run over an in memory transport that can't answer MTU queries.
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    Ok(builder.build())
+}
+
+/// Serve STUN over DTLS on the given socket, see https://datatracker.ietf.org/doc/html/rfc7350.
+/// Each remote address gets its own DTLS session, fed with the datagrams received from it. With
+/// `strict_alpn`, the sessions not negotiating one of the STUN ALPN protocols are closed.
+pub async fn serve(
+    sock: UdpSocket,
+    acceptor: SslAcceptor,
+    strict_alpn: bool,
+    server: Arc<Server>,
+) -> Result<()> {
+    let local_addr = sock.local_addr()?;
+    let sock = Arc::new(sock);
+    let sessions: Sessions = Default::default();
+    let mut tasks = JoinSet::new();
+
+    loop {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (len, src_addr) = tokio::select! {
+            received = sock.recv_from(&mut buf) => received?,
+            _ = server.stopped() => break,
+            // Reap the finished sessions.
+            Some(_) = tasks.join_next() => continue,
+        };
+        buf.truncate(len);
+
+        let mut sessions_guard = sessions.lock().unwrap();
+        let tx = match sessions_guard.get(&src_addr) {
+            Some(tx) if !tx.is_closed() => tx.clone(),
+            _ => {
+                let (tx, rx) = mpsc::channel(SESSION_QUEUE_SIZE);
+                sessions_guard.insert(src_addr, tx.clone());
+                let stream = DatagramStream {
+                    sock: sock.clone(),
+                    peer_addr: src_addr,
+                    rx,
+                };
+                let ssl = Ssl::new(acceptor.context())?;
+                let sessions = sessions.clone();
+                let server = server.clone();
+                tasks.spawn(async move {
+                    log::debug!("new DTLS session with {:?}", src_addr);
+                    if let Err(err) =
+                        handle_session(ssl, stream, local_addr, strict_alpn, server).await
+                    {
+                        log::debug!("DTLS session with {:?} closed: {}", src_addr, err);
+                    }
+                    let mut sessions = sessions.lock().unwrap();
+                    if matches!(sessions.get(&src_addr), Some(tx) if tx.is_closed()) {
+                        sessions.remove(&src_addr);
+                    }
+                });
+                tx
+            }
+        };
+        drop(sessions_guard);
+
+        if tx.try_send(buf).is_err() {
+            log::debug!(
+                "DTLS session with {:?} is busy, dropping datagram",
+                src_addr
+            );
+        }
+    }
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Complete the DTLS handshake and reply to every STUN request received on the session.
+async fn handle_session(
+    mut ssl: Ssl,
+    stream: DatagramStream,
+    local_addr: SocketAddr,
+    strict_alpn: bool,
+    server: Arc<Server>,
+) -> Result<()> {
+    ssl.set_mtu(DTLS_MTU)?;
+    let peer_addr = stream.peer_addr;
+    let mut stream = SslStream::new(ssl, stream)?;
+    tokio::time::timeout(SESSION_IDLE_TIMEOUT, Pin::new(&mut stream).accept()).await??;
+    ensure!(
+        !strict_alpn || stream.ssl().selected_alpn_protocol().is_some(),
+        "no ALPN protocol negotiated"
+    );
+
+    // Messages sent outside of a request, such as TURN Data indications, are queued to be
+    // written by the session.
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(SESSION_QUEUE_SIZE);
+    let source = Source {
+        addr: peer_addr,
+        local_addr,
+        transport: Transport::Dtls,
+        sink: Sink::Stream(tx),
+    };
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let result = loop {
+        tokio::select! {
+            // Each DTLS record carries a whole STUN message.
+            read = tokio::time::timeout(SESSION_IDLE_TIMEOUT, stream.read(&mut buf)) => {
+                let len = match read {
+                    Ok(Ok(0)) => break Ok(()),
+                    Ok(Ok(len)) => len,
+                    Ok(Err(err)) => break Err(err.into()),
+                    Err(err) => break Err(err.into()),
+                };
+                let span = source.span();
+                if let Some(response) = server.handle(&buf[..len], &source).instrument(span.clone()).await {
+                    let send = tracing::info_span!(parent: &span, "send");
+                    if let Err(err) = stream.write_all(&response).instrument(send).await {
+                        break Err(err.into());
+                    }
+                }
+            }
+            Some(bytes) = rx.recv() => {
+                if let Err(err) = stream.write_all(&bytes).await {
+                    break Err(err.into());
+                }
+            }
+            _ = server.stopped() => break Ok(()),
+        }
+    };
+    server.disconnected(&source);
+    result
+}
+
+/// Transport of a single DTLS session, reads yield the datagrams received from the remote
+/// address and writes send a datagram to it through the shared socket.
+struct DatagramStream {
+    sock: Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl AsyncRead for DatagramStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(datagram)) => {
+                let len = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..len]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for DatagramStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.sock.poll_send_to(cx, buf, self.peer_addr)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::UdpSocket;
+
+    use openssl::ssl::{SslConnector, SslMethod, SslOptions, SslVerifyMode};
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+
+    use super::{acceptor, serve, DTLS_MTU};
+    use crate::tls::tests::self_signed_cert;
+
+    /// Blocking client side transport over a connected UDP socket.
+    #[derive(Debug)]
+    struct ConnectedUdp(UdpSocket);
+
+    impl Read for ConnectedUdp {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    impl Write for ConnectedUdp {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_to_binding_request_over_dtls() {
+        let (_cert, dir) = self_signed_cert("stunner-dtls");
+        let acceptor = acceptor(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = sock.local_addr().unwrap();
+        tokio::spawn(serve(sock, acceptor, true, Default::default()));
+
+        let (client_addr, buf) = tokio::task::spawn_blocking(move || {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.connect(server_addr).unwrap();
+            let client_addr = client.local_addr().unwrap();
+
+            let mut connector = SslConnector::builder(SslMethod::dtls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            connector.set_options(SslOptions::NO_QUERY_MTU);
+            connector.set_alpn_protos(b"\x09stun.turn").unwrap();
+            let mut config = connector.build().configure().unwrap();
+            config.set_mtu(DTLS_MTU).unwrap();
+            let mut stream = config
+                .verify_hostname(false)
+                .connect("localhost", ConnectedUdp(client))
+                .unwrap();
+            assert_eq!(
+                stream.ssl().selected_alpn_protocol(),
+                Some(&b"stun.turn"[..])
+            );
+
+            let req_msg =
+                StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+            stream.write_all(&req_msg.encode(None).unwrap()).unwrap();
+            let mut buf = vec![0; 1500];
+            let len = stream.read(&mut buf).unwrap();
+            buf.truncate(len);
+            (client_addr, buf)
+        })
+        .await
+        .unwrap();
+
+        let response = StunMessage::decode(&buf, None).unwrap();
+        assert!(matches!(
+            response.get_header().message_class,
+            StunMessageClass::SuccessResponse
+        ));
+        assert!(
+            matches!(response.get_attributes()[0], StunAttribute::XorMappedAddress { socket_addr } if socket_addr == client_addr)
+        );
+    }
+}
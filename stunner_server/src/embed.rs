@@ -0,0 +1,235 @@
+//! Builder of a server embedded in an application, serving STUN until the application shuts it
+//! down through its [`ShutdownHandle`].
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::auth::Auth;
+use crate::server::Server;
+use crate::turn::Turn;
+use crate::{serve, Listeners};
+
+/// A server ready to serve STUN on the addresses it was built with.
+pub struct StunServer {
+    listeners: Listeners,
+    server: Arc<Server>,
+}
+
+impl StunServer {
+    /// Configure a server.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Handle to shut the server down once it runs.
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            server: self.server.clone(),
+        }
+    }
+
+    /// Bind the listeners and serve until the server is shut down.
+    pub async fn run(self) -> Result<()> {
+        serve(self.listeners, self.server).await
+    }
+}
+
+/// Configuration of a [`StunServer`].
+pub struct Builder {
+    addrs: Vec<SocketAddr>,
+    workers: usize,
+    metrics_addr: Option<SocketAddr>,
+    health_addr: Option<SocketAddr>,
+    server: Server,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            addrs: Vec::new(),
+            workers: 1,
+            metrics_addr: None,
+            health_addr: None,
+            server: Server::default(),
+        }
+    }
+}
+
+impl Builder {
+    /// Serve STUN over UDP and TCP on `addr`. Can be called several times.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Receive the datagrams of each address on this many UDP sockets, bound with SO_REUSEPORT.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Require requests to be authenticated with `auth`.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.server = self.server.with_auth(Some(auth));
+        self
+    }
+
+    /// Relay data for clients with TURN. Configure long-term credentials too, the allocations
+    /// are otherwise open to anyone, see [`Server::open_relay`].
+    pub fn with_turn(mut self, turn: Turn) -> Self {
+        self.server = self.server.with_turn(Some(turn));
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP on `addr`, at /metrics.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Serve health checks over HTTP on `addr`, at /healthz and /readyz.
+    pub fn with_health_addr(mut self, addr: SocketAddr) -> Self {
+        self.health_addr = Some(addr);
+        self
+    }
+
+    /// Configure the server beyond what the builder does, e.g. its access control list.
+    pub fn configure(mut self, configure: impl FnOnce(Server) -> Server) -> Self {
+        self.server = configure(self.server);
+        self
+    }
+
+    pub fn build(self) -> StunServer {
+        StunServer {
+            listeners: Listeners {
+                addrs: self.addrs,
+                workers: self.workers,
+                secure: None,
+                metrics_addr: self.metrics_addr,
+                health_addr: self.health_addr,
+                ws_addr: None,
+                admin: None,
+                #[cfg(feature = "acme")]
+                acme: None,
+                #[cfg(unix)]
+                account: None,
+                #[cfg(target_os = "linux")]
+                sandbox: false,
+            },
+            server: Arc::new(self.server),
+        }
+    }
+
+    /// Build the server and serve until the future returned is dropped.
+    pub async fn run(self) -> Result<()> {
+        self.build().run().await
+    }
+}
+
+/// Shuts down a running [`StunServer`], from another task than the one running it.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    server: Arc<Server>,
+}
+
+impl ShutdownHandle {
+    /// Whether every listener is bound.
+    pub fn is_ready(&self) -> bool {
+        self.server.is_ready()
+    }
+
+    /// Statistics of the server in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.server.metrics()
+    }
+
+    /// Refuse new TURN allocations, wait up to `timeout` for the existing ones to be released
+    /// and stop serving. The server stops running once the responses in flight are sent.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.server.drain(timeout).await;
+        self.server.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::StunServer;
+    use crate::message::{attributes, methods, Class, Message};
+
+    /// A port free over UDP, and most likely over TCP.
+    fn free_addr() -> SocketAddr {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Send a Binding request to `addr`, asserting it's answered.
+    async fn assert_answers(addr: SocketAddr) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        client.send_to(&request.encode(), addr).await.unwrap();
+        let mut buf = [0; 1500];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(client.local_addr().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_until_shut_down() {
+        let addr = free_addr();
+        let server = StunServer::builder().bind(addr).build();
+        let handle = server.handle();
+        let running = tokio::spawn(server.run());
+        while !handle.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_answers(addr).await;
+
+        handle.shutdown(Duration::ZERO).await;
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_on_every_port() {
+        let (primary, fallback) = (free_addr(), free_addr());
+        let server = StunServer::builder().bind(primary).bind(fallback).build();
+        let handle = server.handle();
+        let running = tokio::spawn(server.run());
+        while !handle.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_answers(primary).await;
+        assert_answers(fallback).await;
+        assert!(handle
+            .metrics()
+            .contains("stunner_messages_received_total{method=\"binding\",class=\"request\"} 2"));
+
+        handle.shutdown(Duration::ZERO).await;
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+//! Banning of the sources failing repeatedly, fail2ban style: authenticating with wrong
+//! credentials or sending messages that aren't STUN.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default duration of the window failures are counted in.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Default duration of the bans.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(600);
+
+/// Failures of a source in the current window.
+#[derive(Debug)]
+struct Window {
+    count: u32,
+    started_at: Instant,
+}
+
+/// Counts the failures of each source IP, which is banned once it reaches a number of failures
+/// within a window.
+#[derive(Debug)]
+pub struct Failures {
+    threshold: u32,
+    window: Duration,
+    ban_duration: Duration,
+    windows: Mutex<HashMap<IpAddr, Window>>,
+    /// Number of sources banned so far.
+    bans: AtomicU64,
+}
+
+impl Failures {
+    /// Ban the sources failing `threshold` times within `window` for `ban_duration`.
+    pub fn new(threshold: u32, window: Duration, ban_duration: Duration) -> Self {
+        Failures {
+            threshold: threshold.max(1),
+            window,
+            ban_duration,
+            windows: Default::default(),
+            bans: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a failure of `ip`, returning how long to ban it for when it reached the threshold.
+    pub fn fail(&self, ip: IpAddr) -> Option<Duration> {
+        self.fail_at(ip, Instant::now())
+    }
+
+    fn fail_at(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(ip).or_insert(Window {
+            count: 0,
+            started_at: now,
+        });
+        if now.saturating_duration_since(window.started_at) >= self.window {
+            window.count = 0;
+            window.started_at = now;
+        }
+        window.count += 1;
+        if window.count < self.threshold {
+            return None;
+        }
+        windows.remove(&ip);
+        self.bans.fetch_add(1, Ordering::Relaxed);
+        Some(self.ban_duration)
+    }
+
+    /// Number of sources banned so far.
+    pub fn bans(&self) -> u64 {
+        self.bans.load(Ordering::Relaxed)
+    }
+
+    /// Forget the sources whose window ended.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.windows
+            .lock()
+            .unwrap()
+            .retain(|_, window| now.saturating_duration_since(window.started_at) < self.window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Failures;
+
+    #[test]
+    fn bans_sources_reaching_the_threshold_within_the_window() {
+        let failures = Failures::new(3, Duration::from_secs(60), Duration::from_secs(600));
+        let ip = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        assert_eq!(failures.fail_at(ip, now), None);
+        assert_eq!(failures.fail_at(ip, now + Duration::from_secs(1)), None);
+        // The window started over, the failures before are forgotten.
+        let later = now + Duration::from_secs(61);
+        assert_eq!(failures.fail_at(ip, later), None);
+        assert_eq!(failures.fail_at(ip, later), None);
+        assert_eq!(failures.fail_at(ip, later), Some(Duration::from_secs(600)));
+        assert_eq!(failures.bans(), 1);
+        assert_eq!(failures.fail_at(ip, later), None);
+        assert_eq!(failures.fail_at("192.0.2.2".parse().unwrap(), later), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! Source IP ranges denied from a list published over HTTP by a threat intelligence feed, e.g.
+//! the Spamhaus DROP list, fetched again periodically so that the ranges known to be bad are
+//! blocked without anyone updating the configuration. The list is only downloaded again once
+//! its ETag changed.
+//!
+//! The list holds an address or range in CIDR notation per line, optionally followed by a
+//! comment after a `;`, lines starting with `;` or `#` are ignored:
+//!
+//! ```text
+//! ; Spamhaus DROP List
+//! 192.0.2.0/24 ; SBL000001
+//! ```
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Client, StatusCode};
+
+use crate::acl::Cidr;
+use crate::blocklist;
+use crate::server::Server;
+
+/// Default time between two fetches of the list.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Time after which a fetch of the list is given up on.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Denies the sources in the ranges of a list fetched periodically over HTTP.
+pub struct DenyFeed {
+    client: Client,
+    url: String,
+    interval: Duration,
+    ranges: RwLock<Vec<Cidr>>,
+    /// ETag of the list last fetched, if it had one.
+    etag: Mutex<Option<String>>,
+}
+
+impl DenyFeed {
+    /// Deny the sources in the ranges of the list at `url`, fetched every `interval`.
+    pub fn new(url: String, interval: Duration) -> Result<Self> {
+        let client = Client::builder().timeout(TIMEOUT).build()?;
+        Ok(DenyFeed {
+            client,
+            url,
+            interval,
+            ranges: Default::default(),
+            etag: Default::default(),
+        })
+    }
+
+    /// Whether `ip` is in one of the ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ranges
+            .read()
+            .unwrap()
+            .iter()
+            .any(|cidr| cidr.contains(ip))
+    }
+
+    /// Number of ranges denied.
+    pub fn range_count(&self) -> usize {
+        self.ranges.read().unwrap().len()
+    }
+
+    /// Fetch the list again unless it's unchanged, returning whether it changed. The ranges
+    /// fetched last are kept when it can't be.
+    pub async fn refresh(&self) -> Result<bool> {
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = self.etag.lock().unwrap().clone() {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("could not reach {}", self.url))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+        let response = response.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let contents = response.text().await?;
+        let ranges = blocklist::parse(&contents)
+            .with_context(|| format!("invalid deny list from {}", self.url))?;
+        *self.ranges.write().unwrap() = ranges;
+        *self.etag.lock().unwrap() = etag;
+        Ok(true)
+    }
+
+    /// Fetch the list every interval, starting now, until `server` shuts down.
+    pub async fn run(self: Arc<Self>, server: Arc<Server>) -> Result<()> {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = server.stopped() => return Ok(()),
+            }
+            match self.refresh().await {
+                Ok(true) => log::info!(
+                    "fetched the deny list from {}, {} ranges denied",
+                    self.url,
+                    self.range_count()
+                ),
+                Ok(false) => log::debug!("the deny list from {} is unchanged", self.url),
+                Err(err) => log::error!(
+                    "could not fetch the deny list, keeping the current one: {:#}",
+                    err
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::DenyFeed;
+
+    /// Serve a list tagged "v1", answering 304 Not Modified to the requests already having it,
+    /// returning the requests received.
+    async fn feed(listener: TcpListener, count: usize) -> Vec<String> {
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..len]);
+            }
+            let request = String::from_utf8(buf).unwrap().to_ascii_lowercase();
+            let answer = if request.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                let body = "; Spamhaus DROP List\n192.0.2.0/24 ; SBL000001\n198.51.100.7\n";
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            };
+            stream.write_all(answer.as_bytes()).await.unwrap();
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn fetches_the_list_once_changed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/drop.txt", listener.local_addr().unwrap());
+        let serving = tokio::spawn(feed(listener, 2));
+        let feed = DenyFeed::new(url, Duration::from_secs(3600)).unwrap();
+
+        assert!(feed.refresh().await.unwrap());
+        assert!(feed.contains("192.0.2.42".parse().unwrap()));
+        assert!(feed.contains("198.51.100.7".parse().unwrap()));
+        assert!(!feed.contains("198.51.100.8".parse().unwrap()));
+        assert!(!feed.refresh().await.unwrap());
+        assert_eq!(feed.range_count(), 2);
+        let requests = serving.await.unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+    }
+}
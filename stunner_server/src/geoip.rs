@@ -0,0 +1,275 @@
+//! Country and autonomous system of the sources, looked up in MaxMind databases such as
+//! GeoLite2 Country and GeoLite2 ASN, so that operators of public servers know where their
+//! traffic comes from. They tag the access log entries and split a count of the messages
+//! received in the metrics, and countries can be denied the use of the server.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use maxminddb::{geoip2, Reader};
+
+use crate::stats;
+
+/// Number of autonomous systems counted apart, the messages from the others are counted together.
+const MAX_COUNTED_ASNS: usize = 1000;
+
+/// Label of the messages whose country or autonomous system is unknown.
+const UNKNOWN: &str = "unknown";
+
+/// Label of the messages from autonomous systems beyond the ones counted apart.
+const OTHER: &str = "other";
+
+/// Where a source is.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// ISO 3166-1 alpha-2 code of the country.
+    pub country: Option<String>,
+    /// Number of the autonomous system.
+    pub asn: Option<u32>,
+}
+
+/// Countries whose sources may use the server, evaluated before their messages are decoded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CountryPolicy {
+    /// Countries allowed, all but the denied ones when empty.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl CountryPolicy {
+    /// Whether the sources of `country` are permitted. The sources whose country is unknown are
+    /// only denied when some countries are allowed.
+    pub fn permits(&self, country: Option<&str>) -> bool {
+        let listed = |countries: &[String], country: &str| {
+            countries
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(country))
+        };
+        match country {
+            Some(country) if listed(&self.deny, country) => false,
+            _ if self.allow.is_empty() => true,
+            Some(country) => listed(&self.allow, country),
+            None => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Parse an ISO 3166-1 alpha-2 country code, e.g. FR.
+pub fn parse_country(value: &str) -> Result<String> {
+    ensure!(
+        value.len() == 2 && value.bytes().all(|byte| byte.is_ascii_alphabetic()),
+        "expected a two letter country code, e.g. FR, got {:?}",
+        value
+    );
+    Ok(value.to_ascii_uppercase())
+}
+
+/// Numbers of messages received by origin.
+#[derive(Debug, Default)]
+struct Received {
+    countries: HashMap<String, u64>,
+    /// By autonomous system number, 0 standing for an unknown one.
+    asns: HashMap<u32, u64>,
+    /// From autonomous systems beyond the ones counted apart.
+    other_asns: u64,
+}
+
+/// Looks up the origin of the sources and counts their messages.
+pub struct GeoIp {
+    countries: Option<Reader<Vec<u8>>>,
+    asns: Option<Reader<Vec<u8>>>,
+    policy: CountryPolicy,
+    received: Mutex<Received>,
+}
+
+impl GeoIp {
+    /// Look up countries in the database at `country_db`, and autonomous systems in the one at
+    /// `asn_db`, if given.
+    pub fn open(country_db: Option<&Path>, asn_db: Option<&Path>) -> Result<Self> {
+        let open = |path: &Path| {
+            Reader::open_readfile(path)
+                .with_context(|| format!("could not open MaxMind database {}", path.display()))
+        };
+        Ok(GeoIp {
+            countries: country_db.map(open).transpose()?,
+            asns: asn_db.map(open).transpose()?,
+            policy: Default::default(),
+            received: Default::default(),
+        })
+    }
+
+    /// Drop the messages of the sources whose country isn't permitted by `policy`.
+    pub fn with_country_policy(mut self, policy: CountryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether sources are permitted depending on their country.
+    pub fn has_country_policy(&self) -> bool {
+        !self.policy.is_empty()
+    }
+
+    /// Whether the country of `ip` is permitted to use the server.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.policy.is_empty() {
+            return true;
+        }
+        self.policy.permits(self.country(ip).as_deref())
+    }
+
+    /// Origin of `ip`, as far as the databases know.
+    pub fn lookup(&self, ip: IpAddr) -> Origin {
+        let country = self.country(ip);
+        let asn = self.asns.as_ref().and_then(|reader| {
+            let asn: geoip2::Asn = reader.lookup(ip).ok()?.decode().ok()??;
+            asn.autonomous_system_number
+        });
+        Origin { country, asn }
+    }
+
+    /// ISO 3166-1 alpha-2 code of the country of `ip`, if known.
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.countries.as_ref()?;
+        let country: geoip2::Country = reader.lookup(ip).ok()?.decode().ok()??;
+        country.country.iso_code.map(str::to_string)
+    }
+
+    /// Count a message received from `origin`.
+    pub fn count(&self, origin: &Origin) {
+        let mut received = self.received.lock().unwrap();
+        let country = origin.country.as_deref().unwrap_or(UNKNOWN);
+        match received.countries.get_mut(country) {
+            Some(count) => *count += 1,
+            None => {
+                received.countries.insert(country.to_string(), 1);
+            }
+        }
+        let asn = origin.asn.unwrap_or(0);
+        if let Some(count) = received.asns.get_mut(&asn) {
+            *count += 1;
+        } else if received.asns.len() < MAX_COUNTED_ASNS {
+            received.asns.insert(asn, 1);
+        } else {
+            received.other_asns += 1;
+        }
+    }
+
+    /// Append the counts of messages by origin to `out` in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let received = self.received.lock().unwrap();
+        if self.countries.is_some() {
+            let mut countries: Vec<_> = received
+                .countries
+                .iter()
+                .map(|(country, count)| (country.as_str(), *count))
+                .collect();
+            countries.sort_unstable();
+            stats::render_labeled_counter(
+                out,
+                "stunner_messages_by_country_total",
+                "Messages received, by country of their source.",
+                "country",
+                countries,
+            );
+        }
+        if self.asns.is_some() {
+            let mut asns: Vec<_> = received
+                .asns
+                .iter()
+                .map(|(asn, count)| (*asn, *count))
+                .collect();
+            asns.sort_unstable();
+            let mut asns: Vec<_> = asns
+                .into_iter()
+                .map(|(asn, count)| (asn_label(asn), count))
+                .collect();
+            if received.other_asns > 0 {
+                asns.push((OTHER.to_string(), received.other_asns));
+            }
+            stats::render_labeled_counter(
+                out,
+                "stunner_messages_by_asn_total",
+                "Messages received, by autonomous system of their source.",
+                "asn",
+                asns.iter().map(|(asn, count)| (asn.as_str(), *count)),
+            );
+        }
+    }
+}
+
+/// Label of the autonomous system `asn`, 0 standing for an unknown one.
+fn asn_label(asn: u32) -> String {
+    match asn {
+        0 => UNKNOWN.to_string(),
+        asn => asn.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_country, CountryPolicy, GeoIp, Origin, MAX_COUNTED_ASNS};
+
+    #[test]
+    fn permits_countries() {
+        let deny = CountryPolicy {
+            allow: vec![],
+            deny: vec!["KP".into()],
+        };
+        assert!(!deny.permits(Some("KP")));
+        assert!(deny.permits(Some("FR")));
+        assert!(deny.permits(None));
+
+        let allow = CountryPolicy {
+            allow: vec!["FR".into(), "DE".into()],
+            deny: vec![],
+        };
+        assert!(allow.permits(Some("de")));
+        assert!(!allow.permits(Some("US")));
+        assert!(!allow.permits(None));
+
+        assert_eq!(parse_country("fr").unwrap(), "FR");
+        assert!(parse_country("FRA").is_err());
+        assert!(parse_country("1A").is_err());
+    }
+
+    #[test]
+    fn counts_messages_by_origin() {
+        // Without databases, as test databases can't be distributed.
+        let geoip = GeoIp::open(None, None).unwrap();
+        assert_eq!(
+            geoip.lookup("192.0.2.1".parse().unwrap()),
+            Origin::default()
+        );
+        // All but two of the autonomous systems counted apart were seen already.
+        geoip.received.lock().unwrap().asns = (1..MAX_COUNTED_ASNS as u32 - 1)
+            .map(|asn| (asn, 0))
+            .collect();
+
+        let french = Origin {
+            country: Some("FR".into()),
+            asn: Some(3215),
+        };
+        geoip.count(&french);
+        geoip.count(&french);
+        geoip.count(&Origin::default());
+        geoip.count(&Origin {
+            country: None,
+            asn: Some(64496),
+        });
+
+        let received = geoip.received.lock().unwrap();
+        assert_eq!(received.countries["FR"], 2);
+        assert_eq!(received.countries["unknown"], 2);
+        assert_eq!(received.asns[&3215], 2);
+        assert_eq!(received.asns[&0], 1);
+        assert!(!received.asns.contains_key(&64496));
+        assert_eq!(received.other_asns, 1);
+    }
+}
@@ -0,0 +1,343 @@
+//! Pipeline of handlers the messages go through, each of which may reject a message, answer a
+//! request in place of the server or change the request and its response. The built-in
+//! handlers control the access of the sources, authenticate the requests and log the messages,
+//! and are chained in the order they are configured in.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use clap::ArgEnum;
+
+use crate::auth::Credentials;
+use crate::message::{Class, Message};
+use crate::realm::Realm;
+use crate::server::{Server, Source};
+
+/// Future of a hook, boxed so that handlers can be chained as trait objects.
+pub type HookFuture<'a> = Pin<Box<dyn Future<Output = Verdict> + Send + 'a>>;
+
+/// Future of a hook handing the message to the next handler right away. It's zero-sized, so
+/// that boxing it doesn't allocate.
+pub fn proceed<'a>() -> HookFuture<'a> {
+    Box::pin(Proceed)
+}
+
+struct Proceed;
+
+impl Future for Proceed {
+    type Output = Verdict;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Verdict> {
+        Poll::Ready(Verdict::Continue)
+    }
+}
+
+/// What becomes of a message once it went through a hook.
+#[derive(Debug)]
+pub enum Verdict {
+    /// Hand the message to the next handler, then to the server.
+    Continue,
+    /// Drop the message without a response, for the outcome given.
+    Drop(&'static str),
+    /// Answer the request with this response rather than handling it, for the outcome given.
+    Respond(Message, &'static str),
+}
+
+/// A request being handled, as seen by the handlers.
+pub struct Transaction<'a> {
+    pub source: &'a Source,
+    /// The message as received.
+    pub buf: &'a [u8],
+    /// Realm the request belongs to, if any.
+    pub realm: Option<&'a Realm>,
+    /// Credentials the request was authenticated with, once it is.
+    pub credentials: Option<Credentials>,
+}
+
+/// A stage of the handling of messages. Each hook does nothing unless implemented, e.g. a
+/// handler adding SOFTWARE to the responses:
+///
+/// ```
+/// use stunner_server::handler::{Handler, Transaction};
+/// use stunner_server::message::{Attribute, Message};
+///
+/// const SOFTWARE: u16 = 0x8022;
+///
+/// struct Software;
+///
+/// impl Handler for Software {
+///     fn name(&self) -> &'static str {
+///         "software"
+///     }
+///
+///     fn pre_send(&self, _request: &Message, response: &mut Message, _: &Transaction) {
+///         let value = b"example".to_vec();
+///         response.attributes.push(Attribute { kind: SOFTWARE, value });
+///     }
+/// }
+///
+/// let server = stunner_server::StunServer::builder()
+///     .configure(|server| server.with_handlers(vec![Box::new(Software)]))
+///     .build();
+/// # drop(server);
+/// ```
+pub trait Handler: Send + Sync {
+    /// Name of the handler, for logs.
+    fn name(&self) -> &'static str;
+
+    /// Check a message received from `source` before it's decoded, it can only be dropped.
+    fn pre_decode(&self, _server: &Server, _buf: &[u8], _source: &Source) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Check or change a decoded message before the server handles it.
+    fn post_decode<'a>(
+        &'a self,
+        _server: &'a Server,
+        _message: &'a mut Message,
+        _transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        proceed()
+    }
+
+    /// Change the response to `request` before it's encoded and sent.
+    fn pre_send(&self, _request: &Message, _response: &mut Message, _transaction: &Transaction) {}
+
+    /// Whether `pre_send` needs to see the responses. When no handler does, the Binding success
+    /// responses carrying nothing but a XOR-MAPPED-ADDRESS are encoded from a template instead
+    /// of being built.
+    fn sees_responses(&self) -> bool {
+        true
+    }
+}
+
+/// A handler shared with the application, e.g. to read its state while the server runs.
+impl<T: Handler + ?Sized> Handler for Arc<T> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn pre_decode(&self, server: &Server, buf: &[u8], source: &Source) -> Verdict {
+        (**self).pre_decode(server, buf, source)
+    }
+
+    fn post_decode<'a>(
+        &'a self,
+        server: &'a Server,
+        message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        (**self).post_decode(server, message, transaction)
+    }
+
+    fn pre_send(&self, request: &Message, response: &mut Message, transaction: &Transaction) {
+        (**self).pre_send(request, response, transaction)
+    }
+
+    fn sees_responses(&self) -> bool {
+        (**self).sees_responses()
+    }
+}
+
+/// The built-in handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Builtin {
+    /// Drop the messages of the sources denied by the access control lists, the blocklist, the
+    /// bans and the realms.
+    Acl,
+    /// Authenticate the requests with the credentials of their realm or of the server.
+    Auth,
+    /// Log the messages received and the responses sent at the debug level.
+    Log,
+}
+
+impl Builtin {
+    /// The handler.
+    pub fn handler(self) -> Box<dyn Handler> {
+        match self {
+            Builtin::Acl => Box::new(AclHandler),
+            Builtin::Auth => Box::new(AuthHandler),
+            Builtin::Log => Box::new(LogHandler),
+        }
+    }
+}
+
+/// Handlers of a server unless configured otherwise.
+pub fn default_chain() -> Vec<Box<dyn Handler>> {
+    [Builtin::Acl, Builtin::Auth, Builtin::Log]
+        .into_iter()
+        .map(Builtin::handler)
+        .collect()
+}
+
+struct AclHandler;
+
+impl Handler for AclHandler {
+    fn name(&self) -> &'static str {
+        "acl"
+    }
+
+    fn sees_responses(&self) -> bool {
+        false
+    }
+
+    fn pre_decode(&self, server: &Server, _buf: &[u8], source: &Source) -> Verdict {
+        match server.admits(source.addr) {
+            true => Verdict::Continue,
+            false => Verdict::Drop("denied"),
+        }
+    }
+
+    fn post_decode<'a>(
+        &'a self,
+        server: &'a Server,
+        _message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        match transaction.realm {
+            Some(realm) if !realm.permits(transaction.source.addr.ip()) => {
+                server.stats().denied();
+                log::trace!(
+                    "dropping message from source {:?} denied in realm {:?}",
+                    transaction.source.addr,
+                    realm.name()
+                );
+                Box::pin(async { Verdict::Drop("denied") })
+            }
+            _ => proceed(),
+        }
+    }
+}
+
+struct AuthHandler;
+
+impl Handler for AuthHandler {
+    fn name(&self) -> &'static str {
+        "auth"
+    }
+
+    fn sees_responses(&self) -> bool {
+        false
+    }
+
+    fn post_decode<'a>(
+        &'a self,
+        server: &'a Server,
+        message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        // Indications can't be challenged, only requests are authenticated.
+        if message.class != Class::Request || !server.authenticates(transaction) {
+            return proceed();
+        }
+        Box::pin(async move {
+            match server.authenticate(message, transaction).await {
+                Ok(credentials) => {
+                    transaction.credentials = credentials;
+                    Verdict::Continue
+                }
+                Err(response) => Verdict::Respond(response, "unauthorized"),
+            }
+        })
+    }
+}
+
+struct LogHandler;
+
+impl Handler for LogHandler {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn post_decode<'a>(
+        &'a self,
+        _server: &'a Server,
+        message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        log::debug!("received {:?} from {:?}", message, transaction.source.addr);
+        proceed()
+    }
+
+    fn pre_send(&self, _request: &Message, response: &mut Message, transaction: &Transaction) {
+        log::debug!("replying {:?} to {:?}", response, transaction.source.addr);
+    }
+
+    /// Only to log them.
+    fn sees_responses(&self) -> bool {
+        log::log_enabled!(log::Level::Debug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::{Builtin, Handler, HookFuture, Transaction, Verdict};
+    use crate::auth::{Auth, ShortTermAuth};
+    use crate::message::{methods, Class, Message};
+    use crate::server::{Server, Sink, Source, Transport};
+
+    /// Answers every request 403 Forbidden.
+    struct Forbid;
+
+    impl Handler for Forbid {
+        fn name(&self) -> &'static str {
+            "forbid"
+        }
+
+        fn post_decode<'a>(
+            &'a self,
+            _server: &'a Server,
+            message: &'a mut Message,
+            _transaction: &'a mut Transaction<'_>,
+        ) -> HookFuture<'a> {
+            let response = message.error_response(403, "Forbidden");
+            Box::pin(async { Verdict::Respond(response, "forbidden") })
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_messages_through_the_chain_in_order() {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let source = Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock),
+        };
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let error_code = |server: Server| {
+            let (request, source) = (&request, &source);
+            async move {
+                let response = server.handle(&request.encode(), source).await.unwrap();
+                Message::decode(&response).unwrap().error_code()
+            }
+        };
+        let auth = || {
+            Some(Auth::ShortTerm(ShortTermAuth::new(
+                "user".into(),
+                "pass".into(),
+            )))
+        };
+
+        assert_eq!(
+            error_code(Server::default().with_auth(auth())).await,
+            Some(400)
+        );
+        // Requests aren't authenticated without the auth handler.
+        let server = Server::default()
+            .with_auth(auth())
+            .with_handlers(vec![Builtin::Acl.handler(), Builtin::Log.handler()]);
+        assert_eq!(error_code(server).await, None);
+        // The first handler answering the request stops the chain.
+        let server = Server::default()
+            .with_auth(auth())
+            .with_handlers(vec![Box::new(Forbid), Builtin::Auth.handler()]);
+        assert_eq!(error_code(server).await, Some(403));
+    }
+}
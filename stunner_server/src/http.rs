@@ -0,0 +1,222 @@
+//! HTTP endpoints exposing the server statistics to Prometheus and its health to probes.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::server::Server;
+
+/// Largest HTTP request head accepted.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time a client has to send the head of its request before the connection is closed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of connections served at once on a listener, the others are closed right away.
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// Endpoints served on a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Endpoints {
+    /// Serve the statistics on `GET /metrics`.
+    pub metrics: bool,
+    /// Serve the liveness on `GET /healthz` and the readiness on `GET /readyz`.
+    pub health: bool,
+}
+
+/// Serve the given endpoints of `server`, until it shuts down.
+pub async fn serve(listener: TcpListener, endpoints: Endpoints, server: Arc<Server>) -> Result<()> {
+    log::info!(
+        "serving {:?} over HTTP on addr: {}",
+        endpoints,
+        listener.local_addr()?
+    );
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = server.stopped() => return Ok(()),
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            log::debug!(
+                "closing HTTP connection from {:?}, too many open",
+                peer_addr
+            );
+            continue;
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_connection(stream, endpoints, &server).await {
+                log::debug!("HTTP request from {:?} failed: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request and close the connection.
+async fn handle_connection(
+    mut stream: TcpStream,
+    endpoints: Endpoints,
+    server: &Server,
+) -> Result<()> {
+    let buf = match read_head(&mut stream).await? {
+        Some(buf) => buf,
+        None => return Ok(()),
+    };
+
+    let response = if endpoints.metrics && buf.starts_with(b"GET /metrics ") {
+        response("200 OK", "text/plain; version=0.0.4", &server.metrics())
+    } else if endpoints.health && buf.starts_with(b"GET /healthz ") {
+        response("200 OK", "text/plain", "ok\n")
+    } else if endpoints.health && buf.starts_with(b"GET /readyz ") {
+        if server.is_ready() {
+            response("200 OK", "text/plain", "ready\n")
+        } else {
+            response("503 Service Unavailable", "text/plain", "not ready\n")
+        }
+    } else {
+        response("404 Not Found", "text/plain", "")
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the head of a request, up to the blank line ending it, or `None` when the connection is
+/// closed or the head too large before that. The body if any is left unread. Fails when the
+/// head isn't received within [`REQUEST_TIMEOUT`].
+pub async fn read_head(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let read = async {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+        while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+            let len = stream.read(&mut chunk).await?;
+            if len == 0 || buf.len() + len > MAX_REQUEST_SIZE {
+                return Ok(None);
+            }
+            buf.extend_from_slice(&chunk[..len]);
+        }
+        Ok(Some(buf))
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .context("timed out reading the request head")?
+}
+
+pub fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Endpoints, MAX_CONNECTIONS};
+    use crate::server::Server;
+
+    async fn spawn(endpoints: Endpoints, server: Arc<Server>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, endpoints, server));
+        addr
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let endpoints = Endpoints {
+            metrics: true,
+            health: false,
+        };
+        let addr = spawn(endpoints, Arc::new(Server::default())).await;
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("stunner_decode_failures_total 0\n"));
+
+        for path in ["/", "/healthz"] {
+            let response = get(addr, path).await;
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_the_connections_over_the_limit() {
+        let endpoints = Endpoints {
+            metrics: true,
+            health: false,
+        };
+        let addr = spawn(endpoints, Arc::new(Server::default())).await;
+        let mut idle = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            idle.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap_or(0), 0);
+
+        // Closing a connection makes room for another, once the server notices.
+        idle.pop();
+        loop {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut response = String::new();
+            let served = stream
+                .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+                .await
+                .is_ok()
+                && stream.read_to_string(&mut response).await.is_ok()
+                && response.starts_with("HTTP/1.1 200 OK\r\n");
+            if served {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_readiness_once_listening() {
+        let endpoints = Endpoints {
+            metrics: false,
+            health: true,
+        };
+        let server = Arc::new(Server::default());
+        let addr = spawn(endpoints, server.clone()).await;
+        assert!(get(addr, "/healthz")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        server.set_ready();
+        assert!(get(addr, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/metrics")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
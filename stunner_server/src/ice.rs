@@ -0,0 +1,204 @@
+//! ICE-lite agent answering the connectivity checks of a full ICE agent, so that clients can
+//! be tested against the server, see https://datatracker.ietf.org/doc/html/rfc8445#section-2.5
+//!
+//! The checks are Binding requests signed with the short-term credentials of the agent,
+//! USERNAME being its ufrag and the one of the full agent separated by a colon, and
+//! MESSAGE-INTEGRITY computed with its password. The responses are signed the same way, and
+//! should carry a FINGERPRINT, see [`crate::server::Server::with_fingerprint`].
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::auth::Credentials;
+use crate::handler::{Handler, HookFuture, Transaction, Verdict};
+use crate::message::{attributes, methods, Class, Integrity, Message};
+use crate::server::Server;
+
+/// A lite agent, always in the controlled role, see
+/// https://datatracker.ietf.org/doc/html/rfc8445#section-6.1.1
+pub struct IceLite {
+    ufrag: String,
+    password: String,
+    /// Addresses of the candidates the controlling agent nominated with USE-CANDIDATE.
+    nominated: Mutex<HashSet<SocketAddr>>,
+}
+
+impl IceLite {
+    pub fn new(ufrag: String, password: String) -> Self {
+        IceLite {
+            ufrag,
+            password,
+            nominated: Default::default(),
+        }
+    }
+
+    /// Addresses of the candidates nominated so far.
+    pub fn nominated(&self) -> Vec<SocketAddr> {
+        self.nominated.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Answer the connectivity check `request`, received from `addr` as `buf`, returning the
+    /// credentials to sign the response with, see
+    /// https://datatracker.ietf.org/doc/html/rfc8445#section-7.3
+    fn check(
+        &self,
+        request: &Message,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> (Message, Option<Credentials>) {
+        let (username, integrity) = match (
+            request.get_str(attributes::USERNAME),
+            Integrity::of(request),
+        ) {
+            (Some(username), Some(integrity)) => (username, integrity),
+            _ => return (request.error_response(400, "Missing credentials"), None),
+        };
+        let key = self.password.as_bytes();
+        let local_ufrag = username.split_once(':').map(|(local, _)| local);
+        if local_ufrag != Some(self.ufrag.as_str()) || !integrity.check(buf, key) {
+            return (request.error_response(401, "Unauthorized"), None);
+        }
+        let credentials = Credentials {
+            username: username.to_string(),
+            key: key.to_vec(),
+            integrity,
+        };
+        // Checks carry the priority and the role of the agent sending them, a lite agent can't
+        // take the controlling role so the full agent has to.
+        let response = match (
+            request.get(attributes::PRIORITY),
+            request.get(attributes::ICE_CONTROLLING),
+            request.get(attributes::ICE_CONTROLLED),
+        ) {
+            (Some(_), Some(_), None) => None,
+            (Some(_), _, Some(_)) => Some(request.error_response(487, "Role Conflict")),
+            _ => Some(request.error_response(400, "Missing PRIORITY or role")),
+        };
+        if let Some(response) = response {
+            return (response, Some(credentials));
+        }
+        if request.get(attributes::USE_CANDIDATE).is_some()
+            && self.nominated.lock().unwrap().insert(addr)
+        {
+            log::info!("candidate {} nominated by {:?}", addr, username);
+        }
+        let response = request
+            .success_response()
+            .add_xor_address(attributes::XOR_MAPPED_ADDRESS, addr);
+        (response, Some(credentials))
+    }
+}
+
+impl Handler for IceLite {
+    fn name(&self) -> &'static str {
+        "ice"
+    }
+
+    fn sees_responses(&self) -> bool {
+        false
+    }
+
+    /// Answer the Binding requests carrying credentials, the other messages are handled by the
+    /// server as usual.
+    fn post_decode<'a>(
+        &'a self,
+        _server: &'a Server,
+        message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        let verdict = match (message.method, message.class) {
+            (methods::BINDING, Class::Request)
+                if message.get(attributes::USERNAME).is_some()
+                    || Integrity::of(message).is_some() =>
+            {
+                let (response, credentials) =
+                    self.check(message, transaction.buf, transaction.source.addr);
+                transaction.credentials = credentials;
+                let outcome = match response.class {
+                    Class::SuccessResponse => "connectivity_check",
+                    _ => "rejected_check",
+                };
+                Verdict::Respond(response, outcome)
+            }
+            _ => Verdict::Continue,
+        };
+        Box::pin(async { verdict })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::IceLite;
+    use crate::handler::default_chain;
+    use crate::message::{attributes, check_integrity, methods, Class, Message};
+    use crate::server::{Server, Sink, Source, Transport};
+
+    #[tokio::test]
+    async fn answers_connectivity_checks() {
+        let ice = Arc::new(IceLite::new("lite".into(), "password".into()));
+        let mut handlers = default_chain();
+        handlers.push(Box::new(ice.clone()));
+        let server = Server::default()
+            .with_fingerprint(true)
+            .with_handlers(handlers);
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let source = Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock),
+        };
+        let check = |username: &str, password: &[u8], role: u16| {
+            Message::with_random_transaction_id(methods::BINDING, Class::Request)
+                .add_attribute(attributes::USERNAME, username.as_bytes().to_vec())
+                .add_u32(attributes::PRIORITY, 0x6E00_1EFF)
+                .add_attribute(role, vec![0; 8])
+                .add_attribute(attributes::USE_CANDIDATE, vec![])
+                .encode_with_integrity(password)
+        };
+
+        let response = server
+            .handle(
+                &check("lite:full", b"password", attributes::ICE_CONTROLLING),
+                &source,
+            )
+            .await
+            .unwrap();
+        assert!(check_integrity(&response, b"password"));
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+        assert!(response.get(attributes::FINGERPRINT).is_some());
+        assert_eq!(ice.nominated(), vec![source.addr]);
+
+        let error_code = |buf: Vec<u8>| {
+            let (server, source) = (&server, &source);
+            async move {
+                let response = server.handle(&buf, source).await.unwrap();
+                Message::decode(&response).unwrap().error_code()
+            }
+        };
+        let controlled = check("lite:full", b"password", attributes::ICE_CONTROLLED);
+        assert_eq!(error_code(controlled).await, Some(487));
+        let wrong_password = check("lite:full", b"wrong", attributes::ICE_CONTROLLING);
+        assert_eq!(error_code(wrong_password).await, Some(401));
+        let wrong_ufrag = check("other:full", b"password", attributes::ICE_CONTROLLING);
+        assert_eq!(error_code(wrong_ufrag).await, Some(401));
+        let without_role = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERNAME, b"lite:full".to_vec())
+            .add_u32(attributes::PRIORITY, 0x6E00_1EFF)
+            .encode_with_integrity(b"password");
+        assert_eq!(error_code(without_role).await, Some(400));
+        // Plain Binding requests are still answered.
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        assert_eq!(error_code(request.encode()).await, None);
+    }
+}
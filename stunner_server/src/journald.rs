@@ -0,0 +1,161 @@
+//! Log records written to the systemd journal with its native protocol, keeping the structured
+//! fields of the records so that they can be filtered on, e.g.
+//! `journalctl -u stunner STUN_METHOD=allocate`,
+//! see https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+
+use std::fmt::Write as _;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use env_logger::Logger;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+
+/// Socket the journal receives the records on.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier of the records, as shown by journalctl.
+const SYSLOG_IDENTIFIER: &str = "stunner_server";
+
+/// Writes the records matching the filter of `RUST_LOG` to the journal.
+pub struct Journal {
+    sock: UnixDatagram,
+    filter: Logger,
+}
+
+impl Journal {
+    /// Write the records matching `filter` to the journal `sock` is connected to.
+    pub fn new(sock: UnixDatagram, filter: Logger) -> Self {
+        Journal { sock, filter }
+    }
+}
+
+/// Connect to the journal, before the process is sandboxed.
+pub fn connect() -> io::Result<UnixDatagram> {
+    let sock = UnixDatagram::unbound()?;
+    sock.connect(JOURNAL_SOCKET)?;
+    Ok(sock)
+}
+
+impl Log for Journal {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            // Nowhere to report the records the journal doesn't take.
+            let _ = self.sock.send(&entry(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Journal entry of `record`: its message, priority, target and source location, and its
+/// key-values as fields named after their keys in uppercase.
+fn entry(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::new();
+    append_field(&mut entry, "MESSAGE", &record.args().to_string());
+    append_field(&mut entry, "PRIORITY", priority(record.level()));
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    append_field(&mut entry, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        append_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        append_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut entry));
+    entry
+}
+
+/// Syslog priority of `level`.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Name of the journal field of the record key `key`, made of uppercase letters, digits and
+/// underscores. The fields describing STUN messages are prefixed.
+fn field_name(key: &str) -> String {
+    let name = match key {
+        "method" | "class" | "outcome" => format!("STUN_{}", key),
+        "txid" => "STUN_TRANSACTION_ID".to_string(),
+        key => key.to_string(),
+    };
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .take(64)
+        .collect();
+    // Fields starting with an underscore are trusted ones set by the journal itself.
+    if name.starts_with(|c: char| c == '_' || c.is_ascii_digit()) {
+        format!("F{}", &name[..name.len().min(63)])
+    } else {
+        name
+    }
+}
+
+/// Append a field to `entry`, length-prefixed when its value spans several lines.
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Appends the key-values of a record as journal fields.
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let mut text = String::new();
+        let _ = write!(text, "{}", value);
+        append_field(self.0, &field_name(key.as_str()), &text);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, Record};
+
+    use super::{entry, field_name};
+
+    #[test]
+    fn encodes_records_with_their_fields() {
+        let fields: &[(&str, &str)] = &[("src_addr", "192.0.2.1:5000"), ("method", "allocate")];
+        let record = Record::builder()
+            .args(format_args!("handled allocate"))
+            .level(Level::Warn)
+            .target("stunner_server::access")
+            .key_values(&fields)
+            .build();
+        assert_eq!(
+            String::from_utf8(entry(&record)).unwrap(),
+            "MESSAGE=handled allocate\nPRIORITY=4\nSYSLOG_IDENTIFIER=stunner_server\n\
+             TARGET=stunner_server::access\nSRC_ADDR=192.0.2.1:5000\nSTUN_METHOD=allocate\n"
+        );
+
+        // Values spanning several lines are length-prefixed.
+        let record = Record::builder().args(format_args!("a\nb")).build();
+        assert!(entry(&record).starts_with(b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n"));
+
+        assert_eq!(field_name("txid"), "STUN_TRANSACTION_ID");
+        assert_eq!(field_name("user-name"), "USER_NAME");
+        assert_eq!(field_name("_pid"), "F_PID");
+    }
+}
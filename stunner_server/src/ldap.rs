@@ -0,0 +1,194 @@
+//! Users looked up in an LDAP directory such as Active Directory as requests are authenticated,
+//! so that enterprises reuse their directory for the TURN credentials.
+//!
+//! The key of long-term credentials is derived from the password, which clients never send: the
+//! server can't authenticate users by binding as them. It binds with its own account instead and
+//! reads from the entry of the user their HA1 hash MD5(username:realm:password) in hex, or their
+//! password when the directory exposes it.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use tokio::sync::Mutex;
+
+use crate::auth::PasswordAlgorithm;
+use crate::secret::{parse_ha1, Secret, SecretCache};
+
+/// How long connecting, binding and searching may take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the secret looked up for a username is reused, including when there is no such user.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Placeholder of the username in the search filter.
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// Where and how users are looked up in the directory.
+pub struct LdapConfig {
+    /// URL of the directory, ldap:// or ldaps://.
+    pub url: String,
+    /// Account the server binds with, and its password, anonymous if not given.
+    pub bind: Option<(String, String)>,
+    /// Entry the users are searched under.
+    pub base_dn: String,
+    /// Filter matching the entry of a user, with `{username}` standing for the username.
+    pub filter: String,
+    /// Attribute holding the HA1 hash of a user, in hex.
+    pub ha1_attribute: String,
+    /// Attribute holding the password of a user, preferred to the HA1 hash when set.
+    pub password_attribute: Option<String>,
+}
+
+/// Looks up the long-term credential keys of users in an LDAP directory.
+pub struct LdapDirectory {
+    config: LdapConfig,
+    /// Connection to the directory, opened when first needed and again once closed.
+    connection: Mutex<Option<Ldap>>,
+    cache: SecretCache,
+}
+
+impl LdapDirectory {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapDirectory {
+            config,
+            connection: Mutex::new(None),
+            cache: SecretCache::new(CACHE_TTL),
+        }
+    }
+
+    /// Long-term credential key of `username` in `realm` derived with `algorithm`, if there is
+    /// such a user.
+    pub async fn key(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Result<Option<Vec<u8>>> {
+        let secret = match self.cache.get(username) {
+            Some(secret) => secret,
+            None => {
+                let secret = self.lookup(username).await?;
+                self.cache.insert(username, secret.clone());
+                secret
+            }
+        };
+        Ok(secret.and_then(|secret| secret.key(username, realm, algorithm)))
+    }
+
+    async fn lookup(&self, username: &str) -> Result<Option<Secret>> {
+        let mut ldap = self.connect().await?;
+        let mut attributes = vec![self.config.ha1_attribute.as_str()];
+        attributes.extend(self.config.password_attribute.as_deref());
+        let (entries, _) = ldap
+            .with_timeout(TIMEOUT)
+            .search(
+                &self.config.base_dn,
+                Scope::Subtree,
+                &user_filter(&self.config.filter, username),
+                attributes,
+            )
+            .await?
+            .success()
+            .with_context(|| format!("could not search {}", self.config.base_dn))?;
+        match entries.into_iter().next() {
+            Some(entry) => secret(&SearchEntry::construct(entry).attrs, &self.config),
+            None => Ok(None),
+        }
+    }
+
+    /// Connection to the directory, bound with the account of the server.
+    async fn connect(&self) -> Result<Ldap> {
+        let mut connection = self.connection.lock().await;
+        if let Some(ldap) = connection.as_mut() {
+            if !ldap.is_closed() {
+                return Ok(ldap.clone());
+            }
+        }
+        let settings = LdapConnSettings::new().set_conn_timeout(TIMEOUT);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .with_context(|| format!("could not connect to {}", self.config.url))?;
+        ldap3::drive!(conn);
+        if let Some((dn, password)) = &self.config.bind {
+            ldap.with_timeout(TIMEOUT)
+                .simple_bind(dn, password)
+                .await?
+                .success()
+                .with_context(|| format!("could not bind as {}", dn))?;
+        }
+        *connection = Some(ldap.clone());
+        Ok(ldap)
+    }
+}
+
+/// Search filter matching the entry of `username`, escaped so that it matches no other.
+fn user_filter(filter: &str, username: &str) -> String {
+    filter.replace(USERNAME_PLACEHOLDER, &ldap_escape(username))
+}
+
+/// Secret of a user with the attributes `attrs`, `None` when it has neither a password nor a
+/// HA1 hash. Attribute names are case-insensitive.
+fn secret(attrs: &HashMap<String, Vec<String>>, config: &LdapConfig) -> Result<Option<Secret>> {
+    let value = |attribute: &str| {
+        attrs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+            .and_then(|(_, values)| values.first())
+    };
+    if let Some(password) = config.password_attribute.as_deref().and_then(value) {
+        return Ok(Some(Secret::Password(password.clone())));
+    }
+    value(&config.ha1_attribute)
+        .map(|ha1| Ok(Secret::Ha1(parse_ha1(ha1)?)))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{secret, user_filter, LdapConfig};
+    use crate::auth::key;
+    use crate::message::hex;
+    use crate::secret::Secret;
+
+    #[test]
+    fn escapes_usernames_in_filters() {
+        assert_eq!(
+            user_filter("(&(objectClass=user)(sAMAccountName={username}))", "alice"),
+            "(&(objectClass=user)(sAMAccountName=alice))"
+        );
+        assert_eq!(
+            user_filter("(uid={username})", "*)(uid=*"),
+            r"(uid=\2a\29\28uid=\2a)"
+        );
+    }
+
+    #[test]
+    fn reads_passwords_and_ha1_hashes() {
+        let mut config = LdapConfig {
+            url: "ldap://localhost".into(),
+            bind: None,
+            base_dn: "dc=example,dc=com".into(),
+            filter: "(uid={username})".into(),
+            ha1_attribute: "turnHA1".into(),
+            password_attribute: None,
+        };
+        let ha1 = key("alice", "stunner", "pass");
+        let attrs: HashMap<String, Vec<String>> = [
+            ("turnha1".to_string(), vec![hex(&ha1)]),
+            ("userPassword".to_string(), vec!["pass".to_string()]),
+        ]
+        .into();
+
+        assert_eq!(secret(&attrs, &config).unwrap(), Some(Secret::Ha1(ha1)));
+        config.password_attribute = Some("userPassword".into());
+        assert_eq!(
+            secret(&attrs, &config).unwrap(),
+            Some(Secret::Password("pass".into()))
+        );
+        assert_eq!(secret(&HashMap::new(), &config).unwrap(), None);
+    }
+}
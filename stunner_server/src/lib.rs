@@ -0,0 +1,639 @@
+//! STUN and TURN server, which applications such as game servers and SFUs can embed rather
+//! than running the `stunner_server` binary, see [`StunServer`]:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! let server = stunner_server::StunServer::builder()
+//!     .bind("0.0.0.0:3478".parse()?)
+//!     .build();
+//! let handle = server.handle();
+//! tokio::spawn(server.run());
+//! // Until the application stops.
+//! handle.shutdown(Duration::from_secs(5)).await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The modules are the building blocks of the binary, exposed for applications needing more
+//! control, without the stability guarantees of the builder.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use attribute::Attributes;
+use message::{attributes, methods, Class, Message};
+use server::Server;
+
+pub mod accesslog;
+pub mod acl;
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod activation;
+mod admin;
+#[cfg(target_os = "linux")]
+pub mod affinity;
+pub mod attribute;
+pub mod auth;
+pub mod bench;
+pub mod blocklist;
+pub mod config;
+pub mod demux;
+pub mod discovery;
+#[cfg(feature = "dtls")]
+pub mod dtls;
+mod embed;
+pub mod failures;
+#[cfg(feature = "feed")]
+pub mod feed;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod handler;
+mod http;
+pub mod ice;
+#[cfg(target_os = "linux")]
+mod journald;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub mod logging;
+pub mod logsample;
+pub mod message;
+#[cfg(target_os = "linux")]
+mod mmsg;
+pub mod net;
+#[cfg(target_os = "linux")]
+mod notify;
+pub mod pcap;
+#[cfg(target_os = "linux")]
+mod pktinfo;
+mod pool;
+#[cfg(unix)]
+pub mod privileges;
+mod proxy;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod ratelimit;
+pub mod realm;
+#[cfg(target_os = "linux")]
+mod sandbox;
+#[cfg(any(feature = "sql", feature = "webhook", feature = "ldap"))]
+mod secret;
+pub mod server;
+pub mod shutdown;
+pub mod state;
+mod stats;
+pub mod statsd;
+pub mod tcp;
+pub mod tls;
+pub mod transactions;
+pub mod transport;
+pub mod turn;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(feature = "sql")]
+pub mod userdb;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+mod ws;
+#[cfg(target_os = "linux")]
+pub mod xdp;
+
+pub use embed::{Builder, ShutdownHandle, StunServer};
+
+/// Where the server listens.
+pub struct Listeners {
+    /// Addresses STUN is served on.
+    pub addrs: Vec<SocketAddr>,
+    /// Number of UDP sockets bound on each address.
+    pub workers: usize,
+    pub secure: Option<Secure>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub health_addr: Option<SocketAddr>,
+    pub ws_addr: Option<SocketAddr>,
+    /// Address and token of the admin API, when enabled.
+    pub admin: Option<(SocketAddr, String)>,
+    /// Address where the ACME challenges are answered and client obtaining the certificate of
+    /// `secure`, when enabled.
+    #[cfg(feature = "acme")]
+    pub acme: Option<(SocketAddr, Arc<acme::Acme>)>,
+    /// Account to switch to once the sockets are bound, if any.
+    #[cfg(unix)]
+    pub account: Option<privileges::Account>,
+    /// Whether to restrict the system calls of the process once serving.
+    #[cfg(target_os = "linux")]
+    pub sandbox: bool,
+}
+
+/// Transports secured with the configured certificate, served on the TLS port.
+#[derive(Clone)]
+pub struct Secure {
+    pub port: u16,
+    /// Certificate presented over TLS and QUIC, reloaded on SIGHUP.
+    pub certificate: Arc<tls::Certificate>,
+    pub tls: TlsAcceptor,
+    /// Whether the clients must negotiate a STUN ALPN protocol.
+    pub strict_alpn: bool,
+    #[cfg(feature = "dtls")]
+    pub dtls: Option<openssl::ssl::SslAcceptor>,
+    /// Port and configuration of STUN over QUIC, when enabled.
+    #[cfg(feature = "quic")]
+    pub quic: Option<(u16, quinn::ServerConfig)>,
+}
+
+/// Listen for STUN requests on each of the given addresses, over UDP, TCP and the secure
+/// transports when configured, and serve the HTTP endpoints. The server is ready once every
+/// listener is bound.
+pub async fn serve(listeners: Listeners, server: Arc<Server>) -> Result<()> {
+    let mut tasks = JoinSet::new();
+    #[cfg(target_os = "linux")]
+    let notifier = notify::Notifier::from_env();
+    let mut http = Vec::<(SocketAddr, http::Endpoints)>::new();
+    for (addr, metrics, health) in [
+        (listeners.metrics_addr, true, false),
+        (listeners.health_addr, false, true),
+    ] {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+        // The endpoints share the listener when given the same address.
+        match http.iter_mut().find(|(http_addr, _)| *http_addr == addr) {
+            Some((_, endpoints)) => {
+                endpoints.metrics |= metrics;
+                endpoints.health |= health;
+            }
+            None => http.push((addr, http::Endpoints { metrics, health })),
+        }
+    }
+    for (addr, endpoints) in http {
+        tasks.spawn(http::serve(
+            net::bind_http(addr)?,
+            endpoints,
+            server.clone(),
+        ));
+    }
+    if let Some(addr) = listeners.ws_addr {
+        tasks.spawn(ws::serve(
+            net::bind_tcp(addr, server.socket_config())?,
+            server.clone(),
+        ));
+    }
+    if let Some((addr, token)) = listeners.admin {
+        tasks.spawn(admin::serve(net::bind_http(addr)?, token, server.clone()));
+    }
+    #[cfg(feature = "acme")]
+    if let (Some((addr, acme)), Some(secure)) = (listeners.acme, &listeners.secure) {
+        let listener = net::bind_http(addr)?;
+        tasks.spawn(acme.serve(listener, secure.certificate.clone(), server.clone()));
+    }
+
+    // The secure transports are served once per IP, whatever the number of ports on it.
+    let mut secured = HashSet::new();
+    for addr in listeners.addrs {
+        // The sockets for NAT behavior discovery are already bound.
+        let mut socks: Vec<_> = match server
+            .discovery()
+            .and_then(|discovery| discovery.socket(addr))
+        {
+            Some(sock) => vec![sock.clone()],
+            None => net::bind_udp_workers(addr, listeners.workers, server.socket_config())?
+                .into_iter()
+                .map(Arc::new)
+                .collect(),
+        };
+        // Every worker runs its own receive loop, the first one also serves the other transports.
+        for sock in socks.split_off(1) {
+            tasks.spawn(serve_udp(sock, server.clone()));
+        }
+        serve_addr(
+            &mut tasks,
+            socks.remove(0),
+            listeners
+                .secure
+                .as_ref()
+                .filter(|_| secured.insert(addr.ip())),
+            &server,
+        )?;
+    }
+    if let Some(discovery) = server.discovery() {
+        for sock in discovery.sockets().skip(1) {
+            log::info!(
+                "serving NAT behavior discovery on addr: {}",
+                sock.local_addr()?
+            );
+            tasks.spawn(serve_udp(sock.clone(), server.clone()));
+        }
+    }
+    #[cfg(unix)]
+    if let Some(account) = listeners.account {
+        account.switch().context("could not drop privileges")?;
+    }
+    #[cfg(target_os = "linux")]
+    if listeners.sandbox {
+        sandbox::restrict()?;
+    }
+    tasks.spawn(server.clone().housekeeping());
+    if let Some(statsd) = server.statsd() {
+        tasks.spawn(statsd.clone().run(server.clone()));
+    }
+    #[cfg(feature = "feed")]
+    if let Some(deny_feed) = server.deny_feed() {
+        tasks.spawn(deny_feed.clone().run(server.clone()));
+    }
+    server.set_ready();
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = notifier {
+        tasks.spawn(notifier.serve(server.clone()));
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Listen for STUN requests on the address of the given socket, over both UDP and TCP, and reply
+/// to valid STUN Binding Requests. When secure transports are given they are also served on
+/// their port. The listeners are bound before returning, and served by tasks spawned on `tasks`.
+fn serve_addr(
+    tasks: &mut JoinSet<Result<()>>,
+    sock: Arc<UdpSocket>,
+    secure: Option<&Secure>,
+    server: &Arc<Server>,
+) -> Result<()> {
+    let local_addr = sock.local_addr()?;
+    // Servers SHOULD accept STUN over TCP on the same port as UDP,
+    // see https://datatracker.ietf.org/doc/html/rfc5389#section-9
+    let listener = net::bind_tcp(local_addr, server.socket_config())?;
+    log::info!("serving on addr: {}", local_addr);
+    tasks.spawn(serve_udp(sock, server.clone()));
+    tasks.spawn(tcp::serve(listener, server.clone()));
+
+    if let Some(secure) = secure {
+        let addr = SocketAddr::new(local_addr.ip(), secure.port);
+        serve_secure(tasks, addr, secure, server)?;
+    }
+    Ok(())
+}
+
+/// Serve STUN over TLS, and over DTLS and QUIC when enabled, on the given address.
+fn serve_secure(
+    tasks: &mut JoinSet<Result<()>>,
+    addr: SocketAddr,
+    secure: &Secure,
+    server: &Arc<Server>,
+) -> Result<()> {
+    let listener = net::bind_tcp(addr, server.socket_config())?;
+    log::info!("serving TLS on addr: {}", listener.local_addr()?);
+    tasks.spawn(tls::serve(
+        listener,
+        secure.tls.clone(),
+        secure.strict_alpn,
+        server.clone(),
+    ));
+
+    #[cfg(feature = "dtls")]
+    if let Some(acceptor) = &secure.dtls {
+        let sock = net::bind_udp(addr, server.socket_config())?;
+        log::info!("serving DTLS on addr: {}", sock.local_addr()?);
+        tasks.spawn(dtls::serve(
+            sock,
+            acceptor.clone(),
+            secure.strict_alpn,
+            server.clone(),
+        ));
+    }
+
+    #[cfg(feature = "quic")]
+    if let Some((port, config)) = &secure.quic {
+        let sock = net::bind_udp(SocketAddr::new(addr.ip(), *port), server.socket_config())?;
+        let endpoint = quic::endpoint(sock, config.clone())?;
+        log::info!("serving QUIC on addr: {}", endpoint.local_addr()?);
+        tasks.spawn(quic::serve(endpoint, server.clone()));
+    }
+    Ok(())
+}
+
+/// Reply to STUN requests received on the UDP socket, until the server shuts down and every
+/// datagram received is handled.
+async fn serve_udp(sock: Arc<UdpSocket>, server: Arc<Server>) -> Result<()> {
+    net::enable_pktinfo(&sock)?;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    match uring::Ring::new() {
+        Ok(ring) => return ring.serve(sock, server).await,
+        Err(err) => log::warn!("could not set up io_uring, serving UDP without it: {}", err),
+    }
+    if server.udp_batch_size() > 1 {
+        #[cfg(target_os = "linux")]
+        return mmsg::serve(sock, server).await;
+        #[cfg(not(target_os = "linux"))]
+        log::warn!("batched UDP I/O is only supported on Linux, receiving one datagram at a time");
+    }
+    transport::serve(Arc::new(transport::Udp::new(sock)?), server).await
+}
+
+/// Comprehension-required attributes understood in a Binding Request.
+const BINDING_ATTRIBUTES: [u16; 9] = [
+    attributes::USERNAME,
+    attributes::USERHASH,
+    attributes::MESSAGE_INTEGRITY,
+    attributes::MESSAGE_INTEGRITY_SHA256,
+    attributes::PASSWORD_ALGORITHM,
+    attributes::REALM,
+    attributes::NONCE,
+    attributes::CHANGE_REQUEST,
+    attributes::RESPONSE_PORT,
+];
+
+/// Parse the stun request and create the appropriate response message.
+fn parse_message(buf: &[u8], src_addr: SocketAddr, attributes: &Attributes) -> Option<Message> {
+    let message = match Message::decode(buf) {
+        Ok(message) => message,
+        Err(err) => {
+            log::debug!(
+                "could not parse packet from {:?} : {:?} as a STUN message",
+                src_addr,
+                err
+            );
+            return None;
+        }
+    };
+    match (message.method, message.class) {
+        (methods::BINDING, Class::Request) => {
+            log::debug!(
+                "STUN binding request received {:?} from source address: {:?}",
+                message,
+                src_addr
+            );
+            let known: Vec<_> = BINDING_ATTRIBUTES
+                .into_iter()
+                .chain(attributes.known())
+                .collect();
+            let unknown = message.unknown_attributes(&known);
+            if !unknown.is_empty() {
+                // Reply with UNKNOWN ATTRIBUTE listing them,
+                // see https://datatracker.ietf.org/doc/html/rfc5389#section-7.3.1
+                let value = unknown.iter().flat_map(|kind| kind.to_be_bytes()).collect();
+                let response = message
+                    .error_response(420, "Unknown Attribute")
+                    .add_attribute(attributes::UNKNOWN_ATTRIBUTES, value);
+                return Some(response);
+            }
+            let response = message.success_response();
+            if message.is_legacy() {
+                // RFC 3489 clients only understand MAPPED-ADDRESS,
+                // see https://datatracker.ietf.org/doc/html/rfc5389#section-12.2
+                return Some(response.add_address(attributes::MAPPED_ADDRESS, src_addr));
+            }
+            Some(response.add_xor_address(attributes::XOR_MAPPED_ADDRESS, src_addr))
+        }
+        (methods::BINDING, Class::Indication) => {
+            log::debug!(
+                "STUN indication received {:?} from source address: {:?}",
+                message,
+                src_addr
+            );
+            // No response is generated for an indication https://datatracker.ietf.org/doc/html/rfc5389#section-7.3.2
+            None
+        }
+        (methods::BINDING, class @ Class::ErrorResponse)
+        | (methods::BINDING, class @ Class::SuccessResponse) => {
+            log::debug!("STUN binding {:?}", class);
+            // Reply with BAD REQUEST see https://datatracker.ietf.org/doc/html/rfc5389#section-15.6
+            let response = message.error_response(400, "Invalid binding request class");
+            Some(response)
+        }
+        (method, _) => {
+            log::debug!(
+                "unsupported STUN method {:#05x} received from source address: {:?}",
+                method,
+                src_addr
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+    use tokio::net::UdpSocket;
+
+    use super::{parse_message, serve_udp};
+    use crate::attribute::{Attributes, Echo};
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::server::Server;
+
+    fn parse_response(req_msg: StunMessage, socket: SocketAddr) -> Option<StunMessage> {
+        let response = parse_message(
+            &req_msg.encode(None).unwrap(),
+            socket,
+            &Attributes::default(),
+        )?;
+        Some(StunMessage::decode(&response.encode(), None).unwrap())
+    }
+
+    #[test]
+    fn server_responds_successful_to_binding_request() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let response = parse_response(req_msg, socket).unwrap();
+        let header = response.get_header();
+        let attributes = response.get_attributes();
+        assert!(matches!(
+            header.message_method,
+            StunMessageMethod::BindingRequest
+        ));
+        assert!(matches!(
+            header.message_class,
+            StunMessageClass::SuccessResponse
+        ));
+        assert_eq!(attributes.len(), 1);
+        assert!(
+            matches!(attributes[0], StunAttribute::XorMappedAddress { socket_addr} if socket_addr == socket)
+        );
+    }
+
+    #[test]
+    fn server_doesnt_respond_to_request_with_bad_fingerprint() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_fingerprint();
+        let mut encoded = req_msg.encode(None).unwrap();
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        assert!(parse_message(&encoded, socket, &Attributes::default()).is_some());
+
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(parse_message(&encoded, socket, &Attributes::default()).is_none());
+    }
+
+    #[test]
+    fn server_rejects_unknown_comprehension_required_attributes() {
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x7FF0, vec![0; 4])
+            .add_attribute(0x7FF1, vec![])
+            .add_attribute(0x7FF0, vec![1; 4])
+            // Comprehension-optional attributes are ignored.
+            .add_attribute(0xFFF0, vec![]);
+        let response = parse_message(&request.encode(), socket, &Attributes::default()).unwrap();
+        let response = StunMessage::decode(&response.encode(), None).unwrap();
+        assert!(matches!(
+            response.get_header().message_class,
+            StunMessageClass::ErrorResponse
+        ));
+        assert!(response.get_attributes().iter().any(|attribute| matches!(
+            attribute,
+            StunAttribute::ErrorCode {
+                class: 4,
+                number: 20,
+                ..
+            }
+        )));
+        assert!(response.get_attributes().iter().any(|attribute| matches!(
+            attribute,
+            StunAttribute::UnknownAttributes { types } if types == &[0x7FF0, 0x7FF1]
+        )));
+
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0xFFF0, vec![]);
+        let response = parse_message(&request.encode(), socket, &Attributes::default()).unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+
+        // Unless a handler of the attribute is registered.
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x7FF0, vec![]);
+        let attributes = Attributes::default().register(Box::new(Echo(0x7FF0)));
+        let response = parse_message(&request.encode(), socket, &attributes).unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+    }
+
+    #[test]
+    fn parse_rfc3489_binding_request() {
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let request = Message {
+            cookie: 0x0102_0304,
+            ..Message::with_random_transaction_id(methods::BINDING, Class::Request)
+        };
+        let response = parse_message(&request.encode(), socket, &Attributes::default())
+            .unwrap()
+            .encode();
+        // The whole 128 bit transaction id is echoed.
+        assert_eq!(response[4..20], request.encode()[4..20]);
+        let response = Message::decode(&response).unwrap();
+        assert!(response.is_legacy());
+        assert_eq!(
+            response.get_address(attributes::MAPPED_ADDRESS),
+            Some(socket)
+        );
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn server_drops_datagrams_larger_than_the_receive_buffer() {
+        let server = Arc::new(Server::default().with_recv_buffer_size(64));
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        tokio::spawn(serve_udp(sock, server.clone()));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let large = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x8022, vec![0; 64]);
+        client.send_to(&large.encode(), server_addr).await.unwrap();
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        client
+            .send_to(&request.encode(), server_addr)
+            .await
+            .unwrap();
+
+        let mut buf = [0; 1500];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert!(server.metrics().contains("stunner_truncated_total 1\n"));
+    }
+
+    #[test]
+    fn server_doesnt_respond_to_indication_request() {
+        let req_msg = StunMessage::new(
+            StunMessageMethod::BindingRequest,
+            StunMessageClass::Indication,
+        );
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let response = parse_response(req_msg, socket);
+        assert!(response.is_none());
+    }
+
+    #[test]
+    fn server_responds_with_error_to_success_response() {
+        let req_msg = StunMessage::new(
+            StunMessageMethod::BindingRequest,
+            StunMessageClass::SuccessResponse,
+        );
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let response = parse_response(req_msg, socket).unwrap();
+        let header = response.get_header();
+        let attributes = response.get_attributes();
+        assert!(matches!(
+            header.message_method,
+            StunMessageMethod::BindingRequest
+        ));
+        assert!(matches!(
+            header.message_class,
+            StunMessageClass::ErrorResponse
+        ));
+        assert_eq!(attributes.len(), 1);
+        assert!(
+            matches!(&attributes[0], StunAttribute::ErrorCode { class, number, reason } if class == &4u8 && number == &0u8 && reason == "Invalid binding request class")
+        );
+    }
+
+    #[test]
+    fn server_responds_with_error_to_error_response() {
+        let req_msg = StunMessage::new(
+            StunMessageMethod::BindingRequest,
+            StunMessageClass::ErrorResponse,
+        );
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let response = parse_response(req_msg, socket).unwrap();
+        let header = response.get_header();
+        let attributes = response.get_attributes();
+        assert!(matches!(
+            header.message_method,
+            StunMessageMethod::BindingRequest
+        ));
+        assert!(matches!(
+            header.message_class,
+            StunMessageClass::ErrorResponse
+        ));
+        assert_eq!(attributes.len(), 1);
+        assert!(
+            matches!(&attributes[0], StunAttribute::ErrorCode { class, number, reason } if class == &4u8 && number == &0u8 && reason == "Invalid binding request class")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Log output formats.
+
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ArgEnum;
+use env_logger::fmt::Formatter;
+use env_logger::Logger;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{LevelFilter, Record, SetLoggerError};
+
+#[cfg(target_os = "linux")]
+use crate::journald::{self, Journal};
+
+/// How log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, with the structured fields of the record.
+    Json,
+}
+
+/// Where log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum LogTarget {
+    /// The standard error, in the format of --log-format.
+    Stderr,
+    /// The systemd journal, with the structured fields of the records.
+    Journald,
+}
+
+/// Whether the log level was given at startup, only then it can be changed afterwards.
+static LEVEL_CHANGEABLE: AtomicBool = AtomicBool::new(false);
+
+/// Initialize the logger writing to `target`, configured with the `RUST_LOG` environment
+/// variable. When given, `level` takes precedence over the default level of `RUST_LOG` and caps
+/// its per module levels.
+pub fn init(format: LogFormat, level: Option<LevelFilter>, target: LogTarget) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(write_json);
+    }
+    if level.is_some() {
+        // Records are filtered by the maximum level set below, which can be changed later.
+        builder.filter_level(LevelFilter::Trace);
+    }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    let _ = match target {
+        LogTarget::Stderr => log::set_boxed_logger(Box::new(logger)),
+        LogTarget::Journald => set_journal(logger),
+    };
+    if let Some(level) = level {
+        log::set_max_level(level);
+        LEVEL_CHANGEABLE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Write the records matching `logger` to the journal, or with `logger` when the journal can't
+/// be connected to.
+#[cfg(target_os = "linux")]
+fn set_journal(logger: Logger) -> Result<(), SetLoggerError> {
+    match journald::connect() {
+        Ok(sock) => log::set_boxed_logger(Box::new(Journal::new(sock, logger))),
+        Err(err) => {
+            let result = log::set_boxed_logger(Box::new(logger));
+            log::warn!(
+                "could not connect to the journal, logging to stderr: {}",
+                err
+            );
+            result
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_journal(logger: Logger) -> Result<(), SetLoggerError> {
+    let result = log::set_boxed_logger(Box::new(logger));
+    log::warn!("the journal is only available on Linux, logging to stderr");
+    result
+}
+
+/// Change the log level, when it was given at startup.
+pub fn set_level(level: LevelFilter) {
+    if LEVEL_CHANGEABLE.load(Ordering::Relaxed) {
+        log::set_max_level(level);
+    } else {
+        log::warn!("the log level can only be changed when given at startup");
+    }
+}
+
+/// Write a record as a JSON object on a single line.
+fn write_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"target\":{},\"message\":{}",
+        buf.timestamp_millis(),
+        record.level(),
+        json_string(record.target()),
+        json_string(&record.args().to_string())
+    );
+    let _ = record.key_values().visit(&mut JsonFields(&mut line));
+    line.push('}');
+    writeln!(buf, "{}", line)
+}
+
+/// Appends the key-values of a record as JSON fields.
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let _ = write!(
+            self.0,
+            ",{}:{}",
+            json_string(key.as_str()),
+            json_string(&value.to_string())
+        );
+        Ok(())
+    }
+}
+
+/// Quote and escape a string as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_string;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a \"quote\"\\\n\u{1}"),
+            "\"a \\\"quote\\\"\\\\\\n\\u0001\""
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Sampling of the log lines of floods, e.g. of scanners sending packets which aren't STUN: a
+//! few lines are logged per source and overall in each window, the others are only counted and
+//! summed up by source once the window is over.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Duration of the windows lines are sampled in.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Default number of lines logged per source in a window.
+pub const DEFAULT_PER_SOURCE: u64 = 10;
+
+/// Default number of lines logged overall in a window.
+pub const DEFAULT_MAX: u64 = 100;
+
+/// Number of sources summed up on a line of their own, the others are summed up together.
+const SUMMARIZED_SOURCES: usize = 10;
+
+/// Lines of each source in the current window.
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    logged: u64,
+    /// Lines counted but not logged, when sources aren't told apart.
+    unlogged: u64,
+    /// Lines counted and logged by source.
+    sources: HashMap<IpAddr, (u64, u64)>,
+}
+
+impl Window {
+    fn new(started_at: Instant) -> Self {
+        Window {
+            started_at,
+            logged: 0,
+            unlogged: 0,
+            sources: HashMap::new(),
+        }
+    }
+}
+
+/// Decides which of the lines about events of some kind, e.g. malformed packets, are logged.
+#[derive(Debug)]
+pub struct Sampler {
+    /// The events counted, e.g. "malformed packets".
+    what: &'static str,
+    /// Lines logged per source, `None` when sources aren't told apart.
+    per_source: Option<u64>,
+    max: u64,
+    window: Mutex<Window>,
+}
+
+impl Sampler {
+    /// Log up to `per_source` lines about `what` per source and `max` overall in each window.
+    pub fn new(what: &'static str, per_source: u64, max: u64) -> Self {
+        Sampler {
+            what,
+            per_source: Some(per_source),
+            max,
+            window: Mutex::new(Window::new(Instant::now())),
+        }
+    }
+
+    /// Log up to `max` lines about `what` in each window, keeping no state about their sources.
+    pub fn overall(what: &'static str, max: u64) -> Self {
+        Sampler {
+            what,
+            per_source: None,
+            max,
+            window: Mutex::new(Window::new(Instant::now())),
+        }
+    }
+
+    /// Whether the lines are counted by source, which is state kept about them.
+    pub fn by_source(&self) -> bool {
+        self.per_source.is_some()
+    }
+
+    /// Count an event of `ip`, returning whether to log it.
+    pub fn sample(&self, ip: IpAddr) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let full = window.logged >= self.max;
+        let Some(per_source) = self.per_source else {
+            if full {
+                window.unlogged += 1;
+                return false;
+            }
+            window.logged += 1;
+            return true;
+        };
+        let (count, logged) = window.sources.entry(ip).or_default();
+        *count += 1;
+        if full || *logged >= per_source {
+            return false;
+        }
+        *logged += 1;
+        window.logged += 1;
+        true
+    }
+
+    /// Once the window is over, start a new one and return the lines summing up the events
+    /// which weren't all logged, by source.
+    pub fn flush(&self) -> Vec<String> {
+        self.flush_at(Instant::now())
+    }
+
+    fn flush_at(&self, now: Instant) -> Vec<String> {
+        let window = {
+            let mut window = self.window.lock().unwrap();
+            if now.saturating_duration_since(window.started_at) < WINDOW {
+                return Vec::new();
+            }
+            std::mem::replace(&mut *window, Window::new(now))
+        };
+        let seconds = WINDOW.as_secs();
+        if window.unlogged > 0 {
+            return vec![format!(
+                "{} {} not logged in the last {}s",
+                window.unlogged, self.what, seconds
+            )];
+        }
+        let mut sources: Vec<_> = window
+            .sources
+            .into_iter()
+            .filter(|(_, (count, logged))| count > logged)
+            .map(|(ip, (count, _))| (ip, count))
+            .collect();
+        sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut lines: Vec<_> = sources
+            .iter()
+            .take(SUMMARIZED_SOURCES)
+            .map(|(ip, count)| format!("{}: {} {} in the last {}s", ip, count, self.what, seconds))
+            .collect();
+        let others = &sources[sources.len().min(SUMMARIZED_SOURCES)..];
+        if !others.is_empty() {
+            lines.push(format!(
+                "{} other sources: {} {} in the last {}s",
+                others.len(),
+                others.iter().map(|(_, count)| count).sum::<u64>(),
+                self.what,
+                seconds
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Instant;
+
+    use super::{Sampler, WINDOW};
+
+    #[test]
+    fn sums_up_the_lines_not_logged() {
+        let sampler = Sampler::new("malformed packets", 2, 3);
+        let scanner = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let quiet = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+
+        let logged = (0..10000).filter(|_| sampler.sample(scanner)).count();
+        assert_eq!(logged, 2);
+        assert!(sampler.sample(other));
+        // Over the limit of lines overall.
+        assert!(!sampler.sample(quiet));
+        assert!(sampler.flush().is_empty());
+
+        let lines = sampler.flush_at(Instant::now() + WINDOW);
+        assert_eq!(
+            lines,
+            vec![
+                "192.0.2.1: 10000 malformed packets in the last 60s",
+                "192.0.2.3: 1 malformed packets in the last 60s",
+            ]
+        );
+        // A new window started.
+        assert!(sampler.sample(scanner));
+    }
+
+    #[test]
+    fn keeps_no_state_about_sources_overall() {
+        let sampler = Sampler::overall("malformed packets", 3);
+        assert!(!sampler.by_source());
+        let scanner = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let logged = (0..10).filter(|_| sampler.sample(scanner)).count();
+        assert_eq!(logged, 3);
+        assert!(sampler.window.lock().unwrap().sources.is_empty());
+        assert_eq!(
+            sampler.flush_at(Instant::now() + WINDOW),
+            vec!["7 malformed packets not logged in the last 60s"]
+        );
+    }
+
+    #[test]
+    fn sums_up_the_smallest_sources_together() {
+        let sampler = Sampler::new("malformed packets", 0, 0);
+        for last in 1..=12 {
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, last));
+            for _ in 0..last {
+                sampler.sample(ip);
+            }
+        }
+        let lines = sampler.flush_at(Instant::now() + WINDOW);
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[0], "192.0.2.12: 12 malformed packets in the last 60s");
+        assert_eq!(
+            lines[10],
+            "2 other sources: 3 malformed packets in the last 60s"
+        );
+    }
+}
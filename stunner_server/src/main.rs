@@ -1,200 +1,1517 @@
-use std::net::SocketAddr;
+use std::ffi::OsString;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::Parser;
-use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+
+use stunner_server::accesslog::{AccessLog, Rotation};
+use stunner_server::acl::{Acl, Cidr};
+#[cfg(feature = "acme")]
+use stunner_server::acme;
+#[cfg(target_os = "linux")]
+use stunner_server::affinity;
+use stunner_server::attribute::{self, Attributes};
+use stunner_server::auth::{self, Auth, LongTermAuth, ShortTermAuth};
+use stunner_server::bench;
+use stunner_server::blocklist::Blocklist;
+use stunner_server::demux::Demux;
+use stunner_server::discovery::Discovery;
+#[cfg(feature = "dtls")]
+use stunner_server::dtls;
+use stunner_server::failures::{self, Failures};
+#[cfg(feature = "feed")]
+use stunner_server::feed::{self, DenyFeed};
+#[cfg(feature = "geoip")]
+use stunner_server::geoip;
+use stunner_server::ice::IceLite;
+#[cfg(feature = "ldap")]
+use stunner_server::ldap;
+use stunner_server::logging::{self, LogFormat, LogTarget};
+use stunner_server::logsample;
+use stunner_server::net::SocketConfig;
+use stunner_server::pcap::Capture;
+#[cfg(unix)]
+use stunner_server::privileges;
+#[cfg(feature = "quic")]
+use stunner_server::quic;
+use stunner_server::ratelimit::{GlobalRateLimiter, InFlightLimiter, RateLimiter};
+use stunner_server::realm::{self, Realm};
+use stunner_server::server::{self, MalformedPolicy, Server, UdpOffload};
+use stunner_server::state::SharedState;
+use stunner_server::statsd::{self, Statsd, StatsdConfig};
+use stunner_server::tcp::ConnectionLimits;
+use stunner_server::transactions::{self, ResponseCache};
+use stunner_server::turn::{self, BandwidthLimits, PeerPolicy, PortRange, Quotas, Turn};
+#[cfg(feature = "sql")]
+use stunner_server::userdb;
+#[cfg(feature = "wasm")]
+use stunner_server::wasm;
+#[cfg(feature = "webhook")]
+use stunner_server::webhook;
+#[cfg(target_os = "linux")]
+use stunner_server::xdp::Xdp;
+use stunner_server::{activation, config, handler, net, serve, shutdown, tls, Listeners, Secure};
+
+#[cfg(windows)]
+mod service;
+#[cfg(feature = "otel")]
+mod telemetry;
 
 #[derive(Debug, Parser)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_override_self = true)]
 struct Cli {
-    /// Specify the listening port where the server should run,
-    /// by default 19302 is used
-    #[clap(long, default_value = "3478")]
-    port: u16,
-}
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+    /// Read options from this file, one per line as name = value, or name alone for flags,
+    /// where name is the long option without dashes. Options given on the command line take
+    /// precedence. On SIGHUP the file and --users-file are read again, and the access control
+    /// lists, users, rate limit and log level updated
+    #[clap(long)]
+    config: Option<PathBuf>,
 
-    let opt = Cli::parse();
-    serve(("0", opt.port))
-        .await
-        .expect("could not start server")
+    /// Check the configuration, from the configuration file and the command line, without
+    /// binding any socket: the certificates, users and accounts are loaded and the effective
+    /// configuration printed, the exit status is non-zero when invalid
+    #[clap(long)]
+    check_config: bool,
+
+    /// Specify the listening port where the server should run, 3478 by default. Can be
+    /// repeated to also answer on fallback ports, e.g. 80 and 443 for clients behind restrictive
+    /// firewalls
+    #[clap(long, default_value = "3478", multiple_occurrences = true)]
+    port: Vec<u16>,
+
+    /// Specify an address and port to listen on, e.g. 0.0.0.0:3478 or [::]:3478.
+    /// Can be repeated to serve on multiple interfaces and address families,
+    /// when given --port is ignored. When started by a systemd socket unit, the inherited
+    /// sockets are used for the matching addresses, and by default every inherited UDP socket
+    /// is served
+    #[clap(long, multiple_occurrences = true)]
+    listen: Vec<SocketAddr>,
+
+    /// PEM encoded certificate chain used to serve STUN over TLS,
+    /// requires --tls-key. It's read again along with the key on SIGHUP, so that renewed
+    /// certificates are presented to new connections without restarting, except over DTLS
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the TLS certificate, requires --tls-cert
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Present another certificate to the clients asking for this hostname through SNI, given as
+    /// hostname=cert.pem,key.pem where the hostname may start with *. to match its subdomains.
+    /// Can be repeated, the certificate of --tls-cert is presented to the other clients and over
+    /// DTLS
+    #[clap(
+        long,
+        requires = "tls-cert",
+        multiple_occurrences = true,
+        parse(try_from_str = tls::parse_named_cert)
+    )]
+    tls_sni_cert: Vec<(String, PathBuf, PathBuf)>,
+
+    /// Close the TLS and DTLS connections not negotiating the stun.turn or stun.nat-discovery
+    /// ALPN protocol. Otherwise they're advertised, and only the clients offering other
+    /// protocols alone are rejected
+    #[clap(long)]
+    strict_alpn: bool,
+
+    /// PEM encoded certificate authorities issuing the certificates clients must present to
+    /// connect over TLS, DTLS and QUIC, so that only enrolled devices are served
+    #[clap(long)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Specify the port where STUN over TLS is served when a certificate is configured
+    #[clap(long, default_value = "5349")]
+    tls_port: u16,
+
+    /// Also serve STUN over DTLS on the UDP port of --tls-port, requires --tls-cert
+    #[cfg(feature = "dtls")]
+    #[clap(long, requires = "tls-cert")]
+    dtls: bool,
+
+    /// Also serve STUN over QUIC on the UDP port of --quic-port, requires --tls-cert. Clients
+    /// offer the stun.turn or stun.nat-discovery ALPN protocol, and send STUN messages framed as
+    /// over TCP on each bidirectional stream they open
+    #[cfg(feature = "quic")]
+    #[clap(long, requires = "tls-cert")]
+    experimental_quic: bool,
+
+    /// Specify the UDP port where STUN over QUIC is served
+    #[cfg(feature = "quic")]
+    #[clap(long, default_value = "5350")]
+    quic_port: u16,
+
+    /// Obtain the TLS certificate of this domain from an ACME certificate authority such as
+    /// Let's Encrypt, and renew it before it expires, instead of --tls-cert. Can be repeated for
+    /// the certificate to cover several domains. The challenges of the authority are answered
+    /// over HTTP on --acme-http-addr, and a self-signed certificate is presented until the first
+    /// one is obtained
+    #[cfg(feature = "acme")]
+    #[clap(long, multiple_occurrences = true, conflicts_with = "tls-cert")]
+    acme_domain: Vec<String>,
+
+    /// Contact email address of the ACME account, to be warned of issues with the certificates
+    #[cfg(feature = "acme")]
+    #[clap(long)]
+    acme_email: Option<String>,
+
+    /// Directory where the ACME account key and the certificate obtained are stored, it must be
+    /// writable by --user
+    #[cfg(feature = "acme")]
+    #[clap(long, default_value = "/var/lib/stunner/acme")]
+    acme_dir: PathBuf,
+
+    /// Directory URL of the ACME certificate authority, by default the Let's Encrypt production
+    /// one. Use https://acme-staging-v02.api.letsencrypt.org/directory to try the setup out
+    #[cfg(feature = "acme")]
+    #[clap(long, default_value = "https://acme-v02.api.letsencrypt.org/directory")]
+    acme_directory_url: String,
+
+    /// Specify the address where the HTTP-01 challenges of the ACME certificate authority are
+    /// answered, it must be reachable on port 80 of every --acme-domain
+    #[cfg(feature = "acme")]
+    #[clap(long, default_value = "0.0.0.0:80")]
+    acme_http_addr: SocketAddr,
+
+    /// Enable the TURN relay, allocating relayed transport addresses on --relay-ip. UDP
+    /// allocations can be requested over any transport, TCP ones over TCP or TLS. Requires
+    /// long-term credentials, from --users, --users-file, --auth-secret or a user database, and
+    /// the auth handler, not to relay for anyone
+    #[clap(long, requires = "relay-ip")]
+    turn: bool,
+
+    /// Specify the IP address relayed transport addresses are allocated on, it must be
+    /// reachable by the peers. Can be repeated, once for IPv4 and once for IPv6, to let clients
+    /// request relayed transport addresses of either family
+    #[clap(long, multiple_occurrences = true)]
+    relay_ip: Vec<IpAddr>,
+
+    /// Allocate the relayed transport addresses on the ports of this range only, e.g.
+    /// 49152-65535 when a firewall only lets those through. Allocations are refused with 508
+    /// Insufficient Capacity once every port is in use. By default any free port is used
+    #[clap(long, value_name = "FIRST-LAST")]
+    relay_port_range: Option<PortRange>,
+
+    /// Refuse new TURN allocations with 486 Allocation Quota Reached once the clients
+    /// authenticated as the same user hold this many
+    #[clap(long)]
+    max_allocations_per_user: Option<usize>,
+
+    /// Refuse new TURN allocations with 486 Allocation Quota Reached once the clients of the same
+    /// IP address hold this many
+    #[clap(long)]
+    max_allocations_per_ip: Option<usize>,
+
+    /// Limit the bytes per second each TURN allocation relays, counting both directions. Data
+    /// over the limit is dropped, or waits on TCP allocations
+    #[clap(long, parse(try_from_str = turn::parse_bandwidth))]
+    max_relay_bandwidth: Option<u64>,
+
+    /// Limit the bytes per second each TURN allocation of a user relays instead, given as
+    /// user=bytes. Can be repeated
+    #[clap(long, multiple_occurrences = true, parse(try_from_str = turn::parse_user_bandwidth))]
+    user_relay_bandwidth: Vec<(String, u64)>,
+
+    /// Let TURN clients asking for a MOBILITY-TICKET keep their allocations when their address
+    /// changes, as in RFC 8016
+    #[clap(long)]
+    mobility: bool,
+
+    /// Let TURN clients exchange data with the peers in this IP range, in CIDR notation, even
+    /// when it is private, loopback, link-local or reserved, which are refused by default so
+    /// that the relay can't reach the network of the server. Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    allow_peer: Vec<Cidr>,
+
+    /// Refuse the TURN peers in this IP range, in CIDR notation, even when allowed by
+    /// --allow-peer. Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    deny_peer: Vec<Cidr>,
+
+    /// Require requests to be authenticated with the long-term credentials of a user,
+    /// given as user=password. Can be repeated
+    #[clap(long, multiple_occurrences = true, parse(try_from_str = auth::parse_user))]
+    users: Vec<(String, String)>,
+
+    /// Read users allowed to authenticate from a file of user=password lines
+    #[clap(long)]
+    users_file: Option<PathBuf>,
+
+    /// Specify the realm of the long-term credentials
+    #[clap(long, default_value = "stunner")]
+    realm: String,
+
+    /// Also serve the realm configured in this file, with its own users and access control
+    /// lists, for the requests received on its listeners or naming it in their REALM attribute.
+    /// Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    realm_config: Vec<PathBuf>,
+
+    /// Seconds the nonces handed out to authenticate with long-term credentials remain valid,
+    /// requests with an expired nonce are answered 438 Stale Nonce with a fresh one. Nonces are
+    /// bound to the client IP address
+    #[clap(long, default_value_t = auth::DEFAULT_NONCE_LIFETIME.as_secs())]
+    nonce_lifetime: u64,
+
+    /// Also accept ephemeral long-term credentials minted with this shared secret as in the
+    /// TURN REST API, the username being an expiry UNIX timestamp optionally followed by :user
+    /// and the password base64(HMAC-SHA1(secret, username))
+    #[clap(long)]
+    auth_secret: Option<String>,
+
+    /// Also look up users in this SQLite or PostgreSQL database, e.g. sqlite://users.db, in a
+    /// users table with username, password and ha1 columns, ha1 being the hex MD5 hash of
+    /// username:realm:password
+    #[cfg(feature = "sql")]
+    #[clap(long, conflicts_with = "stateless")]
+    users_db: Option<String>,
+
+    /// Also look up users by POSTing their username and realm as JSON to this HTTP(S) endpoint,
+    /// answering with their password or ha1, or 404 when there is no such user
+    #[cfg(feature = "webhook")]
+    #[clap(long, conflicts_with = "stateless")]
+    auth_webhook: Option<String>,
+
+    /// Seconds to wait for the answer of the authentication webhook
+    #[cfg(feature = "webhook")]
+    #[clap(long, default_value = "5")]
+    auth_webhook_timeout: u64,
+
+    /// Seconds the answer of the authentication webhook for a username is reused
+    #[cfg(feature = "webhook")]
+    #[clap(long, default_value = "60")]
+    auth_webhook_cache_ttl: u64,
+
+    /// Also look up users in the LDAP directory at this URL, e.g. ldaps://ldap.example.com,
+    /// reading the HA1 hash or password of the entry matching --ldap-filter under --ldap-base-dn
+    #[cfg(feature = "ldap")]
+    #[clap(long, requires = "ldap-base-dn", conflicts_with = "stateless")]
+    ldap_url: Option<String>,
+
+    /// Distinguished name of the account binding to the LDAP directory, anonymous if not given
+    #[cfg(feature = "ldap")]
+    #[clap(long)]
+    ldap_bind_dn: Option<String>,
+
+    /// Password of the account binding to the LDAP directory
+    #[cfg(feature = "ldap")]
+    #[clap(long, requires = "ldap-bind-dn")]
+    ldap_bind_password: Option<String>,
+
+    /// Entry the users are searched under in the LDAP directory, e.g. ou=people,dc=example,dc=com
+    #[cfg(feature = "ldap")]
+    #[clap(long)]
+    ldap_base_dn: Option<String>,
+
+    /// LDAP filter matching the entry of a user, {username} standing for the username, e.g.
+    /// (sAMAccountName={username}) for Active Directory
+    #[cfg(feature = "ldap")]
+    #[clap(long, default_value = "(uid={username})")]
+    ldap_filter: String,
+
+    /// LDAP attribute holding the hex MD5 hash of username:realm:password of a user
+    #[cfg(feature = "ldap")]
+    #[clap(long, default_value = "turnHA1")]
+    ldap_ha1_attribute: String,
+
+    /// LDAP attribute holding the password of a user, preferred to the HA1 hash when set
+    #[cfg(feature = "ldap")]
+    #[clap(long)]
+    ldap_password_attribute: Option<String>,
+
+    /// Share the nonce secret and the bans with the other instances of a cluster through the
+    /// Redis server at this URL, e.g. redis://127.0.0.1/. IP addresses are banned by adding them
+    /// to the stunner:bans sorted set scored by the UNIX time their ban ends
+    #[cfg(feature = "redis")]
+    #[clap(long)]
+    redis_url: Option<String>,
+
+    /// Require requests to be signed with the short-term credentials of this username, as
+    /// ICE connectivity checks are, requires --short-term-password
+    #[clap(
+        long,
+        requires = "short-term-password",
+        conflicts_with_all = &["users", "users-file", "auth-secret"]
+    )]
+    short_term_username: Option<String>,
+
+    /// Password of the short-term credentials, requires --short-term-username
+    #[clap(long, requires = "short-term-username")]
+    short_term_password: Option<String>,
+
+    /// Act as an ICE-lite agent with this username fragment, answering the connectivity checks
+    /// of full ICE agents signed with it and --ice-password, and recording the candidates they
+    /// nominate with USE-CANDIDATE. Responses then carry a FINGERPRINT
+    #[clap(
+        long,
+        requires = "ice-password",
+        conflicts_with_all = &["users", "users-file", "auth-secret", "short-term-username"]
+    )]
+    ice_ufrag: Option<String>,
+
+    /// Password of the ICE-lite agent, requires --ice-ufrag
+    #[clap(long, requires = "ice-ufrag")]
+    ice_password: Option<String>,
+
+    /// Add a FINGERPRINT attribute to responses, so that STUN can be told apart from other
+    /// protocols multiplexed on the same port
+    #[clap(long)]
+    fingerprint: bool,
+
+    /// Share the UDP ports with a media server listening on this address: the datagrams which
+    /// aren't STUN, nor TURN ChannelData when relaying, i.e. RTP, RTCP, DTLS, QUIC and ZRTP as
+    /// told apart by RFC 7983, are forwarded to it and its replies sent back to their source
+    #[clap(long)]
+    demux_forward: Option<SocketAddr>,
+
+    /// Enable NAT behavior discovery with a second IP address of the server, requires a
+    /// --listen address of the same family with a specific IP
+    #[clap(long, requires = "listen")]
+    alternate_ip: Option<IpAddr>,
+
+    /// Specify the second port used for NAT behavior discovery, by default one is picked
+    /// by the system
+    #[clap(long, requires = "alternate-ip")]
+    alternate_port: Option<u16>,
+
+    /// Maximum number of messages per second handled from a single source IP, messages
+    /// exceeding it are dropped
+    #[clap(long)]
+    max_rps_per_ip: Option<u32>,
+
+    /// Maximum number of messages from a single source IP handled concurrently, e.g. waiting
+    /// on users to be looked up, messages exceeding it are dropped
+    #[clap(long)]
+    max_in_flight_per_ip: Option<usize>,
+
+    /// Maximum number of packets per second handled from all sources, packets exceeding it are
+    /// dropped as soon as received
+    #[clap(long)]
+    max_pps: Option<u32>,
+
+    /// Number of packets handled at once beyond --max-pps after a quiet period, defaults to one
+    /// second worth of packets
+    #[clap(long, requires = "max-pps")]
+    max_pps_burst: Option<u32>,
+
+    /// Ban the source IPs failing this many times within --ban-window to authenticate, with
+    /// wrong or missing credentials, or to send STUN messages. Failures are also logged at the
+    /// warn level as "<failure> from <ip>" for fail2ban
+    #[clap(long)]
+    ban_after: Option<u32>,
+
+    /// Seconds failures are counted over
+    #[clap(long, default_value_t = failures::DEFAULT_WINDOW.as_secs())]
+    ban_window: u64,
+
+    /// Seconds the source IPs failing too often are banned for
+    #[clap(long, default_value_t = failures::DEFAULT_BAN_DURATION.as_secs())]
+    ban_duration: u64,
+
+    /// Mark the packets sent by the server, to clients and to peers, with this DSCP so that
+    /// network QoS policies can prioritize them, as a number up to 63 or by name, e.g. EF or
+    /// AF41. It's set in the TOS field of IPv4 and the Traffic Class of IPv6
+    #[clap(long, parse(try_from_str = net::parse_dscp))]
+    dscp: Option<u8>,
+
+    /// Bind the sockets serving clients and relaying to peers to this network interface, e.g.
+    /// eth0, with SO_BINDTODEVICE so that they only use it whatever the routes. The HTTP endpoints
+    /// aren't bound to it. Relay sockets, bound after switching to --user, need Linux 5.7 or later
+    #[cfg(target_os = "linux")]
+    #[clap(long)]
+    interface: Option<String>,
+
+    /// Serve Prometheus metrics over HTTP on this address, at /metrics
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve health checks over HTTP on this address, at /healthz for liveness and /readyz for
+    /// readiness, ready once every listener is bound. Can be the same as --metrics-addr
+    #[clap(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// Serve STUN over WebSocket on this address, so that web pages can query the server. Each
+    /// binary message carries a single STUN message
+    #[clap(long)]
+    ws_addr: Option<SocketAddr>,
+
+    /// Serve the admin API to inspect and control the server, JSON over HTTP on --admin-addr,
+    /// to clients sending this token in an Authorization: Bearer header
+    #[clap(long)]
+    admin_token: Option<String>,
+
+    /// Switch to this user, given as a name or id, once the sockets are bound, so that
+    /// privileged ports can be bound as root without running as root. The group defaults to the
+    /// primary group of the user
+    #[cfg(unix)]
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Switch to this group, given as a name or id, once the sockets are bound
+    #[cfg(unix)]
+    #[clap(long)]
+    group: Option<String>,
+
+    /// Once serving, restrict the process with a seccomp filter to the system calls it needs,
+    /// so that it can't be used to run programs, change privileges or load kernel modules.
+    /// Files the account can write and the network remain reachable, see --user
+    #[cfg(target_os = "linux")]
+    #[clap(long)]
+    sandbox: bool,
+
+    /// Install the server as a Windows service running with the other options given, which
+    /// should only use absolute paths, uninstall it, or run as the service, which is what the
+    /// installed service does. The service logs to the Application event log
+    #[cfg(windows)]
+    #[clap(long, arg_enum)]
+    service: Option<service::Command>,
+
+    /// Serve the admin API on this address, it should only be reachable by administrators
+    #[clap(long, default_value = "127.0.0.1:9479")]
+    admin_addr: SocketAddr,
+
+    /// Export the spans of transactions, from their decoding to their response being sent, to
+    /// this OpenTelemetry collector OTLP gRPC endpoint, e.g. http://localhost:4317
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Fraction of the transactions whose spans are exported, between 0 and 1
+    #[cfg(feature = "otel")]
+    #[clap(long, default_value_t = 1.0)]
+    otlp_sample_ratio: f64,
+
+    /// Export the counters, gauges and response times to the statsd daemon at this address over
+    /// UDP, e.g. 127.0.0.1:8125
+    #[clap(long)]
+    statsd_addr: Option<String>,
+
+    /// Prefix of the metric names exported to statsd
+    #[clap(long, default_value = "stunner")]
+    statsd_prefix: String,
+
+    /// Send the method, class or reason of the metrics exported to statsd as DogStatsD tags
+    /// rather than appending them to their names
+    #[clap(long)]
+    statsd_datadog: bool,
+
+    /// Tag sent with every metric exported to statsd as key:value, e.g. env:prod.
+    /// Can be repeated
+    #[clap(long, multiple_occurrences = true, requires = "statsd-datadog")]
+    statsd_tag: Vec<String>,
+
+    /// Seconds between the exports to statsd
+    #[clap(long, default_value_t = statsd::DEFAULT_INTERVAL.as_secs())]
+    statsd_interval: u64,
+
+    /// On shutdown, seconds to wait for the TURN allocations to be released or to expire
+    /// before exiting, relaying their data meanwhile. New allocations are refused and refreshes
+    /// don't extend the existing ones beyond the timeout. A second signal stops right away
+    #[clap(long, default_value = "0")]
+    drain_timeout: u64,
+
+    /// Number of UDP sockets bound on each listen address with SO_REUSEPORT, each with its own
+    /// receive loop, to spread the load over multiple cores
+    #[clap(long, default_value = "1")]
+    workers: usize,
+
+    /// Number of worker threads of the runtime handling the messages, by default one per core
+    #[clap(long)]
+    runtime_threads: Option<NonZeroUsize>,
+
+    /// Largest number of threads of the runtime running blocking tasks, e.g. reading the user
+    /// database or resolving names [default: 512]
+    #[clap(long)]
+    blocking_threads: Option<NonZeroUsize>,
+
+    /// Pin each worker thread of the runtime to its own core, in turn, on hosts dedicated to
+    /// the server
+    #[cfg(target_os = "linux")]
+    #[clap(long)]
+    pin_workers: bool,
+
+    /// Only serve sources in this IP range, in CIDR notation e.g. 10.0.0.0/8.
+    /// Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    allow: Vec<Cidr>,
+
+    /// Drop messages from sources in this IP range, in CIDR notation, even when allowed by
+    /// --allow. Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    deny: Vec<Cidr>,
+
+    /// Also drop messages from the sources in the IP ranges of this file, one in CIDR notation
+    /// per line. It's polled for changes, so that the ranges added take effect within seconds
+    #[clap(long)]
+    blocklist: Option<PathBuf>,
+
+    /// Also drop messages from the sources in the IP ranges of the list at this HTTP URL, e.g.
+    /// https://www.spamhaus.org/drop/drop.txt, one in CIDR notation per line optionally
+    /// followed by a ; comment. It's fetched again every --deny-feed-interval once its ETag
+    /// changed
+    #[cfg(feature = "feed")]
+    #[clap(long)]
+    deny_feed: Option<String>,
+
+    /// Seconds between two fetches of --deny-feed
+    #[cfg(feature = "feed")]
+    #[clap(long, default_value_t = feed::DEFAULT_INTERVAL.as_secs())]
+    deny_feed_interval: u64,
+
+    /// Handlers the messages go through, in order: acl drops the messages of denied sources,
+    /// auth authenticates the requests and log logs the messages and their responses at the
+    /// debug level. Leaving one out disables it, e.g. --handler acl answers requests without
+    /// authenticating them whatever the users configured. Can be repeated
+    #[clap(
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        default_values = &["acl", "auth", "log"]
+    )]
+    handler: Vec<handler::Builtin>,
+
+    /// Also pass the messages through the filter of this WebAssembly module, binary or text,
+    /// after the --handler ones. Its pre_decode and post_decode exports drop the messages or
+    /// answer the requests with an error. Can be repeated
+    #[cfg(feature = "wasm")]
+    #[clap(long, multiple_occurrences = true)]
+    wasm_filter: Vec<PathBuf>,
+
+    /// Echo the attributes of this type, e.g. 0x802F for ORIGIN, from requests back in their
+    /// responses, comprehension-required types are then no longer answered with an
+    /// UNKNOWN-ATTRIBUTES error. Can be repeated
+    #[clap(long, multiple_occurrences = true, parse(try_from_str = attribute::parse_kind))]
+    echo_attribute: Vec<u16>,
+
+    /// Add an attribute given as type=value, with its value in hexadecimal, e.g.
+    /// 0xC001=0102, to every response. Can be repeated
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = attribute::parse_attribute)
+    )]
+    response_attribute: Vec<(u16, Vec<u8>)>,
+
+    /// Size in bytes of the buffer UDP datagrams are received in, larger datagrams are dropped
+    #[clap(long, default_value_t = server::DEFAULT_RECV_BUFFER_SIZE)]
+    recv_buffer_size: usize,
+
+    /// Size in bytes of the kernel receive buffer of the sockets, set with SO_RCVBUF. Raising it
+    /// avoids dropping bursts of datagrams at high packet rates, the kernel caps it, e.g. to
+    /// net.core.rmem_max on Linux
+    #[clap(long)]
+    socket_recv_buffer_size: Option<usize>,
+
+    /// Size in bytes of the kernel send buffer of the sockets, set with SO_SNDBUF, capped by the
+    /// kernel, e.g. to net.core.wmem_max on Linux
+    #[clap(long)]
+    socket_send_buffer_size: Option<usize>,
+
+    /// Receive and send up to this many UDP datagrams per system call with recvmmsg and
+    /// sendmmsg, Linux only, at most 64. The datagrams of a batch are handled in turn
+    #[clap(long, default_value = "1")]
+    udp_batch_size: usize,
+
+    /// Whether the batches of UDP datagrams are received coalesced and their responses sent
+    /// together with generic receive and segmentation offload, with --udp-batch-size above 1 on
+    /// Linux. With auto, they are where the sockets support them
+    #[clap(long, arg_enum, default_value = "auto")]
+    udp_offload: UdpOffload,
+
+    /// Seconds without a message received after which TCP and TLS connections are closed
+    #[clap(long, default_value = "300")]
+    tcp_idle_timeout: u64,
+
+    /// Maximum number of TCP and TLS connections open at once, the ones beyond are closed
+    #[clap(long, default_value = "10000")]
+    max_connections: usize,
+
+    /// Size in bytes of the largest STUN message accepted over TCP and TLS, connections sending
+    /// larger ones are closed
+    #[clap(long, default_value = "8192")]
+    tcp_max_message_size: usize,
+
+    /// Expect a PROXY protocol version 2 header on the connections accepted by the TCP or TLS
+    /// listener bound to this address, e.g. 0.0.0.0:3478, so that responses reflect the address
+    /// of the clients behind an L4 load balancer. Can be repeated
+    #[clap(long, multiple_occurrences = true)]
+    proxy_protocol: Vec<SocketAddr>,
+
+    /// Drop the Binding requests of RFC 3489 clients, which lack the magic cookie, instead of
+    /// answering them with a MAPPED-ADDRESS
+    #[clap(long)]
+    no_rfc3489: bool,
+
+    /// Add the MAPPED-ADDRESS of RFC 3489 to Binding success responses alongside their
+    /// XOR-MAPPED-ADDRESS, for old SIP phones and embedded stacks which only read the former
+    #[clap(long)]
+    compat_mapped_address: bool,
+
+    /// What becomes of the packets which aren't valid STUN messages: drop silently drops them,
+    /// so that spoofed sources can't be flooded with responses, respond answers the requests
+    /// whose header can be read with a 400 Bad Request and log drops them and logs why at the
+    /// info level
+    #[clap(long, arg_enum, default_value = "drop")]
+    malformed_packets: MalformedPolicy,
+
+    /// Number of malformed packets of a single source logged a minute, the others are summed up
+    /// by source once the minute is over
+    #[clap(long, default_value_t = logsample::DEFAULT_PER_SOURCE)]
+    malformed_log_per_source: u64,
+
+    /// Number of malformed packets logged a minute overall
+    #[clap(long, default_value_t = logsample::DEFAULT_MAX)]
+    malformed_log_max: u64,
+
+    /// Seconds the response of each request is kept to answer its retransmissions identically
+    /// instead of handling them again, 0 to handle every retransmission
+    #[clap(long, default_value_t = transactions::DEFAULT_TTL.as_secs())]
+    response_cache_ttl: u64,
+
+    /// Keep no state about clients, so that every response is derived from its request alone
+    /// and instances in several regions can share an anycast address. The response cache is
+    /// disabled, malformed packets are only logged up to --malformed-log-max, and the relay,
+    /// long-term credentials, per IP limits, bans and demultiplexing refused
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "turn",
+            "users",
+            "users-file",
+            "auth-secret",
+            "realm-config",
+            "ice-ufrag",
+            "demux-forward",
+            "max-rps-per-ip",
+            "max-in-flight-per-ip",
+            "ban-after",
+            "malformed-log-per-source",
+        ]
+    )]
+    stateless: bool,
+
+    /// Answer the Binding requests received over IPv4 and carrying no attribute in the kernel,
+    /// with an XDP program attached to this network interface, before they reach the network
+    /// stack. The others are handled as usual. Such requests would escape authentication, the
+    /// access control lists, the limits and the bans, which are refused. Requires the
+    /// CAP_BPF and CAP_NET_ADMIN capabilities
+    #[cfg(target_os = "linux")]
+    #[clap(long)]
+    xdp_interface: Option<String>,
+
+    /// Write an access log to this file, one JSON object per line with the time, transport,
+    /// source address, method, class, outcome, sizes in bytes of the message and its response
+    /// and latency in microseconds of each message handled. Its directory must be writable by
+    /// --user to rotate it
+    #[clap(long)]
+    access_log: Option<PathBuf>,
+
+    /// Rotate the access log once it reaches this size in bytes, the rotated files are suffixed
+    /// with the UNIX time in milliseconds of their rotation
+    #[clap(long, requires = "access-log")]
+    access_log_max_size: Option<u64>,
+
+    /// Rotate the access log once it's this many seconds old
+    #[clap(long, requires = "access-log")]
+    access_log_max_age: Option<u64>,
+
+    /// Number of rotated access log files kept, the older ones are removed. All are kept when 0
+    #[clap(long, default_value = "0")]
+    access_log_keep: usize,
+
+    /// Capture the STUN messages received and the responses sent to this pcap file, in
+    /// synthetic UDP/IP packets whatever their transport, for offline analysis with e.g.
+    /// Wireshark. The file is overwritten
+    #[clap(long)]
+    pcap_out: Option<PathBuf>,
+
+    /// Rotate the capture once it reaches this size in bytes, the rotated files are suffixed
+    /// with the UNIX time in milliseconds of their rotation
+    #[clap(long, requires = "pcap-out")]
+    pcap_max_size: Option<u64>,
+
+    /// Number of rotated capture files kept, the older ones are removed. All are kept when 0
+    #[clap(long, default_value = "0")]
+    pcap_keep: usize,
+
+    /// MaxMind database the countries of the sources are looked up in, e.g. GeoLite2-Country.mmdb,
+    /// to tag the access log entries and count the messages by country in the metrics
+    #[cfg(feature = "geoip")]
+    #[clap(long)]
+    geoip_country_db: Option<PathBuf>,
+
+    /// MaxMind database the autonomous systems of the sources are looked up in, e.g.
+    /// GeoLite2-ASN.mmdb, to tag the access log entries and count the messages by autonomous
+    /// system in the metrics
+    #[cfg(feature = "geoip")]
+    #[clap(long)]
+    geoip_asn_db: Option<PathBuf>,
+
+    /// Drop the messages of the sources of this country, given as an ISO 3166-1 alpha-2 code
+    /// looked up in --geoip-country-db, before they are decoded. Can be repeated
+    #[cfg(feature = "geoip")]
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        requires = "geoip-country-db",
+        parse(try_from_str = geoip::parse_country)
+    )]
+    deny_country: Vec<String>,
+
+    /// Only handle the messages of the sources of this country, given as an ISO 3166-1 alpha-2
+    /// code looked up in --geoip-country-db, the sources of an unknown country being dropped
+    /// too. Can be repeated
+    #[cfg(feature = "geoip")]
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        requires = "geoip-country-db",
+        parse(try_from_str = geoip::parse_country)
+    )]
+    allow_country: Vec<String>,
+
+    /// Format of the log records, json writes one object per line with the source address,
+    /// method, class, transaction id and outcome of each message handled.
+    /// The verbosity is configured with RUST_LOG, e.g. RUST_LOG=info
+    #[clap(long, arg_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Where the log records are written, journald writes them to the systemd journal with
+    /// their structured fields, e.g. SRC_ADDR and STUN_METHOD, regardless of --log-format
+    #[clap(long, arg_enum, default_value = "stderr")]
+    log_target: LogTarget,
+
+    /// Maximum level of the log records, e.g. info or debug, taking precedence over RUST_LOG.
+    /// Can only be reloaded when given at startup
+    #[clap(long)]
+    log_level: Option<log::LevelFilter>,
 }
 
-/// Listen for STUN requests on the given address and reply to valid STUN Binding Requests
-async fn serve(addr: impl ToSocketAddrs) -> Result<()> {
-    let sock = UdpSocket::bind(addr).await?;
-    log::info!("serving on addr: {}", sock.local_addr().unwrap());
-
-    loop {
-        let mut buf = [0; 1024];
-        let (_, src_addr) = sock.recv_from(&mut buf).await?;
-        // Process the response in case of a STUN binding request
-        if let Some(message) = parse_message(&buf, src_addr) {
-            log::trace!("replied {:?} to {:?}", message, src_addr);
-            if let Err(err) = sock.send_to(&message.encode(None).unwrap(), src_addr).await {
-                log::error!(
-                    "could not send response {:?} to address {:?}, reason: {}",
-                    message,
-                    src_addr,
-                    err
-                );
-            }
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Load a STUN server with Binding requests sent from many local ports, and report the
+    /// rate of the requests and responses and the latency percentiles, to size the hardware
+    /// it runs on
+    Bench(bench::Options),
+}
+
+impl Cli {
+    /// Parse the command line, preceded by the options of the configuration file if any.
+    fn load() -> Result<Cli> {
+        Ok(Cli::try_parse_from(Cli::args()?)?)
+    }
+
+    /// Arguments of the command line, preceded by the options of the configuration file if any.
+    fn args() -> Result<Vec<OsString>> {
+        let opt = Cli::try_parse()?;
+        let config = match &opt.config {
+            Some(path) => config::args(path)?,
+            None => Vec::new(),
+        };
+        let mut args = std::env::args_os();
+        Ok(args
+            .next()
+            .into_iter()
+            .chain(config.into_iter().map(Into::into))
+            .chain(args)
+            .collect())
+    }
+
+    /// Addresses STUN is served on, along with the TCP ones on the same addresses when sockets
+    /// were `inherited` from the service manager.
+    fn addrs(&self, inherited: usize) -> Vec<SocketAddr> {
+        if !self.listen.is_empty() {
+            self.listen.clone()
+        } else if inherited > 0 {
+            // Serve on the UDP sockets inherited, along with the TCP ones on the same addresses.
+            activation::addrs(socket2::Type::DGRAM)
+        } else {
+            self.port
+                .iter()
+                .map(|port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, *port)))
+                .collect()
         }
     }
-}
 
-/// Parse the stun request and create the appropriate response message.
-fn parse_message(buf: &[u8], src_addr: SocketAddr) -> Option<StunMessage> {
-    let message = match StunMessage::decode(buf, None) {
-        Ok(message) => message,
-        Err(err) => {
-            log::debug!(
-                "could not parse packet from {:?} : {:?} as a STUN message",
-                src_addr,
-                err
-            );
-            return None;
+    /// Credentials requests must be authenticated with, if any.
+    fn auth(&self, nonce_secret: [u8; 16]) -> Result<Option<Auth>> {
+        if let (Some(username), Some(password)) =
+            (&self.short_term_username, &self.short_term_password)
+        {
+            let auth = ShortTermAuth::new(username.clone(), password.clone());
+            return Ok(Some(Auth::ShortTerm(auth)));
         }
-    };
-    let header = message.get_header();
-    match (header.message_method, header.message_class) {
-        (StunMessageMethod::BindingRequest, StunMessageClass::Request) => {
-            log::debug!(
-                "STUN binding request received {:?} from source address: {:?}",
-                message,
-                src_addr
-            );
-            let response = StunMessage::new(
-                StunMessageMethod::BindingRequest,
-                StunMessageClass::SuccessResponse,
-            )
-            .set_transaction_id(header.transaction_id)
-            .add_attribute(StunAttribute::XorMappedAddress {
-                socket_addr: src_addr,
-            });
-            Some(response)
+        let mut users = match &self.users_file {
+            Some(path) => auth::load_users(path)?,
+            None => Default::default(),
+        };
+        users.extend(self.users.iter().cloned());
+        #[cfg(feature = "sql")]
+        let users_db = self.users_db.is_some();
+        #[cfg(not(feature = "sql"))]
+        let users_db = false;
+        #[cfg(feature = "webhook")]
+        let webhook = self.auth_webhook.is_some();
+        #[cfg(not(feature = "webhook"))]
+        let webhook = false;
+        #[cfg(feature = "ldap")]
+        let directory = self.ldap_url.is_some();
+        #[cfg(not(feature = "ldap"))]
+        let directory = false;
+        if users.is_empty() && self.auth_secret.is_none() && !users_db && !webhook && !directory {
+            return Ok(None);
         }
-        (StunMessageMethod::BindingRequest, StunMessageClass::Indication) => {
-            log::debug!(
-                "STUN indication received {:?} from source address: {:?}",
-                message,
-                src_addr
-            );
-            // No response is generated for an indication https://datatracker.ietf.org/doc/html/rfc5389#section-7.3.2
-            None
-        }
-        (StunMessageMethod::BindingRequest, class @ StunMessageClass::ErrorResponse)
-        | (StunMessageMethod::BindingRequest, class @ StunMessageClass::SuccessResponse) => {
-            log::debug!("STUN binding {:?}", class);
-            // Reply with BAD REQUEST see https://datatracker.ietf.org/doc/html/rfc5389#section-15.6
-            let response = StunMessage::new(
-                StunMessageMethod::BindingRequest,
-                StunMessageClass::ErrorResponse,
+        let auth = LongTermAuth::new(self.realm.clone(), users)
+            .with_auth_secret(self.auth_secret.clone())
+            .with_nonce_lifetime(Duration::from_secs(self.nonce_lifetime))
+            .with_nonce_secret(nonce_secret);
+        #[cfg(feature = "sql")]
+        let auth = match &self.users_db {
+            Some(url) => auth.with_database(Some(userdb::UserDatabase::connect(url)?)),
+            None => auth,
+        };
+        #[cfg(feature = "webhook")]
+        let auth = match &self.auth_webhook {
+            Some(url) => auth.with_webhook(Some(webhook::AuthWebhook::new(
+                url.clone(),
+                Duration::from_secs(self.auth_webhook_timeout),
+                Duration::from_secs(self.auth_webhook_cache_ttl),
+            )?)),
+            None => auth,
+        };
+        #[cfg(feature = "ldap")]
+        let auth = auth.with_directory(self.ldap_url.as_ref().map(|url| {
+            ldap::LdapDirectory::new(ldap::LdapConfig {
+                url: url.clone(),
+                bind: self.ldap_bind_dn.clone().map(|dn| {
+                    let password = self.ldap_bind_password.clone().unwrap_or_default();
+                    (dn, password)
+                }),
+                base_dn: self.ldap_base_dn.clone().unwrap_or_default(),
+                filter: self.ldap_filter.clone(),
+                ha1_attribute: self.ldap_ha1_attribute.clone(),
+                password_attribute: self.ldap_password_attribute.clone(),
+            })
+        }));
+        Ok(Some(Auth::LongTerm(Box::new(auth))))
+    }
+
+    /// State shared with the other instances of a cluster, or kept in memory.
+    async fn state(&self) -> Result<SharedState> {
+        #[cfg(feature = "redis")]
+        if let Some(url) = &self.redis_url {
+            return SharedState::redis(url).await;
+        }
+        Ok(SharedState::default())
+    }
+
+    /// Client obtaining the TLS certificate, when domains are given.
+    #[cfg(feature = "acme")]
+    fn acme(&self) -> Option<Arc<acme::Acme>> {
+        (!self.acme_domain.is_empty()).then(|| {
+            acme::Acme::new(
+                self.acme_domain.clone(),
+                self.acme_email.as_deref(),
+                self.acme_dir.clone(),
+                self.acme_directory_url.clone(),
             )
-            .add_attribute(StunAttribute::ErrorCode {
-                class: 4,
-                number: 0,
-                reason: "Invalid binding request class".into(),
+        })
+    }
+
+    /// Additional realms, with their own credentials and access control lists.
+    fn realms(&self, nonce_secret: [u8; 16]) -> Result<Vec<Realm>> {
+        realm::load(
+            &self.realm_config,
+            Duration::from_secs(self.nonce_lifetime),
+            nonce_secret,
+        )
+    }
+
+    fn acl(&self) -> Acl {
+        Acl::new(self.allow.clone(), self.deny.clone())
+    }
+
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.max_rps_per_ip.map(RateLimiter::new)
+    }
+
+    async fn statsd(&self) -> Result<Option<Arc<Statsd>>> {
+        let addr = match &self.statsd_addr {
+            Some(addr) => addr.clone(),
+            None => return Ok(None),
+        };
+        let statsd = Statsd::connect(StatsdConfig {
+            addr,
+            prefix: self.statsd_prefix.clone(),
+            datadog: self.statsd_datadog,
+            tags: self.statsd_tag.clone(),
+            interval: Duration::from_secs(self.statsd_interval.max(1)),
+        })
+        .await?;
+        Ok(Some(Arc::new(statsd)))
+    }
+
+    #[cfg(feature = "geoip")]
+    fn geoip(&self) -> Result<Option<geoip::GeoIp>> {
+        if self.geoip_country_db.is_none() && self.geoip_asn_db.is_none() {
+            return Ok(None);
+        }
+        let geoip = geoip::GeoIp::open(
+            self.geoip_country_db.as_deref(),
+            self.geoip_asn_db.as_deref(),
+        )?;
+        Ok(Some(geoip.with_country_policy(geoip::CountryPolicy {
+            allow: self.allow_country.clone(),
+            deny: self.deny_country.clone(),
+        })))
+    }
+
+    /// Handlers the messages go through, the built-in ones, the ICE-lite agent then the
+    /// WebAssembly filters.
+    fn handlers(&self) -> Result<Vec<Box<dyn handler::Handler>>> {
+        let mut handlers: Vec<_> = self
+            .handler
+            .iter()
+            .map(|handler| handler.handler())
+            .collect();
+        if let (Some(ufrag), Some(password)) = (&self.ice_ufrag, &self.ice_password) {
+            handlers.push(Box::new(IceLite::new(ufrag.clone(), password.clone())));
+        }
+        #[cfg(feature = "wasm")]
+        if !self.wasm_filter.is_empty() {
+            let engine = wasm::engine()?;
+            for path in &self.wasm_filter {
+                handlers.push(Box::new(wasm::WasmHandler::load(&engine, path)?));
+            }
+        }
+        Ok(handlers)
+    }
+
+    /// Attributes echoed back and added to the responses.
+    fn attributes(&self) -> Attributes {
+        let attributes = self
+            .echo_attribute
+            .iter()
+            .fold(Attributes::default(), |attributes, &kind| {
+                attributes.register(Box::new(attribute::Echo(kind)))
             });
-            Some(response)
+        self.response_attribute
+            .iter()
+            .fold(attributes, |attributes, (kind, value)| {
+                attributes.append(*kind, value.clone())
+            })
+    }
+}
+
+fn main() {
+    let opt = Cli::load().unwrap_or_else(|err| match err.downcast::<clap::Error>() {
+        Ok(err) => err.exit(),
+        Err(err) => panic!("could not load configuration: {:#}", err),
+    });
+    if let Some(Command::Bench(options)) = &opt.command {
+        match bench(options) {
+            Ok(report) => print!("{}", report),
+            Err(err) => {
+                eprintln!("could not run the load test: {:#}", err);
+                std::process::exit(1);
+            }
         }
+        return;
     }
+    if opt.check_config {
+        match check(&opt) {
+            Ok(()) => return,
+            Err(err) => {
+                eprintln!("invalid configuration: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    #[cfg(windows)]
+    if let Some(command) = opt.service {
+        service::main(command).expect("could not run the service command");
+        return;
+    }
+    logging::init(opt.log_format, opt.log_level, opt.log_target);
+    run(opt, shutdown::signal());
+}
+
+#[tokio::main]
+async fn bench(options: &bench::Options) -> Result<bench::Report> {
+    bench::run(options).await
 }
 
-#[cfg(test)]
-mod tests {
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-
-    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
-
-    use super::parse_message;
-
-    #[test]
-    fn server_responds_successful_to_binding_request() {
-        let req_msg =
-            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
-        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        let response = parse_message(&req_msg.encode(None).unwrap(), socket).unwrap();
-        let header = response.get_header();
-        let attributes = response.get_attributes();
-        assert!(matches!(
-            header.message_method,
-            StunMessageMethod::BindingRequest
-        ));
-        assert!(matches!(
-            header.message_class,
-            StunMessageClass::SuccessResponse
-        ));
-        assert_eq!(attributes.len(), 1);
-        assert!(
-            matches!(attributes[0], StunAttribute::XorMappedAddress { socket_addr} if socket_addr == socket)
+/// Options whose values are redacted when printing the configuration, as they hold secrets.
+const SECRETS: &[&str] = &[
+    "users",
+    "auth-secret",
+    "short-term-password",
+    "ice-password",
+    "admin-token",
+    "users-db",
+    "redis-url",
+    "ldap-bind-password",
+];
+
+/// Check that the server can start with the configuration of `opt`, loading everything it needs
+/// but binding no socket, and print the effective configuration.
+#[tokio::main]
+async fn check(opt: &Cli) -> Result<()> {
+    let mut command = Cli::command();
+    let matches = command.try_get_matches_from_mut(Cli::args()?)?;
+    #[cfg_attr(not(feature = "quic"), allow(unused_variables))]
+    let client_verifier = opt
+        .tls_client_ca
+        .as_deref()
+        .map(tls::client_verifier)
+        .transpose()
+        .context("could not load TLS client certificate authorities")?;
+    if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+        #[cfg_attr(not(feature = "quic"), allow(unused_variables))]
+        let certificate = tls::Certificate::load(cert, key, &opt.tls_sni_cert)
+            .context("could not load TLS certificate")?;
+        #[cfg(feature = "dtls")]
+        if opt.dtls {
+            dtls::acceptor(cert, key, opt.tls_client_ca.as_deref())
+                .context("could not load DTLS certificate")?;
+        }
+        #[cfg(feature = "quic")]
+        if opt.experimental_quic {
+            quic::server_config(
+                &certificate,
+                client_verifier,
+                Duration::from_secs(opt.tcp_idle_timeout),
+            )
+            .context("invalid QUIC configuration")?;
+        }
+    }
+    #[cfg(feature = "acme")]
+    if let Some(acme) = opt.acme() {
+        acme.certificate()
+            .context("could not load ACME certificate")?;
+    }
+    opt.auth(Default::default())
+        .context("could not load users")?;
+    opt.realms(Default::default())
+        .context("could not load realms")?;
+    #[cfg(unix)]
+    privileges::Account::lookup(opt.user.as_deref(), opt.group.as_deref())
+        .context("could not look up the account to switch to")?;
+    if let Some(path) = &opt.access_log {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        anyhow::ensure!(
+            dir.is_dir(),
+            "the directory of the access log {:?} does not exist",
+            path
         );
     }
-
-    #[test]
-    fn server_doesnt_respond_to_indication_request() {
-        let req_msg = StunMessage::new(
-            StunMessageMethod::BindingRequest,
-            StunMessageClass::Indication,
+    let addrs = opt.addrs(0);
+    if let Some(ip) = opt.alternate_ip {
+        anyhow::ensure!(
+            addrs.iter().any(|addr| addr.is_ipv4() == ip.is_ipv4()),
+            "no --listen address of the family of --alternate-ip"
         );
-        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+    }
+    print!("{}", config::format(&command, &matches, SECRETS));
+    for addr in addrs {
+        println!("# serving on {}", addr);
+    }
+    Ok(())
+}
+
+/// Serve with the configuration of `opt` until `stop` resolves to the reason to stop, then
+/// shut down gracefully.
+fn run(opt: Cli, stop: impl Future<Output = &'static str>) {
+    runtime(&opt)
+        .expect("could not start the runtime")
+        .block_on(serve_until(opt, stop));
+}
 
-        let response = parse_message(&req_msg.encode(None).unwrap(), socket);
-        assert!(response.is_none());
+/// The runtime to serve on, with the threads configured in `opt`.
+fn runtime(opt: &Cli) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = opt.runtime_threads {
+        builder.worker_threads(threads.get());
     }
+    if let Some(threads) = opt.blocking_threads {
+        builder.max_blocking_threads(threads.get());
+    }
+    #[cfg(target_os = "linux")]
+    if opt.pin_workers {
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-    #[test]
-    fn server_responds_with_error_to_success_response() {
-        let req_msg = StunMessage::new(
-            StunMessageMethod::BindingRequest,
-            StunMessageClass::SuccessResponse,
-        );
-        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        let response = parse_message(&req_msg.encode(None).unwrap(), socket).unwrap();
-        let header = response.get_header();
-        let attributes = response.get_attributes();
-        assert!(matches!(
-            header.message_method,
-            StunMessageMethod::BindingRequest
-        ));
-        assert!(matches!(
-            header.message_class,
-            StunMessageClass::ErrorResponse
-        ));
-        assert_eq!(attributes.len(), 1);
-        assert!(
-            matches!(&attributes[0], StunAttribute::ErrorCode { class, number, reason } if class == &4u8 && number == &0u8 && reason == "Invalid binding request class")
-        );
+        let cores = affinity::cores()?;
+        let workers = opt.runtime_threads.map_or(cores.len(), NonZeroUsize::get);
+        // The worker threads are the first started, the blocking ones only as needed.
+        let started = AtomicUsize::new(0);
+        builder.on_thread_start(move || {
+            let index = started.fetch_add(1, Ordering::Relaxed);
+            if index < workers {
+                let core = cores[index % cores.len()];
+                if let Err(err) = affinity::pin(core) {
+                    log::warn!("could not pin worker thread to core {}: {}", core, err);
+                }
+            }
+        });
     }
+    builder.build()
+}
 
-    #[test]
-    fn server_responds_with_error_to_error_response() {
-        let req_msg = StunMessage::new(
-            StunMessageMethod::BindingRequest,
-            StunMessageClass::ErrorResponse,
-        );
-        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        let response = parse_message(&req_msg.encode(None).unwrap(), socket).unwrap();
-        let header = response.get_header();
-        let attributes = response.get_attributes();
-        assert!(matches!(
-            header.message_method,
-            StunMessageMethod::BindingRequest
-        ));
-        assert!(matches!(
-            header.message_class,
-            StunMessageClass::ErrorResponse
-        ));
-        assert_eq!(attributes.len(), 1);
-        assert!(
-            matches!(&attributes[0], StunAttribute::ErrorCode { class, number, reason } if class == &4u8 && number == &0u8 && reason == "Invalid binding request class")
-        );
+/// Serve as [`run`] does, on the runtime.
+async fn serve_until(opt: Cli, stop: impl Future<Output = &'static str>) {
+    #[cfg(feature = "otel")]
+    let tracer_provider = opt.otlp_endpoint.as_ref().map(|endpoint| {
+        telemetry::init(endpoint, opt.otlp_sample_ratio).expect("could not export traces")
+    });
+    let state = opt
+        .state()
+        .await
+        .expect("could not connect to the shared state");
+    let auth = opt
+        .auth(state.nonce_secret())
+        .expect("could not load users");
+    let realms = opt
+        .realms(state.nonce_secret())
+        .expect("could not load realms");
+    let acl = opt.acl();
+    let statsd = opt
+        .statsd()
+        .await
+        .expect("could not connect to the statsd daemon");
+    let blocklist = opt
+        .blocklist
+        .clone()
+        .map(|path| Blocklist::load(path).expect("could not load the blocklist"));
+    #[cfg(feature = "feed")]
+    let deny_feed = opt.deny_feed.clone().map(|url| {
+        let interval = Duration::from_secs(opt.deny_feed_interval.max(1));
+        Arc::new(DenyFeed::new(url, interval).expect("could not set up the deny feed"))
+    });
+    let rate_limiter = opt.rate_limiter();
+    let access_log = opt.access_log.as_ref().map(|path| {
+        let rotation = Rotation {
+            max_size: opt.access_log_max_size,
+            max_age: opt.access_log_max_age.map(Duration::from_secs),
+            keep: opt.access_log_keep,
+        };
+        AccessLog::open(path.clone(), rotation).expect("could not open the access log")
+    });
+    let capture = opt.pcap_out.as_ref().map(|path| {
+        Capture::open(path.clone(), opt.pcap_max_size, opt.pcap_keep)
+            .expect("could not open the capture")
+    });
+    #[cfg(feature = "geoip")]
+    let geoip = opt.geoip().expect("could not open the GeoIP databases");
+    let attributes = opt.attributes();
+    let handlers = opt
+        .handlers()
+        .expect("could not load the WebAssembly filters");
+    #[cfg(feature = "acme")]
+    let acme = opt.acme();
+    let certificate = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            tls::Certificate::load(cert, key, &opt.tls_sni_cert)
+                .expect("could not load TLS certificate"),
+        ),
+        #[cfg(feature = "acme")]
+        _ if acme.is_some() => Some(
+            acme.as_ref()
+                .unwrap()
+                .certificate()
+                .expect("could not load ACME certificate"),
+        ),
+        _ => None,
+    };
+    let client_verifier = opt.tls_client_ca.as_deref().map(|path| {
+        tls::client_verifier(path).expect("could not load TLS client certificate authorities")
+    });
+    let secure = certificate.map(|certificate| Secure {
+        port: opt.tls_port,
+        tls: certificate.acceptor(client_verifier.clone()),
+        strict_alpn: opt.strict_alpn,
+        #[cfg(feature = "dtls")]
+        dtls: opt
+            .tls_cert
+            .as_ref()
+            .zip(opt.tls_key.as_ref())
+            .filter(|_| opt.dtls)
+            .map(|(cert, key)| {
+                dtls::acceptor(cert, key, opt.tls_client_ca.as_deref())
+                    .expect("could not load DTLS certificate")
+            }),
+        #[cfg(feature = "quic")]
+        quic: opt.experimental_quic.then(|| {
+            let idle_timeout = Duration::from_secs(opt.tcp_idle_timeout);
+            let config = quic::server_config(&certificate, client_verifier, idle_timeout)
+                .expect("invalid QUIC configuration");
+            (opt.quic_port, config)
+        }),
+        certificate,
+    });
+    let socket_config = SocketConfig {
+        dscp: opt.dscp.unwrap_or(0),
+        recv_buffer_size: opt.socket_recv_buffer_size,
+        send_buffer_size: opt.socket_send_buffer_size,
+        #[cfg(target_os = "linux")]
+        interface: opt.interface.clone(),
+    };
+    let inherited = activation::inherit();
+    if inherited > 0 {
+        log::info!("inherited {} sockets from the service manager", inherited);
+    }
+    let addrs = opt.addrs(inherited);
+    let turn = if opt.turn {
+        opt.relay_ip.split_first().map(|(relay_ip, others)| {
+            others
+                .iter()
+                .fold(Turn::new(*relay_ip), |turn, relay_ip| {
+                    turn.with_relay_ip(*relay_ip)
+                })
+                .with_quotas(Quotas {
+                    per_user: opt.max_allocations_per_user,
+                    per_ip: opt.max_allocations_per_ip,
+                })
+                .with_bandwidth_limits(BandwidthLimits {
+                    default: opt.max_relay_bandwidth,
+                    per_user: opt.user_relay_bandwidth.iter().cloned().collect(),
+                })
+                .with_mobility(opt.mobility)
+                .with_port_range(opt.relay_port_range)
+                .with_socket_config(socket_config.clone())
+                .with_peer_policy(PeerPolicy::new(
+                    opt.allow_peer.clone(),
+                    opt.deny_peer.clone(),
+                ))
+        })
+    } else {
+        None
+    };
+    let discovery = opt.alternate_ip.map(|ip| {
+        let primary = addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == ip.is_ipv4())
+            .expect("no --listen address of the family of --alternate-ip");
+        let alternate = SocketAddr::new(ip, opt.alternate_port.unwrap_or(0));
+        Discovery::bind(*primary, alternate, &socket_config)
+            .expect("could not bind NAT behavior discovery sockets")
+    });
+    let server = Server::default()
+        .with_turn(turn)
+        .with_auth(auth)
+        .with_realms(realms)
+        .with_fingerprint(opt.fingerprint || opt.ice_ufrag.is_some())
+        .with_discovery(discovery)
+        .with_rate_limiter(rate_limiter)
+        .with_in_flight_limiter(opt.max_in_flight_per_ip.map(InFlightLimiter::new))
+        .with_global_rate_limiter(opt.max_pps.map(|per_second| {
+            GlobalRateLimiter::new(per_second, opt.max_pps_burst.unwrap_or(per_second))
+        }))
+        .with_acl(acl)
+        .with_blocklist(blocklist)
+        .with_state(state)
+        .with_recv_buffer_size(opt.recv_buffer_size)
+        .with_socket_config(socket_config)
+        .with_udp_batch_size(opt.udp_batch_size.max(1))
+        .with_udp_offload(opt.udp_offload)
+        .with_connection_limits(ConnectionLimits {
+            idle_timeout: Duration::from_secs(opt.tcp_idle_timeout),
+            max_connections: opt.max_connections,
+            max_message_size: opt.tcp_max_message_size,
+        })
+        .with_proxy_protocol(opt.proxy_protocol)
+        .with_rfc3489(!opt.no_rfc3489)
+        .with_compat_mapped_address(opt.compat_mapped_address)
+        .with_malformed_policy(opt.malformed_packets)
+        .with_statsd(statsd)
+        .with_response_cache(
+            (opt.response_cache_ttl > 0 && !opt.stateless)
+                .then(|| ResponseCache::new(Duration::from_secs(opt.response_cache_ttl))),
+        )
+        .with_access_log(access_log)
+        .with_capture(capture)
+        .with_handlers(handlers)
+        .with_attributes(attributes)
+        .with_demux(opt.demux_forward.map(Demux::new))
+        .with_failures(opt.ban_after.map(|threshold| {
+            Failures::new(
+                threshold,
+                Duration::from_secs(opt.ban_window),
+                Duration::from_secs(opt.ban_duration),
+            )
+        }));
+    #[cfg(feature = "geoip")]
+    let server = server.with_geoip(geoip);
+    #[cfg(feature = "feed")]
+    let server = server.with_deny_feed(deny_feed);
+    let server = if opt.stateless {
+        server.with_malformed_log_max(opt.malformed_log_max)
+    } else {
+        server.with_malformed_log_limits(opt.malformed_log_per_source, opt.malformed_log_max)
+    };
+    if server.open_relay() {
+        Cli::command()
+            .error(
+                clap::ErrorKind::MissingRequiredArgument,
+                "--turn requires long-term credentials and the auth handler",
+            )
+            .exit();
+    }
+    if opt.stateless {
+        let stateful = server.stateful_features();
+        if !stateful.is_empty() {
+            Cli::command()
+                .error(
+                    clap::ErrorKind::ArgumentConflict,
+                    format!(
+                        "--stateless can't be combined with: {}",
+                        stateful.join(", ")
+                    ),
+                )
+                .exit();
+        }
+        log::info!("stateless mode, no state is kept about clients");
+    }
+    #[cfg(target_os = "linux")]
+    let server = match &opt.xdp_interface {
+        Some(interface) => {
+            let bypassed = server.kernel_bypassed_features();
+            if !bypassed.is_empty() {
+                Cli::command()
+                    .error(
+                        clap::ErrorKind::ArgumentConflict,
+                        format!(
+                            "--xdp-interface can't be combined with: {}",
+                            bypassed.join(", ")
+                        ),
+                    )
+                    .exit();
+            }
+            let ports: Vec<_> = addrs
+                .iter()
+                .filter(|addr| addr.is_ipv4() || addr.ip().is_unspecified())
+                .map(SocketAddr::port)
+                .collect();
+            let xdp = Xdp::attach(interface, &ports)
+                .expect("could not answer Binding requests in the kernel");
+            log::info!(
+                "answering Binding requests in the kernel on {}",
+                xdp.interface()
+            );
+            server.with_xdp(Some(xdp))
+        }
+        None => server,
+    };
+    let server = Arc::new(server);
+    // The certificate obtained with ACME is reloaded once renewed instead.
+    #[cfg(unix)]
+    let certificate = secure
+        .as_ref()
+        .filter(|_| opt.tls_cert.is_some())
+        .map(|secure| secure.certificate.clone());
+    let listeners = Listeners {
+        addrs,
+        workers: opt.workers,
+        secure,
+        metrics_addr: opt.metrics_addr,
+        health_addr: opt.health_addr,
+        ws_addr: opt.ws_addr,
+        admin: opt.admin_token.map(|token| (opt.admin_addr, token)),
+        #[cfg(feature = "acme")]
+        acme: acme.map(|acme| (opt.acme_http_addr, acme)),
+        #[cfg(unix)]
+        account: privileges::Account::lookup(opt.user.as_deref(), opt.group.as_deref())
+            .expect("could not look up the account to switch to"),
+        #[cfg(target_os = "linux")]
+        sandbox: opt.sandbox,
+    };
+    let mut serving = tokio::spawn(serve(listeners, server.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(server.clone(), certificate));
+    #[cfg(unix)]
+    tokio::spawn(dump_stats_on_user1(server.clone()));
+    let reason = tokio::select! {
+        result = &mut serving => {
+            result.expect("server task panicked").expect("could not start server");
+            return;
+        }
+        reason = stop => reason,
+    };
+
+    log::info!("received {}, shutting down", reason);
+    // Another signal cuts the drain short.
+    tokio::select! {
+        _ = server.drain(Duration::from_secs(opt.drain_timeout)) => {}
+        reason = shutdown::signal() => log::info!("received {}, no longer draining", reason),
+    }
+    server.shutdown();
+    serving
+        .await
+        .expect("server task panicked")
+        .expect("could not stop server");
+    #[cfg(feature = "otel")]
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            log::warn!("could not export the last traces: {}", err);
+        }
+    }
+    log::info!("stopped, {}", server.summary());
+}
+
+/// Load the configuration again on SIGHUP and apply the part of it that can change while
+/// running, the listeners are kept as they are. The TLS certificate is read again from its
+/// files, so that a renewed certificate is presented to new connections.
+#[cfg(unix)]
+async fn reload_on_hangup(server: Arc<Server>, certificate: Option<Arc<tls::Certificate>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("could not listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        let reloaded = Cli::load().and_then(|opt| {
+            let auth = opt.auth(server.state().nonce_secret())?;
+            if let Some(certificate) = &certificate {
+                certificate
+                    .reload()
+                    .context("could not reload TLS certificate")?;
+            }
+            if server.turn().is_some() && !matches!(auth, Some(Auth::LongTerm(_))) {
+                anyhow::bail!("--turn requires long-term credentials");
+            }
+            let realms = opt.realms(server.state().nonce_secret())?;
+            server.reload(opt.acl(), auth, realms, opt.rate_limiter());
+            if let Some(level) = opt.log_level {
+                logging::set_level(level);
+            }
+            Ok(())
+        });
+        match reloaded {
+            Ok(()) => log::info!("received SIGHUP, reloaded configuration"),
+            Err(err) => log::error!(
+                "received SIGHUP, could not reload configuration, keeping the current one: {:#}",
+                err
+            ),
+        }
+    }
+}
+
+/// Log every counter of the server on SIGUSR1.
+#[cfg(unix)]
+async fn dump_stats_on_user1(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user1 = signal(SignalKind::user_defined1()).expect("could not listen for SIGUSR1");
+    while user1.recv().await.is_some() {
+        log::info!("received SIGUSR1, stats: {}", server.dump());
     }
 }
@@ -0,0 +1,967 @@
+//! STUN message encoding and decoding.
+//!
+//! Unlike `stun_coder` this supports any method and attribute, which is required to implement
+//! extensions such as TURN. Attributes are kept in their wire format and typed accessors are
+//! provided for the ones the server understands.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+
+use anyhow::{bail, ensure, Result};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// The magic cookie field MUST contain the fixed value 0x2112A442 in network byte order,
+/// see https://datatracker.ietf.org/doc/html/rfc5389#section-6
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// Size of the fixed STUN header, the message length field doesn't account for it.
+pub const HEADER_SIZE: usize = 20;
+
+/// Size of a transaction id.
+pub const TRANSACTION_ID_SIZE: usize = 12;
+
+/// Size of the MESSAGE-INTEGRITY attribute, including its header.
+const INTEGRITY_ATTRIBUTE_SIZE: usize = 24;
+
+/// Size of the MESSAGE-INTEGRITY-SHA256 attribute, including its header, when not truncated.
+const INTEGRITY_SHA256_ATTRIBUTE_SIZE: usize = 36;
+
+/// Size of the FINGERPRINT attribute, including its header.
+const FINGERPRINT_ATTRIBUTE_SIZE: usize = 8;
+
+/// Value XOR-ed with the CRC-32 of the message in the FINGERPRINT attribute.
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// Address family values of the MAPPED-ADDRESS like attributes.
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// STUN methods, see https://www.iana.org/assignments/stun-parameters/stun-parameters.xhtml
+pub mod methods {
+    pub const BINDING: u16 = 0x001;
+    pub const ALLOCATE: u16 = 0x003;
+    pub const REFRESH: u16 = 0x004;
+    pub const SEND: u16 = 0x006;
+    pub const DATA: u16 = 0x007;
+    pub const CREATE_PERMISSION: u16 = 0x008;
+    pub const CHANNEL_BIND: u16 = 0x009;
+    pub const CONNECT: u16 = 0x00A;
+    pub const CONNECTION_BIND: u16 = 0x00B;
+    pub const CONNECTION_ATTEMPT: u16 = 0x00C;
+}
+
+/// STUN attribute types, see https://www.iana.org/assignments/stun-parameters/stun-parameters.xhtml
+pub mod attributes {
+    pub const MAPPED_ADDRESS: u16 = 0x0001;
+    pub const CHANGE_REQUEST: u16 = 0x0003;
+    pub const SOURCE_ADDRESS: u16 = 0x0004;
+    pub const CHANGED_ADDRESS: u16 = 0x0005;
+    pub const USERNAME: u16 = 0x0006;
+    pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+    pub const ERROR_CODE: u16 = 0x0009;
+    pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+    pub const CHANNEL_NUMBER: u16 = 0x000C;
+    pub const LIFETIME: u16 = 0x000D;
+    pub const XOR_PEER_ADDRESS: u16 = 0x0012;
+    pub const DATA: u16 = 0x0013;
+    pub const REALM: u16 = 0x0014;
+    pub const NONCE: u16 = 0x0015;
+    pub const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+    pub const REQUESTED_ADDRESS_FAMILY: u16 = 0x0017;
+    pub const EVEN_PORT: u16 = 0x0018;
+    pub const REQUESTED_TRANSPORT: u16 = 0x0019;
+    pub const DONT_FRAGMENT: u16 = 0x001A;
+    pub const MESSAGE_INTEGRITY_SHA256: u16 = 0x001C;
+    pub const PASSWORD_ALGORITHM: u16 = 0x001D;
+    pub const USERHASH: u16 = 0x001E;
+    pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    pub const RESERVATION_TOKEN: u16 = 0x0022;
+    pub const PRIORITY: u16 = 0x0024;
+    pub const USE_CANDIDATE: u16 = 0x0025;
+    pub const RESPONSE_PORT: u16 = 0x0027;
+    pub const CONNECTION_ID: u16 = 0x002A;
+    pub const PASSWORD_ALGORITHMS: u16 = 0x8002;
+    pub const FINGERPRINT: u16 = 0x8028;
+    pub const ICE_CONTROLLED: u16 = 0x8029;
+    pub const ICE_CONTROLLING: u16 = 0x802A;
+    pub const RESPONSE_ORIGIN: u16 = 0x802B;
+    pub const OTHER_ADDRESS: u16 = 0x802C;
+    pub const MOBILITY_TICKET: u16 = 0x8030;
+}
+
+/// STUN message class, see https://datatracker.ietf.org/doc/html/rfc5389#section-6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+impl Class {
+    /// Bits of the message type field used by the class.
+    const MASK: u16 = 0b0000_0001_0001_0000;
+
+    fn from_type(message_type: u16) -> Self {
+        match message_type & Self::MASK {
+            0b0000_0000_0000_0000 => Class::Request,
+            0b0000_0000_0001_0000 => Class::Indication,
+            0b0000_0001_0000_0000 => Class::SuccessResponse,
+            _ => Class::ErrorResponse,
+        }
+    }
+
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0b0000_0000_0000_0000,
+            Class::Indication => 0b0000_0000_0001_0000,
+            Class::SuccessResponse => 0b0000_0001_0000_0000,
+            Class::ErrorResponse => 0b0000_0001_0001_0000,
+        }
+    }
+}
+
+/// A STUN attribute in its wire format, without padding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+/// A STUN message, see https://datatracker.ietf.org/doc/html/rfc5389#section-6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub method: u16,
+    pub class: Class,
+    /// The magic cookie, or the start of the 128 bit transaction id of RFC 3489 messages.
+    pub cookie: u32,
+    pub transaction_id: [u8; TRANSACTION_ID_SIZE],
+    pub attributes: Vec<Attribute>,
+}
+
+/// Return the method of the STUN message in the buffer without decoding it.
+pub fn peek_method(buf: &[u8]) -> Option<u16> {
+    let message_type = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]);
+    Some(message_type & !Class::MASK)
+}
+
+/// Why the header of a packet isn't the one of a STUN message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    TooShort,
+    /// The most significant 2 bits of the message type aren't zeroes.
+    InvalidType,
+    /// The message length isn't a multiple of 4 or doesn't match the bytes received.
+    InvalidLength,
+    NoMagicCookie,
+}
+
+impl HeaderError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HeaderError::TooShort => "shorter than a STUN header",
+            HeaderError::InvalidType => "invalid message type",
+            HeaderError::InvalidLength => "message length not matching the bytes received",
+            HeaderError::NoMagicCookie => "no magic cookie",
+        }
+    }
+}
+
+/// Check the fixed header of the packet in `buf` before decoding it, so that packets of other
+/// protocols are told apart at little cost. Messages without the magic cookie, of RFC 3489, are
+/// only accepted when `legacy`.
+pub fn check_header(buf: &[u8], legacy: bool) -> Result<(), HeaderError> {
+    if buf.len() < HEADER_SIZE {
+        return Err(HeaderError::TooShort);
+    }
+    if buf[0] & 0xC0 != 0 {
+        return Err(HeaderError::InvalidType);
+    }
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if !len.is_multiple_of(4) || HEADER_SIZE + len != buf.len() {
+        return Err(HeaderError::InvalidLength);
+    }
+    if !legacy && buf[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err(HeaderError::NoMagicCookie);
+    }
+    Ok(())
+}
+
+/// The request in `buf` without its attributes, going by its header only, to answer it when
+/// the rest can't be decoded.
+pub fn peek_request(buf: &[u8]) -> Option<Message> {
+    let header = buf.get(..HEADER_SIZE)?;
+    let message_type = u16::from_be_bytes([header[0], header[1]]);
+    if message_type & 0xC000 != 0 || Class::from_type(message_type) != Class::Request {
+        return None;
+    }
+    let mut transaction_id = [0; TRANSACTION_ID_SIZE];
+    transaction_id.copy_from_slice(&header[8..]);
+    Some(Message {
+        method: message_type & !Class::MASK,
+        class: Class::Request,
+        cookie: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        transaction_id,
+        attributes: Vec::new(),
+    })
+}
+
+impl Message {
+    /// Create a new message without attributes.
+    pub fn new(method: u16, class: Class, transaction_id: [u8; TRANSACTION_ID_SIZE]) -> Self {
+        Message {
+            method,
+            class,
+            cookie: MAGIC_COOKIE,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Whether the message lacks the magic cookie, as sent by RFC 3489 clients,
+    /// see https://datatracker.ietf.org/doc/html/rfc5389#section-12
+    pub fn is_legacy(&self) -> bool {
+        self.cookie != MAGIC_COOKIE
+    }
+
+    /// Create a new message with a random transaction id.
+    pub fn with_random_transaction_id(method: u16, class: Class) -> Self {
+        Message::new(method, class, rand::random())
+    }
+
+    /// Create the success response to this request.
+    pub fn success_response(&self) -> Self {
+        Message {
+            cookie: self.cookie,
+            ..Message::new(self.method, Class::SuccessResponse, self.transaction_id)
+        }
+    }
+
+    /// Create an error response to this request carrying the given error code and reason,
+    /// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.6
+    pub fn error_response(&self, code: u16, reason: &str) -> Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        Message {
+            cookie: self.cookie,
+            ..Message::new(self.method, Class::ErrorResponse, self.transaction_id)
+        }
+        .add_attribute(attributes::ERROR_CODE, value)
+    }
+
+    /// Decode a STUN message, bytes after the length announced in the header are ignored.
+    /// A FINGERPRINT attribute must be the last one and match the message.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(
+            buf.len() >= HEADER_SIZE,
+            "message shorter than a STUN header"
+        );
+        let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+        // The most significant 2 bits of every STUN message MUST be zeroes.
+        ensure!(
+            message_type & 0xC000 == 0,
+            "invalid message type {:#06x}",
+            message_type
+        );
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        ensure!(
+            len.is_multiple_of(4),
+            "message length {} is not a multiple of 4",
+            len
+        );
+        // Without the magic cookie this is an RFC 3489 message, up to the caller to handle it.
+        let cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        ensure!(
+            buf.len() >= HEADER_SIZE + len,
+            "message length {} exceeds the {} bytes received",
+            len,
+            buf.len() - HEADER_SIZE
+        );
+
+        let mut transaction_id = [0; TRANSACTION_ID_SIZE];
+        transaction_id.copy_from_slice(&buf[8..HEADER_SIZE]);
+
+        let mut attributes = Vec::new();
+        // The attributes following MESSAGE-INTEGRITY aren't covered by it and are ignored, but
+        // for MESSAGE-INTEGRITY-SHA256 and FINGERPRINT, and only FINGERPRINT may follow
+        // MESSAGE-INTEGRITY-SHA256, see https://datatracker.ietf.org/doc/html/rfc8489#section-14.5
+        let mut integrity = None;
+        let mut body = &buf[HEADER_SIZE..HEADER_SIZE + len];
+        while !body.is_empty() {
+            if body.len() < 4 {
+                bail!("truncated attribute header");
+            }
+            let kind = u16::from_be_bytes([body[0], body[1]]);
+            let attr_len = u16::from_be_bytes([body[2], body[3]]) as usize;
+            let padded_len = (attr_len + 3) & !3;
+            ensure!(
+                body.len() >= 4 + padded_len,
+                "attribute {:#06x} of length {} exceeds the message",
+                kind,
+                attr_len
+            );
+            if kind == attributes::FINGERPRINT {
+                ensure!(
+                    body.len() == FINGERPRINT_ATTRIBUTE_SIZE,
+                    "FINGERPRINT is not the last attribute"
+                );
+                let offset = HEADER_SIZE + len - FINGERPRINT_ATTRIBUTE_SIZE;
+                ensure!(
+                    body[4..] == fingerprint(&buf[..offset]).to_be_bytes(),
+                    "FINGERPRINT mismatch"
+                );
+            }
+            let ignored = match integrity {
+                None => false,
+                Some(attributes::MESSAGE_INTEGRITY) => !matches!(
+                    kind,
+                    attributes::MESSAGE_INTEGRITY_SHA256 | attributes::FINGERPRINT
+                ),
+                Some(_) => kind != attributes::FINGERPRINT,
+            };
+            if !ignored {
+                if matches!(
+                    kind,
+                    attributes::MESSAGE_INTEGRITY | attributes::MESSAGE_INTEGRITY_SHA256
+                ) {
+                    integrity = Some(kind);
+                }
+                attributes.push(Attribute {
+                    kind,
+                    value: body[4..4 + attr_len].to_vec(),
+                });
+            }
+            body = &body[4 + padded_len..];
+        }
+
+        Ok(Message {
+            method: message_type & !Class::MASK,
+            class: Class::from_type(message_type),
+            cookie,
+            transaction_id,
+            attributes,
+        })
+    }
+
+    /// Encode the message into its wire format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Encode the message into its wire format at the end of `buf`, which is expected empty.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.method | self.class.bits()).to_be_bytes());
+        // Length is filled in once the attributes are written.
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&self.cookie.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        for attribute in &self.attributes {
+            buf.extend_from_slice(&attribute.kind.to_be_bytes());
+            buf.extend_from_slice(&(attribute.value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&attribute.value);
+            buf.resize((buf.len() + 3) & !3, 0);
+        }
+        let len = (buf.len() - HEADER_SIZE) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// Encode the message followed by a MESSAGE-INTEGRITY attribute computed with the given key.
+    #[cfg(test)]
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        append_integrity(&mut buf, key);
+        buf
+    }
+
+    /// Encode the message followed by a MESSAGE-INTEGRITY-SHA256 attribute computed with the
+    /// given key.
+    #[cfg(test)]
+    pub fn encode_with_integrity_sha256(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        append_integrity_sha256(&mut buf, key);
+        buf
+    }
+
+    /// Append an attribute to the message.
+    pub fn add_attribute(mut self, kind: u16, value: Vec<u8>) -> Self {
+        self.attributes.push(Attribute { kind, value });
+        self
+    }
+
+    /// Append an address attribute XOR'ed with the magic cookie and transaction id,
+    /// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.2
+    pub fn add_xor_address(self, kind: u16, addr: SocketAddr) -> Self {
+        let value = encode_address(xor_address(addr, &self.transaction_id));
+        self.add_attribute(kind, value)
+    }
+
+    /// Append an address attribute in the MAPPED-ADDRESS format.
+    pub fn add_address(self, kind: u16, addr: SocketAddr) -> Self {
+        self.add_attribute(kind, encode_address(addr))
+    }
+
+    /// Append a 32 bit unsigned integer attribute.
+    pub fn add_u32(self, kind: u16, value: u32) -> Self {
+        self.add_attribute(kind, value.to_be_bytes().to_vec())
+    }
+
+    /// Types of the comprehension-required attributes of the message not in `known`,
+    /// see https://datatracker.ietf.org/doc/html/rfc5389#section-15
+    pub fn unknown_attributes(&self, known: &[u16]) -> Vec<u16> {
+        let mut unknown = Vec::new();
+        for attribute in &self.attributes {
+            // Attribute types above 0x7FFF are comprehension-optional.
+            if attribute.kind < 0x8000
+                && !known.contains(&attribute.kind)
+                && !unknown.contains(&attribute.kind)
+            {
+                unknown.push(attribute.kind);
+            }
+        }
+        unknown
+    }
+
+    /// Value of the first attribute of the given type.
+    pub fn get(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.kind == kind)
+            .map(|attribute| attribute.value.as_slice())
+    }
+
+    /// Value of the first XOR'ed address attribute of the given type.
+    pub fn get_xor_address(&self, kind: u16) -> Option<SocketAddr> {
+        let addr = decode_address(self.get(kind)?)?;
+        Some(xor_address(addr, &self.transaction_id))
+    }
+
+    /// Values of every XOR'ed address attribute of the given type, `None` if one is invalid.
+    pub fn get_xor_addresses(&self, kind: u16) -> Option<Vec<SocketAddr>> {
+        self.attributes
+            .iter()
+            .filter(|attribute| attribute.kind == kind)
+            .map(|attribute| {
+                let addr = decode_address(&attribute.value)?;
+                Some(xor_address(addr, &self.transaction_id))
+            })
+            .collect()
+    }
+
+    /// Value of the first address attribute of the given type in the MAPPED-ADDRESS format.
+    #[cfg(test)]
+    pub fn get_address(&self, kind: u16) -> Option<SocketAddr> {
+        decode_address(self.get(kind)?)
+    }
+
+    /// Value of the first UTF-8 text attribute of the given type.
+    pub fn get_str(&self, kind: u16) -> Option<&str> {
+        std::str::from_utf8(self.get(kind)?).ok()
+    }
+
+    /// Value of the first 32 bit unsigned integer attribute of the given type.
+    pub fn get_u32(&self, kind: u16) -> Option<u32> {
+        Some(u32::from_be_bytes(self.get(kind)?.try_into().ok()?))
+    }
+
+    /// Error code carried by the ERROR-CODE attribute.
+    pub fn error_code(&self) -> Option<u16> {
+        let value = self.get(attributes::ERROR_CODE)?;
+        if value.len() < 4 {
+            return None;
+        }
+        Some((value[2] & 0b111) as u16 * 100 + value[3] as u16)
+    }
+}
+
+/// Attribute protecting the integrity of a message with the key of its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrity {
+    /// MESSAGE-INTEGRITY, see https://datatracker.ietf.org/doc/html/rfc5389#section-15.4
+    Sha1,
+    /// MESSAGE-INTEGRITY-SHA256, see https://datatracker.ietf.org/doc/html/rfc8489#section-14.6
+    Sha256,
+}
+
+impl Integrity {
+    /// Integrity attribute of a message, MESSAGE-INTEGRITY-SHA256 when it has both.
+    pub fn of(message: &Message) -> Option<Self> {
+        if message.get(attributes::MESSAGE_INTEGRITY_SHA256).is_some() {
+            Some(Integrity::Sha256)
+        } else if message.get(attributes::MESSAGE_INTEGRITY).is_some() {
+            Some(Integrity::Sha1)
+        } else {
+            None
+        }
+    }
+
+    /// Verify this integrity attribute of an encoded message with the given key.
+    pub fn check(self, buf: &[u8], key: &[u8]) -> bool {
+        match self {
+            Integrity::Sha1 => check_integrity(buf, key),
+            Integrity::Sha256 => check_integrity_sha256(buf, key),
+        }
+    }
+
+    /// Append this integrity attribute computed with the given key to an encoded message.
+    pub fn append(self, buf: &mut Vec<u8>, key: &[u8]) {
+        match self {
+            Integrity::Sha1 => append_integrity(buf, key),
+            Integrity::Sha256 => append_integrity_sha256(buf, key),
+        }
+    }
+}
+
+/// Verify the MESSAGE-INTEGRITY attribute of an encoded message with the given key,
+/// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.4
+pub fn check_integrity(buf: &[u8], key: &[u8]) -> bool {
+    let offset = match attribute_offset(buf, attributes::MESSAGE_INTEGRITY) {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let value = match buf.get(offset + 4..offset + INTEGRITY_ATTRIBUTE_SIZE) {
+        Some(value) => value,
+        None => return false,
+    };
+    integrity_mac::<Hmac<Sha1>>(buf, offset, INTEGRITY_ATTRIBUTE_SIZE, key)
+        .verify_slice(value)
+        .is_ok()
+}
+
+/// Verify the MESSAGE-INTEGRITY-SHA256 attribute of an encoded message with the given key, the
+/// HMAC may be truncated to its first 16 bytes or more,
+/// see https://datatracker.ietf.org/doc/html/rfc8489#section-14.6
+pub fn check_integrity_sha256(buf: &[u8], key: &[u8]) -> bool {
+    let offset = match attribute_offset(buf, attributes::MESSAGE_INTEGRITY_SHA256) {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+    if !(16..=INTEGRITY_SHA256_ATTRIBUTE_SIZE - 4).contains(&attr_len)
+        || !attr_len.is_multiple_of(4)
+    {
+        return false;
+    }
+    let value = match buf.get(offset + 4..offset + 4 + attr_len) {
+        Some(value) => value,
+        None => return false,
+    };
+    integrity_mac::<Hmac<Sha256>>(buf, offset, 4 + attr_len, key)
+        .verify_truncated_left(value)
+        .is_ok()
+}
+
+/// HMAC of an encoded message up to its integrity attribute at `offset`, of `size` bytes with
+/// its header. The length is adjusted to point to the end of the attribute, ignoring any
+/// attribute that follows such as FINGERPRINT.
+fn integrity_mac<M: Mac + hmac::digest::KeyInit>(
+    buf: &[u8],
+    offset: usize,
+    size: usize,
+    key: &[u8],
+) -> M {
+    let len = (offset + size - HEADER_SIZE) as u16;
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&buf[..2]);
+    mac.update(&len.to_be_bytes());
+    mac.update(&buf[4..offset]);
+    mac
+}
+
+/// Append a MESSAGE-INTEGRITY attribute computed with the given key to an encoded message,
+/// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.4
+pub fn append_integrity(buf: &mut Vec<u8>, key: &[u8]) {
+    // The length used for the HMAC accounts for the MESSAGE-INTEGRITY attribute itself.
+    let len = (buf.len() - HEADER_SIZE + INTEGRITY_ATTRIBUTE_SIZE) as u16;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(buf);
+    buf.extend_from_slice(&attributes::MESSAGE_INTEGRITY.to_be_bytes());
+    buf.extend_from_slice(&((INTEGRITY_ATTRIBUTE_SIZE - 4) as u16).to_be_bytes());
+    buf.extend_from_slice(&mac.finalize().into_bytes());
+}
+
+/// Append a MESSAGE-INTEGRITY-SHA256 attribute computed with the given key to an encoded
+/// message, see https://datatracker.ietf.org/doc/html/rfc8489#section-14.6
+pub fn append_integrity_sha256(buf: &mut Vec<u8>, key: &[u8]) {
+    let len = (buf.len() - HEADER_SIZE + INTEGRITY_SHA256_ATTRIBUTE_SIZE) as u16;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(buf);
+    buf.extend_from_slice(&attributes::MESSAGE_INTEGRITY_SHA256.to_be_bytes());
+    buf.extend_from_slice(&((INTEGRITY_SHA256_ATTRIBUTE_SIZE - 4) as u16).to_be_bytes());
+    buf.extend_from_slice(&mac.finalize().into_bytes());
+}
+
+/// Append a FINGERPRINT attribute to an encoded message,
+/// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.5
+pub fn append_fingerprint(buf: &mut Vec<u8>) {
+    // The CRC covers the length accounting for the FINGERPRINT attribute itself.
+    let len = (buf.len() - HEADER_SIZE + FINGERPRINT_ATTRIBUTE_SIZE) as u16;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    let crc = fingerprint(buf);
+    buf.extend_from_slice(&attributes::FINGERPRINT.to_be_bytes());
+    buf.extend_from_slice(&((FINGERPRINT_ATTRIBUTE_SIZE - 4) as u16).to_be_bytes());
+    buf.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn fingerprint(buf: &[u8]) -> u32 {
+    crc32fast::hash(buf) ^ FINGERPRINT_XOR
+}
+
+/// Offset of the first attribute of the given type in an encoded message.
+fn attribute_offset(buf: &[u8], kind: u16) -> Option<usize> {
+    let len = u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+    let end = buf.len().min(HEADER_SIZE + len);
+    let mut offset = HEADER_SIZE;
+    while offset + 4 <= end {
+        if u16::from_be_bytes([buf[offset], buf[offset + 1]]) == kind {
+            return Some(offset);
+        }
+        let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        offset += 4 + ((attr_len + 3) & !3);
+    }
+    None
+}
+
+/// Lowercase hexadecimal representation of bytes, e.g. of a transaction id.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Encode an address in the MAPPED-ADDRESS format,
+/// see https://datatracker.ietf.org/doc/html/rfc5389#section-15.1
+pub fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    // Sized for an IPv6 address, the value is written without reallocating.
+    let mut value = Vec::with_capacity(20);
+    value.push(0);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    value
+}
+
+/// Decode an address in the MAPPED-ADDRESS format.
+pub fn decode_address(value: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let ip = match (value.get(1)?, value.len()) {
+        (&FAMILY_IPV4, 8) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&value[4..]).ok()?)),
+        (&FAMILY_IPV6, 20) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&value[4..]).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// XOR an address with the magic cookie and transaction id, the operation is its own inverse.
+pub fn xor_address(addr: SocketAddr, transaction_id: &[u8; TRANSACTION_ID_SIZE]) -> SocketAddr {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            octets
+                .iter_mut()
+                .zip(cookie.iter())
+                .for_each(|(octet, mask)| *octet ^= mask);
+            IpAddr::V4(octets.into())
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets
+                .iter_mut()
+                .zip(cookie.iter().chain(transaction_id.iter()))
+                .for_each(|(octet, mask)| *octet ^= mask);
+            IpAddr::V6(octets.into())
+        }
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Binding success responses carrying nothing but a XOR-MAPPED-ADDRESS, encoded once for an
+/// IPv4 and an IPv6 address.
+static BINDING_TEMPLATES: OnceLock<[Vec<u8>; 2]> = OnceLock::new();
+
+/// Encode into `buf` the Binding success response to the request `transaction_id` received from
+/// `addr`, carrying nothing but its XOR-MAPPED-ADDRESS. The response isn't built, the
+/// transaction id and address are patched into a template encoded once.
+pub fn encode_binding_success(
+    transaction_id: &[u8; TRANSACTION_ID_SIZE],
+    addr: SocketAddr,
+    buf: &mut Vec<u8>,
+) {
+    let templates = BINDING_TEMPLATES.get_or_init(|| {
+        [
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            Ipv6Addr::UNSPECIFIED.into(),
+        ]
+        .map(|ip| {
+            Message::new(
+                methods::BINDING,
+                Class::SuccessResponse,
+                [0; TRANSACTION_ID_SIZE],
+            )
+            .add_xor_address(attributes::XOR_MAPPED_ADDRESS, SocketAddr::new(ip, 0))
+            .encode()
+        })
+    });
+    let start = buf.len();
+    buf.extend_from_slice(&templates[addr.is_ipv6() as usize]);
+    let message = &mut buf[start..];
+    message[8..HEADER_SIZE].copy_from_slice(transaction_id);
+    // The value follows the type and length of the attribute, a reserved byte and the family.
+    let value = &mut message[HEADER_SIZE + 4..];
+    let xored = xor_address(addr, transaction_id);
+    value[2..4].copy_from_slice(&xored.port().to_be_bytes());
+    match xored.ip() {
+        IpAddr::V4(ip) => value[4..8].copy_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => value[4..20].copy_from_slice(&ip.octets()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{
+        append_fingerprint, attributes, check_header, check_integrity, check_integrity_sha256,
+        encode_binding_success, methods, peek_method, peek_request, Class, HeaderError, Integrity,
+        Message,
+    };
+
+    const SOFTWARE: u16 = 0x8022;
+
+    #[test]
+    fn checks_headers() {
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(SOFTWARE, b"test".to_vec())
+            .encode();
+        assert_eq!(check_header(&request, false), Ok(()));
+        assert_eq!(
+            check_header(&request[..19], false),
+            Err(HeaderError::TooShort)
+        );
+        // A DNS query for example.com, whose id sets the 2 first bits.
+        let dns = b"\xc4\x2a\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+        assert_eq!(check_header(dns, false), Err(HeaderError::InvalidType));
+        let mut padded = request.clone();
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            check_header(&padded, false),
+            Err(HeaderError::InvalidLength)
+        );
+        let mut legacy = request.clone();
+        legacy[4] = 0;
+        assert_eq!(
+            check_header(&legacy, false),
+            Err(HeaderError::NoMagicCookie)
+        );
+        assert_eq!(check_header(&legacy, true), Ok(()));
+    }
+
+    #[test]
+    fn encodes_binding_success_from_template() {
+        for addr in ["192.0.2.1:32853", "[2001:db8::1]:32853"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            let mut buf = vec![0xFF];
+            encode_binding_success(&request.transaction_id, addr, &mut buf);
+            let built = request
+                .success_response()
+                .add_xor_address(attributes::XOR_MAPPED_ADDRESS, addr)
+                .encode();
+            assert_eq!(&buf[1..], built);
+        }
+    }
+
+    #[test]
+    fn peeks_requests() {
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let mut buf = request.encode();
+        // An attribute exceeding the message.
+        buf.extend_from_slice(&[0x80, 0x22, 0, 8, 0, 0, 0, 0]);
+        buf[3] = 8;
+        assert!(Message::decode(&buf).is_err());
+        assert_eq!(peek_request(&buf), Some(request.clone()));
+        let indication = Message {
+            class: Class::Indication,
+            ..request
+        };
+        assert_eq!(peek_request(&indication.encode()), None);
+        assert_eq!(peek_request(&buf[..19]), None);
+    }
+
+    #[test]
+    fn decodes_stun_coder_messages() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_attribute(StunAttribute::Software {
+                    description: String::from("stunner"),
+                });
+        let encoded = req_msg.encode(None).unwrap();
+
+        let message = Message::decode(&encoded).unwrap();
+        assert_eq!(peek_method(&encoded), Some(methods::BINDING));
+        assert_eq!(message.method, methods::BINDING);
+        assert_eq!(message.class, Class::Request);
+        assert_eq!(message.transaction_id, req_msg.get_header().transaction_id);
+        assert_eq!(message.get(SOFTWARE), Some(&b"stunner"[..]));
+        assert_eq!(message.encode(), encoded);
+    }
+
+    #[test]
+    fn encodes_xor_addresses() {
+        for socket in ["192.0.2.1:32853", "[2001:db8::1]:32853"] {
+            let socket: SocketAddr = socket.parse().unwrap();
+            let message = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+                .add_xor_address(attributes::XOR_MAPPED_ADDRESS, socket);
+            assert_eq!(
+                message.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+                Some(socket)
+            );
+
+            let decoded = StunMessage::decode(&message.encode(), None).unwrap();
+            assert!(
+                matches!(decoded.get_attributes()[0], StunAttribute::XorMappedAddress { socket_addr } if socket_addr == socket)
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_error_responses() {
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request);
+        let response = request.error_response(437, "Allocation Mismatch");
+        let decoded = Message::decode(&response.encode()).unwrap();
+        assert_eq!(decoded.class, Class::ErrorResponse);
+        assert_eq!(decoded.transaction_id, request.transaction_id);
+        assert_eq!(decoded.error_code(), Some(437));
+    }
+
+    #[test]
+    fn checks_stun_coder_message_integrity() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_attribute(StunAttribute::Username {
+                    value: String::from("user"),
+                })
+                .add_message_integrity()
+                .add_fingerprint();
+        let encoded = req_msg.encode(Some("pass")).unwrap();
+        assert!(check_integrity(&encoded, b"pass"));
+        assert!(!check_integrity(&encoded, b"wrong"));
+
+        let response = Message::decode(&encoded)
+            .unwrap()
+            .success_response()
+            .encode_with_integrity(b"pass");
+        assert!(check_integrity(&response, b"pass"));
+        assert!(StunMessage::decode(&response, Some("pass")).is_ok());
+        assert!(StunMessage::decode(&response, Some("wrong")).is_err());
+    }
+
+    #[test]
+    fn checks_sha256_integrity() {
+        let message = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(attributes::USERNAME, b"user".to_vec());
+        let mut encoded = message.encode_with_integrity_sha256(b"pass");
+        assert_eq!(
+            Integrity::of(&Message::decode(&encoded).unwrap()),
+            Some(Integrity::Sha256)
+        );
+        assert!(check_integrity_sha256(&encoded, b"pass"));
+        assert!(!check_integrity_sha256(&encoded, b"wrong"));
+        assert!(!check_integrity(&encoded, b"pass"));
+        append_fingerprint(&mut encoded);
+        assert!(check_integrity_sha256(&encoded, b"pass"));
+
+        // The HMAC may be truncated to 16 bytes, but no less.
+        for (size, valid) in [(16, true), (12, false)] {
+            let mut truncated = message.encode();
+            let len = (truncated.len() - 20 + 4 + size) as u16;
+            truncated[2..4].copy_from_slice(&len.to_be_bytes());
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"pass").unwrap();
+            mac.update(&truncated);
+            truncated.extend_from_slice(&attributes::MESSAGE_INTEGRITY_SHA256.to_be_bytes());
+            truncated.extend_from_slice(&(size as u16).to_be_bytes());
+            truncated.extend_from_slice(&mac.finalize().into_bytes()[..size]);
+            assert_eq!(check_integrity_sha256(&truncated, b"pass"), valid);
+        }
+    }
+
+    #[test]
+    fn checks_fingerprints() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_fingerprint();
+        let mut encoded = req_msg.encode(None).unwrap();
+        let message = Message::decode(&encoded).unwrap();
+        assert!(message.get(attributes::FINGERPRINT).is_some());
+
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(Message::decode(&encoded).is_err());
+
+        let mut response = message.success_response().encode_with_integrity(b"pass");
+        append_fingerprint(&mut response);
+        assert!(Message::decode(&response).is_ok());
+        assert!(check_integrity(&response, b"pass"));
+        assert!(StunMessage::decode(&response, Some("pass")).is_ok());
+    }
+
+    #[test]
+    fn ignores_attributes_after_message_integrity() {
+        let peer = "203.0.113.1:5000".parse().unwrap();
+        let request =
+            Message::with_random_transaction_id(methods::CREATE_PERMISSION, Class::Request)
+                .add_xor_address(attributes::XOR_PEER_ADDRESS, peer);
+        let mut encoded = request.encode_with_integrity(b"pass");
+        // Appended by someone without the key.
+        let appended = Message::new(
+            methods::CREATE_PERMISSION,
+            Class::Request,
+            request.transaction_id,
+        )
+        .add_xor_address(
+            attributes::XOR_PEER_ADDRESS,
+            "10.0.0.1:5000".parse().unwrap(),
+        )
+        .encode();
+        encoded.extend_from_slice(&appended[super::HEADER_SIZE..]);
+        let len = (encoded.len() - super::HEADER_SIZE) as u16;
+        encoded[2..4].copy_from_slice(&len.to_be_bytes());
+        append_fingerprint(&mut encoded);
+
+        let message = Message::decode(&encoded).unwrap();
+        assert_eq!(
+            message.get_xor_addresses(attributes::XOR_PEER_ADDRESS),
+            Some(vec![peer])
+        );
+        assert!(message.get(attributes::MESSAGE_INTEGRITY).is_some());
+        assert!(message.get(attributes::FINGERPRINT).is_some());
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let encoded = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(SOFTWARE, b"stunner".to_vec())
+            .encode();
+        assert!(Message::decode(&encoded[..encoded.len() - 4]).is_err());
+        assert!(Message::decode(&encoded[..10]).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Batched UDP I/O on Linux, several datagrams are received with a single recvmmsg system call
+//! and their responses sent with a single sendmmsg one.
+//!
+//! Where the socket supports them, generic receive offload has the kernel coalesce the datagrams
+//! of a source into a single message, and generic segmentation offload sends the responses to a
+//! source as a single message, split into datagrams by the kernel or the network card.
+
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::{mem, ptr};
+
+use anyhow::Result;
+use socket2::{SockAddr, SockAddrStorage};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tracing::Instrument;
+
+use crate::pktinfo::{self, msghdr, Control, UDP_GRO, UDP_SEGMENT};
+use crate::server::{Server, Sink, Source, Transport, UdpOffload};
+
+/// Largest number of datagrams received or sent per system call.
+pub const MAX_BATCH_SIZE: usize = 64;
+
+/// Size of the buffers datagrams are received in with generic receive offload, which coalesces
+/// up to 64KiB.
+const GRO_BUFFER_SIZE: usize = 1 << 16;
+
+/// Largest message sent with generic segmentation offload, the largest UDP payload over IPv4.
+const MAX_GSO_SIZE: usize = 65507;
+
+/// Generic receive and segmentation offload, as enabled on a socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Offload {
+    gro: bool,
+    gso: bool,
+}
+
+impl Offload {
+    /// Enable what `offload` asks for and the socket supports, failing when it is required.
+    fn enable(sock: &UdpSocket, offload: UdpOffload) -> io::Result<Self> {
+        if offload == UdpOffload::Off {
+            return Ok(Offload::default());
+        }
+        let fd = sock.as_raw_fd();
+        let gro = setsockopt(fd, UDP_GRO, 1);
+        // Sockets supporting it report the default segment size.
+        let gso = getsockopt(fd, UDP_SEGMENT);
+        match (offload, gro, gso) {
+            (UdpOffload::On, Err(err), _) | (UdpOffload::On, _, Err(err)) => Err(err),
+            (_, gro, gso) => Ok(Offload {
+                gro: gro.is_ok(),
+                gso: gso.is_ok(),
+            }),
+        }
+    }
+}
+
+fn setsockopt(fd: RawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: the option value is a c_int of the given size, as UDP options expect.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn getsockopt(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the option value is a c_int of the given size, as UDP options expect.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            name,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Reply to STUN requests received on the UDP socket in batches, until the server shuts down.
+/// The datagrams of a batch are handled in turn and their responses sent together.
+pub async fn serve(sock: Arc<UdpSocket>, server: Arc<Server>) -> Result<()> {
+    let local_addr = sock.local_addr()?;
+    let offload = Offload::enable(&sock, server.udp_offload())?;
+    log::debug!(
+        "UDP generic receive offload {} and segmentation offload {} on addr: {}",
+        if offload.gro { "enabled" } else { "disabled" },
+        if offload.gso { "enabled" } else { "disabled" },
+        local_addr
+    );
+    let mut gso = offload.gso;
+    // One extra byte tells apart the datagrams that don't fit the buffer.
+    let size = if offload.gro {
+        GRO_BUFFER_SIZE
+    } else {
+        server.recv_buffer_size() + 1
+    };
+    let mut bufs = vec![vec![0; size]; server.udp_batch_size()];
+    bufs.truncate(MAX_BATCH_SIZE);
+    let mut received = Vec::with_capacity(bufs.len());
+    let mut responses = Vec::with_capacity(bufs.len());
+    loop {
+        tokio::select! {
+            result = recv_batch(&sock, local_addr, &mut bufs, &mut received) => result?,
+            _ = server.stopped() => return Ok(()),
+        };
+        for (buf, &(len, segment_size, src_addr, dst_addr)) in bufs.iter().zip(&received) {
+            // The datagrams coalesced all have the segment size, but the last which may be
+            // shorter. Empty datagrams are handled too.
+            let datagrams = buf[..len]
+                .chunks(segment_size.max(1))
+                .chain((len == 0).then_some(&buf[..0]));
+            for datagram in datagrams {
+                if datagram.len() > server.recv_buffer_size() {
+                    server.truncated(src_addr);
+                    continue;
+                }
+                let source = Source {
+                    addr: src_addr,
+                    local_addr: dst_addr,
+                    transport: Transport::Udp,
+                    sink: Sink::Datagram(sock.clone()),
+                };
+                let span = source.span();
+                if let Some(response) = server.handle(datagram, &source).instrument(span).await {
+                    responses.push((response, src_addr, dst_addr));
+                }
+            }
+        }
+        send_batch(&sock, &responses, &mut gso).await;
+        for (response, _, _) in responses.drain(..) {
+            server.buffers().put(response);
+        }
+    }
+}
+
+/// Receive up to one message in each of the buffers, replacing the content of `received` with
+/// the length, segment size, source and destination address of the messages received, at least
+/// one. Messages are datagrams, or datagrams of the segment size coalesced.
+async fn recv_batch(
+    sock: &UdpSocket,
+    local_addr: SocketAddr,
+    bufs: &mut [Vec<u8>],
+    received: &mut Vec<Received>,
+) -> io::Result<()> {
+    loop {
+        sock.readable().await?;
+        match sock.try_io(Interest::READABLE, || {
+            recvmmsg(sock.as_raw_fd(), local_addr, bufs, received)
+        }) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Length, segment size, source and destination address of a message received.
+type Received = (usize, usize, SocketAddr, SocketAddr);
+
+/// A datagram to send, to its address from the local address it is paired with.
+type Datagram = (Vec<u8>, SocketAddr, SocketAddr);
+
+/// Send every datagram to its address, from the local address it is paired with, with generic
+/// segmentation offload if `gso`. A datagram that can't be sent is skipped, segmentation offload
+/// is given up on when it fails, e.g. on network cards which can't compute checksums.
+async fn send_batch(sock: &UdpSocket, mut datagrams: &[Datagram], gso: &mut bool) {
+    while !datagrams.is_empty() {
+        if let Err(err) = sock.writable().await {
+            log::error!(
+                "could not send {} responses, reason: {}",
+                datagrams.len(),
+                err
+            );
+            return;
+        }
+        match sock.try_io(Interest::WRITABLE, || {
+            sendmmsg(sock.as_raw_fd(), datagrams, *gso)
+        }) {
+            Ok(sent) => datagrams = &datagrams[sent..],
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            Err(err) if *gso && segments(datagrams) > 1 => {
+                log::warn!(
+                    "could not send responses with UDP generic segmentation offload, sending them \
+                     one by one, reason: {}",
+                    err
+                );
+                *gso = false;
+            }
+            Err(err) => {
+                // sendmmsg only fails when the first datagram can't be sent.
+                log::error!(
+                    "could not send response to address {:?}, reason: {}",
+                    datagrams[0].1,
+                    err
+                );
+                datagrams = &datagrams[1..];
+            }
+        }
+    }
+}
+
+/// Receive up to [`MAX_BATCH_SIZE`] datagrams, the message headers live on the stack so that
+/// receiving a batch doesn't allocate.
+fn recvmmsg(
+    fd: RawFd,
+    local_addr: SocketAddr,
+    bufs: &mut [Vec<u8>],
+    received: &mut Vec<Received>,
+) -> io::Result<()> {
+    let count = bufs.len().min(MAX_BATCH_SIZE);
+    let mut addrs = [(); MAX_BATCH_SIZE].map(|_| SockAddrStorage::zeroed());
+    let mut controls = [Control::default(); MAX_BATCH_SIZE];
+    // SAFETY: all zeros is a valid iovec and mmsghdr, they are initialized below.
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    for ((((buf, iovec), addr), control), msg) in bufs
+        .iter_mut()
+        .zip(&mut iovecs)
+        .zip(&mut addrs)
+        .zip(&mut controls)
+        .zip(&mut msgs)
+    {
+        iovec.iov_base = buf.as_mut_ptr().cast();
+        iovec.iov_len = buf.len();
+        msg.msg_hdr = msghdr(iovec, (addr as *mut SockAddrStorage).cast(), addr.size_of());
+        pktinfo::recv_control(&mut msg.msg_hdr, control);
+    }
+    // SAFETY: every message points to a buffer, an address storage and a control buffer of the
+    // given sizes, which outlive the call.
+    let count = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    received.clear();
+    for (msg, addr) in msgs.iter().zip(addrs).take(count as usize) {
+        // SAFETY: the kernel initialized the address of each message received, and set its
+        // length.
+        let addr = unsafe { SockAddr::new(addr, msg.msg_hdr.msg_namelen) };
+        let addr = addr.as_socket().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, "received from a non IP address")
+        })?;
+        let local_addr = pktinfo::destination(&msg.msg_hdr, local_addr);
+        let len = msg.msg_len as usize;
+        let segment_size = pktinfo::segment_size(&msg.msg_hdr).unwrap_or(len);
+        received.push((len, segment_size, addr, local_addr));
+    }
+    Ok(())
+}
+
+/// Number of the first datagrams which can be sent as a single message with generic
+/// segmentation offload: those to the same address from the same local address, of the size of
+/// the first but the last which may be shorter.
+fn segments(datagrams: &[Datagram]) -> usize {
+    let (first, addr, local_addr) = &datagrams[0];
+    let size = first.len();
+    let mut total = size;
+    let mut count = 1;
+    for (bytes, other_addr, other_local_addr) in &datagrams[1..] {
+        if (other_addr, other_local_addr) != (addr, local_addr)
+            || bytes.len() > size
+            || total + bytes.len() > MAX_GSO_SIZE
+        {
+            break;
+        }
+        total += bytes.len();
+        count += 1;
+        if bytes.len() < size {
+            break;
+        }
+    }
+    count
+}
+
+/// Send up to [`MAX_BATCH_SIZE`] of the datagrams, returning how many were sent. With `gso`, the
+/// datagrams which can be are sent together as a single message. Like for receiving, the message
+/// headers live on the stack.
+fn sendmmsg(fd: RawFd, datagrams: &[Datagram], gso: bool) -> io::Result<usize> {
+    let count = datagrams.len().min(MAX_BATCH_SIZE);
+    let datagrams = &datagrams[..count];
+    let mut addrs = [(); MAX_BATCH_SIZE].map(|_| SockAddr::from(SocketAddr::from(([0; 4], 0))));
+    let mut controls = [Control::default(); MAX_BATCH_SIZE];
+    // Number of datagrams sent as each message.
+    let mut sizes = [0; MAX_BATCH_SIZE];
+    // SAFETY: all zeros is a valid iovec and mmsghdr, they are initialized below.
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    for ((bytes, _, _), iovec) in datagrams.iter().zip(&mut iovecs) {
+        iovec.iov_base = bytes.as_ptr() as *mut libc::c_void;
+        iovec.iov_len = bytes.len();
+    }
+    let mut first = 0;
+    let mut messages = 0;
+    for (((sock_addr, control), msg), size) in addrs
+        .iter_mut()
+        .zip(&mut controls)
+        .zip(&mut msgs)
+        .zip(&mut sizes)
+    {
+        if first == count {
+            break;
+        }
+        let (bytes, addr, local_addr) = &datagrams[first];
+        *size = if gso {
+            segments(&datagrams[first..])
+        } else {
+            1
+        };
+        *sock_addr = SockAddr::from(*addr);
+        msg.msg_hdr = msghdr(
+            &mut iovecs[first],
+            sock_addr.as_ptr() as *mut libc::c_void,
+            sock_addr.len(),
+        );
+        msg.msg_hdr.msg_iovlen = *size as _;
+        pktinfo::set_source(&mut msg.msg_hdr, control, *local_addr);
+        if *size > 1 {
+            pktinfo::set_segment_size(&mut msg.msg_hdr, control, bytes.len() as u16);
+        }
+        first += *size;
+        messages += 1;
+    }
+    // SAFETY: every message points to datagrams, an address and control messages of the given
+    // sizes, which outlive the call. The kernel doesn't write to them.
+    let sent = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), messages as libc::c_uint, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sizes[..sent as usize].iter().sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use std::os::unix::io::AsRawFd;
+
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::ratelimit::GlobalRateLimiter;
+    use crate::server::{Server, UdpOffload};
+
+    use super::Offload;
+
+    #[tokio::test]
+    async fn replies_to_batches_of_requests() {
+        let server = Server::default()
+            .with_recv_buffer_size(64)
+            .with_udp_batch_size(4);
+        let server = Arc::new(server);
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Queue the requests before serving so that they are received in batches.
+        let large = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x8022, vec![0; 64]);
+        client.send_to(&large.encode(), server_addr).await.unwrap();
+        let mut requests = Vec::new();
+        for _ in 0..10 {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            client
+                .send_to(&request.encode(), server_addr)
+                .await
+                .unwrap();
+            requests.push(request);
+        }
+        tokio::spawn(super::serve(sock, server.clone()));
+
+        for request in requests {
+            let mut buf = [0; 1500];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = Message::decode(&buf[..len]).unwrap();
+            assert_eq!(response.transaction_id, request.transaction_id);
+            assert_eq!(
+                response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+                Some(client.local_addr().unwrap())
+            );
+        }
+        assert!(server.metrics().contains("stunner_truncated_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn limits_the_packet_rate() {
+        let server = Server::default()
+            .with_udp_batch_size(4)
+            .with_global_rate_limiter(Some(GlobalRateLimiter::new(1, 3)));
+        let server = Arc::new(server);
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..10 {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            client
+                .send_to(&request.encode(), server_addr)
+                .await
+                .unwrap();
+        }
+        tokio::spawn(super::serve(sock, server.clone()));
+
+        // Only the burst is answered.
+        let mut buf = [0; 1500];
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let more = tokio::time::timeout(Duration::from_millis(200), client.recv_from(&mut buf));
+        assert!(more.await.is_err());
+        assert!(server
+            .metrics()
+            .contains("stunner_global_rate_limited_total 7\n"));
+    }
+
+    #[tokio::test]
+    async fn replies_to_coalesced_requests() {
+        let server = Server::default()
+            .with_udp_batch_size(4)
+            .with_udp_offload(UdpOffload::On);
+        let server = Arc::new(server);
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let offload = Offload::enable(&client, UdpOffload::On).unwrap();
+        assert_eq!(
+            offload,
+            Offload {
+                gro: true,
+                gso: true
+            }
+        );
+
+        // Sent as a single message, split into datagrams of the size of the requests.
+        let requests: Vec<_> = (0..10)
+            .map(|_| Message::with_random_transaction_id(methods::BINDING, Class::Request))
+            .collect();
+        let datagrams: Vec<_> = requests
+            .iter()
+            .map(|request| (request.encode(), server_addr, client_addr))
+            .collect();
+        assert_eq!(super::segments(&datagrams), 10);
+        let sent = super::sendmmsg(client.as_raw_fd(), &datagrams, true).unwrap();
+        assert_eq!(sent, 10);
+        tokio::spawn(super::serve(sock, server.clone()));
+
+        // Received coalesced, as the responses to the client.
+        let mut buf = vec![0; super::GRO_BUFFER_SIZE];
+        let mut received = Vec::new();
+        while received.len() < 10 {
+            let mut batch = Vec::new();
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                super::recv_batch(
+                    &client,
+                    client_addr,
+                    std::slice::from_mut(&mut buf),
+                    &mut batch,
+                ),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let (len, segment_size, _, _) = batch[0];
+            received.extend(
+                buf[..len]
+                    .chunks(segment_size)
+                    .map(|datagram| Message::decode(datagram).unwrap().transaction_id),
+            );
+        }
+        let expected: Vec<_> = requests
+            .iter()
+            .map(|request| request.transaction_id)
+            .collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,381 @@
+use std::io;
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+use crate::activation;
+
+/// Backlog of pending TCP connections.
+const TCP_BACKLOG: i32 = 1024;
+
+/// Options applied to the sockets serving clients and relaying their data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// Differentiated Services Code Point the packets sent are marked with, in the TOS field of
+    /// IPv4 and the Traffic Class of IPv6, so that network QoS policies can prioritize them. 0
+    /// for none.
+    pub dscp: u8,
+    /// Size in bytes requested for the kernel receive buffer with SO_RCVBUF, so that bursts of
+    /// packets aren't dropped.
+    pub recv_buffer_size: Option<usize>,
+    /// Size in bytes requested for the kernel send buffer with SO_SNDBUF.
+    pub send_buffer_size: Option<usize>,
+    /// Network interface the sockets are bound to with SO_BINDTODEVICE, so that they only send
+    /// and receive through it whatever the routes.
+    #[cfg(target_os = "linux")]
+    pub interface: Option<String>,
+}
+
+/// Parse a DSCP given as a number up to 63 or by name, EF, CS0 to CS7 or AF11 to AF43.
+pub fn parse_dscp(s: &str) -> Result<u8> {
+    let upper = s.to_ascii_uppercase();
+    let dscp = if upper == "EF" {
+        46
+    } else if let Some(class) = upper.strip_prefix("CS") {
+        match class.parse::<u8>() {
+            Ok(class @ 0..=7) => class * 8,
+            _ => bail!("invalid class selector {:?}", s),
+        }
+    } else if let Some(af) = upper.strip_prefix("AF") {
+        match af.as_bytes() {
+            [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => (class - b'0') * 8 + (drop - b'0') * 2,
+            _ => bail!("invalid assured forwarding class {:?}", s),
+        }
+    } else {
+        match s.parse() {
+            Ok(dscp @ 0..=63) => dscp,
+            _ => bail!(
+                "invalid DSCP {:?}, expected a number up to 63, EF, CSx or AFxy",
+                s
+            ),
+        }
+    };
+    Ok(dscp)
+}
+
+/// Apply the interface, DSCP and buffer sizes of `config`, if set, to a socket of the family of
+/// `addr`.
+fn configure(socket: SockRef, addr: SocketAddr, config: &SocketConfig) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &config.interface {
+        socket
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("could not bind to interface {}: {}", interface, err),
+                )
+            })?;
+    }
+    let tos = (config.dscp as u32) << 2;
+    if tos != 0 {
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+            #[cfg(unix)]
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+            #[cfg(not(unix))]
+            SocketAddr::V6(_) => {}
+        }
+    }
+    let protocol = if socket.r#type()? == Type::DGRAM {
+        "UDP"
+    } else {
+        "TCP"
+    };
+    if let Some(recv) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(recv)?;
+        let granted = socket.recv_buffer_size()?;
+        log_buffer_size(protocol, addr, "receive", recv, granted);
+    }
+    if let Some(send) = config.send_buffer_size {
+        socket.set_send_buffer_size(send)?;
+        let granted = socket.send_buffer_size()?;
+        log_buffer_size(protocol, addr, "send", send, granted);
+    }
+    Ok(())
+}
+
+/// Log the size of a buffer granted by the kernel, which caps it, e.g. to net.core.rmem_max and
+/// net.core.wmem_max on Linux.
+fn log_buffer_size(protocol: &str, addr: SocketAddr, kind: &str, requested: usize, granted: usize) {
+    // Linux doubles the size requested to account for its bookkeeping.
+    let expected = if cfg!(target_os = "linux") {
+        requested * 2
+    } else {
+        requested
+    };
+    if granted < expected {
+        log::warn!(
+            "{} socket on {} got a {} buffer of {} bytes instead of {}, capped by the kernel",
+            protocol,
+            addr,
+            kind,
+            granted,
+            requested
+        );
+    } else {
+        log::info!(
+            "{} socket on {} got a {} buffer of {} bytes",
+            protocol,
+            addr,
+            kind,
+            granted
+        );
+    }
+}
+
+/// Create a socket for the given address family. IPv6 sockets only handle IPv6 traffic so that
+/// the IPv4 and IPv6 wildcard addresses can be bound at the same time on the same port.
+fn socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind a UDP socket to the given address, or use the one inherited from the service manager.
+pub fn bind_udp(addr: SocketAddr, config: &SocketConfig) -> Result<UdpSocket> {
+    if let Some(socket) = activation::take(addr, Type::DGRAM) {
+        socket.set_nonblocking(true)?;
+        configure(SockRef::from(&socket), addr, config)?;
+        return Ok(UdpSocket::from_std(socket.into())?);
+    }
+    let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    configure(SockRef::from(&socket), addr, config)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("could not bind UDP socket to {}", addr))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Bind `count` UDP sockets to the given address. When there are several of them they share
+/// the address with SO_REUSEPORT, letting the kernel spread the datagrams received between them.
+pub fn bind_udp_workers(
+    addr: SocketAddr,
+    count: usize,
+    config: &SocketConfig,
+) -> Result<Vec<UdpSocket>> {
+    if count <= 1 {
+        return Ok(vec![bind_udp(addr, config)?]);
+    }
+    bind_reuse_port(addr, count, config)
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(
+    mut addr: SocketAddr,
+    count: usize,
+    config: &SocketConfig,
+) -> Result<Vec<UdpSocket>> {
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+        configure(SockRef::from(&socket), addr, config)?;
+        socket.set_reuse_port(true)?;
+        socket
+            .bind(&addr.into())
+            .with_context(|| format!("could not bind UDP socket to {}", addr))?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        // The following sockets share the port picked by the system for the first one.
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(
+    _addr: SocketAddr,
+    _count: usize,
+    _config: &SocketConfig,
+) -> Result<Vec<UdpSocket>> {
+    anyhow::bail!("multiple workers require SO_REUSEPORT, which is only available on unix")
+}
+
+/// Bind a TCP listener to the given address, or use the one inherited from the service manager.
+pub fn bind_tcp(addr: SocketAddr, config: &SocketConfig) -> Result<TcpListener> {
+    bind_tcp_listener(addr, Some(config))
+}
+
+/// Bind the TCP listener of an HTTP endpoint. As they are meant for operators, not clients, the
+/// interface, DSCP and buffer sizes aren't applied, e.g. to still serve them on the loopback.
+pub fn bind_http(addr: SocketAddr) -> Result<TcpListener> {
+    bind_tcp_listener(addr, None)
+}
+
+fn bind_tcp_listener(addr: SocketAddr, config: Option<&SocketConfig>) -> Result<TcpListener> {
+    if let Some(socket) = activation::take(addr, Type::STREAM) {
+        socket.set_nonblocking(true)?;
+        if let Some(config) = config {
+            configure(SockRef::from(&socket), addr, config)?;
+        }
+        return Ok(TcpListener::from_std(socket.into())?);
+    }
+    let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+    if let Some(config) = config {
+        configure(SockRef::from(&socket), addr, config)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("could not bind TCP listener to {}", addr))?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Bind the TCP listener of the relayed transport address of a TCP allocation. It shares its
+/// address with the connections opened to peers, see [`connect_from`].
+pub fn bind_relay_tcp(addr: SocketAddr, config: &SocketConfig) -> io::Result<TcpListener> {
+    let socket = relay_tcp_socket(addr, config)?;
+    socket.bind(addr)?;
+    socket.listen(TCP_BACKLOG as u32)
+}
+
+/// Open a TCP connection to `peer` from `local_addr`, the address of a relay listener,
+/// see https://datatracker.ietf.org/doc/html/rfc6062#section-5.2
+pub async fn connect_from(
+    local_addr: SocketAddr,
+    peer: SocketAddr,
+    config: &SocketConfig,
+) -> io::Result<TcpStream> {
+    let socket = relay_tcp_socket(local_addr, config)?;
+    socket.bind(local_addr)?;
+    socket.connect(peer).await
+}
+
+/// TCP socket allowed to share its address with the other sockets of a relay.
+fn relay_tcp_socket(addr: SocketAddr, config: &SocketConfig) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    configure(SockRef::from(&socket), addr, config)?;
+    Ok(socket)
+}
+
+/// Have the destination address of the datagrams received reported when the socket is bound to
+/// a wildcard address, so that responses can be sent from it. Only supported on Linux.
+pub fn enable_pktinfo(sock: &UdpSocket) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return crate::pktinfo::enable(sock);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sock;
+        Ok(())
+    }
+}
+
+/// Set the DF bit of the datagrams sent on `sock` when `enabled`, see
+/// https://datatracker.ietf.org/doc/html/rfc5766#section-12. Only supported on Linux.
+pub fn set_dont_fragment(sock: &UdpSocket, enabled: bool) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return crate::pktinfo::set_dont_fragment(sock, enabled);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (sock, enabled);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Receive a datagram, returning its length, source address and the local address it was sent
+/// to, which is `local_addr` unless reported, see [`enable_pktinfo`].
+pub async fn recv_from(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+    local_addr: SocketAddr,
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    #[cfg(target_os = "linux")]
+    return crate::pktinfo::recv_from(sock, buf, local_addr).await;
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = sock.recv_from(buf).await?;
+        Ok((len, addr, local_addr))
+    }
+}
+
+/// Send a datagram to `target` from the local address a datagram of the client was received on,
+/// or from the one picked by the system when unspecified, always the case outside of Linux.
+pub async fn send_to(
+    sock: &UdpSocket,
+    buf: &[u8],
+    target: SocketAddr,
+    local_addr: SocketAddr,
+) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    return crate::pktinfo::send_to(sock, buf, target, local_addr).await;
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = local_addr;
+        sock.send_to(buf, target).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use socket2::SockRef;
+
+    use super::{bind_tcp, bind_udp, bind_udp_workers, parse_dscp, SocketConfig};
+
+    #[tokio::test]
+    async fn binds_both_families_on_the_same_port() {
+        let config = SocketConfig::default();
+        let udp_v4 = bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), &config).unwrap();
+        let port = udp_v4.local_addr().unwrap().port();
+        let tcp_v4 = bind_tcp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), &config).unwrap();
+        let udp_v6 = bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), &config).unwrap();
+        let tcp_v6 = bind_tcp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), &config).unwrap();
+
+        assert_eq!(tcp_v4.local_addr().unwrap().port(), port);
+        assert_eq!(udp_v6.local_addr().unwrap().port(), port);
+        assert_eq!(tcp_v6.local_addr().unwrap().port(), port);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_workers_on_the_same_address() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let workers = bind_udp_workers(addr, 3, &SocketConfig::default()).unwrap();
+        assert_eq!(workers.len(), 3);
+        let addr = workers[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for worker in &workers {
+            assert_eq!(worker.local_addr().unwrap(), addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_the_config_of_each_socket() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let config = SocketConfig {
+            dscp: 46,
+            ..Default::default()
+        };
+        let marked = bind_udp(addr, &config).unwrap();
+        let unmarked = bind_udp(addr, &SocketConfig::default()).unwrap();
+        assert_eq!(SockRef::from(&marked).tos_v4().unwrap(), 46 << 2);
+        assert_eq!(SockRef::from(&unmarked).tos_v4().unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_dscp() {
+        assert_eq!(parse_dscp("0").unwrap(), 0);
+        assert_eq!(parse_dscp("34").unwrap(), 34);
+        assert_eq!(parse_dscp("ef").unwrap(), 46);
+        assert_eq!(parse_dscp("CS6").unwrap(), 48);
+        assert_eq!(parse_dscp("AF41").unwrap(), 34);
+        assert_eq!(parse_dscp("AF13").unwrap(), 14);
+        assert!(parse_dscp("64").is_err());
+        assert!(parse_dscp("CS8").is_err());
+        assert!(parse_dscp("AF51").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! systemd service notifications, telling a `Type=notify` unit the server is ready and pinging
+//! its watchdog, see https://www.freedesktop.org/software/systemd/man/sd_notify.html
+
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::server::Server;
+
+/// Connection to the notification socket of the service manager.
+pub struct Notifier {
+    sock: UnixDatagram,
+    /// Time after which the service manager considers the server hung without a ping, if it
+    /// watches it.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connect to the socket at `NOTIFY_SOCKET`, when started by a service manager expecting
+    /// notifications. Must be called before the process is sandboxed.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        let watchdog = watchdog(
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::process::id(),
+        );
+        match Notifier::connect(&path.to_string_lossy(), watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                log::warn!("could not connect to the service manager: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Connect to the socket at `path`, in the abstract namespace when starting with `@`.
+    fn connect(path: &str, watchdog: Option<Duration>) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let sock = UnixDatagram::unbound()?;
+        sock.connect_addr(&addr)?;
+        sock.set_nonblocking(true)?;
+        Ok(Notifier { sock, watchdog })
+    }
+
+    /// Tell the service manager the server is ready, then ping the watchdog until the server
+    /// stops, as long as messages keep being handled or none is waiting, so that the pings stop
+    /// when the handling of messages hangs, not only the runtime.
+    pub async fn serve(self, server: Arc<Server>) -> Result<()> {
+        self.notify("READY=1");
+        let watchdog = match self.watchdog {
+            Some(watchdog) => watchdog,
+            None => return Ok(()),
+        };
+        // Pinged twice per period as recommended, so that a late ping isn't fatal.
+        let mut interval = tokio::time::interval(watchdog / 2);
+        let mut before = server.liveness();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = server.stopped() => return Ok(()),
+            }
+            let liveness = server.liveness();
+            if liveness.progressed_since(before) {
+                self.notify("WATCHDOG=1");
+            } else {
+                log::warn!(
+                    "no message handled in {:?} while {} are, not pinging the watchdog",
+                    watchdog / 2,
+                    liveness.handling
+                );
+            }
+            before = liveness;
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.sock.send(state.as_bytes()) {
+            log::warn!("could not notify the service manager of {}: {}", state, err);
+        }
+    }
+}
+
+/// Watchdog period according to the values of the `WATCHDOG_PID` and `WATCHDOG_USEC`
+/// environment variables, if it applies to this process.
+fn watchdog(watchdog_pid: Option<&str>, watchdog_usec: Option<&str>, pid: u32) -> Option<Duration> {
+    if matches!(watchdog_pid, Some(watchdog_pid) if watchdog_pid.parse() != Ok(pid)) {
+        return None;
+    }
+    match watchdog_usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{watchdog, Notifier};
+    use crate::handler::{Handler, HookFuture, Transaction};
+    use crate::message::{methods, Class, Message};
+    use crate::server::{Server, Sink, Source, Transport};
+
+    /// Never done handling the messages.
+    struct Hang;
+
+    impl Handler for Hang {
+        fn name(&self) -> &'static str {
+            "hang"
+        }
+
+        fn post_decode<'a>(
+            &'a self,
+            _server: &'a Server,
+            _message: &'a mut Message,
+            _transaction: &'a mut Transaction<'_>,
+        ) -> HookFuture<'a> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    /// The states received by the service manager so far.
+    fn states(manager: &UnixDatagram) -> Vec<String> {
+        let mut buf = [0; 64];
+        let mut states = Vec::new();
+        while let Ok(len) = manager.recv(&mut buf) {
+            states.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        states
+    }
+
+    #[test]
+    fn reads_the_watchdog_period_of_this_process() {
+        let period = Some(Duration::from_secs(30));
+        assert_eq!(watchdog(None, Some("30000000"), 42), period);
+        assert_eq!(watchdog(Some("42"), Some("30000000"), 42), period);
+        assert_eq!(watchdog(Some("41"), Some("30000000"), 42), None);
+        assert_eq!(watchdog(None, Some("0"), 42), None);
+        assert_eq!(watchdog(None, None, 42), None);
+    }
+
+    #[tokio::test]
+    async fn notifies_readiness_and_pings_the_watchdog() {
+        let name = format!("stunner-notify-test-{}", std::process::id());
+        let manager =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        manager.set_nonblocking(true).unwrap();
+        let notifier =
+            Notifier::connect(&format!("@{}", name), Some(Duration::from_millis(40))).unwrap();
+        let server = Arc::new(Server::default());
+        let serving = tokio::spawn(notifier.serve(server.clone()));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        server.shutdown();
+        serving.await.unwrap().unwrap();
+        let states = states(&manager);
+        // Pinged right away, then every 20ms.
+        assert_eq!(states[0], "READY=1");
+        assert!(states.len() >= 3);
+        assert!(states[1..].iter().all(|state| state == "WATCHDOG=1"));
+    }
+
+    #[tokio::test]
+    async fn stops_pinging_the_watchdog_while_messages_hang() {
+        let name = format!("stunner-notify-hang-test-{}", std::process::id());
+        let manager =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        manager.set_nonblocking(true).unwrap();
+        let notifier =
+            Notifier::connect(&format!("@{}", name), Some(Duration::from_millis(40))).unwrap();
+        let server = Arc::new(Server::default().with_handlers(vec![Box::new(Hang)]));
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let source = Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock),
+        };
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let hung = tokio::spawn({
+            let server = server.clone();
+            async move { server.handle(&request.encode(), &source).await }
+        });
+        while server.liveness().handling == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let serving = tokio::spawn(notifier.serve(server.clone()));
+        tokio::time::sleep(Duration::from_millis(70)).await;
+        assert_eq!(states(&manager), vec!["READY=1"]);
+
+        // Pinged again once nothing is waiting.
+        hung.abort();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.shutdown();
+        serving.await.unwrap().unwrap();
+        assert!(states(&manager).contains(&"WATCHDOG=1".to_string()));
+    }
+}
@@ -0,0 +1,327 @@
+//! Capture of the STUN messages received and sent to a pcap file, for offline analysis with
+//! e.g. Wireshark when debugging interoperability issues on a server without a GUI. Messages
+//! are written as UDP datagrams in synthetic IP packets whatever the transport they were
+//! received on, see https://www.tcpdump.org/manpages/pcap-savefile.5.html
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::accesslog::rotated_files;
+
+/// Number of packets waiting to be written beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 65536;
+
+/// Link type of packets starting with their IP header,
+/// see https://www.tcpdump.org/linktypes.html
+const LINKTYPE_RAW: u32 = 101;
+
+/// Size of the file header.
+const HEADER_SIZE: u64 = 24;
+
+/// Writes the packets to the file on a thread of its own, so that handling messages never
+/// waits on the disk.
+#[derive(Debug)]
+pub struct Capture {
+    tx: SyncSender<Vec<u8>>,
+    /// Packets dropped as the writer couldn't keep up.
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// Write the packets to the file at `path`, truncated if it exists. Once it reaches
+    /// `max_size` bytes if given, it's renamed aside suffixed with the UNIX time in milliseconds
+    /// and a new one started, `keep` rotated files being kept if not 0.
+    pub fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> Result<Self> {
+        let writer = Writer::open(path, max_size, keep)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("pcap".into())
+            .spawn(move || writer.run(rx))?;
+        Ok(Capture {
+            tx,
+            dropped: AtomicU64::default(),
+        })
+    }
+
+    /// Capture `payload` sent from `src` to `dst`, dropping it if too many packets are waiting
+    /// to be written.
+    pub fn record(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        if self
+            .tx
+            .try_send(format_packet(src, dst, payload, SystemTime::now()))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of packets dropped as the writer couldn't keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Record of `payload` sent from `src` to `dst` at `time`, with its IP and UDP headers.
+fn format_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8], time: SystemTime) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    // Both addresses are written in the same family, IPv4 ones being mapped to IPv6 otherwise.
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => (IpAddr::V4(src), IpAddr::V4(dst)),
+        (src, dst) => (IpAddr::V6(to_ipv6(src)), IpAddr::V6(to_ipv6(dst))),
+    };
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    // Sum of the pseudo header of the UDP checksum.
+    let mut pseudo_sum = 17 + udp_len as u32;
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = [0; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // Don't fragment.
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = !fold(sum(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+            pseudo_sum += sum(&src_ip.octets()) + sum(&dst_ip.octets());
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            pseudo_sum += sum(&src_ip.octets()) + sum(&dst_ip.octets());
+        }
+        _ => unreachable!("addresses are in the same family"),
+    }
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    let checksum = match !fold(pseudo_sum + sum(&udp)) {
+        // A zero checksum means none was computed.
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + packet.len());
+    record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&packet);
+    record
+}
+
+/// `ip`, mapped to IPv6 if needed.
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Sum of the 16 bit words of `bytes`, padded with a zero byte if needed.
+fn sum(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Internet checksum of a sum, in ones' complement.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Header of a file of raw IP packets with microsecond timestamps, little-endian.
+fn file_header() -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&0xA1B2_C3D4_u32.to_le_bytes());
+    header[4..6].copy_from_slice(&2_u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4_u16.to_le_bytes());
+    header[16..20].copy_from_slice(&u32::from(u16::MAX).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+/// Capture file being written.
+struct Writer {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl Writer {
+    fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> Result<Self> {
+        let file = create(&path).with_context(|| format!("could not open capture {:?}", path))?;
+        Ok(Writer {
+            path,
+            max_size,
+            keep,
+            file,
+            size: HEADER_SIZE,
+        })
+    }
+
+    /// Write the packets received until the sender is dropped, flushing whenever none is
+    /// waiting.
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        loop {
+            let record = match rx.try_recv() {
+                Ok(record) => record,
+                Err(TryRecvError::Empty) => {
+                    if let Err(err) = self.file.flush() {
+                        log::warn!("could not write capture: {}", err);
+                    }
+                    match rx.recv() {
+                        Ok(record) => record,
+                        Err(_) => return,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            if let Err(err) = self.write(&record) {
+                log::warn!("could not write capture: {}", err);
+            }
+        }
+        let _ = self.file.flush();
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        let len = record.len() as u64;
+        // Files hold at least a packet, even when larger than the maximum size.
+        let too_large = matches!(self.max_size, Some(max_size) if self.size + len > max_size);
+        if too_large && self.size > HEADER_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Rename the file aside and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", millis));
+        fs::rename(&self.path, &rotated)?;
+        self.file = create(&self.path)?;
+        self.size = HEADER_SIZE;
+        if self.keep > 0 {
+            for old in rotated_files(&self.path)?.into_iter().rev().skip(self.keep) {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the capture file at `path`, with its header.
+fn create(path: &PathBuf) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    let mut file = BufWriter::new(file);
+    file.write_all(&file_header())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{file_header, fold, format_packet, sum, Capture};
+    use crate::accesslog::rotated_files;
+
+    #[test]
+    fn formats_udp_packets() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        let record = format_packet(
+            "192.0.2.1:5000".parse().unwrap(),
+            "198.51.100.2:3478".parse().unwrap(),
+            &[1, 2, 3],
+            time,
+        );
+        assert_eq!(&record[..4], &1_u32.to_le_bytes());
+        assert_eq!(&record[4..8], &500_000_u32.to_le_bytes());
+        assert_eq!(&record[8..12], &31_u32.to_le_bytes());
+        let packet = &record[16..];
+        assert_eq!(packet.len(), 31);
+        // The IPv4 header sums to 0 with its checksum.
+        assert_eq!(fold(sum(&packet[..20])), 0xFFFF);
+        assert_eq!(&packet[12..16], &[192, 0, 2, 1]);
+        assert_eq!(&packet[20..26], &[0x13, 0x88, 0x0D, 0x96, 0, 11]);
+        assert_eq!(&packet[28..], &[1, 2, 3]);
+
+        // Mixed families are written as IPv6.
+        let record = format_packet(
+            "192.0.2.1:5000".parse().unwrap(),
+            "[2001:db8::1]:3478".parse().unwrap(),
+            &[],
+            time,
+        );
+        assert_eq!(record.len(), 16 + 40 + 8);
+        assert_eq!(record[16], 0x60);
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("stunner-pcap-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("stun.pcap");
+        let capture = Capture::open(path.clone(), Some(100), 1).unwrap();
+        let (src, dst) = (
+            "192.0.2.1:5000".parse().unwrap(),
+            "198.51.100.2:3478".parse().unwrap(),
+        );
+        for _ in 0..3 {
+            capture.record(src, dst, &[0; 20]);
+        }
+        // The writer thread stops once the capture is dropped and every packet written.
+        drop(capture);
+        for _ in 0..100 {
+            if rotated_files(&path).unwrap().len() == 1
+                && std::fs::metadata(&path).unwrap().len() == 24 + 64
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let rotated = rotated_files(&path).unwrap();
+        assert_eq!(rotated.len(), 1);
+        let content = std::fs::read(&rotated[0]).unwrap();
+        assert_eq!(&content[..24], &file_header());
+        assert_eq!(content.len(), 24 + 64);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24 + 64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
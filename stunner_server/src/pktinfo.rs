@@ -0,0 +1,395 @@
+//! Destination address of the datagrams received on UDP sockets bound to a wildcard address on
+//! Linux, reported with IP_PKTINFO and IPV6_RECVPKTINFO. Responses are sent from that address so
+//! that on multihomed hosts they don't leave from another address, which NATs would drop.
+//!
+//! The control messages of UDP generic segmentation and receive offload, giving the size of the
+//! datagrams sent or received together, are read and written along with them.
+//!
+//! The DF bit of the datagrams sent is set with IP_MTU_DISCOVER and IPV6_MTU_DISCOVER.
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{mem, ptr};
+
+use socket2::{SockAddr, SockAddrStorage};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Options and control messages of UDP generic segmentation and receive offload, which the
+/// libc crate lacks for glibc.
+pub const UDP_SEGMENT: libc::c_int = 103;
+pub const UDP_GRO: libc::c_int = 104;
+
+/// Size of a control message carrying an `in_pktinfo` or an `in6_pktinfo`, the largest, followed
+/// by one carrying a segment size.
+// SAFETY: CMSG_SPACE only computes a size.
+const CONTROL_SIZE: usize = unsafe {
+    libc::CMSG_SPACE(mem::size_of::<libc::in6_pktinfo>() as libc::c_uint)
+        + libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as libc::c_uint)
+} as usize;
+
+/// Buffer for the control message of a datagram, aligned like a `cmsghdr`.
+#[derive(Clone, Copy)]
+#[repr(C, align(8))]
+pub struct Control([u8; CONTROL_SIZE]);
+
+impl Default for Control {
+    fn default() -> Self {
+        Control([0; CONTROL_SIZE])
+    }
+}
+
+/// Have the destination address of the datagrams received reported, when the socket is bound to
+/// a wildcard address. A socket bound to a specific address always receives on it.
+pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+    let (level, name) = match sock.local_addr()? {
+        addr if !addr.ip().is_unspecified() => return Ok(()),
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+    let enabled: libc::c_int = 1;
+    // SAFETY: the option value is a c_int of the given size, as the option expects.
+    let result = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            (&enabled as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the DF bit of the datagrams sent when `enabled`, or let the system pick it as it does by
+/// default otherwise, fragmenting the datagrams larger than the path MTU.
+pub fn set_dont_fragment(sock: &UdpSocket, enabled: bool) -> io::Result<()> {
+    let (level, name, value) = match (sock.local_addr()?, enabled) {
+        (SocketAddr::V4(_), true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        (SocketAddr::V4(_), false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_WANT,
+        ),
+        (SocketAddr::V6(_), true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+        (SocketAddr::V6(_), false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_WANT,
+        ),
+    };
+    // SAFETY: the option value is a c_int of the given size, as the option expects.
+    let result = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a datagram, returning its length, source address and the local address it was
+/// received on. The latter is `local_addr` unless the socket reports the destination addresses.
+pub async fn recv_from(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+    local_addr: SocketAddr,
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    loop {
+        sock.readable().await?;
+        match sock.try_io(Interest::READABLE, || {
+            recvmsg(sock.as_raw_fd(), buf, local_addr)
+        }) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Send a datagram to `target` from the IP address of `local_addr`, or from the one picked by
+/// the system when it is unspecified.
+pub async fn send_to(
+    sock: &UdpSocket,
+    buf: &[u8],
+    target: SocketAddr,
+    local_addr: SocketAddr,
+) -> io::Result<usize> {
+    if local_addr.ip().is_unspecified() {
+        return sock.send_to(buf, target).await;
+    }
+    loop {
+        sock.writable().await?;
+        match sock.try_io(Interest::WRITABLE, || {
+            sendmsg(sock.as_raw_fd(), buf, target, local_addr)
+        }) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+fn recvmsg(
+    fd: RawFd,
+    buf: &mut [u8],
+    local_addr: SocketAddr,
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut addr = SockAddrStorage::zeroed();
+    let mut control = Control::default();
+    let mut iovec = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg = msghdr(
+        &mut iovec,
+        (&mut addr as *mut SockAddrStorage).cast(),
+        addr.size_of(),
+    );
+    recv_control(&mut msg, &mut control);
+    // SAFETY: the message points to a buffer, an address storage and a control buffer of the
+    // given sizes, which outlive the call.
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel initialized the address and set its length.
+    let addr = unsafe { SockAddr::new(addr, msg.msg_namelen) };
+    let addr = addr
+        .as_socket()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "received from a non IP address"))?;
+    Ok((len as usize, addr, destination(&msg, local_addr)))
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], target: SocketAddr, local_addr: SocketAddr) -> io::Result<usize> {
+    let target = SockAddr::from(target);
+    let mut control = Control::default();
+    let mut iovec = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg = msghdr(
+        &mut iovec,
+        target.as_ptr() as *mut libc::c_void,
+        target.len(),
+    );
+    set_source(&mut msg, &mut control, local_addr);
+    // SAFETY: the message points to a datagram, an address and a control message of the given
+    // sizes, which outlive the call. The kernel doesn't write to them.
+    let len = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// Header of a message with a single buffer and an address, without control data.
+pub fn msghdr(
+    iovec: *mut libc::iovec,
+    name: *mut libc::c_void,
+    namelen: libc::socklen_t,
+) -> libc::msghdr {
+    // SAFETY: all zeros is a valid msghdr, some platforms have private padding fields.
+    let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_name = name;
+    hdr.msg_namelen = namelen;
+    hdr.msg_iov = iovec;
+    hdr.msg_iovlen = 1;
+    hdr
+}
+
+/// Point the message to the first `len` bytes of the control buffer.
+fn set_control(msg: &mut libc::msghdr, control: &mut Control, len: usize) {
+    msg.msg_control = control.0.as_mut_ptr().cast();
+    msg.msg_controllen = len as _;
+}
+
+/// Receive the control messages of a datagram in `control`.
+pub fn recv_control(msg: &mut libc::msghdr, control: &mut Control) {
+    set_control(msg, control, CONTROL_SIZE);
+}
+
+/// Local address a received message was sent to, according to its control messages, or
+/// `local_addr` when they don't tell.
+pub fn destination(msg: &libc::msghdr, local_addr: SocketAddr) -> SocketAddr {
+    // SAFETY: the control messages were written by the kernel within the length it set, the
+    // CMSG macros don't read past it.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    let ip = Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr));
+                    return SocketAddr::new(IpAddr::V4(ip), local_addr.port());
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                    // The interface tells apart link-local addresses.
+                    let addr = SocketAddrV6::new(ip, local_addr.port(), 0, info.ipi6_ifindex);
+                    return SocketAddr::V6(addr);
+                }
+                _ => cmsg = libc::CMSG_NXTHDR(msg, cmsg),
+            }
+        }
+    }
+    local_addr
+}
+
+/// Add a control message to send the message from the IP address of `local_addr`, unless it is
+/// unspecified.
+pub fn set_source(msg: &mut libc::msghdr, control: &mut Control, local_addr: SocketAddr) {
+    let len = match local_addr {
+        addr if addr.ip().is_unspecified() => return,
+        SocketAddr::V4(_) => mem::size_of::<libc::in_pktinfo>(),
+        SocketAddr::V6(_) => mem::size_of::<libc::in6_pktinfo>(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(len as libc::c_uint) } as usize;
+    set_control(msg, control, space);
+    // SAFETY: the control buffer is aligned and large enough for a single control message
+    // carrying either structure.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(msg);
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as libc::c_uint) as _;
+        let data = libc::CMSG_DATA(cmsg);
+        match local_addr {
+            SocketAddr::V4(addr) => {
+                (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                let info = libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from(*addr.ip()).to_be(),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                ptr::write_unaligned(data as *mut libc::in_pktinfo, info);
+            }
+            SocketAddr::V6(addr) => {
+                (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                let info = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    ipi6_ifindex: addr.scope_id(),
+                };
+                ptr::write_unaligned(data as *mut libc::in6_pktinfo, info);
+            }
+        }
+    }
+}
+
+/// Size of the datagrams coalesced by UDP generic receive offload into a received message,
+/// according to its control messages, if they were.
+pub fn segment_size(msg: &libc::msghdr) -> Option<usize> {
+    // SAFETY: as when looking for the destination address.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if ((*cmsg).cmsg_level, (*cmsg).cmsg_type) == (libc::SOL_UDP, UDP_GRO) {
+                let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return Some(size as usize);
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    None
+}
+
+/// Add a control message to have the message split into datagrams of `size` bytes by UDP
+/// generic segmentation offload, after the one added by [`set_source`] if any.
+pub fn set_segment_size(msg: &mut libc::msghdr, control: &mut Control, size: u16) {
+    let offset: usize = if msg.msg_control.is_null() {
+        0
+    } else {
+        msg.msg_controllen as _
+    };
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) } as usize;
+    set_control(msg, control, offset + space);
+    // SAFETY: the control buffer has room for a control message carrying a segment size after
+    // the one carrying the source address, which is aligned.
+    unsafe {
+        let cmsg = control.0.as_mut_ptr().add(offset) as *mut libc::cmsghdr;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as libc::c_uint) as _;
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn reports_destination_addresses() {
+        let sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        super::enable(&sock).unwrap();
+        let local_addr = sock.local_addr().unwrap();
+        // Not the address the system would pick to reach the client.
+        let server_addr: SocketAddr = ([127, 0, 0, 2], local_addr.port()).into();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", server_addr).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, addr, destination) = super::recv_from(&sock, &mut buf, local_addr).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(addr, client.local_addr().unwrap());
+        assert_eq!(destination, server_addr);
+
+        super::send_to(&sock, b"pong", addr, destination)
+            .await
+            .unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, server_addr);
+    }
+
+    #[tokio::test]
+    async fn sets_dont_fragment() {
+        let discovery = |sock: &UdpSocket| {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: the option value is a c_int of the given size, as the option expects.
+            let result = unsafe {
+                libc::getsockopt(
+                    std::os::unix::io::AsRawFd::as_raw_fd(sock),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    (&mut value as *mut libc::c_int).cast(),
+                    &mut len,
+                )
+            };
+            assert_eq!(result, 0);
+            value
+        };
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        super::set_dont_fragment(&sock, true).unwrap();
+        assert_eq!(discovery(&sock), libc::IP_PMTUDISC_DO);
+        super::set_dont_fragment(&sock, false).unwrap();
+        assert_eq!(discovery(&sock), libc::IP_PMTUDISC_WANT);
+    }
+}
@@ -0,0 +1,158 @@
+//! Reusable byte buffers, so that the datagrams are received and their responses encoded in
+//! buffers reused once the server has warmed up. Handling a Binding request answered from the
+//! template then doesn't allocate, the other requests still allocate their attributes and
+//! responses, and datagrams not received in batches are each handled by a task of their own.
+
+use std::sync::Mutex;
+
+/// Number of buffers kept for reuse, the ones returned beyond are freed.
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+/// A pool of byte buffers, buffers are taken from it and returned once used.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Take an empty buffer, reusing the allocation of a returned one when available.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Return a buffer to the pool.
+    pub fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{BufferPool, MAX_POOLED_BUFFERS};
+    use crate::message::{methods, Class, Message};
+    use crate::server::{Server, Sink, Source, Transport};
+    use crate::transport::{self, Udp};
+
+    /// Counts the allocations of each thread, so that the tests running alongside don't
+    /// count.
+    struct CountingAllocator;
+
+    /// Size of the receive buffers of the servers under test, odd enough to tell their
+    /// allocations apart.
+    const RECV_BUFFER_SIZE: usize = 1999;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        /// Allocations of receive buffers.
+        static RECV_BUFFERS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            // Received in buffers of one extra byte.
+            if layout.size() == RECV_BUFFER_SIZE + 1 {
+                RECV_BUFFERS.with(|allocations| allocations.set(allocations.get() + 1));
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[tokio::test]
+    async fn answers_binding_requests_without_allocating() {
+        let server = Server::default();
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let source = Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock),
+        };
+        let request =
+            Message::with_random_transaction_id(methods::BINDING, Class::Request).encode();
+        for _ in 0..10 {
+            let response = server.handle(&request, &source).await.unwrap();
+            server.buffers().put(response);
+        }
+
+        let before = ALLOCATIONS.with(Cell::get);
+        for _ in 0..100 {
+            let response = server.handle(&request, &source).await.unwrap();
+            server.buffers().put(response);
+        }
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+        assert_eq!(allocations, 0);
+    }
+
+    #[tokio::test]
+    async fn receives_datagrams_in_pooled_buffers() {
+        let server = Arc::new(Server::default().with_recv_buffer_size(RECV_BUFFER_SIZE));
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let udp = Arc::new(Udp::new(sock).unwrap());
+        let serving = tokio::spawn(transport::serve(udp, server.clone()));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            Message::with_random_transaction_id(methods::BINDING, Class::Request).encode();
+        let mut buf = [0; 1500];
+        let mut exchange = async || {
+            client.send_to(&request, server_addr).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        };
+        for _ in 0..10 {
+            exchange().await;
+        }
+
+        // Each datagram is still handled by a task of its own, but received in a buffer taken
+        // back from the pool once its task is reaped.
+        let before = RECV_BUFFERS.with(Cell::get);
+        for _ in 0..100 {
+            exchange().await;
+        }
+        assert_eq!(RECV_BUFFERS.with(Cell::get) - before, 0);
+
+        server.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn reuses_returned_buffers() {
+        let pool = BufferPool::default();
+        let mut buf = pool.take();
+        buf.extend_from_slice(&[1; 1500]);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 1500);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.take().capacity(), 0);
+
+        for _ in 0..MAX_POOLED_BUFFERS + 1 {
+            pool.put(vec![0; 1]);
+        }
+        assert_eq!(pool.buffers.lock().unwrap().len(), MAX_POOLED_BUFFERS);
+    }
+}
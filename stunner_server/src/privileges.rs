@@ -0,0 +1,155 @@
+//! Switching to an unprivileged account once the sockets are bound, so that the server can
+//! listen on privileged ports without running as root.
+
+use std::ffi::CString;
+use std::{io, mem, ptr};
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// Size of the buffer the system account databases entries are read into.
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+/// User and group ids to switch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    /// User id, unless only the group changes.
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+}
+
+impl Account {
+    /// Look up the account of `user` and `group`, given as names or numeric ids. The group
+    /// defaults to the primary group of the user.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>> {
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match (group, user) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some((_, gid))) => gid,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Account {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+        }))
+    }
+
+    /// Switch the process to the account, failing if it could still regain root privileges.
+    pub fn switch(&self) -> Result<()> {
+        // SAFETY: the group list is a single id, the calls have no other memory effect.
+        unsafe {
+            if libc::setgroups(1, &self.gid) != 0 {
+                return Err(io::Error::last_os_error()).context("could not set groups");
+            }
+            if libc::setgid(self.gid) != 0 {
+                return Err(io::Error::last_os_error()).context("could not set group id");
+            }
+            if let Some(uid) = self.uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error()).context("could not set user id");
+                }
+            }
+        }
+        // SAFETY: these calls only read the ids of the process.
+        let (uid, euid, gid, egid) = unsafe {
+            (
+                libc::getuid(),
+                libc::geteuid(),
+                libc::getgid(),
+                libc::getegid(),
+            )
+        };
+        ensure!(gid == self.gid && egid == self.gid, "group id not switched");
+        if let Some(expected) = self.uid {
+            ensure!(uid == expected && euid == expected, "user id not switched");
+            ensure!(euid != 0, "refusing to keep running as root");
+            // SAFETY: as above, the call succeeds only if root privileges can be regained.
+            ensure!(
+                unsafe { libc::setuid(0) } != 0,
+                "root privileges can still be regained"
+            );
+        }
+        log::info!("switched to user id {} and group id {}", uid, self.gid);
+        Ok(())
+    }
+}
+
+/// User id and primary group id of `user`.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    // SAFETY: all zeros is a valid passwd, the entry's strings point into `buf`, which outlives
+    // it, and the result points to `entry` or is null.
+    unsafe {
+        let mut entry: libc::passwd = mem::zeroed();
+        let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+        let mut result = ptr::null_mut();
+        let err = match user.parse() {
+            Ok(uid) => libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err))
+                .with_context(|| format!("could not look up user {:?}", user));
+        }
+        if result.is_null() {
+            bail!("no such user {:?}", user);
+        }
+        Ok((entry.pw_uid, entry.pw_gid))
+    }
+}
+
+/// Group id of `group`.
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group)?;
+    // SAFETY: as for the users, with a group entry.
+    unsafe {
+        let mut entry: libc::group = mem::zeroed();
+        let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+        let mut result = ptr::null_mut();
+        let err = match group.parse() {
+            Ok(gid) => libc::getgrgid_r(gid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+            Err(_) => libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err))
+                .with_context(|| format!("could not look up group {:?}", group));
+        }
+        if result.is_null() {
+            bail!("no such group {:?}", group);
+        }
+        Ok(entry.gr_gid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Account;
+
+    #[test]
+    fn looks_up_accounts() {
+        assert_eq!(Account::lookup(None, None).unwrap(), None);
+        let root = Account {
+            uid: Some(0),
+            gid: 0,
+        };
+        assert_eq!(Account::lookup(Some("root"), None).unwrap(), Some(root));
+        assert_eq!(Account::lookup(Some("0"), Some("0")).unwrap(), Some(root));
+        assert_eq!(
+            Account::lookup(None, Some("root")).unwrap(),
+            Some(Account { uid: None, gid: 0 })
+        );
+        assert!(Account::lookup(Some("no-such-user-stunner"), None).is_err());
+        assert!(Account::lookup(None, Some("no-such-group-stunner")).is_err());
+    }
+}
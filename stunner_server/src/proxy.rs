@@ -0,0 +1,153 @@
+//! PROXY protocol version 2, conveying the address of the clients connecting through an L4 load
+//! balancer, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::server::Server;
+
+/// Signature starting every version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Commands, the connection was made by the proxy itself or on behalf of a client.
+const LOCAL: u8 = 0x0;
+const PROXY: u8 = 0x1;
+
+/// Address families.
+const AF_UNSPEC: u8 = 0x0;
+const AF_INET: u8 = 0x1;
+const AF_INET6: u8 = 0x2;
+const AF_UNIX: u8 = 0x3;
+
+/// Address of the client of a connection accepted on `local_addr` from `peer_addr`, read from
+/// the PROXY protocol header when the listener expects one. Returns `None` when the header is
+/// invalid or doesn't arrive in time, the connection should then be closed.
+pub async fn client_addr<S>(
+    stream: &mut S,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    server: &Server,
+) -> Option<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    if !server.expects_proxy_header(local_addr) {
+        return Some(peer_addr);
+    }
+    let timeout = server.connection_limits().idle_timeout;
+    match tokio::time::timeout(timeout, read_header(stream)).await {
+        Ok(Ok(addr)) => {
+            let addr = addr.unwrap_or(peer_addr);
+            log::debug!("connection from {:?} proxied for {:?}", peer_addr, addr);
+            Some(addr)
+        }
+        Ok(Err(err)) => {
+            log::debug!(
+                "invalid PROXY protocol header from {:?}: {}",
+                peer_addr,
+                err
+            );
+            None
+        }
+        Err(_) => {
+            log::debug!("no PROXY protocol header from {:?} in time", peer_addr);
+            None
+        }
+    }
+}
+
+/// Read the PROXY protocol header at the start of the stream, returning the source address it
+/// carries. Returns `None` for connections made by the proxy itself and for addresses other than
+/// IP ones, the address of the peer should then be used.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 16];
+    stream.read_exact(&mut header).await?;
+    ensure!(header[..12] == SIGNATURE, "missing PROXY protocol header");
+    let (version, command) = (header[12] >> 4, header[12] & 0x0F);
+    ensure!(
+        version == 2,
+        "unsupported PROXY protocol version {}",
+        version
+    );
+    let family = header[13] >> 4;
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    // The addresses are followed by optional TLVs, read along and ignored.
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs).await?;
+
+    match (command, family) {
+        (LOCAL, _) | (PROXY, AF_UNSPEC | AF_UNIX) => Ok(None),
+        (PROXY, AF_INET) => {
+            ensure!(len >= 12, "truncated IPv4 addresses");
+            let ip: [u8; 4] = addrs[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::from((Ipv4Addr::from(ip), port))))
+        }
+        (PROXY, AF_INET6) => {
+            ensure!(len >= 36, "truncated IPv6 addresses");
+            let ip: [u8; 16] = addrs[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::from((Ipv6Addr::from(ip), port))))
+        }
+        (PROXY, family) => bail!("unsupported address family {}", family),
+        (command, _) => bail!("unsupported command {}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, SocketAddr};
+    use std::str::FromStr;
+
+    use super::{read_header, SIGNATURE};
+
+    fn header(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        // Over TCP.
+        header.push(family << 4 | 0x1);
+        header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        header.extend_from_slice(addrs);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_client_addresses() {
+        let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0x1F, 0x90, 0x0D, 0x96];
+        let stream = header(0x1, 0x1, &addrs);
+        let addr: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        assert_eq!(read_header(&mut &stream[..]).await.unwrap(), Some(addr));
+
+        let addr: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let mut addrs = [0; 36];
+        addrs[..16].copy_from_slice(&Ipv6Addr::from_str("2001:db8::1").unwrap().octets());
+        addrs[32..34].copy_from_slice(&addr.port().to_be_bytes());
+        // TLVs following the addresses are skipped, the stream carries STUN after the header.
+        let stream = [
+            header(0x1, 0x2, &[&addrs[..], &[0x04, 0, 0]].concat()),
+            vec![0; 20],
+        ]
+        .concat();
+        let mut reader = &stream[..];
+        assert_eq!(read_header(&mut reader).await.unwrap(), Some(addr));
+        assert_eq!(reader, [0; 20]);
+
+        let stream = header(0x0, 0x0, &[]);
+        assert_eq!(read_header(&mut &stream[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        let mut stream = header(0x1, 0x1, &[192, 0, 2, 1]);
+        assert!(read_header(&mut &stream[..]).await.is_err());
+        stream[0] = b'G';
+        assert!(read_header(&mut &stream[..]).await.is_err());
+        let stream = b"PROXY TCP4 192.0.2.1 198.51.100.1 8080 3478\r\n";
+        assert!(read_header(&mut &stream[..]).await.is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Experimental STUN over QUIC, as a reference responder for QUIC based NAT traversal. Each
+//! bidirectional stream opened by a client carries STUN messages framed as over TCP.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, TransportConfig};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+
+use crate::server::{Server, Transport};
+use crate::tcp::handle_connection;
+use crate::tls::Certificate;
+
+/// QUIC configuration of a server presenting `certificate`, requiring client certificates
+/// accepted by `client_verifier` if given, and closing the connections idle for `idle_timeout`.
+/// Clients must offer one of the STUN ALPN protocols, as QUIC requires one.
+pub fn server_config(
+    certificate: &Arc<Certificate>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    idle_timeout: Duration,
+) -> Result<ServerConfig> {
+    let tls = certificate.server_config(client_verifier);
+    let crypto = QuicServerConfig::try_from(tls).context("invalid TLS configuration for QUIC")?;
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(
+        idle_timeout
+            .try_into()
+            .context("idle timeout too long for QUIC")?,
+    ));
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// QUIC endpoint accepting connections on `sock`.
+pub fn endpoint(sock: UdpSocket, config: ServerConfig) -> Result<Endpoint> {
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        sock.into_std()?,
+        Arc::new(TokioRuntime),
+    )?)
+}
+
+/// Accept QUIC connections and serve STUN requests on each of their streams, until the server
+/// shuts down and every connection is closed.
+pub async fn serve(endpoint: Endpoint, server: Arc<Server>) -> Result<()> {
+    let local_addr = endpoint.local_addr()?;
+    let mut connections = JoinSet::new();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = server.stopped() => break,
+            // Reap the finished connections.
+            Some(_) = connections.join_next() => continue,
+        };
+        let peer_addr = incoming.remote_address();
+        let permit = match server.admit_connection(peer_addr) {
+            Some(permit) => permit,
+            None => {
+                incoming.refuse();
+                continue;
+            }
+        };
+        log::debug!("accepted QUIC connection from {:?}", peer_addr);
+        let server = server.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log::debug!("QUIC handshake with {:?} failed: {}", peer_addr, err);
+                    return;
+                }
+            };
+            if let Err(err) = serve_connection(connection, peer_addr, local_addr, server).await {
+                log::debug!("QUIC connection with {:?} closed: {}", peer_addr, err);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    endpoint.close(0u32.into(), b"shutting down");
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Serve each bidirectional stream opened on `connection` as a STUN over TCP connection.
+async fn serve_connection(
+    connection: Connection,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    server: Arc<Server>,
+) -> Result<()> {
+    let mut streams = JoinSet::new();
+    loop {
+        let (send, recv) = tokio::select! {
+            accepted = connection.accept_bi() => accepted?,
+            _ = server.stopped() => break,
+            // Reap the finished streams.
+            Some(_) = streams.join_next() => continue,
+        };
+        let server = server.clone();
+        streams.spawn(async move {
+            let stream = tokio::io::join(recv, send);
+            if let Err(err) =
+                handle_connection(stream, peer_addr, local_addr, Transport::Quic, server).await
+            {
+                log::debug!("QUIC stream with {:?} closed: {}", peer_addr, err);
+            }
+        });
+    }
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::{ClientConfig, Endpoint};
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+    use tokio_rustls::rustls::{self, RootCertStore};
+
+    use super::{endpoint, serve, server_config};
+    use crate::server::Server;
+    use crate::tcp::read_message;
+    use crate::tls::tests::self_signed_cert;
+    use crate::tls::Certificate;
+
+    #[tokio::test]
+    async fn replies_on_each_stream() {
+        let (cert, dir) = self_signed_cert("stunner-quic-test");
+        let certificate =
+            Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[]).unwrap();
+        let config = server_config(&certificate, None, Duration::from_secs(10)).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_endpoint = endpoint(sock, config).unwrap();
+        let server_addr = server_endpoint.local_addr().unwrap();
+        let server = Arc::new(Server::default());
+        let serving = tokio::spawn(serve(server_endpoint, server.clone()));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"stun.nat-discovery".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        let client_addr: SocketAddr = client.local_addr().unwrap();
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            let request =
+                StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+            send.write_all(&request.encode(None).unwrap())
+                .await
+                .unwrap();
+            let response = read_message(&mut recv, 8192).await.unwrap().unwrap();
+            let response = StunMessage::decode(&response, None).unwrap();
+            assert!(matches!(
+                response.get_header().message_class,
+                StunMessageClass::SuccessResponse
+            ));
+            assert!(matches!(
+                response.get_attributes()[0],
+                StunAttribute::XorMappedAddress { socket_addr } if socket_addr == client_addr
+            ));
+        }
+
+        server.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+//! Rate limiting of the packets received by the server and of the messages of each source IP,
+//! limiting of the requests of each source IP handled concurrently, and bandwidth limiting of
+//! the data relayed by an allocation.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tokens left to a source and when they were last refilled.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn full(rate: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: rate,
+            refilled_at: now,
+        }
+    }
+
+    /// Add the tokens earned at `rate` per second since last refilled, up to one second worth.
+    fn refill(&mut self, rate: f64, now: Instant) {
+        self.refill_up_to(rate, rate, now);
+    }
+
+    /// Add the tokens earned at `rate` per second since last refilled, up to `capacity`.
+    fn refill_up_to(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        self.refilled_at = now;
+    }
+}
+
+/// Token bucket limiter of the packets received by the server from all sources, so that a flood
+/// can't take all the CPU of the host whatever the number of addresses it comes from.
+#[derive(Debug)]
+pub struct GlobalRateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+    /// Number of packets dropped so far.
+    dropped: AtomicU64,
+}
+
+impl GlobalRateLimiter {
+    /// Allow `per_second` packets per second, with bursts of up to `burst` packets.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        GlobalRateLimiter {
+            rate: per_second as f64,
+            burst,
+            bucket: Mutex::new(Bucket::full(burst, Instant::now())),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a packet may be handled, counting it as dropped otherwise.
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill_up_to(self.rate, self.burst, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        drop(bucket);
+
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!("packet rate exceeded, {} dropped so far", dropped);
+        false
+    }
+
+    /// Number of packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Token bucket limiter allowing each source IP a number of messages per second, with bursts of
+/// up to one second worth of messages.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    /// Number of messages dropped so far.
+    dropped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        RateLimiter {
+            rate: per_second as f64,
+            buckets: Default::default(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a message from `ip` may be handled, counting it as dropped otherwise.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(self.rate, now));
+        bucket.refill(self.rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        drop(buckets);
+
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!(
+            "rate limited message from {:?}, {} dropped so far",
+            ip,
+            dropped
+        );
+        false
+    }
+
+    /// Number of messages allowed per second.
+    pub fn per_second(&self) -> u32 {
+        self.rate as u32
+    }
+
+    /// Number of messages dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Forget the sources whose bucket is full again, they are treated as new sources.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let rate = self.rate;
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.refilled_at);
+            bucket.tokens + elapsed.as_secs_f64() * rate < rate
+        });
+    }
+}
+
+/// Limiter of the number of messages of each source IP handled concurrently, bounding the
+/// memory and CPU a single client can take.
+#[derive(Debug)]
+pub struct InFlightLimiter {
+    max: usize,
+    /// Messages being handled by source IP, sources without any are removed.
+    counts: Mutex<HashMap<IpAddr, usize>>,
+    /// Number of messages dropped so far.
+    dropped: AtomicU64,
+}
+
+impl InFlightLimiter {
+    pub fn new(max: usize) -> Self {
+        InFlightLimiter {
+            max,
+            counts: Default::default(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a message from `ip` as handled until the guard returned is dropped, unless too
+    /// many are already, counting it as dropped then.
+    pub fn acquire(&self, ip: IpAddr) -> Option<InFlight<'_>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count < self.max {
+            *count += 1;
+            return Some(InFlight { limiter: self, ip });
+        }
+        drop(counts);
+
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!(
+            "too many messages in flight from {:?}, {} dropped so far",
+            ip,
+            dropped
+        );
+        None
+    }
+
+    /// Number of messages dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// A message being handled, until dropped.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    limiter: &'a InFlightLimiter,
+    ip: IpAddr,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Token bucket limiter of the bytes relayed by an allocation in both directions, with bursts
+/// of up to one second worth of bytes.
+#[derive(Debug)]
+pub struct Bandwidth {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        Bandwidth {
+            rate,
+            bucket: Mutex::new(Bucket::full(rate, Instant::now())),
+        }
+    }
+
+    /// Whether a datagram of `len` bytes may be relayed, it is dropped otherwise.
+    pub fn allow(&self, len: usize) -> bool {
+        self.allow_at(len, Instant::now())
+    }
+
+    fn allow_at(&self, len: usize, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.rate, now);
+        if bucket.tokens >= len as f64 {
+            bucket.tokens -= len as f64;
+            return true;
+        }
+        false
+    }
+
+    /// Wait until `len` bytes of a stream may be relayed.
+    pub async fn consume(&self, len: usize) {
+        let wait = self.consume_at(len, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `len` tokens, going into debt when there are not enough, returning how long it
+    /// takes to pay it back.
+    fn consume_at(&self, len: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.rate, now);
+        bucket.tokens -= len as f64;
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use super::{Bandwidth, GlobalRateLimiter, InFlightLimiter, RateLimiter};
+
+    #[test]
+    fn limits_all_packets_with_bursts() {
+        let limiter = GlobalRateLimiter::new(10, 3);
+        let now = Instant::now();
+
+        assert!((0..3).all(|_| limiter.allow_at(now)));
+        assert!(!limiter.allow_at(now));
+        assert_eq!(limiter.dropped(), 1);
+
+        // A token every 100ms, never more than the burst.
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.allow_at(later));
+        assert!(!limiter.allow_at(later));
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!((0..5).filter(|_| limiter.allow_at(much_later)).count(), 3);
+    }
+
+    #[test]
+    fn limits_the_messages_in_flight() {
+        let limiter = InFlightLimiter::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        let first = limiter.acquire(ip).unwrap();
+        let second = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+        assert!(limiter.acquire(other).is_some());
+        drop(first);
+        let third = limiter.acquire(ip).unwrap();
+        assert_eq!(limiter.dropped(), 1);
+        drop((second, third));
+        assert!(limiter.counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn limits_each_source_ip() {
+        let limiter = RateLimiter::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(other, now));
+        assert_eq!(limiter.dropped(), 1);
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow_at(ip, later));
+        assert!(!limiter.allow_at(ip, later));
+    }
+
+    #[test]
+    fn limits_relayed_bytes() {
+        let bandwidth = Bandwidth::new(1000);
+        let now = Instant::now();
+
+        assert!(bandwidth.allow_at(600, now));
+        assert!(!bandwidth.allow_at(600, now));
+        assert!(bandwidth.allow_at(400, now));
+        let later = now + Duration::from_millis(600);
+        assert!(bandwidth.allow_at(600, later));
+
+        // Streams wait for the bytes they relay to be paid back.
+        assert_eq!(bandwidth.consume_at(500, later), Duration::from_millis(500));
+        let later = later + Duration::from_millis(500);
+        assert_eq!(bandwidth.consume_at(100, later), Duration::from_millis(100));
+        // Bursts are limited to one second worth of bytes.
+        let later = later + Duration::from_secs(10);
+        assert_eq!(bandwidth.consume_at(1000, later), Duration::ZERO);
+    }
+}
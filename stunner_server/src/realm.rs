@@ -0,0 +1,234 @@
+//! Additional realms, each with its own users and access control lists, so that a single
+//! instance serves several tenants in isolation. A request belongs to the realm of the listener
+//! it was received on, or else to the realm named by its REALM attribute, falling back to the
+//! credentials of the command line.
+//!
+//! Each realm is configured in its own file, holding options as the configuration file does:
+//!
+//! ```text
+//! # /etc/stunner/realms/acme.conf
+//! realm = acme.example.com
+//! users-file = /etc/stunner/acme.users
+//! allow = 198.51.100.0/24
+//! listener = 0.0.0.0:3479
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+
+use crate::acl::{Acl, Cidr};
+use crate::auth::{self, Auth, LongTermAuth};
+use crate::config;
+use crate::message::{attributes, Message};
+use crate::turn::{self, BandwidthLimits};
+#[cfg(feature = "sql")]
+use crate::userdb::UserDatabase;
+
+/// Options of a realm file.
+#[derive(Parser, Debug)]
+#[clap(no_binary_name = true)]
+struct RealmOptions {
+    /// Name of the realm, sent in the challenges
+    #[clap(long)]
+    realm: String,
+
+    /// User allowed to authenticate, given as user=password
+    #[clap(long, multiple_occurrences = true, parse(try_from_str = auth::parse_user))]
+    users: Vec<(String, String)>,
+
+    /// File of user=password lines
+    #[clap(long)]
+    users_file: Option<PathBuf>,
+
+    /// Shared secret ephemeral credentials are minted with
+    #[clap(long)]
+    auth_secret: Option<String>,
+
+    /// Database users are looked up in
+    #[cfg(feature = "sql")]
+    #[clap(long)]
+    users_db: Option<String>,
+
+    /// Only permit sources in this IP range
+    #[clap(long, multiple_occurrences = true)]
+    allow: Vec<Cidr>,
+
+    /// Never permit sources in this IP range
+    #[clap(long, multiple_occurrences = true)]
+    deny: Vec<Cidr>,
+
+    /// Bytes per second relayed by each TURN allocation in both directions
+    #[clap(long, parse(try_from_str = turn::parse_bandwidth))]
+    max_relay_bandwidth: Option<u64>,
+
+    /// Bytes per second relayed by each TURN allocation of a user, given as user=bytes
+    #[clap(long, multiple_occurrences = true, parse(try_from_str = turn::parse_user_bandwidth))]
+    user_relay_bandwidth: Vec<(String, u64)>,
+
+    /// Address of a listener whose requests all belong to the realm, among the --listen ones
+    #[clap(long, multiple_occurrences = true)]
+    listener: Vec<SocketAddr>,
+}
+
+/// A realm and the users and sources it admits.
+pub struct Realm {
+    name: String,
+    auth: Arc<Auth>,
+    acl: Acl,
+    /// Limits of the bytes relayed by the allocations of the realm, overriding the ones of the
+    /// server when set.
+    bandwidth: BandwidthLimits,
+    listeners: Vec<SocketAddr>,
+}
+
+impl Realm {
+    /// Load the realm configured in the file at `path`, handing out nonces valid for
+    /// `nonce_lifetime` signed with `nonce_secret`.
+    pub fn load(path: &Path, nonce_lifetime: Duration, nonce_secret: [u8; 16]) -> Result<Self> {
+        let args = config::args(path)?;
+        let opt = RealmOptions::try_parse_from(args)
+            .with_context(|| format!("invalid realm file {}", path.display()))?;
+        let mut users = match &opt.users_file {
+            Some(path) => auth::load_users(path)?,
+            None => Default::default(),
+        };
+        users.extend(opt.users);
+        let auth = LongTermAuth::new(opt.realm.clone(), users)
+            .with_auth_secret(opt.auth_secret)
+            .with_nonce_lifetime(nonce_lifetime)
+            .with_nonce_secret(nonce_secret);
+        #[cfg(feature = "sql")]
+        let auth = match &opt.users_db {
+            Some(url) => auth.with_database(Some(UserDatabase::connect(url)?)),
+            None => auth,
+        };
+        Ok(Realm {
+            name: opt.realm,
+            auth: Arc::new(Auth::LongTerm(Box::new(auth))),
+            acl: Acl::new(opt.allow, opt.deny),
+            bandwidth: BandwidthLimits {
+                default: opt.max_relay_bandwidth,
+                per_user: opt.user_relay_bandwidth.into_iter().collect(),
+            },
+            listeners: opt.listener,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Credentials the requests of the realm must be authenticated with.
+    pub fn auth(&self) -> &Arc<Auth> {
+        &self.auth
+    }
+
+    /// Whether messages from `ip` may be handled in the realm.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.acl.permits(ip)
+    }
+
+    /// Bytes per second relayed by the allocations of `username` if given, unless the limits
+    /// of the server apply.
+    pub fn relay_bandwidth(&self, username: Option<&str>) -> Option<u64> {
+        self.bandwidth.get(username)
+    }
+
+    /// Whether the requests received on `local_addr` belong to the realm.
+    fn listens_on(&self, local_addr: SocketAddr) -> bool {
+        self.listeners.iter().any(|listener| {
+            *listener == local_addr
+                || (listener.ip().is_unspecified() && listener.port() == local_addr.port())
+        })
+    }
+}
+
+/// Load the realms configured in the files at `paths`, whose names must be distinct.
+pub fn load(
+    paths: &[PathBuf],
+    nonce_lifetime: Duration,
+    nonce_secret: [u8; 16],
+) -> Result<Vec<Realm>> {
+    let mut realms: Vec<Realm> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let realm = Realm::load(path, nonce_lifetime, nonce_secret)?;
+        ensure!(
+            realms.iter().all(|other| other.name != realm.name),
+            "realm {:?} of {} is configured twice",
+            realm.name,
+            path.display()
+        );
+        realms.push(realm);
+    }
+    Ok(realms)
+}
+
+/// Realm of a request received on `local_addr`: the one of that listener, or else the one named
+/// by its REALM attribute.
+pub fn select<'a>(
+    realms: &'a [Realm],
+    request: &Message,
+    local_addr: SocketAddr,
+) -> Option<&'a Realm> {
+    realms
+        .iter()
+        .find(|realm| realm.listens_on(local_addr))
+        .or_else(|| {
+            let name = request.get_str(attributes::REALM)?;
+            realms.iter().find(|realm| realm.name == name)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{load, select};
+    use crate::message::{attributes, methods, Class, Message};
+
+    #[test]
+    fn selects_realms_by_listener_then_by_name() {
+        let dir = std::env::temp_dir().join(format!("stunner-realms-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let paths = [dir.join("acme.conf"), dir.join("globex.conf")];
+        std::fs::write(
+            &paths[0],
+            "realm = acme\nusers = alice=pass\ndeny = 192.0.2.0/24\nlistener = 0.0.0.0:3479\n\
+             max-relay-bandwidth = 100000\nuser-relay-bandwidth = alice=1000\n",
+        )
+        .unwrap();
+        std::fs::write(&paths[1], "realm = globex\nauth-secret = secret\n").unwrap();
+        let realms = load(&paths, Duration::from_secs(60), [0; 16]).unwrap();
+
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let named = request
+            .clone()
+            .add_attribute(attributes::REALM, b"globex".to_vec());
+        let realm = |request: &Message, local_addr: &str| {
+            select(&realms, request, local_addr.parse().unwrap()).map(|realm| realm.name())
+        };
+        assert_eq!(realm(&request, "10.0.0.1:3479"), Some("acme"));
+        assert_eq!(realm(&named, "10.0.0.1:3479"), Some("acme"));
+        assert_eq!(realm(&named, "10.0.0.1:3478"), Some("globex"));
+        assert_eq!(realm(&request, "10.0.0.1:3478"), None);
+        assert!(!realms[0].permits("192.0.2.1".parse().unwrap()));
+        assert!(realms[1].permits("192.0.2.1".parse().unwrap()));
+        assert_eq!(realms[0].relay_bandwidth(Some("alice")), Some(1000));
+        assert_eq!(realms[0].relay_bandwidth(None), Some(100_000));
+        assert_eq!(realms[1].relay_bandwidth(None), None);
+
+        // Realm names must be distinct.
+        assert!(load(
+            &[paths[0].clone(), paths[0].clone()],
+            Duration::ZERO,
+            [0; 16]
+        )
+        .is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+//! Secrets of the users looked up from an external store as requests are authenticated, and
+//! the cache sparing a lookup for each request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use crate::auth::PasswordAlgorithm;
+
+/// Number of usernames cached, expired entries are dropped when reached.
+const MAX_CACHED_USERS: usize = 10_000;
+
+/// What the key of a user is derived from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    Password(String),
+    /// MD5(username:realm:password), only good for the MD5 password algorithm.
+    Ha1(Vec<u8>),
+}
+
+impl Secret {
+    /// Long-term credential key of `username` in `realm` derived with `algorithm`, if the
+    /// secret allows it.
+    pub fn key(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Option<Vec<u8>> {
+        match (self, algorithm) {
+            (Secret::Password(password), algorithm) => {
+                Some(algorithm.key(username, realm, password))
+            }
+            (Secret::Ha1(ha1), PasswordAlgorithm::Md5) => Some(ha1.clone()),
+            (Secret::Ha1(_), PasswordAlgorithm::Sha256) => None,
+        }
+    }
+}
+
+/// Secrets looked up by username, including the absence of such a user, reused for `ttl`.
+pub struct SecretCache {
+    entries: Mutex<HashMap<String, (Option<Secret>, Instant)>>,
+    ttl: Duration,
+}
+
+impl SecretCache {
+    pub fn new(ttl: Duration) -> Self {
+        SecretCache {
+            entries: Default::default(),
+            ttl,
+        }
+    }
+
+    /// Secret of `username` looked up less than `ttl` ago, `Some(None)` when there was no such
+    /// user.
+    pub fn get(&self, username: &str) -> Option<Option<Secret>> {
+        match self.entries.lock().unwrap().get(username) {
+            Some((secret, expires_at)) if *expires_at > Instant::now() => Some(secret.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, username: &str, secret: Option<Secret>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHED_USERS {
+            entries.retain(|_, (_, expires_at)| *expires_at > now);
+            if entries.len() >= MAX_CACHED_USERS {
+                entries.clear();
+            }
+        }
+        entries.insert(username.to_string(), (secret, now + self.ttl));
+    }
+}
+
+/// Parse a HA1 hash, 16 bytes in hex.
+pub fn parse_ha1(ha1: &str) -> Result<Vec<u8>> {
+    if ha1.len() != 32 || !ha1.is_ascii() {
+        bail!("invalid HA1 hash {:?}", ha1);
+    }
+    (0..ha1.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&ha1[i..i + 2], 16)?))
+        .collect()
+}
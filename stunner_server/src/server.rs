@@ -0,0 +1,2021 @@
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::ArgEnum;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tracing::{field, Instrument, Span};
+
+use crate::accesslog::{self, AccessLog};
+use crate::acl::Acl;
+use crate::attribute::Attributes;
+use crate::auth::{Auth, Credentials};
+use crate::blocklist::Blocklist;
+use crate::demux::Demux;
+use crate::discovery::Discovery;
+use crate::failures::Failures;
+#[cfg(feature = "feed")]
+use crate::feed::DenyFeed;
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::handler::{self, Handler, Transaction, Verdict};
+use crate::logsample::{self, Sampler};
+use crate::message::{
+    self, append_fingerprint, attributes, hex, methods, peek_method, peek_request, Attribute,
+    Class, HeaderError, Integrity, Message, HEADER_SIZE,
+};
+use crate::net::{self, SocketConfig};
+use crate::parse_message;
+use crate::pcap::Capture;
+use crate::pool::BufferPool;
+use crate::ratelimit::{GlobalRateLimiter, InFlightLimiter, RateLimiter};
+use crate::realm::{self, Realm};
+use crate::shutdown::Shutdown;
+use crate::state::{self, SharedState};
+use crate::stats::{self, Stats};
+use crate::statsd::Statsd;
+use crate::tcp::ConnectionLimits;
+use crate::transactions::ResponseCache;
+use crate::turn::{ChannelData, Direction, DropReason, FiveTuple, PeerConnection, Turn};
+#[cfg(target_os = "linux")]
+use crate::xdp::Xdp;
+
+/// Default size of the buffer datagrams are received in, the Ethernet MTU.
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 1500;
+
+/// Interval between sweeps of expired state.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Transport protocol a message was received on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Dtls,
+    Ws,
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+/// What becomes of the packets which aren't valid STUN messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum MalformedPolicy {
+    /// Drop them silently, so that spoofed sources can't be flooded with responses.
+    Drop,
+    /// Answer the requests whose header can be read with a 400 Bad Request.
+    Respond,
+    /// Drop them and log why at the info level.
+    Log,
+}
+
+/// Whether the batches of UDP datagrams are received coalesced and sent with generic receive
+/// and segmentation offload, on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum UdpOffload {
+    /// Use it on the sockets which support it.
+    Auto,
+    /// Use it, failing to serve on the sockets which don't support it.
+    On,
+    Off,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Dtls => "dtls",
+            Transport::Ws => "ws",
+            #[cfg(feature = "quic")]
+            Transport::Quic => "quic",
+        }
+    }
+}
+
+/// How to reach a client outside of a request/response exchange.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// Datagrams are sent through the socket the client messages were received on.
+    Datagram(Arc<UdpSocket>),
+    /// Messages are queued to the task writing to the client connection.
+    Stream(mpsc::Sender<Vec<u8>>),
+}
+
+/// Where a message was received from.
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// Address of the client.
+    pub addr: SocketAddr,
+    /// Local address the message was received on.
+    pub local_addr: SocketAddr,
+    pub transport: Transport,
+    pub sink: Sink,
+}
+
+impl Source {
+    /// Transport 5-tuple identifying the client, see https://datatracker.ietf.org/doc/html/rfc5766#section-2.2
+    pub fn five_tuple(&self) -> FiveTuple {
+        FiveTuple {
+            client: self.addr,
+            server: self.local_addr,
+            transport: self.transport,
+        }
+    }
+
+    /// Span of the transaction of a message received from the client, from its decoding to
+    /// its response being sent. The method and outcome are recorded once known.
+    pub fn span(&self) -> Span {
+        tracing::info_span!(
+            "transaction",
+            transport = self.transport.as_str(),
+            client = %self.addr,
+            method = field::Empty,
+            outcome = field::Empty,
+        )
+    }
+
+    /// Send a message to the client outside of a request/response exchange.
+    pub async fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        match &self.sink {
+            Sink::Datagram(sock) => net::send_to(sock, &bytes, self.addr, self.local_addr)
+                .await
+                .map(|_| ()),
+            Sink::Stream(tx) => tx
+                .send(bytes)
+                .await
+                .map_err(|_| io::Error::from(ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+/// State shared by every transport the server listens on.
+pub struct Server {
+    turn: Option<Turn>,
+    /// Credentials requests must be authenticated with, when configured.
+    auth: RwLock<Option<Arc<Auth>>>,
+    /// Additional realms, with their own credentials and access control lists.
+    realms: RwLock<Arc<Vec<Realm>>>,
+    /// Whether responses carry a FINGERPRINT attribute.
+    fingerprint: bool,
+    /// Alternate addresses for NAT behavior discovery, when configured.
+    discovery: Option<Discovery>,
+    /// Limit of messages handled per source IP, when configured.
+    rate_limiter: RwLock<Option<RateLimiter>>,
+    in_flight_limiter: Option<InFlightLimiter>,
+    /// Limit of packets received from all sources, when configured.
+    global_rate_limiter: Option<GlobalRateLimiter>,
+    /// Source IP ranges allowed to use the server.
+    acl: RwLock<Acl>,
+    /// Ranges denied from a file read again as it changes, when configured.
+    blocklist: Option<Blocklist>,
+    /// Nonce secret and bans, possibly shared with other instances.
+    state: SharedState,
+    /// Size of the buffer datagrams are received in, larger datagrams are dropped.
+    recv_buffer_size: usize,
+    /// Number of UDP datagrams received and sent per system call.
+    udp_batch_size: usize,
+    udp_offload: UdpOffload,
+    /// Buffers datagrams are received in and responses encoded to.
+    buffers: BufferPool,
+    /// Options of the sockets clients are served on.
+    socket_config: SocketConfig,
+    connection_limits: ConnectionLimits,
+    /// A permit for each TCP and TLS connection that can be open.
+    connections: Arc<Semaphore>,
+    /// Addresses of the TCP and TLS listeners whose connections start with a PROXY protocol
+    /// header.
+    proxy_protocol: Vec<SocketAddr>,
+    /// Answer RFC 3489 Binding requests, which lack the magic cookie.
+    rfc3489: bool,
+    /// Add a MAPPED-ADDRESS to the Binding success responses carrying a XOR-MAPPED-ADDRESS.
+    compat_mapped_address: bool,
+    malformed_policy: MalformedPolicy,
+    /// Decides which malformed packets are logged, so that floods don't flood the logs.
+    malformed_log: Sampler,
+    stats: Stats,
+    /// Daemon the metrics are exported to with statsd, when configured.
+    statsd: Option<Arc<Statsd>>,
+    /// Responses of the recent transactions, sent again to retransmitted requests when
+    /// configured.
+    response_cache: Option<ResponseCache>,
+    /// Log of the messages handled, when configured.
+    access_log: Option<AccessLog>,
+    /// Capture of the messages received and sent, when configured.
+    capture: Option<Capture>,
+    /// Origin of the sources, looked up when configured.
+    #[cfg(feature = "geoip")]
+    geoip: Option<GeoIp>,
+    /// Source IP ranges denied from a threat intelligence feed, when configured.
+    #[cfg(feature = "feed")]
+    deny_feed: Option<Arc<DenyFeed>>,
+    /// Failures of the sources, banned once they fail too often when configured.
+    failures: Option<Failures>,
+    /// Handlers the messages go through, in order.
+    handlers: Vec<Box<dyn Handler>>,
+    /// Attributes handled on top of the ones the server knows.
+    attributes: Attributes,
+    /// Forwarder of the datagrams which aren't STUN, when sharing the port with a media server.
+    demux: Option<Demux>,
+    /// Program answering Binding requests in the kernel, when attached.
+    #[cfg(target_os = "linux")]
+    xdp: Option<Xdp>,
+    shutdown: Shutdown,
+    /// Set when shutting down, new TURN allocations are refused while the existing ones drain.
+    draining: AtomicBool,
+    /// Set once every listener is bound.
+    ready: AtomicBool,
+    /// Messages being handled, and handled so far, for the watchdog to tell a hung server from
+    /// an idle one.
+    handling: AtomicUsize,
+    handled: AtomicU64,
+    started_at: Instant,
+}
+
+/// Progress of the handling of messages, see [`Server::liveness`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Liveness {
+    /// Messages being handled.
+    pub handling: usize,
+    /// Messages handled since the server started.
+    pub handled: u64,
+}
+
+impl Liveness {
+    /// Whether the server made progress since `before`: it handled messages or is idle, rather
+    /// than stuck handling some.
+    pub fn progressed_since(self, before: Liveness) -> bool {
+        self.handling == 0 || self.handled != before.handled
+    }
+}
+
+/// Counts a message as being handled until dropped, whether its handling completes or is
+/// cancelled.
+struct Handling<'a>(&'a Server);
+
+impl Drop for Handling<'_> {
+    fn drop(&mut self) {
+        self.0.handling.fetch_sub(1, Ordering::Relaxed);
+        self.0.handled.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            turn: None,
+            auth: RwLock::new(None),
+            realms: Default::default(),
+            fingerprint: false,
+            discovery: None,
+            rate_limiter: RwLock::new(None),
+            in_flight_limiter: None,
+            global_rate_limiter: None,
+            acl: RwLock::new(Acl::default()),
+            blocklist: None,
+            state: SharedState::default(),
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            udp_batch_size: 1,
+            udp_offload: UdpOffload::Auto,
+            buffers: BufferPool::default(),
+            socket_config: SocketConfig::default(),
+            connection_limits: ConnectionLimits::default(),
+            connections: Arc::new(Semaphore::new(ConnectionLimits::default().max_connections)),
+            proxy_protocol: Vec::new(),
+            rfc3489: true,
+            compat_mapped_address: false,
+            malformed_log: Sampler::new(
+                "malformed packets",
+                logsample::DEFAULT_PER_SOURCE,
+                logsample::DEFAULT_MAX,
+            ),
+            malformed_policy: MalformedPolicy::Drop,
+            stats: Stats::default(),
+            statsd: None,
+            response_cache: None,
+            access_log: None,
+            capture: None,
+            #[cfg(feature = "geoip")]
+            geoip: None,
+            #[cfg(feature = "feed")]
+            deny_feed: None,
+            failures: None,
+            handlers: handler::default_chain(),
+            attributes: Attributes::default(),
+            demux: None,
+            #[cfg(target_os = "linux")]
+            xdp: None,
+            shutdown: Shutdown::default(),
+            draining: AtomicBool::default(),
+            ready: AtomicBool::default(),
+            handling: AtomicUsize::new(0),
+            handled: AtomicU64::new(0),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Server {
+    /// Relay data for clients with TURN. Configure long-term credentials too, the allocations
+    /// are otherwise open to anyone, see [`Server::open_relay`].
+    pub fn with_turn(mut self, turn: Option<Turn>) -> Self {
+        self.turn = turn;
+        self
+    }
+
+    /// Require requests to be authenticated.
+    pub fn with_auth(mut self, auth: Option<Auth>) -> Self {
+        self.auth = RwLock::new(auth.map(Arc::new));
+        self
+    }
+
+    /// Serve additional `realms`, see [`realm::select`].
+    pub fn with_realms(mut self, realms: Vec<Realm>) -> Self {
+        self.realms = RwLock::new(Arc::new(realms));
+        self
+    }
+
+    /// Add a FINGERPRINT attribute to responses.
+    pub fn with_fingerprint(mut self, fingerprint: bool) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Support NAT behavior discovery on the sockets of `discovery`.
+    pub fn with_discovery(mut self, discovery: Option<Discovery>) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Drop the messages of sources exceeding the rate of `rate_limiter`.
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        self.rate_limiter = RwLock::new(rate_limiter);
+        self
+    }
+
+    /// Drop the messages of sources with as many handled concurrently as `in_flight_limiter`
+    /// allows.
+    pub fn with_in_flight_limiter(mut self, in_flight_limiter: Option<InFlightLimiter>) -> Self {
+        self.in_flight_limiter = in_flight_limiter;
+        self
+    }
+
+    /// Drop the packets received beyond the rate of `global_rate_limiter`, whatever their
+    /// source, before they are decoded.
+    pub fn with_global_rate_limiter(
+        mut self,
+        global_rate_limiter: Option<GlobalRateLimiter>,
+    ) -> Self {
+        self.global_rate_limiter = global_rate_limiter;
+        self
+    }
+
+    /// Drop the messages of sources not permitted by `acl`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = RwLock::new(acl);
+        self
+    }
+
+    /// Keep the nonce secret and the bans in `state`.
+    pub fn with_state(mut self, state: SharedState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Nonce secret and bans, possibly shared with other instances.
+    pub fn state(&self) -> &SharedState {
+        &self.state
+    }
+
+    /// TURN state, when relaying data for clients.
+    pub fn turn(&self) -> Option<&Turn> {
+        self.turn.as_ref()
+    }
+
+    /// Number of messages handled per second and source IP, if limited.
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.rate_limiter
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(RateLimiter::per_second)
+    }
+
+    /// Limit the number of messages handled per second and source IP, or stop limiting it.
+    pub fn set_rate_limit(&self, per_second: Option<u32>) {
+        *self.rate_limiter.write().unwrap() = per_second.map(RateLimiter::new);
+    }
+
+    /// Message counters.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Export the metrics to a statsd daemon with `statsd`.
+    pub fn with_statsd(mut self, statsd: Option<Arc<Statsd>>) -> Self {
+        self.statsd = statsd;
+        self
+    }
+
+    /// Exporter of the metrics to a statsd daemon, when configured.
+    pub fn statsd(&self) -> Option<&Arc<Statsd>> {
+        self.statsd.as_ref()
+    }
+
+    /// Answer retransmitted requests with the response cached in `response_cache` rather than
+    /// handling them again.
+    pub fn with_response_cache(mut self, response_cache: Option<ResponseCache>) -> Self {
+        self.response_cache = response_cache;
+        self
+    }
+
+    /// Log the messages handled to `access_log`.
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Capture the messages received and the responses sent to `capture`.
+    pub fn with_capture(mut self, capture: Option<Capture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Drop the messages of the sources in the ranges of `deny_feed`, fetched periodically
+    /// while serving.
+    #[cfg(feature = "feed")]
+    pub fn with_deny_feed(mut self, deny_feed: Option<Arc<DenyFeed>>) -> Self {
+        self.deny_feed = deny_feed;
+        self
+    }
+
+    /// The threat intelligence feed the sources are denied from, if configured.
+    #[cfg(feature = "feed")]
+    pub fn deny_feed(&self) -> Option<&Arc<DenyFeed>> {
+        self.deny_feed.as_ref()
+    }
+
+    /// Report the Binding requests answered in the kernel by `xdp` in the metrics. The program
+    /// stays attached as long as the server lives.
+    #[cfg(target_os = "linux")]
+    pub fn with_xdp(mut self, xdp: Option<Xdp>) -> Self {
+        self.xdp = xdp;
+        self
+    }
+
+    /// Look up the origin of the sources with `geoip`, to tag the access log entries and count
+    /// the messages by origin.
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, geoip: Option<GeoIp>) -> Self {
+        self.geoip = geoip;
+        self
+    }
+
+    /// Pass the messages through `handlers`, in order, rather than the default chain of
+    /// [`handler::default_chain`].
+    pub fn with_handlers(mut self, handlers: Vec<Box<dyn Handler>>) -> Self {
+        self.handlers = handlers;
+        self
+    }
+
+    /// Handle the attributes of `attributes` in requests, and add its extra attributes to the
+    /// responses.
+    pub fn with_attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Forward the datagrams which aren't STUN, nor ChannelData when relaying, with `demux`.
+    pub fn with_demux(mut self, demux: Option<Demux>) -> Self {
+        self.demux = demux;
+        self
+    }
+
+    /// Forwarder of the datagrams which aren't STUN, when configured.
+    pub fn demux(&self) -> Option<&Demux> {
+        self.demux.as_ref()
+    }
+
+    /// Drop the messages of sources in the ranges of `blocklist`, on top of the access control
+    /// lists.
+    pub fn with_blocklist(mut self, blocklist: Option<Blocklist>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    /// Ban the sources failing to authenticate or sending malformed messages too often.
+    pub fn with_failures(mut self, failures: Option<Failures>) -> Self {
+        self.failures = failures;
+        self
+    }
+
+    /// Replace the access control lists, credentials, realms and rate limit of the running
+    /// server. The rate limit starts over.
+    pub fn reload(
+        &self,
+        acl: Acl,
+        auth: Option<Auth>,
+        realms: Vec<Realm>,
+        rate_limiter: Option<RateLimiter>,
+    ) {
+        *self.acl.write().unwrap() = acl;
+        *self.auth.write().unwrap() = auth.map(Arc::new);
+        *self.realms.write().unwrap() = Arc::new(realms);
+        *self.rate_limiter.write().unwrap() = rate_limiter;
+    }
+
+    /// Receive datagrams in buffers of `size` bytes.
+    pub fn with_recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Size of the buffer datagrams are received in.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.recv_buffer_size
+    }
+
+    /// Receive and send up to `size` UDP datagrams per system call, where supported.
+    pub fn with_udp_batch_size(mut self, size: usize) -> Self {
+        self.udp_batch_size = size;
+        self
+    }
+
+    /// Number of UDP datagrams received and sent per system call.
+    pub fn udp_batch_size(&self) -> usize {
+        self.udp_batch_size
+    }
+
+    /// Receive the batches of UDP datagrams coalesced and send their responses together with
+    /// generic receive and segmentation offload, depending on `offload`.
+    pub fn with_udp_offload(mut self, offload: UdpOffload) -> Self {
+        self.udp_offload = offload;
+        self
+    }
+
+    pub fn udp_offload(&self) -> UdpOffload {
+        self.udp_offload
+    }
+
+    /// Buffers to receive datagrams in, the responses returned by [`Server::handle`] are taken
+    /// from them too. Return them once sent.
+    pub fn buffers(&self) -> &BufferPool {
+        &self.buffers
+    }
+
+    /// Apply `config` to the sockets clients are served on.
+    pub fn with_socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    /// Options of the sockets clients are served on.
+    pub fn socket_config(&self) -> &SocketConfig {
+        &self.socket_config
+    }
+
+    /// Limit the TCP and TLS connections.
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self.connections = Arc::new(Semaphore::new(limits.max_connections));
+        self
+    }
+
+    /// Limits of the TCP and TLS connections.
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.connection_limits
+    }
+
+    /// Admit a new TCP or TLS connection from `addr`, returning the permit to keep while it's
+    /// open, or `None` when too many are open already.
+    pub fn admit_connection(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        let permit = self.connections.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.stats.connection_rejected();
+            log::debug!("too many connections open, closing the one from {:?}", addr);
+        }
+        permit
+    }
+
+    /// Expect a PROXY protocol header at the start of the connections accepted by the TCP and
+    /// TLS listeners bound to `addrs`.
+    pub fn with_proxy_protocol(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.proxy_protocol = addrs;
+        self
+    }
+
+    /// Whether connections accepted by the listener bound to `local_addr` start with a PROXY
+    /// protocol header.
+    pub fn expects_proxy_header(&self, local_addr: SocketAddr) -> bool {
+        self.proxy_protocol.contains(&local_addr)
+    }
+
+    /// Answer Binding requests without the magic cookie sent by RFC 3489 clients with a
+    /// MAPPED-ADDRESS, when `enabled`. Otherwise they are dropped like any invalid message.
+    pub fn with_rfc3489(mut self, enabled: bool) -> Self {
+        self.rfc3489 = enabled;
+        self
+    }
+
+    /// Log up to `per_source` malformed packets of each source and `max` overall a minute, the
+    /// others being summed up by source once the minute is over.
+    pub fn with_malformed_log_limits(mut self, per_source: u64, max: u64) -> Self {
+        self.malformed_log = Sampler::new("malformed packets", per_source, max);
+        self
+    }
+
+    /// Log up to `max` malformed packets a minute, keeping no state about their sources.
+    pub fn with_malformed_log_max(mut self, max: u64) -> Self {
+        self.malformed_log = Sampler::overall("malformed packets", max);
+        self
+    }
+
+    /// Add a MAPPED-ADDRESS alongside the XOR-MAPPED-ADDRESS of Binding success responses when
+    /// `enabled`, for the old clients which only look for the former.
+    pub fn with_compat_mapped_address(mut self, enabled: bool) -> Self {
+        self.compat_mapped_address = enabled;
+        self
+    }
+
+    /// Handle the packets which aren't valid STUN messages according to `policy`.
+    pub fn with_malformed_policy(mut self, policy: MalformedPolicy) -> Self {
+        self.malformed_policy = policy;
+        self
+    }
+
+    /// Whether a packet just received may be handled, according to the packet rate limit of the
+    /// server.
+    fn admit(&self) -> bool {
+        match &self.global_rate_limiter {
+            Some(limiter) => limiter.allow(),
+            None => true,
+        }
+    }
+
+    /// Count a datagram from `addr` dropped because it doesn't fit the receive buffer.
+    pub fn truncated(&self, addr: SocketAddr) {
+        self.stats.truncated();
+        log::debug!(
+            "dropping datagram from {:?} larger than {} bytes",
+            addr,
+            self.recv_buffer_size
+        );
+    }
+
+    /// Alternate addresses for NAT behavior discovery, when configured.
+    pub fn discovery(&self) -> Option<&Discovery> {
+        self.discovery.as_ref()
+    }
+
+    /// Whether the messages of `addr` are handled, i.e. it's permitted by the access control
+    /// list and the country policy, and neither blocklisted nor banned.
+    pub fn admits(&self, addr: SocketAddr) -> bool {
+        if !self.acl.read().unwrap().permits(addr.ip()) {
+            self.stats.denied();
+            log::trace!("dropping message from denied source {:?}", addr);
+            return false;
+        }
+        if let Some(blocklist) = &self.blocklist {
+            if blocklist.contains(addr.ip()) {
+                self.stats.denied();
+                log::trace!("dropping message from blocklisted source {:?}", addr);
+                return false;
+            }
+        }
+        #[cfg(feature = "feed")]
+        if let Some(deny_feed) = &self.deny_feed {
+            if deny_feed.contains(addr.ip()) {
+                self.stats.denied();
+                log::trace!("dropping message from source {:?} of the deny feed", addr);
+                return false;
+            }
+        }
+        #[cfg(feature = "geoip")]
+        if let Some(geoip) = &self.geoip {
+            if !geoip.permits(addr.ip()) {
+                self.stats.denied();
+                log::trace!(
+                    "dropping message from source {:?} of a denied country",
+                    addr
+                );
+                return false;
+            }
+        }
+        if self.state.is_banned(addr.ip()) {
+            self.stats.denied();
+            log::trace!("dropping message from banned source {:?}", addr);
+            return false;
+        }
+        true
+    }
+
+    /// Handle a message received from `source`, returning the encoded response to send back
+    /// if any. Its method and outcome are recorded in the current span, see [`Source::span`].
+    pub async fn handle(&self, buf: &[u8], source: &Source) -> Option<Vec<u8>> {
+        self.handling.fetch_add(1, Ordering::Relaxed);
+        let _handling = Handling(self);
+        if !self.admit() {
+            return None;
+        }
+        if let Some(demux) = &self.demux {
+            if source.transport == Transport::Udp && !demux.handles(buf, self.turn.is_some()) {
+                demux.forward(buf, source).await;
+                return None;
+            }
+        }
+        for handler in &self.handlers {
+            if let Verdict::Drop(outcome) = handler.pre_decode(self, buf, source) {
+                log::trace!(
+                    "{} handler dropped message from {:?}: {}",
+                    handler.name(),
+                    source.addr,
+                    outcome
+                );
+                return None;
+            }
+        }
+        if let Some(capture) = &self.capture {
+            capture.record(source.addr, source.local_addr, buf);
+        }
+        // Relayed data is limited by the bandwidth of its allocation rather than by the rate of
+        // messages.
+        if let (Some(turn), Some(message)) = (&self.turn, ChannelData::decode(buf)) {
+            turn.channel_data(&message, source).await;
+            return None;
+        }
+        if let Err(err) = message::check_header(buf, self.rfc3489) {
+            self.stats.header_rejected();
+            let logged = self.sample_malformed(source);
+            // Legacy messages are well-formed, only not served.
+            if err != HeaderError::NoMagicCookie {
+                self.fail(source, "malformed message", logged).await;
+            }
+            return self.malformed(buf, source, err.as_str(), logged);
+        }
+        if let Some(rate_limiter) = &*self.rate_limiter.read().unwrap() {
+            if !rate_limiter.allow(source.addr.ip()) {
+                return None;
+            }
+        }
+        let _in_flight = match &self.in_flight_limiter {
+            Some(limiter) => Some(limiter.acquire(source.addr.ip())?),
+            None => None,
+        };
+
+        let received_at = Instant::now();
+        let decoded = tracing::info_span!("decode").in_scope(|| Message::decode(buf));
+        let mut request = match decoded {
+            Ok(request) => request,
+            Err(err) => {
+                self.stats.decode_failure();
+                let logged = self.sample_malformed(source);
+                self.fail(source, "malformed message", logged).await;
+                return self.malformed(buf, source, &err.to_string(), logged);
+            }
+        };
+        // Only Binding existed in RFC 3489, the other methods require the magic cookie.
+        if request.is_legacy() && request.method != methods::BINDING {
+            self.stats.decode_failure();
+            log::debug!(
+                "dropping message without magic cookie from {:?}",
+                source.addr
+            );
+            return None;
+        }
+        self.stats.received(request.method, request.class);
+        let span = Span::current();
+        span.record("method", &*stats::method_label(request.method));
+
+        let (response, outcome) = match self.cached_response(&request, source) {
+            Some(response) => (Some(response), "retransmission"),
+            None => {
+                let (response, outcome) = self
+                    .respond(buf, &mut request, source, received_at)
+                    .instrument(tracing::info_span!("handle"))
+                    .await;
+                if let (Some(cache), Some(response)) = (&self.response_cache, &response) {
+                    if request.class == Class::Request {
+                        cache.insert(source.five_tuple(), request.transaction_id, response);
+                    }
+                }
+                (response, outcome)
+            }
+        };
+        span.record("outcome", outcome);
+        #[cfg(feature = "geoip")]
+        let origin = self.geoip.as_ref().map(|geoip| {
+            let origin = geoip.lookup(source.addr.ip());
+            geoip.count(&origin);
+            origin
+        });
+        #[cfg(feature = "geoip")]
+        let (country, asn) = origin.as_ref().map_or((None, None), |origin| {
+            (origin.country.as_deref(), origin.asn)
+        });
+        #[cfg(not(feature = "geoip"))]
+        let (country, asn) = (None, None);
+        if let Some(access_log) = &self.access_log {
+            access_log.record(&accesslog::Entry {
+                transport: source.transport,
+                src_addr: source.addr,
+                method: &stats::method_label(request.method),
+                class: stats::class_label(request.class),
+                outcome,
+                bytes_in: buf.len(),
+                bytes_out: response.as_ref().map_or(0, Vec::len),
+                latency: received_at.elapsed(),
+                country,
+                asn,
+            });
+        }
+        log::info!(
+            target: "stunner_server::access",
+            src_addr:% = source.addr,
+            method = &*stats::method_label(request.method),
+            class = stats::class_label(request.class),
+            txid = hex(&request.transaction_id).as_str(),
+            outcome = outcome;
+            "handled {} {} from {}: {}",
+            stats::method_label(request.method),
+            stats::class_label(request.class),
+            source.addr,
+            outcome
+        );
+        if let (Some(capture), Some(response)) = (&self.capture, &response) {
+            capture.record(source.local_addr, source.addr, response);
+        }
+        response
+    }
+
+    /// Count a malformed packet of `source`, returning whether to log its lines: they are
+    /// sampled so that floods of them don't flood the logs.
+    fn sample_malformed(&self, source: &Source) -> bool {
+        let logged = log::log_enabled!(self.malformed_log_level())
+            || self.failures.is_some()
+                && log::log_enabled!(target: "stunner_server::failures", log::Level::Warn);
+        logged && self.malformed_log.sample(source.addr.ip())
+    }
+
+    /// Handle the packet in `buf` from `source`, which isn't a valid STUN message for
+    /// `reason`, according to the malformed packet policy, logging it if `logged`. Returns the
+    /// encoded response to send back if any.
+    fn malformed(
+        &self,
+        buf: &[u8],
+        source: &Source,
+        reason: &str,
+        logged: bool,
+    ) -> Option<Vec<u8>> {
+        if logged {
+            match self.malformed_policy {
+                MalformedPolicy::Drop | MalformedPolicy::Respond => {
+                    log::debug!("malformed packet from {:?}: {}", source.addr, reason)
+                }
+                MalformedPolicy::Log => log::info!(
+                    "dropping malformed packet of {} bytes from {:?}, starting with {}: {}",
+                    buf.len(),
+                    source.addr,
+                    hex(&buf[..buf.len().min(HEADER_SIZE)]),
+                    reason
+                ),
+            }
+        }
+        if self.malformed_policy != MalformedPolicy::Respond {
+            return None;
+        }
+        let response = peek_request(buf)?.error_response(400, "Bad Request");
+        self.sent(&response, Instant::now());
+        Some(self.encode(&response, None))
+    }
+
+    /// Level malformed packets are logged at.
+    fn malformed_log_level(&self) -> log::Level {
+        match self.malformed_policy {
+            MalformedPolicy::Log => log::Level::Info,
+            _ => log::Level::Debug,
+        }
+    }
+
+    /// Response already sent to `source` for `request` if it's a retransmission, encoded in a
+    /// pooled buffer.
+    fn cached_response(&self, request: &Message, source: &Source) -> Option<Vec<u8>> {
+        let cache = self.response_cache.as_ref()?;
+        if request.class != Class::Request {
+            return None;
+        }
+        let mut buf = self.buffers.take();
+        if cache.get(source.five_tuple(), request.transaction_id, &mut buf) {
+            return Some(buf);
+        }
+        self.buffers.put(buf);
+        None
+    }
+
+    /// Count `response`, to a request received at `received_at`.
+    fn sent(&self, response: &Message, received_at: Instant) {
+        self.count_sent(response.method, response.class, received_at);
+    }
+
+    /// Count a response of `method` and `class`, to a request received at `received_at`.
+    fn count_sent(&self, method: u16, class: Class, received_at: Instant) {
+        let latency = received_at.elapsed();
+        self.stats.sent(method, class, latency);
+        if let Some(statsd) = &self.statsd {
+            statsd.time(latency);
+        }
+    }
+
+    /// Respond to a decoded message, returning the encoded response to send back if any and
+    /// a short description of the outcome.
+    async fn respond(
+        &self,
+        buf: &[u8],
+        request: &mut Message,
+        source: &Source,
+        received_at: Instant,
+    ) -> (Option<Vec<u8>>, &'static str) {
+        // Not holding the locks while users are looked up.
+        let realms = self.realms.read().unwrap().clone();
+        let mut transaction = Transaction {
+            source,
+            buf,
+            realm: realm::select(&realms, request, source.local_addr),
+            credentials: None,
+        };
+        for handler in &self.handlers {
+            match handler.post_decode(self, request, &mut transaction).await {
+                Verdict::Continue => {}
+                Verdict::Drop(outcome) => return (None, outcome),
+                Verdict::Respond(mut response, outcome) => {
+                    self.pre_send(request, &mut response, &transaction);
+                    self.sent(&response, received_at);
+                    let bytes = self.encode(&response, transaction.credentials.as_ref());
+                    return (Some(bytes), outcome);
+                }
+            }
+        }
+
+        if self.answers_from_template(request, &transaction) {
+            let _span = tracing::info_span!("encode").entered();
+            let mut bytes = self.buffers.take();
+            message::encode_binding_success(&request.transaction_id, source.addr, &mut bytes);
+            if self.fingerprint {
+                append_fingerprint(&mut bytes);
+            }
+            self.count_sent(methods::BINDING, Class::SuccessResponse, received_at);
+            return (Some(bytes), "success");
+        }
+
+        let username = transaction
+            .credentials
+            .as_ref()
+            .map(|credentials| credentials.username.as_str());
+        let bandwidth = transaction
+            .realm
+            .and_then(|realm| realm.relay_bandwidth(username));
+        // The realm of the credentials, that allocations are counted by.
+        let auth = match (username, transaction.realm) {
+            (None, _) => None,
+            (Some(_), Some(realm)) => Some(realm.auth().clone()),
+            (Some(_), None) => self.auth.read().unwrap().clone(),
+        };
+        let realm = auth.as_deref().and_then(Auth::realm);
+        let mut response = match self
+            .dispatch(buf, request, source, realm, username, bandwidth)
+            .await
+        {
+            Some(response) => response,
+            None => return (None, "no_response"),
+        };
+        let mut redirect = None;
+        if (response.method, response.class) == (methods::BINDING, Class::SuccessResponse) {
+            (response, redirect) = self.route_binding(request, response, source);
+        }
+        self.attributes.apply(request, &mut response);
+        self.pre_send(request, &mut response, &transaction);
+        let bytes = self.encode(&response, transaction.credentials.as_ref());
+        self.sent(&response, received_at);
+        let outcome = match response.class {
+            Class::ErrorResponse => "error",
+            _ => "success",
+        };
+        match redirect {
+            Some((sock, addr, local_addr)) => {
+                if let Err(err) = net::send_to(&sock, &bytes, addr, local_addr).await {
+                    log::debug!("could not send response to {:?}: {}", addr, err);
+                }
+                (None, outcome)
+            }
+            None => (Some(bytes), outcome),
+        }
+    }
+
+    /// Whether the response to `request` is a Binding success response carrying nothing but a
+    /// XOR-MAPPED-ADDRESS, which is then encoded from a template rather than built and encoded.
+    fn answers_from_template(&self, request: &Message, transaction: &Transaction) -> bool {
+        (request.method, request.class) == (methods::BINDING, Class::Request)
+            && request.attributes.is_empty()
+            && !request.is_legacy()
+            && transaction.credentials.is_none()
+            && self.discovery.is_none()
+            && !self.compat_mapped_address
+            && self.attributes.is_empty()
+            && !self.handlers.iter().any(|handler| handler.sees_responses())
+    }
+
+    /// Pass `response` through the handlers before it's encoded.
+    fn pre_send(&self, request: &Message, response: &mut Message, transaction: &Transaction) {
+        if self.compat_mapped_address
+            && (response.method, response.class) == (methods::BINDING, Class::SuccessResponse)
+            && response.get(attributes::MAPPED_ADDRESS).is_none()
+        {
+            if let Some(addr) = response.get_xor_address(attributes::XOR_MAPPED_ADDRESS) {
+                response.attributes.push(Attribute {
+                    kind: attributes::MAPPED_ADDRESS,
+                    value: message::encode_address(addr),
+                });
+            }
+        }
+        for handler in &self.handlers {
+            handler.pre_send(request, response, transaction);
+        }
+    }
+
+    /// Whether requests of `transaction` are authenticated, with the credentials of its realm or
+    /// of the server.
+    pub fn authenticates(&self, transaction: &Transaction) -> bool {
+        transaction.realm.is_some() || self.auth.read().unwrap().is_some()
+    }
+
+    /// Whether the TURN relay is enabled without the Allocate requests being authenticated
+    /// with long-term credentials, as required by
+    /// https://datatracker.ietf.org/doc/html/rfc5766#section-6.2: anyone could relay through
+    /// the server.
+    pub fn open_relay(&self) -> bool {
+        let long_term_auth = matches!(
+            self.auth.read().unwrap().as_deref(),
+            Some(Auth::LongTerm(_))
+        );
+        let auth_handler = self.handlers.iter().any(|handler| handler.name() == "auth");
+        self.turn.is_some() && !(long_term_auth && auth_handler)
+    }
+
+    /// Authenticate `request` with the credentials of the realm of `transaction`, or of the
+    /// server, returning the credentials it was authenticated with if any are configured or
+    /// the error response to send back.
+    pub async fn authenticate(
+        &self,
+        request: &Message,
+        transaction: &Transaction<'_>,
+    ) -> Result<Option<Credentials>, Message> {
+        let source = transaction.source;
+        let auth = match transaction.realm {
+            Some(realm) => realm.auth().clone(),
+            None => match self.auth.read().unwrap().clone() {
+                Some(auth) => auth,
+                None => return Ok(None),
+            },
+        };
+        match auth
+            .authenticate(request, transaction.buf, source.addr.ip())
+            .await
+        {
+            Ok(credentials) => Ok(Some(credentials)),
+            Err(response) => {
+                log::debug!("rejected {:?} from {:?}", request, source.addr);
+                // Challenging a request without credentials is part of authenticating.
+                let failed = match response.error_code() {
+                    Some(400) => true,
+                    Some(401) => Integrity::of(request).is_some(),
+                    _ => false,
+                };
+                if failed {
+                    self.fail(source, "authentication failure", true).await;
+                }
+                Err(response)
+            }
+        }
+    }
+
+    /// When banning, log a failure of `source` in a format stable for fail2ban if `logged`, and
+    /// ban it once it failed too often.
+    async fn fail(&self, source: &Source, failure: &str, logged: bool) {
+        let Some(failures) = &self.failures else {
+            return;
+        };
+        let ip = source.addr.ip();
+        if logged {
+            log::warn!(
+                target: "stunner_server::failures",
+                src_addr:% = source.addr,
+                failure = failure;
+                "{} from {}",
+                failure,
+                ip
+            );
+        }
+        let duration = match failures.fail(ip) {
+            Some(duration) => duration,
+            None => return,
+        };
+        match self.state.ban(ip, duration).await {
+            Ok(()) => log::warn!(
+                target: "stunner_server::failures",
+                "banned {} for {}s",
+                ip,
+                duration.as_secs()
+            ),
+            Err(err) => log::error!("could not ban {}: {:#}", ip, err),
+        }
+    }
+
+    /// Number of open TCP and TLS connections.
+    pub fn connection_count(&self) -> usize {
+        self.connection_limits.max_connections - self.connections.available_permits()
+    }
+
+    /// Statistics of the server in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        self.stats.render(&mut out);
+        if let Some(rate_limiter) = &*self.rate_limiter.read().unwrap() {
+            stats::render_counter(
+                &mut out,
+                "stunner_rate_limited_total",
+                "Messages dropped by the per source IP rate limit.",
+                rate_limiter.dropped(),
+            );
+        }
+        if let Some(global_rate_limiter) = &self.global_rate_limiter {
+            stats::render_counter(
+                &mut out,
+                "stunner_global_rate_limited_total",
+                "Packets dropped by the packet rate limit of the server.",
+                global_rate_limiter.dropped(),
+            );
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(xdp) = &self.xdp {
+            stats::render_counter(
+                &mut out,
+                "stunner_xdp_answered_total",
+                "Binding requests answered in the kernel.",
+                xdp.answered(),
+            );
+        }
+        if let Some(in_flight_limiter) = &self.in_flight_limiter {
+            stats::render_counter(
+                &mut out,
+                "stunner_in_flight_limited_total",
+                "Messages dropped by the per source IP limit of messages in flight.",
+                in_flight_limiter.dropped(),
+            );
+        }
+        stats::render_gauge(
+            &mut out,
+            "stunner_connections",
+            "Open TCP and TLS connections.",
+            self.connection_count() as u64,
+        );
+        if let Some(blocklist) = &self.blocklist {
+            stats::render_gauge(
+                &mut out,
+                "stunner_blocklist_ranges",
+                "IP ranges denied by the blocklist file.",
+                blocklist.range_count() as u64,
+            );
+        }
+        #[cfg(feature = "feed")]
+        if let Some(deny_feed) = &self.deny_feed {
+            stats::render_gauge(
+                &mut out,
+                "stunner_deny_feed_ranges",
+                "IP ranges denied by the threat intelligence feed.",
+                deny_feed.range_count() as u64,
+            );
+        }
+        if let Some(response_cache) = &self.response_cache {
+            stats::render_counter(
+                &mut out,
+                "stunner_retransmissions_total",
+                "Retransmitted requests answered with the response cached for them.",
+                response_cache.hits(),
+            );
+        }
+        if let Some(access_log) = &self.access_log {
+            stats::render_counter(
+                &mut out,
+                "stunner_access_log_dropped_total",
+                "Access log entries dropped as they couldn't be written fast enough.",
+                access_log.dropped(),
+            );
+        }
+        #[cfg(feature = "geoip")]
+        if let Some(geoip) = &self.geoip {
+            geoip.render(&mut out);
+        }
+        if let Some(failures) = &self.failures {
+            stats::render_counter(
+                &mut out,
+                "stunner_failure_bans_total",
+                "Sources banned for failing to authenticate or sending malformed messages.",
+                failures.bans(),
+            );
+        }
+        if let Some(turn) = &self.turn {
+            stats::render_gauge(
+                &mut out,
+                "stunner_turn_allocations",
+                "Active TURN allocations.",
+                turn.allocation_count() as u64,
+            );
+            stats::render_counter(
+                &mut out,
+                "stunner_turn_unknown_sources_total",
+                "TURN messages from clients without the allocation they require.",
+                turn.unknown_sources(),
+            );
+            stats::render_counter(
+                &mut out,
+                "stunner_turn_forbidden_peers_total",
+                "TURN requests refused as their peer isn't permitted.",
+                turn.forbidden_peers(),
+            );
+            stats::render_counter(
+                &mut out,
+                "stunner_turn_ports_exhausted_total",
+                "TURN allocations refused as every port of the relay port range was in use.",
+                turn.ports_exhausted(),
+            );
+            stats::render_labeled_counter(
+                &mut out,
+                "stunner_turn_dropped_packets_total",
+                "Datagrams of relayed data dropped.",
+                "reason",
+                DropReason::ALL.map(|reason| (reason.as_str(), turn.dropped().get(reason))),
+            );
+            let (realms, users) = turn.allocations_by_owner();
+            stats::render_labeled_gauge(
+                &mut out,
+                "stunner_turn_realm_allocations",
+                "Active TURN allocations of the clients that authenticated, by realm.",
+                "realm",
+                realms.iter().map(|(realm, count)| (realm.as_str(), *count)),
+            );
+            stats::render_labeled_gauge(
+                &mut out,
+                "stunner_turn_user_allocations",
+                "Active TURN allocations of the clients that authenticated, by username.",
+                "user",
+                users.iter().map(|(user, count)| (user.as_str(), *count)),
+            );
+            let relayed = turn.relayed();
+            stats::render_labeled_counter(
+                &mut out,
+                "stunner_turn_relayed_bytes_total",
+                "Bytes of data relayed between clients and peers.",
+                "direction",
+                Direction::ALL.map(|direction| (direction.as_str(), relayed.bytes(direction))),
+            );
+            stats::render_labeled_counter(
+                &mut out,
+                "stunner_turn_relayed_packets_total",
+                "Datagrams, or chunks over TCP, of data relayed between clients and peers.",
+                "direction",
+                Direction::ALL.map(|direction| (direction.as_str(), relayed.packets(direction))),
+            );
+            turn.lifetimes().render(
+                &mut out,
+                "stunner_turn_allocation_lifetime_seconds",
+                "How long the deleted TURN allocations lasted.",
+            );
+        }
+        out
+    }
+
+    /// Complete a Binding success response for NAT behavior discovery, returning the socket,
+    /// address and local address to send it with when it doesn't simply go back the way the
+    /// request came from.
+    fn route_binding(
+        &self,
+        request: &Message,
+        response: Message,
+        source: &Source,
+    ) -> (Message, Option<(Arc<UdpSocket>, SocketAddr, SocketAddr)>) {
+        let (response, origin) = match &self.discovery {
+            Some(discovery) => discovery.respond(request, response, source),
+            None => (response, None),
+        };
+        // RESPONSE-PORT only makes sense over UDP,
+        // see https://datatracker.ietf.org/doc/html/rfc5780#section-7.5
+        let port = match (source.transport, request.get(attributes::RESPONSE_PORT)) {
+            (Transport::Udp, Some([high, low, ..])) => Some(u16::from_be_bytes([*high, *low])),
+            _ => None,
+        };
+        if origin.is_none() && port.is_none() {
+            return (response, None);
+        }
+
+        let (sock, local_addr) = match (origin, &source.sink) {
+            // The discovery sockets are bound to specific addresses, left to the system to pick.
+            (Some(sock), _) => (sock, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+            (None, Sink::Datagram(sock)) => (sock.clone(), source.local_addr),
+            (None, Sink::Stream(_)) => return (response, None),
+        };
+        let mut addr = source.addr;
+        if let Some(port) = port {
+            addr.set_port(port);
+        }
+        (response, Some((sock, addr, local_addr)))
+    }
+
+    /// Encode a response, signed with the key the request was authenticated with if any.
+    fn encode(&self, response: &Message, credentials: Option<&Credentials>) -> Vec<u8> {
+        let _span = tracing::info_span!("encode").entered();
+        let mut bytes = self.buffers.take();
+        response.encode_into(&mut bytes);
+        if let Some(credentials) = credentials {
+            credentials.integrity.append(&mut bytes, &credentials.key);
+        }
+        if self.fingerprint {
+            append_fingerprint(&mut bytes);
+        }
+        bytes
+    }
+
+    /// Handle a message with the service of its method, authenticated as `username` in `realm`
+    /// if given. The allocations it creates relay at most `bandwidth` bytes per second if given.
+    async fn dispatch(
+        &self,
+        buf: &[u8],
+        request: &Message,
+        source: &Source,
+        realm: Option<&str>,
+        username: Option<&str>,
+        bandwidth: Option<u64>,
+    ) -> Option<Message> {
+        if (request.method, request.class) == (methods::ALLOCATE, Class::Request)
+            && self.turn.is_some()
+            && self.draining.load(Ordering::Relaxed)
+        {
+            return Some(request.error_response(508, "Server shutting down"));
+        }
+        match (peek_method(buf), &self.turn) {
+            (Some(method), Some(turn)) if method != methods::BINDING => {
+                turn.handle(buf, source, realm, username, bandwidth).await
+            }
+            _ => parse_message(buf, source.addr, &self.attributes),
+        }
+    }
+
+    /// Release the state kept for a connection oriented source once its connection is closed.
+    pub fn disconnected(&self, source: &Source) {
+        if let Some(turn) = &self.turn {
+            turn.release(&source.five_tuple());
+        }
+    }
+
+    /// Take the TURN peer data connection bound to the client data connection of `source` by a
+    /// ConnectionBind request, if any.
+    pub fn take_peer_connection(&self, source: &Source) -> Option<PeerConnection> {
+        self.turn.as_ref()?.take_bound(&source.five_tuple())
+    }
+
+    /// Refuse new TURN allocations and wait up to `timeout` for the existing ones to be
+    /// released or to expire, keeping relaying their data meanwhile. Refreshes don't extend the
+    /// allocations beyond the timeout.
+    pub async fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::Relaxed);
+        let turn = match &self.turn {
+            Some(turn) => turn,
+            None => return,
+        };
+        let deadline = Instant::now() + timeout;
+        turn.drain(deadline);
+        if turn.allocation_count() > 0 && !timeout.is_zero() {
+            log::info!(
+                "draining {} TURN allocations for up to {:?}",
+                turn.allocation_count(),
+                timeout
+            );
+        }
+        while turn.allocation_count() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                log::info!(
+                    "{} TURN allocations still active, giving up on draining",
+                    turn.allocation_count()
+                );
+                return;
+            }
+            tokio::time::sleep(HOUSEKEEPING_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// Mark the server as ready to serve clients, once every listener is bound.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Progress of the handling of messages, by every transport.
+    pub fn liveness(&self) -> Liveness {
+        Liveness {
+            handling: self.handling.load(Ordering::Relaxed),
+            handled: self.handled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the server is serving clients, and not shutting down.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed) && !self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Ask the tasks serving clients to stop, see [`Server::stopped`].
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Wait until the server is shutting down. Serving tasks stop receiving new messages,
+    /// send the responses in flight and return.
+    pub async fn stopped(&self) {
+        self.shutdown.wait().await;
+    }
+
+    /// One line summary of the activity of the server.
+    pub fn summary(&self) -> String {
+        let (received, sent) = self.stats.totals();
+        format!("received {} messages and sent {} responses", received, sent)
+    }
+
+    /// The configured features keeping state about clients between their messages, none of
+    /// which may be used when every response must be derivable from its request alone, e.g.
+    /// when instances in several regions share an anycast address.
+    pub fn stateful_features(&self) -> Vec<&'static str> {
+        let long_term_auth = matches!(
+            self.auth.read().unwrap().as_deref(),
+            Some(Auth::LongTerm(_))
+        ) || !self.realms.read().unwrap().is_empty();
+        [
+            ("TURN relay", self.turn.is_some()),
+            ("long-term credentials", long_term_auth),
+            ("response cache", self.response_cache.is_some()),
+            (
+                "per IP rate limit",
+                self.rate_limiter.read().unwrap().is_some(),
+            ),
+            ("per IP in-flight limit", self.in_flight_limiter.is_some()),
+            ("bans", self.failures.is_some()),
+            ("demultiplexing", self.demux.is_some()),
+            ("per source log sampling", self.malformed_log.by_source()),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect()
+    }
+
+    /// The features the Binding requests answered in the kernel would escape, which can't be
+    /// configured along with XDP.
+    pub fn kernel_bypassed_features(&self) -> Vec<&'static str> {
+        let auth = self.auth.read().unwrap().is_some() || !self.realms.read().unwrap().is_empty();
+        #[cfg(feature = "geoip")]
+        let country_policy = self.geoip.as_ref().is_some_and(GeoIp::has_country_policy);
+        #[cfg(not(feature = "geoip"))]
+        let country_policy = false;
+        #[cfg(feature = "feed")]
+        let deny_feed = self.deny_feed.is_some();
+        #[cfg(not(feature = "feed"))]
+        let deny_feed = false;
+        [
+            ("authentication", auth),
+            ("FINGERPRINT", self.fingerprint),
+            ("NAT behavior discovery", self.discovery.is_some()),
+            ("MAPPED-ADDRESS", self.compat_mapped_address),
+            ("attribute handlers", !self.attributes.is_empty()),
+            (
+                "response handlers",
+                self.handlers.iter().any(|handler| handler.sees_responses()),
+            ),
+            ("access control lists", !self.acl.read().unwrap().is_empty()),
+            ("blocklist", self.blocklist.is_some()),
+            ("country policy", country_policy),
+            ("deny feed", deny_feed),
+            (
+                "per IP rate limit",
+                self.rate_limiter.read().unwrap().is_some(),
+            ),
+            ("per IP in-flight limit", self.in_flight_limiter.is_some()),
+            ("packet rate limit", self.global_rate_limiter.is_some()),
+            ("bans", self.failures.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect()
+    }
+
+    /// Time since the server was created.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Every counter on a single line, for logs.
+    pub fn dump(&self) -> String {
+        let mut dump = format!(
+            "uptime={}s connections={} {}",
+            self.uptime().as_secs(),
+            self.connection_count(),
+            self.stats.dump()
+        );
+        let limiters = [
+            (
+                "rate_limited",
+                self.rate_limiter
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|limiter| limiter.dropped()),
+            ),
+            (
+                "in_flight_limited",
+                self.in_flight_limiter
+                    .as_ref()
+                    .map(|limiter| limiter.dropped()),
+            ),
+            (
+                "global_rate_limited",
+                self.global_rate_limiter
+                    .as_ref()
+                    .map(|limiter| limiter.dropped()),
+            ),
+        ];
+        for (name, dropped) in limiters {
+            if let Some(dropped) = dropped {
+                dump += &format!(" {}={}", name, dropped);
+            }
+        }
+        if let Some(turn) = &self.turn {
+            dump += &format!(
+                " allocations={} unknown_sources={}",
+                turn.allocation_count(),
+                turn.unknown_sources()
+            );
+            for reason in DropReason::ALL {
+                dump += &format!(
+                    " dropped_{}={}",
+                    reason.as_str(),
+                    turn.dropped().get(reason)
+                );
+            }
+        }
+        dump
+    }
+
+    /// Periodically expire the state kept by the server, until it shuts down.
+    pub async fn housekeeping(self: Arc<Self>) -> Result<()> {
+        let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        let mut sync = tokio::time::interval(state::SYNC_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = sync.tick() => {
+                    if let Err(err) = self.state.sync().await {
+                        log::warn!("could not synchronize the shared state: {:#}", err);
+                    }
+                    continue;
+                }
+                _ = self.stopped() => return Ok(()),
+            }
+            if let Some(turn) = &self.turn {
+                turn.expire();
+            }
+            if let Some(rate_limiter) = &*self.rate_limiter.read().unwrap() {
+                rate_limiter.expire();
+            }
+            if let Some(failures) = &self.failures {
+                failures.expire();
+            }
+            if let Some(response_cache) = &self.response_cache {
+                response_cache.expire();
+            }
+            if let Some(demux) = &self.demux {
+                demux.expire();
+            }
+            for line in self.malformed_log.flush() {
+                log::log!(self.malformed_log_level(), "{}", line);
+            }
+            if let Some(blocklist) = &self.blocklist {
+                match blocklist.refresh() {
+                    Ok(true) => log::info!(
+                        "reloaded the blocklist, {} ranges denied",
+                        blocklist.range_count()
+                    ),
+                    Ok(false) => {}
+                    Err(err) => log::error!(
+                        "could not reload the blocklist, keeping the current one: {:#}",
+                        err
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{MalformedPolicy, Server, Sink, Source, Transport};
+    use crate::auth::{Auth, Credentials, LongTermAuth};
+    use crate::failures::Failures;
+    use crate::handler::{Builtin, Transaction};
+    use crate::message::{attributes, methods, Class, Integrity, Message};
+    use crate::ratelimit::{GlobalRateLimiter, RateLimiter};
+    use crate::realm;
+    use crate::transactions::ResponseCache;
+    use crate::turn::Turn;
+
+    thread_local! {
+        /// Records logged on this thread, once capturing.
+        static RECORDS: RefCell<Option<Vec<(String, String)>>> = const { RefCell::new(None) };
+    }
+
+    /// Keeps the target and line of the records logged on the threads capturing them.
+    struct Capture;
+
+    impl log::Log for Capture {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            RECORDS.with(|records| records.borrow().is_some())
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS.with(|records| {
+                if let Some(records) = &mut *records.borrow_mut() {
+                    records.push((record.target().to_string(), record.args().to_string()));
+                }
+            })
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Capture the records logged on this thread from now on.
+    fn capture_logs() {
+        static CAPTURE: Capture = Capture;
+        if log::set_logger(&CAPTURE).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        RECORDS.with(|records| *records.borrow_mut() = Some(Vec::new()));
+    }
+
+    /// The target and line of the records captured on this thread.
+    fn captured() -> Vec<(String, String)> {
+        RECORDS.with(|records| records.borrow().clone().unwrap_or_default())
+    }
+
+    /// A client over UDP, answered on a loopback socket.
+    async fn udp_source() -> Source {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        Source {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock),
+        }
+    }
+
+    #[test]
+    fn dumps_every_counter() {
+        let server = Server::default().with_rate_limiter(Some(RateLimiter::new(10)));
+        let dump = server.dump();
+        assert!(
+            dump.starts_with("uptime=0s connections=0 messages_received=0"),
+            "{}",
+            dump
+        );
+        assert!(dump.ends_with(" rate_limited=0"), "{}", dump);
+        assert!(!dump.contains("in_flight_limited"), "{}", dump);
+    }
+
+    #[test]
+    fn lists_the_stateful_features() {
+        assert_eq!(
+            Server::default().stateful_features(),
+            vec!["per source log sampling"]
+        );
+        let stateless = Server::default().with_malformed_log_max(100);
+        assert!(stateless.stateful_features().is_empty());
+        let server = stateless
+            .with_turn(Some(Turn::new("127.0.0.1".parse().unwrap())))
+            .with_rate_limiter(Some(RateLimiter::new(10)));
+        assert_eq!(
+            server.stateful_features(),
+            vec!["TURN relay", "per IP rate limit"]
+        );
+    }
+
+    #[test]
+    fn lists_the_features_bypassed_in_the_kernel() {
+        assert!(Server::default().kernel_bypassed_features().is_empty());
+        let server = Server::default()
+            .with_fingerprint(true)
+            .with_global_rate_limiter(Some(GlobalRateLimiter::new(10, 10)));
+        assert_eq!(
+            server.kernel_bypassed_features(),
+            vec!["FINGERPRINT", "packet rate limit"]
+        );
+    }
+
+    #[tokio::test]
+    async fn samples_the_lines_of_malformed_packets() {
+        capture_logs();
+        let server = Server::default()
+            .with_malformed_log_limits(3, 100)
+            .with_failures(Some(Failures::new(
+                1000,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            )));
+        let source = udp_source().await;
+        for _ in 0..20 {
+            assert!(server.handle(&[0xFF; 20], &source).await.is_none());
+        }
+
+        let records = captured();
+        let failures = records
+            .iter()
+            .filter(|(target, _)| target == "stunner_server::failures")
+            .count();
+        let details = records
+            .iter()
+            .filter(|(_, line)| line.starts_with("malformed packet from"))
+            .count();
+        assert_eq!((failures, details), (3, 3), "{:?}", records);
+    }
+
+    #[tokio::test]
+    async fn refuses_unauthenticated_allocations() {
+        let turn = || Some(Turn::new("127.0.0.1".parse().unwrap()));
+        assert!(Server::default().with_turn(turn()).open_relay());
+        let auth = || {
+            let users = [("user".to_string(), "pass".to_string())].into();
+            Some(Auth::LongTerm(Box::new(LongTermAuth::new(
+                "stunner".into(),
+                users,
+            ))))
+        };
+        let unchecked = Server::default()
+            .with_turn(turn())
+            .with_auth(auth())
+            .with_handlers(vec![Builtin::Acl.handler()]);
+        assert!(unchecked.open_relay());
+        let server = Server::default().with_turn(turn()).with_auth(auth());
+        assert!(!server.open_relay());
+
+        let source = udp_source().await;
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        assert_eq!(Message::decode(&response).unwrap().error_code(), Some(401));
+        assert!(server.turn().unwrap().allocations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_allocations_while_draining() {
+        let server = Server::default().with_turn(Some(Turn::new("127.0.0.1".parse().unwrap())));
+        let source = udp_source().await;
+        server.drain(Duration::ZERO).await;
+
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        assert_eq!(Message::decode(&response).unwrap().error_code(), Some(508));
+    }
+
+    #[tokio::test]
+    async fn answers_retransmissions_from_the_response_cache() {
+        let server = Server::default()
+            .with_turn(Some(Turn::new("127.0.0.1".parse().unwrap())))
+            .with_response_cache(Some(ResponseCache::new(Duration::from_secs(40))));
+        let source = udp_source().await;
+        let allocate = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+        assert!(server.handle(&allocate.encode(), &source).await.is_some());
+
+        // Deleting the allocation isn't idempotent, the retransmission succeeds all the same.
+        let delete = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_attribute(attributes::LIFETIME, vec![0; 4]);
+        let response = server.handle(&delete.encode(), &source).await.unwrap();
+        assert_eq!(
+            Message::decode(&response).unwrap().class,
+            Class::SuccessResponse
+        );
+        let retransmitted = server.handle(&delete.encode(), &source).await.unwrap();
+        assert_eq!(retransmitted, response);
+        assert!(server
+            .metrics()
+            .contains("stunner_retransmissions_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn drops_rfc3489_requests_when_disabled() {
+        let source = udp_source().await;
+        let request = Message {
+            cookie: 0x0102_0304,
+            ..Message::with_random_transaction_id(methods::BINDING, Class::Request)
+        };
+
+        let server = Server::default();
+        assert!(server.handle(&request.encode(), &source).await.is_some());
+        let server = Server::default().with_rfc3489(false);
+        assert!(server.handle(&request.encode(), &source).await.is_none());
+        assert!(server
+            .metrics()
+            .contains("stunner_header_rejects_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn answers_binding_requests_from_a_template() {
+        let source = udp_source().await;
+        let server = Server::default().with_fingerprint(true);
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let mut transaction = Transaction {
+            source: &source,
+            buf: &[],
+            realm: None,
+            credentials: None,
+        };
+        assert!(server.answers_from_template(&request, &transaction));
+
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        // Decoding checks the FINGERPRINT.
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+        assert!(server.summary().contains("sent 1 responses"));
+
+        // Requests with attributes or credentials get a response built for them.
+        let with_attribute = request
+            .clone()
+            .add_attribute(attributes::RESPONSE_PORT, vec![0, 1, 0, 0]);
+        assert!(!server.answers_from_template(&with_attribute, &transaction));
+        transaction.credentials = Some(Credentials {
+            username: "user".into(),
+            key: vec![],
+            integrity: Integrity::Sha1,
+        });
+        assert!(!server.answers_from_template(&request, &transaction));
+    }
+
+    #[tokio::test]
+    async fn adds_mapped_address_when_configured() {
+        let source = udp_source().await;
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+
+        let server = Server::default().with_compat_mapped_address(true);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+        assert_eq!(
+            response.get_address(attributes::MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+
+        let response = Server::default()
+            .handle(&request.encode(), &source)
+            .await
+            .unwrap();
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(response.get(attributes::MAPPED_ADDRESS), None);
+    }
+
+    #[tokio::test]
+    async fn answers_malformed_requests_when_configured() {
+        let source = udp_source().await;
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let mut malformed = request.encode();
+        // An attribute exceeding the message.
+        malformed.extend_from_slice(&[0x80, 0x22, 0, 8, 0, 0, 0, 0]);
+        malformed[3] = 8;
+
+        assert!(Server::default()
+            .handle(&malformed, &source)
+            .await
+            .is_none());
+        let server = Server::default().with_malformed_policy(MalformedPolicy::Respond);
+        let response = server.handle(&malformed, &source).await.unwrap();
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(response.error_code(), Some(400));
+        // Packets of other protocols are still dropped.
+        assert!(server.handle(&[0xC0; 40], &source).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn challenges_in_the_realm_of_the_listener() {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local_addr = sock.local_addr().unwrap();
+        let path =
+            std::env::temp_dir().join(format!("stunner-realm-{}.conf", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            format!(
+                "realm = acme\nusers = alice=pass\ndeny = 127.0.0.2\nlistener = {}\n",
+                local_addr
+            ),
+        )
+        .unwrap();
+        let realms = realm::load(
+            std::slice::from_ref(&path),
+            Duration::from_secs(60),
+            [0; 16],
+        )
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+        let server = Server::default().with_realms(realms);
+        let source = |addr: &str| Source {
+            addr: addr.parse().unwrap(),
+            local_addr,
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock.clone()),
+        };
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+
+        let response = server
+            .handle(&request.encode(), &source("127.0.0.1:1"))
+            .await
+            .unwrap();
+        let response = Message::decode(&response).unwrap();
+        assert_eq!(response.error_code(), Some(401));
+        assert_eq!(response.get_str(attributes::REALM), Some("acme"));
+        assert!(server
+            .handle(&request.encode(), &source("127.0.0.2:1"))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn encodes_responses_in_pooled_buffers() {
+        let server = Server::default();
+        let source = udp_source().await;
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        let ptr = response.as_ptr();
+        server.buffers().put(response);
+        let response = server.handle(&request.encode(), &source).await.unwrap();
+        assert_eq!(response.as_ptr(), ptr);
+        assert_eq!(
+            Message::decode(&response).unwrap().transaction_id,
+            request.transaction_id
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_binding_response_to_response_port() {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let source = Source {
+            addr: client.local_addr().unwrap(),
+            local_addr: sock.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock.clone()),
+        };
+
+        let port = other.local_addr().unwrap().port();
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(
+                attributes::RESPONSE_PORT,
+                vec![(port >> 8) as u8, port as u8, 0, 0],
+            );
+        let server = Server::default();
+        assert!(server.handle(&request.encode(), &source).await.is_none());
+
+        let mut buf = [0; 1024];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(5), other.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, sock.local_addr().unwrap());
+        let response = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+    }
+}
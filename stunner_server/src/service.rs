@@ -0,0 +1,332 @@
+//! Running as a Windows service, started and stopped by the service control manager, with the
+//! log records reported to the Application event log as there is no console to write them to.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::ArgEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio::sync::oneshot;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::{ERROR_SUCCESS, HANDLE, WIN32_ERROR};
+use windows_sys::Win32::System::EventLog::{
+    RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+    EVENTLOG_WARNING_TYPE,
+};
+use windows_sys::Win32::System::Registry::{
+    RegCloseKey, RegCreateKeyExW, RegDeleteKeyW, RegSetValueExW, HKEY, HKEY_LOCAL_MACHINE,
+    KEY_SET_VALUE, REG_DWORD, REG_EXPAND_SZ, REG_OPTION_NON_VOLATILE,
+};
+
+use crate::Cli;
+
+/// Name of the service, and source of its events in the event log.
+const NAME: &str = "stunner";
+
+/// Account the installed service runs as, which can bind ports but has few other privileges.
+const ACCOUNT: &str = r"NT AUTHORITY\LocalService";
+
+/// Registry key of the source of the events of the service in the Application event log.
+const EVENT_SOURCE_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application\stunner";
+
+/// Message file of the events, whose messages 1 to 1000 are the string reported with the event,
+/// so that the event viewer shows the log records as they are.
+const EVENT_MESSAGE_FILE: &str = r"%SystemRoot%\System32\EventCreate.exe";
+
+/// Identifier of the events, one of the messages of [`EVENT_MESSAGE_FILE`].
+const EVENT_ID: u32 = 1;
+
+/// Time the service control manager is told stopping can take, on top of the drain timeout.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+/// What to do with the Windows service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Command {
+    /// Install the service, started with the system.
+    Install,
+    /// Stop the service if running and uninstall it.
+    Uninstall,
+    /// Run as the service, when started by the service control manager.
+    Run,
+}
+
+/// Carry out `command`.
+pub fn main(command: Command) -> Result<()> {
+    match command {
+        Command::Install => install(),
+        Command::Uninstall => uninstall(),
+        Command::Run => service_dispatcher::start(NAME, ffi_service_main)
+            .context("could not connect to the service control manager"),
+    }
+}
+
+/// Install the service, running the current executable with the arguments it was given.
+fn install() -> Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: NAME.into(),
+        display_name: "Stunner STUN and TURN server".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: launch_arguments(std::env::args_os().skip(1)),
+        dependencies: Vec::new(),
+        account_name: Some(ACCOUNT.into()),
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .context("could not install the service")?;
+    service.set_description("Serves STUN and TURN to clients behind NATs")?;
+    register_event_source().context("could not register the source of the events")?;
+    println!("installed the {} service", NAME);
+    Ok(())
+}
+
+/// Stop the service if it's running and uninstall it.
+fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .context("could not open the service")?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop().context("could not stop the service")?;
+    }
+    service
+        .delete()
+        .context("could not uninstall the service")?;
+    if let Err(err) = unregister_event_source() {
+        eprintln!("could not unregister the source of the events: {}", err);
+    }
+    println!("uninstalled the {} service", NAME);
+    Ok(())
+}
+
+/// Register the service as the source of its events in the Application event log.
+fn register_event_source() -> io::Result<()> {
+    let key_name = wide(OsStr::new(EVENT_SOURCE_KEY));
+    let mut key: HKEY = ptr::null_mut();
+    // SAFETY: the key name is a null terminated wide string and `key` receives the handle.
+    check(unsafe {
+        RegCreateKeyExW(
+            HKEY_LOCAL_MACHINE,
+            key_name.as_ptr(),
+            0,
+            ptr::null(),
+            REG_OPTION_NON_VOLATILE,
+            KEY_SET_VALUE,
+            ptr::null(),
+            &mut key,
+            ptr::null_mut(),
+        )
+    })?;
+    let message_file = wide(OsStr::new(EVENT_MESSAGE_FILE));
+    let types_supported =
+        (EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE) as u32;
+    let set = |name: &str, kind, data: &[u8]| {
+        let name = wide(OsStr::new(name));
+        // SAFETY: the handle is open, the name is a null terminated wide string and the data
+        // is of the given length.
+        check(unsafe {
+            RegSetValueExW(
+                key,
+                name.as_ptr(),
+                0,
+                kind,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        })
+    };
+    let message_file: Vec<u8> = message_file.iter().flat_map(|c| c.to_le_bytes()).collect();
+    let result = set("EventMessageFile", REG_EXPAND_SZ, &message_file)
+        .and_then(|()| set("TypesSupported", REG_DWORD, &types_supported.to_le_bytes()));
+    // SAFETY: the handle is open and not used afterwards.
+    unsafe { RegCloseKey(key) };
+    result
+}
+
+/// Remove the registration of the source of the events.
+fn unregister_event_source() -> io::Result<()> {
+    let key_name = wide(OsStr::new(EVENT_SOURCE_KEY));
+    // SAFETY: the key name is a null terminated wide string.
+    check(unsafe { RegDeleteKeyW(HKEY_LOCAL_MACHINE, key_name.as_ptr()) })
+}
+
+/// Error of a registry function returning `code`.
+fn check(code: WIN32_ERROR) -> io::Result<()> {
+    match code {
+        ERROR_SUCCESS => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code as i32)),
+    }
+}
+
+/// Arguments the service is started with: the ones given to install it, the command replaced
+/// with `run`.
+fn launch_arguments(mut args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut launch_arguments = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--service" {
+            args.next();
+        } else if !arg.to_string_lossy().starts_with("--service=") {
+            launch_arguments.push(arg);
+        }
+    }
+    launch_arguments.extend(["--service".into(), "run".into()]);
+    launch_arguments
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Entry point of the service, called by the service control manager on a thread of its own.
+fn service_main(_arguments: Vec<OsString>) {
+    match EventLog::register() {
+        Some(event_log) => {
+            let _ = log::set_boxed_logger(Box::new(event_log));
+        }
+        None => return,
+    }
+    // Failures to start panic, the message must be reported as the default hook writes it to
+    // the missing console.
+    panic::set_hook(Box::new(|info| log::error!("{}", info)));
+    if let Err(err) = serve() {
+        log::error!("service failed: {:#}", err);
+    }
+}
+
+/// Serve until the service control manager asks the service to stop.
+fn serve() -> Result<()> {
+    // Parsed again as the command line was already parsed on the main thread.
+    let opt = Cli::load()?;
+    log::set_max_level(opt.log_level.unwrap_or(LevelFilter::Info));
+    let stop_wait_hint = STOP_WAIT_HINT + Duration::from_secs(opt.drain_timeout);
+
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let mut stop_tx = Some(stop_tx);
+    let status = service_control_handler::register(NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop_tx) = stop_tx.take() {
+                let _ = stop_tx.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let report = move |state, exit_code, wait_hint| {
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let reported = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        });
+        if let Err(err) = reported {
+            log::warn!("could not report the service {:?}: {}", state, err);
+        }
+    };
+
+    report(
+        ServiceState::Running,
+        ServiceExitCode::NO_ERROR,
+        Duration::ZERO,
+    );
+    log::info!("service started");
+    let stop = async move {
+        let _ = stop_rx.await;
+        report(
+            ServiceState::StopPending,
+            ServiceExitCode::NO_ERROR,
+            stop_wait_hint,
+        );
+        "a stop request"
+    };
+    let exit_code = match panic::catch_unwind(AssertUnwindSafe(|| crate::run(opt, stop))) {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(ServiceState::Stopped, exit_code, Duration::ZERO);
+    Ok(())
+}
+
+/// Logger reporting the records as events of the service in the Application event log.
+struct EventLog(HANDLE);
+
+// SAFETY: event source handles can be used from any thread.
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    fn register() -> Option<Self> {
+        let source = wide(OsStr::new(NAME));
+        // SAFETY: the source name is a null terminated wide string.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        (!handle.is_null()).then_some(EventLog(handle))
+    }
+}
+
+impl Log for EventLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let event_type = match record.level() {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = format!("{}: {}", record.target(), record.args());
+        let message = wide(OsStr::new(&message));
+        let strings = [message.as_ptr()];
+        // SAFETY: the single string is a null terminated wide string, there is no raw data.
+        unsafe {
+            ReportEventW(
+                self.0,
+                event_type,
+                0,
+                EVENT_ID,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// `s` as a null terminated wide string.
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(iter::once(0)).collect()
+}
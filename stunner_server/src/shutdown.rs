@@ -0,0 +1,75 @@
+//! Graceful shutdown of the tasks serving clients.
+
+use tokio::sync::watch;
+
+/// Signals the tasks serving clients to stop.
+#[derive(Debug)]
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Shutdown { tx, rx }
+    }
+}
+
+impl Shutdown {
+    /// Ask every task waiting on [`Shutdown::wait`] to stop.
+    pub fn trigger(&self) {
+        // A receiver is kept alive by `self`, the send can't fail.
+        let _ = self.tx.send(true);
+    }
+
+    /// Wait until the shutdown is triggered.
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Wait for a termination signal, SIGINT or SIGTERM on unix, returning its name.
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn wakes_up_waiting_tasks() {
+        let shutdown = Shutdown::default();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), shutdown.wait())
+                .await
+                .is_err()
+        );
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.wait())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! State shared by the instances of a cluster behind a load balancer, so that clients are
+//! treated the same whichever instance their messages reach. It is kept in memory by default,
+//! or in Redis to be shared.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+#[cfg(feature = "redis")]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+#[cfg(feature = "redis")]
+use anyhow::{bail, Context};
+#[cfg(feature = "redis")]
+use redis::aio::ConnectionManager;
+
+#[cfg(feature = "redis")]
+use crate::message::hex;
+
+/// Interval between synchronizations with the shared state.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Redis key of the secret signing nonces, 16 bytes in hex.
+#[cfg(feature = "redis")]
+const NONCE_SECRET_KEY: &str = "stunner:nonce-secret";
+
+/// Redis key of the sorted set of banned IP addresses, scored by the UNIX time their ban ends.
+#[cfg(feature = "redis")]
+const BANS_KEY: &str = "stunner:bans";
+
+/// Banned IP addresses and when their ban ends.
+type Bans = HashMap<IpAddr, SystemTime>;
+
+/// Secret signing the nonces, so that a nonce handed out by an instance is valid on the others,
+/// and IP addresses whose messages are dropped.
+pub struct SharedState {
+    nonce_secret: [u8; 16],
+    /// Bans as of the last synchronization.
+    bans: Mutex<Bans>,
+    #[cfg(feature = "redis")]
+    redis: Option<ConnectionManager>,
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        SharedState {
+            nonce_secret: rand::random(),
+            bans: Default::default(),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+}
+
+impl SharedState {
+    /// Share the state through the Redis server at `url`, e.g. redis://127.0.0.1/. The first
+    /// instance to connect picks the nonce secret, IP addresses are banned by adding them to
+    /// the `stunner:bans` sorted set.
+    #[cfg(feature = "redis")]
+    pub async fn redis(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let mut redis = ConnectionManager::new(client)
+            .await
+            .with_context(|| format!("could not connect to {}", url))?;
+        redis::cmd("SET")
+            .arg(NONCE_SECRET_KEY)
+            .arg(hex(&rand::random::<[u8; 16]>()))
+            .arg("NX")
+            .exec_async(&mut redis)
+            .await?;
+        let nonce_secret: String = redis::cmd("GET")
+            .arg(NONCE_SECRET_KEY)
+            .query_async(&mut redis)
+            .await?;
+        let state = SharedState {
+            nonce_secret: parse_secret(&nonce_secret)?,
+            bans: Default::default(),
+            redis: Some(redis),
+        };
+        state.sync().await?;
+        Ok(state)
+    }
+
+    /// Secret the nonces are signed with.
+    pub fn nonce_secret(&self) -> [u8; 16] {
+        self.nonce_secret
+    }
+
+    /// Whether the messages of `ip` must be dropped.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.bans.lock().unwrap().get(&ip) {
+            Some(ends_at) => *ends_at > SystemTime::now(),
+            None => false,
+        }
+    }
+
+    /// Drop the messages of `ip` for `duration`.
+    pub async fn ban(&self, ip: IpAddr, duration: Duration) -> Result<()> {
+        let ends_at = SystemTime::now() + duration;
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            let score = ends_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            redis::cmd("ZADD")
+                .arg(BANS_KEY)
+                .arg(score)
+                .arg(ip.to_string())
+                .exec_async(&mut redis.clone())
+                .await?;
+        }
+        self.bans.lock().unwrap().insert(ip, ends_at);
+        Ok(())
+    }
+
+    /// Lift the ban of `ip`, returning whether it was banned.
+    pub async fn unban(&self, ip: IpAddr) -> Result<bool> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            redis::cmd("ZREM")
+                .arg(BANS_KEY)
+                .arg(ip.to_string())
+                .exec_async(&mut redis.clone())
+                .await?;
+        }
+        Ok(self.bans.lock().unwrap().remove(&ip).is_some())
+    }
+
+    /// Banned IP addresses and when their ban ends.
+    pub fn bans(&self) -> Vec<(IpAddr, SystemTime)> {
+        let now = SystemTime::now();
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, ends_at)| **ends_at > now)
+            .map(|(ip, ends_at)| (*ip, *ends_at))
+            .collect()
+    }
+
+    /// Forget the bans that ended, and fetch the current ones from Redis when shared.
+    pub async fn sync(&self) -> Result<()> {
+        let now = SystemTime::now();
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            let mut redis = redis.clone();
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            redis::cmd("ZREMRANGEBYSCORE")
+                .arg(BANS_KEY)
+                .arg("-inf")
+                .arg(now)
+                .exec_async(&mut redis)
+                .await?;
+            let entries: Vec<(String, f64)> = redis::cmd("ZRANGE")
+                .arg(BANS_KEY)
+                .arg(0)
+                .arg(-1)
+                .arg("WITHSCORES")
+                .query_async(&mut redis)
+                .await?;
+            *self.bans.lock().unwrap() = parse_bans(entries);
+            return Ok(());
+        }
+        self.bans
+            .lock()
+            .unwrap()
+            .retain(|_, ends_at| *ends_at > now);
+        Ok(())
+    }
+}
+
+/// Parse the nonce secret stored in Redis.
+#[cfg(feature = "redis")]
+fn parse_secret(secret: &str) -> Result<[u8; 16]> {
+    if secret.len() != 32 || !secret.is_ascii() {
+        bail!("invalid nonce secret in {}", NONCE_SECRET_KEY);
+    }
+    let mut bytes = [0; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&secret[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("invalid nonce secret in {}", NONCE_SECRET_KEY))?;
+    }
+    Ok(bytes)
+}
+
+/// Bans from the members of a sorted set of IP addresses scored by the UNIX time their ban
+/// ends, members which aren't IP addresses are skipped.
+#[cfg(feature = "redis")]
+fn parse_bans(entries: Vec<(String, f64)>) -> Bans {
+    entries
+        .into_iter()
+        .filter_map(|(ip, ends_at)| match ip.parse() {
+            Ok(ip) => Some((ip, UNIX_EPOCH + Duration::from_secs_f64(ends_at.max(0.0)))),
+            Err(_) => {
+                log::warn!("ignoring ban of invalid IP address {:?}", ip);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::SharedState;
+
+    #[tokio::test]
+    async fn drops_ended_bans() {
+        let now = SystemTime::now();
+        let state = SharedState::default();
+        state.bans.lock().unwrap().extend([
+            ("192.0.2.1".parse().unwrap(), now + Duration::from_secs(60)),
+            ("192.0.2.2".parse().unwrap(), now - Duration::from_secs(1)),
+        ]);
+        assert!(state.is_banned("192.0.2.1".parse().unwrap()));
+        assert!(!state.is_banned("192.0.2.2".parse().unwrap()));
+        assert!(!state.is_banned("192.0.2.3".parse().unwrap()));
+
+        state.sync().await.unwrap();
+        assert_eq!(state.bans.lock().unwrap().len(), 1);
+        assert!(state.is_banned("192.0.2.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn parses_bans() {
+        let bans = super::parse_bans(vec![
+            ("192.0.2.1".into(), 1700000000.0),
+            ("2001:db8::1".into(), 1700000060.0),
+            ("not an ip".into(), 1700000000.0),
+        ]);
+        assert_eq!(bans.len(), 2);
+        assert_eq!(
+            bans[&"2001:db8::1".parse().unwrap()],
+            std::time::UNIX_EPOCH + Duration::from_secs(1700000060)
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Server statistics, rendered in the Prometheus text exposition format,
+//! see https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::message::{methods, Class};
+
+/// Upper bounds in seconds of the response latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Number of methods counted on their own, the methods of STUN and TURN all fall below.
+const COUNTED_METHODS: usize = methods::CONNECTION_ATTEMPT as usize + 1;
+
+/// Method the methods above [`COUNTED_METHODS`] are counted as, out of the 12 bits of methods so
+/// that it's none of them.
+const OTHER_METHOD: u16 = u16::MAX;
+
+/// Every class, in the order of their counters.
+const CLASSES: [Class; 4] = [
+    Class::Request,
+    Class::Indication,
+    Class::SuccessResponse,
+    Class::ErrorResponse,
+];
+
+/// Message counters above zero, by method and class label.
+pub type Counts = BTreeMap<(u16, &'static str), u64>;
+
+/// Counters updated as messages are handled.
+#[derive(Debug)]
+pub struct Stats {
+    /// Messages received.
+    received: MessageCounts,
+    /// Responses sent.
+    sent: MessageCounts,
+    decode_failures: AtomicU64,
+    /// Packets dropped as their header isn't the one of a STUN message, before decoding them.
+    header_rejects: AtomicU64,
+    /// Messages dropped because of the source access control lists.
+    denied: AtomicU64,
+    /// Datagrams dropped because they don't fit the receive buffer.
+    truncated: AtomicU64,
+    /// TCP and TLS connections closed because of the connection limit.
+    connections_rejected: AtomicU64,
+    latency: Histogram,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            received: Default::default(),
+            sent: Default::default(),
+            decode_failures: Default::default(),
+            header_rejects: Default::default(),
+            denied: Default::default(),
+            truncated: Default::default(),
+            connections_rejected: Default::default(),
+            latency: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Stats {
+    pub fn received(&self, method: u16, class: Class) {
+        self.received.count(method, class);
+    }
+
+    pub fn decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn header_rejected(&self) {
+        self.header_rejects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn truncated(&self) {
+        self.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a response sent `latency` after its request was received.
+    pub fn sent(&self, method: u16, class: Class, latency: Duration) {
+        self.sent.count(method, class);
+        self.latency.observe(latency);
+    }
+
+    /// Total number of messages received and responses sent.
+    pub fn totals(&self) -> (u64, u64) {
+        let received = self.received.snapshot().values().sum();
+        let sent = self.sent.snapshot().values().sum();
+        (received, sent)
+    }
+
+    /// Totals of the counters, by name.
+    pub fn counters(&self) -> [(&'static str, u64); 7] {
+        let (received, sent) = self.totals();
+        [
+            ("messages_received", received),
+            ("responses_sent", sent),
+            (
+                "decode_failures",
+                self.decode_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "header_rejects",
+                self.header_rejects.load(Ordering::Relaxed),
+            ),
+            ("denied", self.denied.load(Ordering::Relaxed)),
+            ("truncated", self.truncated.load(Ordering::Relaxed)),
+            (
+                "connections_rejected",
+                self.connections_rejected.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// Counters of the messages received and responses sent above zero, by method and class
+    /// label.
+    pub fn messages(&self) -> [(&'static str, Counts); 2] {
+        [
+            ("received", self.received.snapshot()),
+            ("sent", self.sent.snapshot()),
+        ]
+    }
+
+    /// The counters on a single line, for logs.
+    pub fn dump(&self) -> String {
+        let mut fields: Vec<String> = self
+            .counters()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        for (direction, counts) in self.messages() {
+            for ((method, class), count) in counts {
+                let method = method_label(method);
+                fields.push(format!("{}.{}.{}={}", direction, method, class, count));
+            }
+        }
+        fields.join(" ")
+    }
+
+    /// Append the statistics to `out` in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        render_messages(
+            out,
+            "stunner_messages_received_total",
+            "Messages received.",
+            &self.received.snapshot(),
+        );
+        render_messages(
+            out,
+            "stunner_responses_sent_total",
+            "Responses sent.",
+            &self.sent.snapshot(),
+        );
+        render_counter(
+            out,
+            "stunner_decode_failures_total",
+            "Packets that could not be decoded as STUN messages.",
+            self.decode_failures.load(Ordering::Relaxed),
+        );
+        render_counter(
+            out,
+            "stunner_header_rejects_total",
+            "Packets dropped without decoding them because their header is not a STUN one.",
+            self.header_rejects.load(Ordering::Relaxed),
+        );
+        render_counter(
+            out,
+            "stunner_denied_total",
+            "Messages dropped because their source is not allowed.",
+            self.denied.load(Ordering::Relaxed),
+        );
+        render_counter(
+            out,
+            "stunner_truncated_total",
+            "Datagrams dropped because they are larger than the receive buffer.",
+            self.truncated.load(Ordering::Relaxed),
+        );
+        render_counter(
+            out,
+            "stunner_connections_rejected_total",
+            "TCP and TLS connections closed because too many are open.",
+            self.connections_rejected.load(Ordering::Relaxed),
+        );
+        self.latency.render(
+            out,
+            "stunner_response_duration_seconds",
+            "Time taken to respond to requests.",
+        );
+    }
+}
+
+/// Message counters by method and class.
+#[derive(Debug, Default)]
+struct MessageCounts {
+    /// Counters of the methods below [`COUNTED_METHODS`], by method and class.
+    counted: [[AtomicU64; CLASSES.len()]; COUNTED_METHODS],
+    /// Counters of the other methods together by class, only seen from broken or hostile
+    /// clients, which could otherwise add a label for each method.
+    other: [AtomicU64; CLASSES.len()],
+}
+
+impl MessageCounts {
+    fn count(&self, method: u16, class: Class) {
+        let counters = self.counted.get(method as usize).unwrap_or(&self.other);
+        let index = CLASSES.iter().position(|&c| c == class).unwrap();
+        counters[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters above zero, by method and class label.
+    fn snapshot(&self) -> Counts {
+        let mut counts = Counts::new();
+        let other = (OTHER_METHOD as usize, &self.other);
+        for (method, counters) in self.counted.iter().enumerate().chain([other]) {
+            for (class, counter) in CLASSES.iter().zip(counters) {
+                let count = counter.load(Ordering::Relaxed);
+                if count > 0 {
+                    counts.insert((method as u16, class_label(*class)), count);
+                }
+            }
+        }
+        counts
+    }
+}
+
+/// Histogram of durations with fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    /// Upper bounds in seconds of the buckets.
+    bounds: &'static [f64],
+    /// Observations in each bucket, the last one counting those above every bound.
+    buckets: Box<[AtomicU64]>,
+    /// Sum of the observations in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Append the histogram to `out` in the Prometheus text format.
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        count += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Append a counter to `out` in the Prometheus text format.
+pub fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Append a counter split by the values of `label` to `out` in the Prometheus text format.
+pub fn render_labeled_counter<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl IntoIterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+/// Append a gauge to `out` in the Prometheus text format.
+pub fn render_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Append a gauge split by the values of `label` to `out` in the Prometheus text format.
+pub fn render_labeled_gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl IntoIterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count);
+    }
+}
+
+/// Escape a label value of the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_messages(out: &mut String, name: &str, help: &str, counts: &Counts) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for ((method, class), count) in counts {
+        let _ = writeln!(
+            out,
+            "{}{{method=\"{}\",class=\"{}\"}} {}",
+            name,
+            method_label(*method),
+            class,
+            count
+        );
+    }
+}
+
+/// Name of a method, as used in labels and logs.
+pub fn method_label(method: u16) -> Cow<'static, str> {
+    match method {
+        methods::BINDING => "binding".into(),
+        methods::ALLOCATE => "allocate".into(),
+        methods::REFRESH => "refresh".into(),
+        methods::SEND => "send".into(),
+        methods::DATA => "data".into(),
+        methods::CREATE_PERMISSION => "create_permission".into(),
+        methods::CHANNEL_BIND => "channel_bind".into(),
+        methods::CONNECT => "connect".into(),
+        methods::CONNECTION_BIND => "connection_bind".into(),
+        methods::CONNECTION_ATTEMPT => "connection_attempt".into(),
+        OTHER_METHOD => "other".into(),
+        method => format!("{:#05x}", method).into(),
+    }
+}
+
+/// Name of a class, as used in labels and logs.
+pub fn class_label(class: Class) -> &'static str {
+    match class {
+        Class::Request => "request",
+        Class::Indication => "indication",
+        Class::SuccessResponse => "success_response",
+        Class::ErrorResponse => "error_response",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Stats;
+    use crate::message::{methods, Class};
+
+    #[test]
+    fn renders_prometheus_text() {
+        let stats = Stats::default();
+        stats.received(methods::BINDING, Class::Request);
+        stats.received(methods::BINDING, Class::Request);
+        stats.received(0x0fff, Class::Indication);
+        stats.received(0x0ffe, Class::Indication);
+        stats.decode_failure();
+        stats.sent(
+            methods::BINDING,
+            Class::SuccessResponse,
+            Duration::from_micros(300),
+        );
+
+        let mut out = String::new();
+        stats.render(&mut out);
+        for line in [
+            "stunner_messages_received_total{method=\"binding\",class=\"request\"} 2",
+            "stunner_messages_received_total{method=\"other\",class=\"indication\"} 2",
+            "stunner_responses_sent_total{method=\"binding\",class=\"success_response\"} 1",
+            "stunner_decode_failures_total 1",
+            "stunner_response_duration_seconds_bucket{le=\"0.0001\"} 0",
+            "stunner_response_duration_seconds_bucket{le=\"0.0005\"} 1",
+            "stunner_response_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "stunner_response_duration_seconds_sum 0.0003",
+            "stunner_response_duration_seconds_count 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                out
+            );
+        }
+        assert_eq!(stats.totals(), (4, 1));
+        assert_eq!(
+            stats.dump(),
+            "messages_received=4 responses_sent=1 decode_failures=1 header_rejects=0 denied=0 \
+             truncated=0 connections_rejected=0 received.binding.request=2 received.other.indication=2 \
+             sent.binding.success_response=1"
+        );
+    }
+}
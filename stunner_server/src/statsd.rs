@@ -0,0 +1,307 @@
+//! Export of the counters, gauges and response times of the server to a statsd daemon over UDP,
+//! for monitoring stacks that don't scrape Prometheus, see
+//! https://github.com/statsd/statsd/blob/master/docs/metric_types.md
+//!
+//! Counters are sent as the increments since the last flush. Plain statsd has no tags, the
+//! method, class or reason of a metric are appended to its name instead, e.g.
+//! `stunner.messages.received.binding.request:3|c`. With the DogStatsD extension they are sent
+//! as tags along with the configured ones, e.g.
+//! `stunner.messages.received:3|c|#method:binding,class:request,env:prod`.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+
+use crate::server::Server;
+use crate::stats;
+use crate::turn::{Direction, DropReason};
+
+/// Default interval between flushes.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Size of the datagrams the metrics are sent in, fitting the MTU of most networks.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Response times kept between flushes, the others are accounted for by the sample rate.
+const MAX_TIMINGS: usize = 1000;
+
+/// Where and how the metrics are exported.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// Address of the daemon, host:port.
+    pub addr: String,
+    /// Prefix of the metric names.
+    pub prefix: String,
+    /// Send tags with the DogStatsD extension rather than appending them to the names.
+    pub datadog: bool,
+    /// Tags sent with every metric, as key:value, with the DogStatsD extension.
+    pub tags: Vec<String>,
+    pub interval: Duration,
+}
+
+/// Response times observed since the last flush.
+#[derive(Debug, Default)]
+struct Timings {
+    samples: Vec<Duration>,
+    /// Number of response times observed, including the ones not kept.
+    observed: u64,
+}
+
+/// Sends the metrics of the server to a statsd daemon.
+#[derive(Debug)]
+pub struct Statsd {
+    config: StatsdConfig,
+    sock: UdpSocket,
+    timings: Mutex<Timings>,
+}
+
+impl Statsd {
+    /// Resolve the address of the daemon and bind the socket the metrics are sent from, before
+    /// privileges are dropped and system calls restricted.
+    pub async fn connect(config: StatsdConfig) -> Result<Self> {
+        let addr = tokio::net::lookup_host(&config.addr)
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", config.addr))?;
+        let ip = match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let sock = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        sock.connect(addr).await?;
+        Ok(Statsd {
+            config,
+            sock,
+            timings: Default::default(),
+        })
+    }
+
+    /// Observe the time taken to respond to a request.
+    pub fn time(&self, latency: Duration) {
+        let mut timings = self.timings.lock().unwrap();
+        timings.observed += 1;
+        if timings.samples.len() < MAX_TIMINGS {
+            timings.samples.push(latency);
+        }
+    }
+
+    /// Flush the metrics of `server` periodically until it stops, and once more then.
+    pub async fn run(self: Arc<Self>, server: Arc<Server>) -> Result<()> {
+        let mut interval = tokio::time::interval(self.config.interval);
+        let mut totals = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.flush(&server, &mut totals).await,
+                _ = server.stopped() => {
+                    self.flush(&server, &mut totals).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Send the metrics of `server`, the counters as their increments since their `totals`
+    /// last sent.
+    async fn flush(&self, server: &Server, totals: &mut HashMap<String, u64>) {
+        let mut metrics = Metrics {
+            config: &self.config,
+            totals,
+            lines: Vec::new(),
+        };
+        for (name, value) in server.stats().counters() {
+            metrics.counter(name, &[], value);
+        }
+        for (direction, counts) in server.stats().messages() {
+            let name = match direction {
+                "received" => "messages.received",
+                _ => "responses.sent",
+            };
+            for ((method, class), count) in counts {
+                let method = stats::method_label(method);
+                metrics.counter(name, &[("method", &method), ("class", class)], count);
+            }
+        }
+        metrics.gauge("connections", server.connection_count() as u64);
+        if let Some(turn) = server.turn() {
+            metrics.gauge("turn.allocations", turn.allocation_count() as u64);
+            metrics.counter("turn.unknown_sources", &[], turn.unknown_sources());
+            for reason in DropReason::ALL {
+                metrics.counter(
+                    "turn.dropped_packets",
+                    &[("reason", reason.as_str())],
+                    turn.dropped().get(reason),
+                );
+            }
+            let relayed = turn.relayed();
+            for direction in Direction::ALL {
+                let tags = [("direction", direction.as_str())];
+                metrics.counter("turn.relayed_bytes", &tags, relayed.bytes(direction));
+                metrics.counter("turn.relayed_packets", &tags, relayed.packets(direction));
+            }
+        }
+        let timings = std::mem::take(&mut *self.timings.lock().unwrap());
+        let rate = timings.samples.len() as f64 / timings.observed.max(1) as f64;
+        for latency in timings.samples {
+            metrics.timing("response_time", latency, rate);
+        }
+
+        for datagram in datagrams(&metrics.lines) {
+            if let Err(err) = self.sock.send(datagram.as_bytes()).await {
+                log::debug!("could not send metrics to {}: {}", self.config.addr, err);
+            }
+        }
+    }
+}
+
+/// Lines of the metrics being flushed.
+struct Metrics<'a> {
+    config: &'a StatsdConfig,
+    /// Totals of the counters last sent, by name and tags.
+    totals: &'a mut HashMap<String, u64>,
+    lines: Vec<String>,
+}
+
+impl Metrics<'_> {
+    /// Add the increment of the counter `name` since its last total.
+    fn counter(&mut self, name: &str, tags: &[(&str, &str)], total: u64) {
+        let key = tags.iter().fold(name.to_string(), |key, (tag, value)| {
+            format!("{},{}={}", key, tag, value)
+        });
+        let last = self.totals.insert(key, total).unwrap_or(0);
+        if total > last {
+            self.line(name, tags, total - last, "c", None);
+        }
+    }
+
+    fn gauge(&mut self, name: &str, value: u64) {
+        self.line(name, &[], value, "g", None);
+    }
+
+    /// Add a timing in milliseconds, sampled at `rate`.
+    fn timing(&mut self, name: &str, value: Duration, rate: f64) {
+        let rate = (rate < 1.0).then_some(rate);
+        self.line(name, &[], value.as_secs_f64() * 1000.0, "ms", rate);
+    }
+
+    fn line(
+        &mut self,
+        name: &str,
+        tags: &[(&str, &str)],
+        value: impl Display,
+        kind: &str,
+        rate: Option<f64>,
+    ) {
+        let mut line = format!("{}.{}", self.config.prefix, name);
+        if !self.config.datadog {
+            for (_, value) in tags {
+                line.push('.');
+                line.push_str(value);
+            }
+        }
+        let _ = write!(line, ":{}|{}", value, kind);
+        if let Some(rate) = rate {
+            let _ = write!(line, "|@{:.3}", rate);
+        }
+        if self.config.datadog {
+            let tags: Vec<String> = tags
+                .iter()
+                .map(|(tag, value)| format!("{}:{}", tag, value))
+                .chain(self.config.tags.iter().cloned())
+                .collect();
+            if !tags.is_empty() {
+                let _ = write!(line, "|#{}", tags.join(","));
+            }
+        }
+        self.lines.push(line);
+    }
+}
+
+/// The lines joined in datagrams of up to [`MAX_DATAGRAM_SIZE`] bytes, unless a single line
+/// exceeds it.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_SIZE => {
+                datagram.push('\n');
+                datagram.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{datagrams, Statsd, StatsdConfig, MAX_DATAGRAM_SIZE};
+    use crate::message::{methods, Class};
+    use crate::server::Server;
+
+    async fn receive(sock: &UdpSocket) -> String {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(5), sock.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_increments_and_timings() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = StatsdConfig {
+            addr: daemon.local_addr().unwrap().to_string(),
+            prefix: "stunner".into(),
+            datadog: false,
+            tags: vec!["env:test".into()],
+            interval: Duration::from_secs(10),
+        };
+        let server = Server::default();
+        server.stats().received(methods::BINDING, Class::Request);
+        server.stats().received(methods::BINDING, Class::Request);
+
+        let statsd = Statsd::connect(config.clone()).await.unwrap();
+        statsd.time(Duration::from_micros(1500));
+        let mut totals = HashMap::new();
+        statsd.flush(&server, &mut totals).await;
+        let lines = receive(&daemon).await;
+        assert!(lines.contains("stunner.messages_received:2|c\n"));
+        assert!(lines.contains("stunner.messages.received.binding.request:2|c\n"));
+        assert!(lines.contains("stunner.connections:0|g\n"));
+        assert!(lines.ends_with("stunner.response_time:1.5|ms"));
+
+        // Only the increments are sent, with tags over DogStatsD.
+        let statsd = Statsd::connect(StatsdConfig {
+            datadog: true,
+            ..config
+        })
+        .await
+        .unwrap();
+        server.stats().received(methods::BINDING, Class::Request);
+        statsd.flush(&server, &mut totals).await;
+        let lines = receive(&daemon).await;
+        assert!(lines
+            .contains("stunner.messages.received:1|c|#method:binding,class:request,env:test\n"));
+        assert!(!lines.contains("decode_failures"));
+    }
+
+    #[test]
+    fn joins_lines_in_datagrams() {
+        let lines = vec!["a".repeat(1000), "b".repeat(400), "c".repeat(100)];
+        let datagrams = datagrams(&lines);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].len(), 1401);
+        assert_eq!(datagrams[1], lines[2]);
+    }
+}
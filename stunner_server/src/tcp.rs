@@ -0,0 +1,436 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::message::HEADER_SIZE;
+use crate::proxy;
+use crate::server::{Server, Sink, Source, Transport};
+use crate::turn::channel_data_len;
+
+/// Number of messages queued to be written to a connection before senders wait.
+const CONNECTION_QUEUE_SIZE: usize = 32;
+
+/// Limits of the TCP and TLS connections, so that idle or misbehaving clients can't exhaust the
+/// memory or file descriptors of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Connections without a message received for this long are closed.
+    pub idle_timeout: Duration,
+    /// Connections accepted beyond this number are closed right away.
+    pub max_connections: usize,
+    /// Connections announcing a larger message are closed, instead of buffering it.
+    pub max_message_size: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            idle_timeout: Duration::from_secs(300),
+            max_connections: 10_000,
+            max_message_size: 8192,
+        }
+    }
+}
+
+/// Accept TCP connections and serve STUN requests on each of them, until the server shuts down
+/// and every connection is closed.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) -> Result<()> {
+    let local_addr = listener.local_addr()?;
+    let mut connections = JoinSet::new();
+    loop {
+        let (mut stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = server.stopped() => break,
+            // Reap the finished connections.
+            Some(_) = connections.join_next() => continue,
+        };
+        let permit = match server.admit_connection(peer_addr) {
+            Some(permit) => permit,
+            None => continue,
+        };
+        log::debug!("accepted TCP connection from {:?}", peer_addr);
+        let server = server.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            let peer_addr =
+                match proxy::client_addr(&mut stream, peer_addr, local_addr, &server).await {
+                    Some(addr) => addr,
+                    None => return,
+                };
+            if let Err(err) =
+                handle_connection(stream, peer_addr, local_addr, Transport::Tcp, server).await
+            {
+                log::debug!("TCP connection with {:?} closed: {}", peer_addr, err);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Reply to every STUN request received on the stream until the peer closes it, stays idle for
+/// too long or the server shuts down. The connection is kept open after responding, it's up to
+/// the client to close it, see https://datatracker.ietf.org/doc/html/rfc5389#section-7.2.2
+/// Once bound to a TURN peer data connection, the stream relays its data instead.
+pub async fn handle_connection<S>(
+    stream: S,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    transport: Transport,
+    server: Arc<Server>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    // Responses and messages sent outside of a request, such as TURN Data indications,
+    // are written in order by a single writer.
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(CONNECTION_QUEUE_SIZE);
+    let source = Source {
+        addr: peer_addr,
+        local_addr,
+        transport,
+        sink: Sink::Stream(tx),
+    };
+
+    let limits = server.connection_limits();
+    let read = async {
+        loop {
+            let buf = tokio::time::timeout(
+                limits.idle_timeout,
+                read_message(&mut reader, limits.max_message_size),
+            )
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "connection idle for too long"))??;
+            let buf = match buf {
+                Some(buf) => buf,
+                None => return Ok(None),
+            };
+            let span = source.span();
+            let response = server.handle(&buf, &source).instrument(span.clone()).await;
+            // The response to a ConnectionBind request is the last message on the connection,
+            // written once the queue is flushed.
+            if let Some(peer) = server.take_peer_connection(&source) {
+                return Ok(Some((peer, response)));
+            }
+            if let Some(response) = response {
+                source
+                    .send(response)
+                    .instrument(tracing::info_span!(parent: &span, "send"))
+                    .await?;
+            }
+        }
+    };
+    let write = async {
+        while let Some(bytes) = rx.recv().await {
+            writer.write_all(&bytes).await?;
+            server.buffers().put(bytes);
+        }
+        Ok::<_, anyhow::Error>(None)
+    };
+
+    let result = tokio::select! {
+        result = read => result,
+        result = write => result,
+        _ = server.stopped() => Ok(None),
+    };
+    server.disconnected(&source);
+
+    // Flush what was queued before the peer closed the connection, it may be gone already.
+    rx.close();
+    while let Ok(bytes) = rx.try_recv() {
+        if writer.write_all(&bytes).await.is_err() {
+            break;
+        }
+    }
+    let (peer, response) = match result {
+        Ok(Some(bound)) => bound,
+        result => {
+            let _ = writer.shutdown().await;
+            return result.map(|_| ());
+        }
+    };
+    if let Some(response) = response {
+        writer.write_all(&response).await?;
+        server.buffers().put(response);
+    }
+    let mut stream = reader.unsplit(writer);
+    peer.relay(&mut stream).await?;
+    Ok(())
+}
+
+/// Read a single STUN or ChannelData message from the stream, using the length field of the
+/// header to frame it. Returns `None` if the stream was closed before a new message started, and
+/// an error if the message is larger than `max_size`.
+pub async fn read_message<S>(stream: &mut S, max_size: usize) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    // ChannelData messages may be shorter than a STUN header, the 4 bytes they start with are
+    // enough to tell them apart.
+    let mut header = [0; 4];
+    match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let size = match channel_data_len(header) {
+        Some(size) => size,
+        // The most significant 2 bits of every STUN message MUST be zeroes, anything else
+        // means the stream isn't carrying STUN and we can't frame it.
+        None if header[0] & 0b1100_0000 != 0 => {
+            return Err(Error::new(ErrorKind::InvalidData, "received a non STUN message").into());
+        }
+        None => HEADER_SIZE + u16::from_be_bytes([header[2], header[3]]) as usize,
+    };
+    if size > max_size {
+        let err = format!("message of {} bytes exceeds the limit", size);
+        return Err(Error::new(ErrorKind::InvalidData, err).into());
+    }
+    let mut buf = vec![0; size];
+    buf[..header.len()].copy_from_slice(&header);
+    stream.read_exact(&mut buf[header.len()..]).await?;
+    Ok(Some(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{handle_connection, read_message, ConnectionLimits};
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::server::{Server, Transport};
+    use crate::turn::{PeerPolicy, Turn};
+
+    const MAX_SIZE: usize = 8192;
+
+    #[tokio::test]
+    async fn frames_consecutive_messages() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_attribute(StunAttribute::Software {
+                    description: String::from("stunner"),
+                });
+        let encoded = req_msg.encode(None).unwrap();
+        let stream = [encoded.clone(), encoded.clone()].concat();
+
+        let mut reader = BufReader::new(stream.as_slice());
+        assert_eq!(
+            read_message(&mut reader, MAX_SIZE).await.unwrap(),
+            Some(encoded.clone())
+        );
+        assert_eq!(
+            read_message(&mut reader, MAX_SIZE).await.unwrap(),
+            Some(encoded)
+        );
+        assert_eq!(read_message(&mut reader, MAX_SIZE).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn frames_channel_data() {
+        let binding =
+            Message::with_random_transaction_id(methods::BINDING, Class::Request).encode();
+        let channel_data = b"\x40\x00\x00\x05hello\0\0\0\x40\x01\x00\x00".to_vec();
+        let stream = [channel_data.clone(), binding.clone()].concat();
+
+        let mut reader = BufReader::new(stream.as_slice());
+        assert_eq!(
+            read_message(&mut reader, MAX_SIZE).await.unwrap(),
+            Some(channel_data[..12].to_vec())
+        );
+        // Empty, and shorter than a STUN header.
+        assert_eq!(
+            read_message(&mut reader, MAX_SIZE).await.unwrap(),
+            Some(channel_data[12..].to_vec())
+        );
+        assert_eq!(
+            read_message(&mut reader, MAX_SIZE).await.unwrap(),
+            Some(binding)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_larger_than_the_limit() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .add_attribute(StunAttribute::Software {
+                    description: String::from("stunner"),
+                });
+        let encoded = req_msg.encode(None).unwrap();
+
+        let mut reader = BufReader::new(encoded.as_slice());
+        assert!(read_message(&mut reader, encoded.len() - 1).await.is_err());
+        let mut reader = BufReader::new(encoded.as_slice());
+        assert!(read_message(&mut reader, encoded.len()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let (_client, stream) = tokio::io::duplex(1024);
+        let limits = ConnectionLimits {
+            idle_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let server = Arc::new(Server::default().with_connection_limits(limits));
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handle_connection(stream, socket, socket, Transport::Tcp, server),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_beyond_the_limit() {
+        let limits = ConnectionLimits {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::default().with_connection_limits(limits));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, server.clone()));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0)) || read.is_err());
+        assert!(server
+            .metrics()
+            .contains("stunner_connections_rejected_total 1\n"));
+    }
+
+    async fn request(stream: &mut TcpStream, request: Message) -> Message {
+        stream.write_all(&request.encode()).await.unwrap();
+        let buf = read_message(stream, MAX_SIZE).await.unwrap().unwrap();
+        Message::decode(&buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn relays_tcp_allocations_to_peers() {
+        let peers = PeerPolicy::new(vec!["127.0.0.1".parse().unwrap()], Vec::new());
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(peers);
+        let server = Server::default().with_turn(Some(turn));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, Arc::new(server)));
+
+        let mut control = TcpStream::connect(addr).await.unwrap();
+        let allocate = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![6, 0, 0, 0]);
+        let relayed_addr = request(&mut control, allocate)
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+
+        // The client connects to a peer, from the relayed address.
+        let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = Message::with_random_transaction_id(methods::CONNECT, Class::Request)
+            .add_xor_address(
+                attributes::XOR_PEER_ADDRESS,
+                peer_listener.local_addr().unwrap(),
+            );
+        let response = request(&mut control, connect.clone()).await;
+        let id = response.get_u32(attributes::CONNECTION_ID).unwrap();
+        let (mut peer, from) = peer_listener.accept().await.unwrap();
+        assert_eq!(from, relayed_addr);
+        let response = request(&mut control, connect).await;
+        assert_eq!(response.error_code(), Some(446));
+
+        let mut data = TcpStream::connect(addr).await.unwrap();
+        let bind = Message::with_random_transaction_id(methods::CONNECTION_BIND, Class::Request)
+            .add_u32(attributes::CONNECTION_ID, id);
+        let response = request(&mut data, bind).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+        data.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        peer.write_all(b"pong").await.unwrap();
+        data.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // A permitted peer connecting to the relayed address is offered to the client.
+        let other_peer = TcpStream::connect(relayed_addr).await.unwrap();
+        let attempt = read_message(&mut control, MAX_SIZE).await.unwrap().unwrap();
+        let attempt = Message::decode(&attempt).unwrap();
+        assert_eq!(
+            (attempt.method, attempt.class),
+            (methods::CONNECTION_ATTEMPT, Class::Indication)
+        );
+        assert_eq!(
+            attempt.get_xor_address(attributes::XOR_PEER_ADDRESS),
+            Some(other_peer.local_addr().unwrap())
+        );
+        assert!(attempt.get_u32(attributes::CONNECTION_ID).is_some());
+
+        // Deleting the allocation closes the data connections.
+        let refresh = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_u32(attributes::LIFETIME, 0);
+        request(&mut control, refresh).await;
+        let read = tokio::time::timeout(Duration::from_secs(5), data.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0)) || read.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_non_stun_stream() {
+        let mut reader = BufReader::new(&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]);
+        assert!(read_message(&mut reader, MAX_SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_to_binding_request() {
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let handle = tokio::spawn(handle_connection(
+            server,
+            socket,
+            socket,
+            Transport::Tcp,
+            Arc::new(Server::default()),
+        ));
+        client
+            .write_all(&req_msg.encode(None).unwrap())
+            .await
+            .unwrap();
+        let buf = read_message(&mut client, MAX_SIZE).await.unwrap().unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+
+        let response = StunMessage::decode(&buf, None).unwrap();
+        let header = response.get_header();
+        assert!(matches!(
+            header.message_class,
+            StunMessageClass::SuccessResponse
+        ));
+        assert!(
+            matches!(response.get_attributes()[0], StunAttribute::XorMappedAddress { socket_addr } if socket_addr == socket)
+        );
+    }
+}
@@ -0,0 +1,26 @@
+//! Export of the tracing spans of transactions to an OpenTelemetry collector over OTLP, to break
+//! down where the time handling them goes in Jaeger or Tempo.
+
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Export the spans of a `ratio` of the transactions to the OTLP gRPC endpoint at `endpoint`,
+/// e.g. http://localhost:4317. The spans left are exported when the provider is shut down.
+pub fn init(endpoint: &str, ratio: f64) -> Result<SdkTracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::TraceIdRatioBased(ratio))
+        .with_resource(Resource::builder().with_service_name("stunner").build())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("stunner"));
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+    Ok(provider)
+}
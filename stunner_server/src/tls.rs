@@ -0,0 +1,506 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::crypto::ring;
+#[cfg(feature = "acme")]
+use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::proxy;
+use crate::server::{Server, Transport};
+use crate::tcp::handle_connection;
+
+/// ALPN protocol identifiers of STUN, advertised to the clients,
+/// see https://datatracker.ietf.org/doc/html/rfc7443
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [b"stun.turn", b"stun.nat-discovery"];
+
+/// Certificate chain and private key served over TLS, read again from their PEM files on
+/// [`Certificate::reload`] so that renewed certificates are served without restarting.
+#[derive(Debug)]
+pub struct Certificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    /// Certificates presented instead to the clients asking for these hostnames through SNI.
+    named: Vec<(String, Certificate)>,
+}
+
+impl Certificate {
+    /// Load the PEM encoded certificate chain and private key at the given paths, along with the
+    /// certificates presented instead to the clients asking for their hostname through SNI, see
+    /// [`parse_named_cert`].
+    pub fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        named: &[(String, PathBuf, PathBuf)],
+    ) -> Result<Arc<Self>> {
+        let mut certificate = Certificate::new(cert_path, key_path, load_key(cert_path, key_path)?);
+        for (name, cert_path, key_path) in named {
+            let key = load_key(cert_path, key_path)?;
+            certificate
+                .named
+                .push((name.clone(), Certificate::new(cert_path, key_path, key)));
+        }
+        Ok(Arc::new(certificate))
+    }
+
+    /// Generate a self-signed certificate for `names`, presented until a certificate is written
+    /// at the given paths and reloaded.
+    #[cfg(feature = "acme")]
+    pub fn self_signed(cert_path: &Path, key_path: &Path, names: &[String]) -> Result<Arc<Self>> {
+        let cert = rcgen::generate_simple_self_signed(names.to_vec())?;
+        let key = CertifiedKey::from_der(
+            vec![cert.cert.der().clone()],
+            PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into(),
+            &ring::default_provider(),
+        )
+        .context("invalid self-signed certificate")?;
+        Ok(Arc::new(Certificate::new(cert_path, key_path, key)))
+    }
+
+    fn new(cert_path: &Path, key_path: &Path, key: CertifiedKey) -> Self {
+        Certificate {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            key: RwLock::new(Arc::new(key)),
+            named: Vec::new(),
+        }
+    }
+
+    /// Read the certificate chains and private keys again, the handshakes already started keep
+    /// the previous ones. On error the previous ones are all kept.
+    pub fn reload(&self) -> Result<()> {
+        let key = load_key(&self.cert_path, &self.key_path)?;
+        let named = self
+            .named
+            .iter()
+            .map(|(_, certificate)| load_key(&certificate.cert_path, &certificate.key_path))
+            .collect::<Result<Vec<_>>>()?;
+        *self.key.write().unwrap() = Arc::new(key);
+        for ((_, certificate), key) in self.named.iter().zip(named) {
+            *certificate.key.write().unwrap() = Arc::new(key);
+        }
+        Ok(())
+    }
+
+    /// Certificate presented to the clients asking for `name`, the ones given for the exact
+    /// name being preferred over the wildcard ones.
+    fn named(&self, name: &str) -> Option<&Certificate> {
+        let parent = name.split_once('.').map(|(_, parent)| parent);
+        let matching = |exact: bool| {
+            self.named
+                .iter()
+                .find(|(pattern, _)| match (pattern.strip_prefix("*."), exact) {
+                    (None, true) => pattern.eq_ignore_ascii_case(name),
+                    (Some(domain), false) => {
+                        matches!(parent, Some(parent) if parent.eq_ignore_ascii_case(domain))
+                    }
+                    _ => false,
+                })
+        };
+        matching(true)
+            .or_else(|| matching(false))
+            .map(|(_, certificate)| certificate)
+    }
+
+    /// TLS configuration of a server presenting this certificate, and requiring clients to
+    /// present one accepted by `client_verifier` if given. The clients offering ALPN protocols
+    /// must offer one of [`ALPN_PROTOCOLS`].
+    pub fn server_config(
+        self: &Arc<Self>,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> ServerConfig {
+        let builder = ServerConfig::builder();
+        let mut config = match client_verifier {
+            Some(client_verifier) => builder.with_client_cert_verifier(client_verifier),
+            None => builder.with_no_client_auth(),
+        }
+        .with_cert_resolver(self.clone());
+        config.alpn_protocols = ALPN_PROTOCOLS
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+        config
+    }
+
+    /// TLS acceptor presenting this certificate, see [`Certificate::server_config`].
+    pub fn acceptor(
+        self: &Arc<Self>,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::new(self.server_config(client_verifier)))
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificate = client_hello
+            .server_name()
+            .and_then(|name| self.named(name))
+            .unwrap_or(self);
+        Some(certificate.key.read().unwrap().clone())
+    }
+}
+
+/// Parse a `hostname=cert.pem,key.pem` certificate presented to the clients asking for the
+/// hostname, which may start with `*.` to match any of its subdomains.
+pub fn parse_named_cert(value: &str) -> Result<(String, PathBuf, PathBuf)> {
+    match value
+        .split_once('=')
+        .and_then(|(name, paths)| Some((name, paths.split_once(',')?)))
+    {
+        Some((name, (cert, key))) if !name.is_empty() && !cert.is_empty() && !key.is_empty() => {
+            Ok((name.into(), cert.into(), key.into()))
+        }
+        _ => bail!("expected hostname=cert.pem,key.pem, got {:?}", value),
+    }
+}
+
+/// Verifier of client certificates, requiring them to be issued by one of the PEM encoded
+/// certificate authorities at `path`.
+pub fn client_verifier(path: &Path) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(
+        File::open(path).with_context(|| format!("could not open {:?}", path))?,
+    )) {
+        let cert = cert.with_context(|| format!("could not parse certificates from {:?}", path))?;
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid certificate authority in {:?}", path))?;
+    }
+    ensure!(!roots.is_empty(), "no certificate found in {:?}", path);
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(ring::default_provider()))
+        .build()
+        .context("invalid client certificate authorities")
+}
+
+/// Load a PEM encoded certificate chain and the matching private key.
+fn load_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).with_context(|| format!("could not open {:?}", cert_path))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("could not parse certificates from {:?}", cert_path))?;
+    ensure!(!certs.is_empty(), "no certificate found in {:?}", cert_path);
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path).with_context(|| format!("could not open {:?}", key_path))?,
+    ))
+    .with_context(|| format!("could not parse private key from {:?}", key_path))?
+    .with_context(|| format!("no private key found in {:?}", key_path))?;
+
+    CertifiedKey::from_der(certs, key, &ring::default_provider())
+        .context("invalid TLS certificate or key")
+}
+
+/// Accept TLS connections and serve STUN requests on each of them, until the server shuts down
+/// and every connection is closed, see https://datatracker.ietf.org/doc/html/rfc5389#section-7.2.2
+/// With `strict_alpn`, the connections not negotiating one of [`ALPN_PROTOCOLS`] are closed.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    strict_alpn: bool,
+    server: Arc<Server>,
+) -> Result<()> {
+    let local_addr = listener.local_addr()?;
+    let mut connections = JoinSet::new();
+    loop {
+        let (mut stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = server.stopped() => break,
+            // Reap the finished connections.
+            Some(_) = connections.join_next() => continue,
+        };
+        let permit = match server.admit_connection(peer_addr) {
+            Some(permit) => permit,
+            None => continue,
+        };
+        log::debug!("accepted TLS connection from {:?}", peer_addr);
+        let acceptor = acceptor.clone();
+        let server = server.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            let peer_addr =
+                match proxy::client_addr(&mut stream, peer_addr, local_addr, &server).await {
+                    Some(addr) => addr,
+                    None => return,
+                };
+            let handshake = tokio::time::timeout(
+                server.connection_limits().idle_timeout,
+                acceptor.accept(stream),
+            );
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    log::debug!("TLS handshake with {:?} failed: {}", peer_addr, err);
+                    return;
+                }
+                Err(_) => {
+                    log::debug!("TLS handshake with {:?} timed out", peer_addr);
+                    return;
+                }
+            };
+            if strict_alpn && stream.get_ref().1.alpn_protocol().is_none() {
+                log::debug!("TLS client {:?} negotiated no ALPN protocol", peer_addr);
+                return;
+            }
+            if let Err(err) =
+                handle_connection(stream, peer_addr, local_addr, Transport::Tls, server).await
+            {
+                log::debug!("TLS connection with {:?} closed: {}", peer_addr, err);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use super::{client_verifier, parse_named_cert, serve, Certificate};
+    use crate::server::{Server, Transport};
+    use crate::tcp::{handle_connection, read_message};
+
+    /// Write a self signed certificate for localhost to `cert.pem` and `key.pem` in a new
+    /// temporary directory, the caller is responsible for removing it.
+    pub(crate) fn self_signed_cert(name: &str) -> (CertifiedKey, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("cert.pem"))
+            .unwrap()
+            .write_all(cert.cert.pem().as_bytes())
+            .unwrap();
+        File::create(dir.join("key.pem"))
+            .unwrap()
+            .write_all(cert.key_pair.serialize_pem().as_bytes())
+            .unwrap();
+        (cert, dir)
+    }
+
+    #[tokio::test]
+    async fn replies_to_binding_request_over_tls() {
+        let (cert, dir) = self_signed_cert("stunner-tls");
+        let acceptor = Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[])
+            .unwrap()
+            .acceptor(None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.cert.der().to_vec()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let (client, server) = tokio::io::duplex(4096);
+        let handle = tokio::spawn(async move {
+            let stream = acceptor.accept(server).await.unwrap();
+            handle_connection(
+                stream,
+                socket,
+                socket,
+                Transport::Tls,
+                Arc::new(Server::default()),
+            )
+            .await
+        });
+        let mut client = connector
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+
+        let req_msg =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+        client
+            .write_all(&req_msg.encode(None).unwrap())
+            .await
+            .unwrap();
+        let buf = read_message(&mut client, 8192).await.unwrap().unwrap();
+        client.shutdown().await.unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+
+        let response = StunMessage::decode(&buf, None).unwrap();
+        assert!(matches!(
+            response.get_header().message_class,
+            StunMessageClass::SuccessResponse
+        ));
+        assert!(
+            matches!(response.get_attributes()[0], StunAttribute::XorMappedAddress { socket_addr } if socket_addr == socket)
+        );
+    }
+
+    #[tokio::test]
+    async fn presents_the_certificate_of_the_requested_name() {
+        let (default, dir) = self_signed_cert("stunner-tls-sni");
+        let named = rcgen::generate_simple_self_signed(vec!["*.example.test".into()]).unwrap();
+        std::fs::write(dir.join("named.pem"), named.cert.pem()).unwrap();
+        std::fs::write(dir.join("named-key.pem"), named.key_pair.serialize_pem()).unwrap();
+        let sni_cert = parse_named_cert(&format!(
+            "*.example.test={},{}",
+            dir.join("named.pem").display(),
+            dir.join("named-key.pem").display()
+        ))
+        .unwrap();
+        let acceptor = Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[sni_cert])
+            .unwrap()
+            .acceptor(None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(parse_named_cert("example.test=cert.pem").is_err());
+        assert!(parse_named_cert("=cert.pem,key.pem").is_err());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(default.cert.der().clone()).unwrap();
+        roots.add(named.cert.der().clone()).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        for (name, expected) in [("localhost", &default), ("stun.example.test", &named)] {
+            let (client, server) = tokio::io::duplex(4096);
+            let acceptor = acceptor.clone();
+            let handle = tokio::spawn(async move { acceptor.accept(server).await.map(drop) });
+            let client = connector
+                .connect(ServerName::try_from(name).unwrap(), client)
+                .await
+                .unwrap();
+            let presented = &client.get_ref().1.peer_certificates().unwrap()[0];
+            assert_eq!(presented, expected.cert.der());
+            handle.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_connections_without_stun_alpn_when_strict() {
+        let (cert, dir) = self_signed_cert("stunner-tls-alpn");
+        let acceptor = Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[])
+            .unwrap()
+            .acceptor(None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::default());
+        tokio::spawn(serve(listener, acceptor, true, server.clone()));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        for (protocols, answered) in [
+            (vec![b"stun.turn".to_vec()], true),
+            (vec![b"h2".to_vec()], false),
+            (vec![], false),
+        ] {
+            let mut config = ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+            config.alpn_protocols = protocols;
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let connected = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await;
+            let mut client = match connected {
+                Ok(client) => client,
+                // Rejected during the handshake.
+                Err(_) => {
+                    assert!(!answered);
+                    continue;
+                }
+            };
+            let request =
+                StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request);
+            client
+                .write_all(&request.encode(None).unwrap())
+                .await
+                .unwrap();
+            let response = read_message(&mut client, 8192).await.ok().flatten();
+            assert_eq!(response.is_some(), answered);
+        }
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates() {
+        let (cert, dir) = self_signed_cert("stunner-tls-client-ca");
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["device".into()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+        let acceptor = Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[])
+            .unwrap()
+            .acceptor(Some(client_verifier(&dir.join("ca.pem")).unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let builder = || ClientConfig::builder().with_root_certificates(roots.clone());
+        let enrolled = builder()
+            .with_client_auth_cert(
+                vec![client_cert.der().clone()],
+                PrivatePkcs8KeyDer::from(client_key.serialize_der()).into(),
+            )
+            .unwrap();
+        for (config, accepted) in [(builder().with_no_client_auth(), false), (enrolled, true)] {
+            let (client, server) = tokio::io::duplex(4096);
+            let acceptor = acceptor.clone();
+            let handle = tokio::spawn(async move { acceptor.accept(server).await.is_ok() });
+            let client = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), client)
+                .await;
+            assert_eq!(handle.await.unwrap(), accepted);
+            drop(client);
+        }
+    }
+
+    #[test]
+    fn reloads_the_certificate() {
+        let (first, dir) = self_signed_cert("stunner-tls-reload");
+        let certificate =
+            Certificate::load(&dir.join("cert.pem"), &dir.join("key.pem"), &[]).unwrap();
+        let presented = || certificate.key.read().unwrap().cert[0].to_vec();
+        assert_eq!(presented(), first.cert.der().to_vec());
+
+        // Renewed in place.
+        let (second, dir) = self_signed_cert("stunner-tls-reload");
+        certificate.reload().unwrap();
+        assert_eq!(presented(), second.cert.der().to_vec());
+
+        // The previous certificate is kept when the new one is invalid.
+        std::fs::write(dir.join("cert.pem"), "").unwrap();
+        assert!(certificate.reload().is_err());
+        assert_eq!(presented(), second.cert.der().to_vec());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Responses of the recent transactions, sent again when their request is retransmitted instead
+//! of handling it twice, so that clients get the same answer to a request whose response was
+//! lost even when handling it isn't idempotent,
+//! see https://datatracker.ietf.org/doc/html/rfc5389#section-7.3.1
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::message::TRANSACTION_ID_SIZE;
+use crate::turn::FiveTuple;
+
+/// Default duration the responses are kept, the 39.5 seconds a client retransmits a request
+/// for over UDP, rounded up.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(40);
+
+/// Number of responses kept, beyond which the responses of new transactions aren't.
+const MAX_ENTRIES: usize = 65536;
+
+/// A transaction, identified by its ID among the ones of the client.
+type Key = (FiveTuple, [u8; TRANSACTION_ID_SIZE]);
+
+/// Response of a transaction, until it expires.
+#[derive(Debug)]
+struct Entry {
+    response: Vec<u8>,
+    expires_at: Instant,
+}
+
+/// Encoded responses of the recent transactions of each client.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<Key, Entry>>,
+    /// Number of retransmitted requests answered from the cache.
+    hits: AtomicU64,
+}
+
+impl ResponseCache {
+    /// Keep the responses for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            entries: Default::default(),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Append to `buf` the response sent to `client` for the transaction `transaction_id`,
+    /// returning whether there is a recent one.
+    pub fn get(
+        &self,
+        client: FiveTuple,
+        transaction_id: [u8; TRANSACTION_ID_SIZE],
+        buf: &mut Vec<u8>,
+    ) -> bool {
+        match self.entries.lock().unwrap().get(&(client, transaction_id)) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                buf.extend_from_slice(&entry.response)
+            }
+            _ => return false,
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Keep `response`, sent to `client` for the transaction `transaction_id`.
+    pub fn insert(
+        &self,
+        client: FiveTuple,
+        transaction_id: [u8; TRANSACTION_ID_SIZE],
+        response: &[u8],
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            return;
+        }
+        entries.insert(
+            (client, transaction_id),
+            Entry {
+                response: response.to_vec(),
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    /// Number of retransmitted requests answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Forget the expired responses.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ResponseCache;
+    use crate::server::Transport;
+    use crate::turn::FiveTuple;
+
+    #[test]
+    fn caches_responses_by_client_and_transaction() {
+        let cache = ResponseCache::new(Duration::from_secs(40));
+        let client = FiveTuple {
+            client: "192.0.2.1:5000".parse().unwrap(),
+            server: "198.51.100.1:3478".parse().unwrap(),
+            transport: Transport::Tcp,
+        };
+        let other = FiveTuple {
+            transport: Transport::Udp,
+            ..client
+        };
+        cache.insert(client, [1; 12], b"response");
+
+        let mut buf = Vec::new();
+        assert!(cache.get(client, [1; 12], &mut buf));
+        assert_eq!(buf, b"response");
+        assert!(!cache.get(client, [2; 12], &mut buf));
+        assert!(!cache.get(other, [1; 12], &mut buf));
+        assert_eq!(cache.hits(), 1);
+
+        // Expired at once.
+        let cache = ResponseCache::new(Duration::ZERO);
+        cache.insert(client, [1; 12], b"response");
+        assert!(!cache.get(client, [1; 12], &mut buf));
+        cache.expire();
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,528 @@
+//! Transports the server receives messages on and sends responses through, so that new
+//! transports or in-process tests need nothing more than receiving and sending messages.
+//!
+//! UDP sockets are served through [`Udp`], and [`channel`] serves clients in the same process,
+//! e.g. in tests. [`Stream`] carries messages framed by their length over any stream, for
+//! applications bringing their own. The TCP and TLS listeners don't serve their connections
+//! through a transport but with [`crate::tcp::handle_connection`], as TURN can turn them into
+//! peer data connections, which requires owning the stream.
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::net;
+use crate::server::{self, Server, Sink, Source};
+use crate::tcp::{self, ConnectionLimits};
+
+/// Number of datagrams handled concurrently on a transport, before receiving waits.
+const MAX_DATAGRAMS_IN_FLIGHT: usize = 1024;
+
+/// Number of messages queued to be written to a stream or a channel before senders wait.
+const QUEUE_SIZE: usize = 32;
+
+/// A message received, of `len` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    pub len: usize,
+    /// Address of the client.
+    pub peer: SocketAddr,
+    /// Local address the message was received on.
+    pub local_addr: SocketAddr,
+}
+
+/// Carries messages between clients and the server.
+pub trait Transport: Send + Sync + 'static {
+    /// Protocol of the transport, for logs and metrics.
+    fn protocol(&self) -> server::Transport;
+
+    /// Whether messages are datagrams: handled concurrently, their responses possibly sent out
+    /// of order, and dropped when larger than the receive buffer of the server.
+    fn is_datagram(&self) -> bool {
+        false
+    }
+
+    /// Receive a message in `buf`, sized for the largest datagram. Returns `None` once the
+    /// transport is closed.
+    fn recv(&self, buf: &mut Vec<u8>) -> impl Future<Output = io::Result<Option<Received>>> + Send;
+
+    /// Send `bytes` to the client `peer`, from `local_addr`.
+    fn send(
+        &self,
+        bytes: &[u8],
+        peer: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// How to reach the clients outside of a request/response exchange.
+    fn sink(&self) -> Sink;
+}
+
+/// Reply to the messages received on `transport`, until the server shuts down or the transport
+/// is closed, and every message received is handled.
+pub async fn serve<T: Transport>(transport: Arc<T>, server: Arc<Server>) -> Result<()> {
+    // Datagrams are handled by their own task, so a slow response doesn't hold back the
+    // datagrams received after it.
+    let mut in_flight = JoinSet::new();
+    loop {
+        // Stop receiving until a datagram is handled when too many are in flight.
+        if in_flight.len() >= MAX_DATAGRAMS_IN_FLIGHT {
+            in_flight.join_next().await;
+            continue;
+        }
+        // One extra byte tells apart the datagrams that don't fit the buffer.
+        let mut buf = server.buffers().take();
+        buf.resize(server.recv_buffer_size() + 1, 0);
+        let received = tokio::select! {
+            received = transport.recv(&mut buf) => received?,
+            _ = server.stopped() => None,
+            // Reap the handled datagrams.
+            Some(_) = in_flight.join_next() => {
+                server.buffers().put(buf);
+                continue;
+            }
+        };
+        let Received {
+            len,
+            peer,
+            local_addr,
+        } = match received {
+            Some(received) => received,
+            None => {
+                server.buffers().put(buf);
+                break;
+            }
+        };
+        let datagram = transport.is_datagram();
+        if datagram && len > server.recv_buffer_size() {
+            server.truncated(peer);
+            server.buffers().put(buf);
+            continue;
+        }
+        let source = Source {
+            addr: peer,
+            local_addr,
+            transport: transport.protocol(),
+            sink: transport.sink(),
+        };
+        let span = source.span();
+        let transport = transport.clone();
+        let server = server.clone();
+        let handle = async move {
+            if let Some(response) = server.handle(&buf[..len], &source).await {
+                let sent = transport
+                    .send(&response, peer, local_addr)
+                    .instrument(tracing::info_span!("send"))
+                    .await;
+                if let Err(err) = sent {
+                    log::error!(
+                        "could not send response to address {:?}, reason: {}",
+                        peer,
+                        err
+                    );
+                }
+                server.buffers().put(response);
+            }
+            server.buffers().put(buf);
+        };
+        if datagram {
+            in_flight.spawn(handle.instrument(span));
+        } else {
+            handle.instrument(span).await;
+        }
+    }
+    while in_flight.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// A UDP socket.
+#[derive(Debug)]
+pub struct Udp {
+    sock: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+}
+
+impl Udp {
+    pub fn new(sock: Arc<UdpSocket>) -> io::Result<Self> {
+        let local_addr = sock.local_addr()?;
+        Ok(Udp { sock, local_addr })
+    }
+}
+
+impl Transport for Udp {
+    fn protocol(&self) -> server::Transport {
+        server::Transport::Udp
+    }
+
+    fn is_datagram(&self) -> bool {
+        true
+    }
+
+    async fn recv(&self, buf: &mut Vec<u8>) -> io::Result<Option<Received>> {
+        let (len, peer, local_addr) = net::recv_from(&self.sock, buf, self.local_addr).await?;
+        Ok(Some(Received {
+            len,
+            peer,
+            local_addr,
+        }))
+    }
+
+    async fn send(&self, bytes: &[u8], peer: SocketAddr, local_addr: SocketAddr) -> io::Result<()> {
+        net::send_to(&self.sock, bytes, peer, local_addr).await?;
+        Ok(())
+    }
+
+    fn sink(&self) -> Sink {
+        Sink::Datagram(self.sock.clone())
+    }
+}
+
+/// A stream to a single client, e.g. a TCP connection or a TLS session, carrying messages
+/// framed by their length. It's closed once idle for too long.
+pub struct Stream<S> {
+    reader: Mutex<ReadHalf<S>>,
+    /// Messages queued to the task writing to the stream.
+    tx: mpsc::Sender<Vec<u8>>,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    protocol: server::Transport,
+    limits: ConnectionLimits,
+}
+
+impl<S> Stream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Carry the messages of `stream` between `peer` and `local_addr`, within `limits`. The
+    /// messages sent are written by a task spawned on the current runtime.
+    pub fn new(
+        stream: S,
+        peer: SocketAddr,
+        local_addr: SocketAddr,
+        protocol: server::Transport,
+        limits: ConnectionLimits,
+    ) -> Self {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(bytes) = rx.recv().await {
+                if let Err(err) = writer.write_all(&bytes).await {
+                    log::debug!("could not write to {:?}: {}", peer, err);
+                    return;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+        Stream {
+            reader: Mutex::new(reader),
+            tx,
+            peer,
+            local_addr,
+            protocol,
+            limits,
+        }
+    }
+}
+
+impl<S> Transport for Stream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    fn protocol(&self) -> server::Transport {
+        self.protocol
+    }
+
+    async fn recv(&self, buf: &mut Vec<u8>) -> io::Result<Option<Received>> {
+        let mut reader = self.reader.lock().await;
+        let message = tokio::time::timeout(
+            self.limits.idle_timeout,
+            tcp::read_message(&mut *reader, self.limits.max_message_size),
+        )
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "connection idle for too long"))?
+        .map_err(io::Error::other)?;
+        let message = match message {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        buf.clear();
+        buf.extend_from_slice(&message);
+        Ok(Some(Received {
+            len: message.len(),
+            peer: self.peer,
+            local_addr: self.local_addr,
+        }))
+    }
+
+    async fn send(
+        &self,
+        bytes: &[u8],
+        _peer: SocketAddr,
+        _local_addr: SocketAddr,
+    ) -> io::Result<()> {
+        self.tx
+            .send(bytes.to_vec())
+            .await
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+
+    fn sink(&self) -> Sink {
+        Sink::Stream(self.tx.clone())
+    }
+}
+
+/// Messages exchanged with a single client in the same process, see [`channel`].
+#[derive(Debug)]
+pub struct Channel {
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+    tx: mpsc::Sender<Vec<u8>>,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    protocol: server::Transport,
+}
+
+/// The client end of a [`Channel`].
+#[derive(Debug)]
+pub struct ChannelClient {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+/// A channel carrying the messages of a client at `peer` to the server at `local_addr`, over
+/// what the server takes for `protocol`, and the client end of it. The channel is closed once
+/// the client end is dropped.
+pub fn channel(
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    protocol: server::Transport,
+) -> (Channel, ChannelClient) {
+    let (client_tx, server_rx) = mpsc::channel(QUEUE_SIZE);
+    let (server_tx, client_rx) = mpsc::channel(QUEUE_SIZE);
+    let channel = Channel {
+        rx: Mutex::new(server_rx),
+        tx: server_tx,
+        peer,
+        local_addr,
+        protocol,
+    };
+    let client = ChannelClient {
+        tx: client_tx,
+        rx: client_rx,
+    };
+    (channel, client)
+}
+
+impl Transport for Channel {
+    fn protocol(&self) -> server::Transport {
+        self.protocol
+    }
+
+    async fn recv(&self, buf: &mut Vec<u8>) -> io::Result<Option<Received>> {
+        let message = match self.rx.lock().await.recv().await {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        buf.clear();
+        buf.extend_from_slice(&message);
+        Ok(Some(Received {
+            len: message.len(),
+            peer: self.peer,
+            local_addr: self.local_addr,
+        }))
+    }
+
+    async fn send(
+        &self,
+        bytes: &[u8],
+        _peer: SocketAddr,
+        _local_addr: SocketAddr,
+    ) -> io::Result<()> {
+        self.tx
+            .send(bytes.to_vec())
+            .await
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+
+    fn sink(&self) -> Sink {
+        Sink::Stream(self.tx.clone())
+    }
+}
+
+impl ChannelClient {
+    /// Send a message to the server.
+    pub async fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        self.tx
+            .send(bytes)
+            .await
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+
+    /// Receive the next message from the server, `None` once it stopped serving the channel.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UdpSocket;
+
+    use super::{channel, serve, Stream, Udp};
+    use crate::handler::{proceed, Handler, HookFuture, Transaction};
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::ratelimit::GlobalRateLimiter;
+    use crate::server::{Server, Transport};
+    use crate::tcp::{read_message, ConnectionLimits};
+
+    /// Never done handling the first message.
+    #[derive(Default)]
+    struct HangFirst {
+        hung: AtomicBool,
+    }
+
+    impl Handler for HangFirst {
+        fn name(&self) -> &'static str {
+            "hang first"
+        }
+
+        fn post_decode<'a>(
+            &'a self,
+            _server: &'a Server,
+            _message: &'a mut Message,
+            _transaction: &'a mut Transaction<'_>,
+        ) -> HookFuture<'a> {
+            if self.hung.swap(true, Ordering::Relaxed) {
+                proceed()
+            } else {
+                Box::pin(std::future::pending())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_clients_in_process() {
+        let server = Arc::new(Server::default());
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let (channel, mut client) =
+            channel(peer, "198.51.100.1:3478".parse().unwrap(), Transport::Udp);
+        let serving = tokio::spawn(serve(Arc::new(channel), server.clone()));
+
+        for _ in 0..2 {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            client.send(request.encode()).await.unwrap();
+            let response = Message::decode(&client.recv().await.unwrap()).unwrap();
+            assert_eq!(response.transaction_id, request.transaction_id);
+            assert_eq!(
+                response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+                Some(peer)
+            );
+        }
+        assert!(server
+            .summary()
+            .contains("received 2 messages and sent 2 responses"));
+
+        // Served until the client end is dropped.
+        drop(client);
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn handles_datagrams_concurrently() {
+        let server =
+            Arc::new(Server::default().with_handlers(vec![Box::new(HangFirst::default())]));
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let serving = tokio::spawn(serve(Arc::new(Udp::new(sock).unwrap()), server));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The second request is answered while the first one is still being handled.
+        let requests: Vec<_> = (0..2)
+            .map(|_| Message::with_random_transaction_id(methods::BINDING, Class::Request))
+            .collect();
+        for request in &requests {
+            client
+                .send_to(&request.encode(), server_addr)
+                .await
+                .unwrap();
+        }
+        let mut buf = [0; 1500];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(response.transaction_id, requests[1].transaction_id);
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn drops_packets_beyond_the_server_rate() {
+        let server = Arc::new(
+            Server::default().with_global_rate_limiter(Some(GlobalRateLimiter::new(1, 2))),
+        );
+        let (channel, client) = channel(
+            "192.0.2.1:5000".parse().unwrap(),
+            "198.51.100.1:3478".parse().unwrap(),
+            Transport::Udp,
+        );
+        let serving = tokio::spawn(serve(Arc::new(channel), server.clone()));
+
+        for _ in 0..3 {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            client.send(request.encode()).await.unwrap();
+        }
+        drop(client);
+        serving.await.unwrap().unwrap();
+        assert!(server
+            .summary()
+            .contains("received 2 messages and sent 2 responses"));
+        assert!(server
+            .metrics()
+            .contains("stunner_global_rate_limited_total 1"));
+    }
+
+    #[tokio::test]
+    async fn serves_streams() {
+        let server = Arc::new(Server::default());
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let local_addr = "198.51.100.1:3478".parse().unwrap();
+        let stream = Stream::new(
+            stream,
+            peer,
+            local_addr,
+            Transport::Tcp,
+            ConnectionLimits::default(),
+        );
+        let serving = tokio::spawn(serve(Arc::new(stream), server));
+
+        // Two requests written at once are framed apart.
+        let requests: Vec<_> = (0..2)
+            .map(|_| Message::with_random_transaction_id(methods::BINDING, Class::Request))
+            .collect();
+        client
+            .write_all(&[requests[0].encode(), requests[1].encode()].concat())
+            .await
+            .unwrap();
+        for request in &requests {
+            let response = read_message(&mut client, 1024).await.unwrap().unwrap();
+            let response = Message::decode(&response).unwrap();
+            assert_eq!(response.transaction_id, request.transaction_id);
+        }
+        client.shutdown().await.unwrap();
+        serving.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::channel::{ChannelData, Channels};
+use super::connection::{self, PeerConnection, Peers, Pending};
+use super::{
+    Direction, DropReason, RelayStats, Traffic, MOBILITY_TICKET_SIZE, RESERVATION_TOKEN_SIZE,
+};
+use crate::message::{attributes, methods, Class, Message, TRANSACTION_ID_SIZE};
+use crate::ratelimit::Bandwidth;
+use crate::server::Source;
+
+/// Largest UDP payload that can be relayed.
+const MAX_RELAY_DATAGRAM_SIZE: usize = 65535;
+
+/// Lifetime of a permission unless refreshed,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-8
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Peers allowed to exchange data with the client through the relay, by IP address, until their
+/// permission expires.
+#[derive(Debug, Clone, Default)]
+struct Permissions(Arc<Mutex<HashMap<IpAddr, Instant>>>);
+
+impl Permissions {
+    /// Install or refresh the permission of `peer`.
+    fn install(&self, peer: IpAddr) {
+        let now = Instant::now();
+        let mut permissions = self.0.lock().unwrap();
+        permissions.retain(|_, expires_at| *expires_at > now);
+        permissions.insert(peer, now + PERMISSION_LIFETIME);
+    }
+
+    fn contains(&self, peer: IpAddr) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+}
+
+/// How data is relayed between the client and its peers.
+#[derive(Debug)]
+enum Relay {
+    /// In Send and Data indications, as datagrams sent and received on the socket.
+    Udp(Arc<UdpSocket>),
+    /// On a client data connection per peer connection, see
+    /// https://datatracker.ietf.org/doc/html/rfc6062
+    Tcp {
+        peers: Peers,
+        /// Dropped with the allocation, closing the peer data connections.
+        _closed: watch::Sender<()>,
+    },
+}
+
+/// A relayed transport address allocated to a client,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-5
+#[derive(Debug)]
+pub struct Allocation {
+    /// Transaction id of the Allocate request that created the allocation,
+    /// used to recognize retransmissions.
+    pub transaction_id: [u8; TRANSACTION_ID_SIZE],
+    pub relayed_addr: SocketAddr,
+    pub expires_at: Instant,
+    /// Realm the client authenticated in, if any.
+    pub realm: Option<String>,
+    /// User the client authenticated as, if any.
+    pub username: Option<String>,
+    /// Token of the port reserved along with the relayed transport address, if any.
+    pub reservation_token: Option<[u8; RESERVATION_TOKEN_SIZE]>,
+    /// Ticket the client refreshes the allocation with from another address, if it asked for
+    /// mobility.
+    pub mobility_ticket: Option<[u8; MOBILITY_TICKET_SIZE]>,
+    /// Whether the DF bit is set on all the datagrams relayed to peers, as the client asked
+    /// for with DONT-FRAGMENT.
+    pub dont_fragment: bool,
+    /// Where the data of peers is relayed to, updated as the client moves, unless this is a
+    /// TCP allocation.
+    client: Option<watch::Sender<Source>>,
+    relay: Relay,
+    permissions: Permissions,
+    channels: Channels,
+    /// Limit of the bytes relayed in both directions, if any.
+    bandwidth: Option<Arc<Bandwidth>>,
+    relay_task: JoinHandle<()>,
+    created_at: Instant,
+    /// Data relayed, shared with the tasks relaying it.
+    traffic: Arc<Traffic>,
+    /// Statistics of the relay, updated as the allocation relays data and once it is deleted.
+    stats: Arc<RelayStats>,
+}
+
+impl Allocation {
+    /// Create an allocation relaying the datagrams received on `relay` to `client`, at most
+    /// `bandwidth` bytes per second in both directions if given. The datagrams it can't relay
+    /// are counted in `stats`.
+    pub fn new(
+        transaction_id: [u8; TRANSACTION_ID_SIZE],
+        relay: UdpSocket,
+        client: Source,
+        expires_at: Instant,
+        bandwidth: Option<u64>,
+        stats: Arc<RelayStats>,
+    ) -> std::io::Result<Self> {
+        let relayed_addr = relay.local_addr()?;
+        let relay = Arc::new(relay);
+        let permissions = Permissions::default();
+        let channels = Channels::default();
+        let bandwidth = bandwidth.map(|bandwidth| Arc::new(Bandwidth::new(bandwidth)));
+        let traffic = Arc::new(Traffic::default());
+        let (client, client_rx) = watch::channel(client);
+        let relay_task = tokio::spawn(relay_to_client(
+            relay.clone(),
+            permissions.clone(),
+            channels.clone(),
+            bandwidth.clone(),
+            traffic.clone(),
+            stats.clone(),
+            client_rx,
+        ));
+        Ok(Allocation {
+            transaction_id,
+            relayed_addr,
+            expires_at,
+            realm: None,
+            username: None,
+            reservation_token: None,
+            mobility_ticket: None,
+            dont_fragment: false,
+            client: Some(client),
+            relay: Relay::Udp(relay),
+            permissions,
+            channels,
+            bandwidth,
+            relay_task,
+            created_at: Instant::now(),
+            traffic,
+            stats,
+        })
+    }
+
+    /// Create a TCP allocation accepting the connections of peers on `listener`, which wait in
+    /// `pending` for the client to bind them, relaying at most `bandwidth` bytes per second in
+    /// both directions over all of them if given. Once deleted, it is counted in `stats`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_tcp(
+        transaction_id: [u8; TRANSACTION_ID_SIZE],
+        listener: TcpListener,
+        client: Source,
+        expires_at: Instant,
+        pending: Pending,
+        bandwidth: Option<u64>,
+        stats: Arc<RelayStats>,
+    ) -> std::io::Result<Self> {
+        let relayed_addr = listener.local_addr()?;
+        let (closed, closed_rx) = watch::channel(());
+        let bandwidth = bandwidth.map(|bandwidth| Arc::new(Bandwidth::new(bandwidth)));
+        let traffic = Arc::new(Traffic::default());
+        let peers = Peers::new(closed_rx, bandwidth.clone(), traffic.clone());
+        let permissions = Permissions::default();
+        let relay_task = tokio::spawn(accept_peers(
+            listener,
+            permissions.clone(),
+            peers.clone(),
+            pending,
+            client,
+        ));
+        Ok(Allocation {
+            transaction_id,
+            relayed_addr,
+            expires_at,
+            realm: None,
+            username: None,
+            reservation_token: None,
+            mobility_ticket: None,
+            dont_fragment: false,
+            client: None,
+            relay: Relay::Tcp {
+                peers,
+                _closed: closed,
+            },
+            permissions,
+            channels: Channels::default(),
+            bandwidth,
+            relay_task,
+            created_at: Instant::now(),
+            traffic,
+            stats,
+        })
+    }
+
+    /// Relay the data of peers to `client` from now on, after it moved to another address,
+    /// see https://datatracker.ietf.org/doc/html/rfc8016
+    pub fn move_to(&self, client: Source) {
+        if let Some(sender) = &self.client {
+            sender.send_replace(client);
+        }
+    }
+
+    /// Socket relaying data from the client to a peer, unless this is a TCP allocation.
+    pub fn relay_to(&self) -> Option<Arc<UdpSocket>> {
+        match &self.relay {
+            Relay::Udp(relay) => Some(relay.clone()),
+            Relay::Tcp { .. } => None,
+        }
+    }
+
+    /// Bind `number` to `peer`, installing or refreshing the permission of the peer, unless this
+    /// is a TCP allocation or either is bound to another channel or peer.
+    pub fn bind_channel(&self, number: u16, peer: SocketAddr) -> bool {
+        if !matches!(self.relay, Relay::Udp(_)) || !self.channels.bind(number, peer) {
+            return false;
+        }
+        self.permit(peer.ip());
+        true
+    }
+
+    /// Peer channel `number` is bound to.
+    pub fn channel_peer(&self, number: u16) -> Option<SocketAddr> {
+        self.channels.peer(number)
+    }
+
+    /// Whether a datagram of `len` bytes may be relayed within the bandwidth of the allocation.
+    pub fn allows(&self, len: usize) -> bool {
+        self.bandwidth
+            .as_ref()
+            .is_none_or(|bandwidth| bandwidth.allow(len))
+    }
+
+    /// Peers connected to the relayed transport address, if this is a TCP allocation.
+    pub fn peers(&self) -> Option<&Peers> {
+        match &self.relay {
+            Relay::Udp(_) => None,
+            Relay::Tcp { peers, .. } => Some(peers),
+        }
+    }
+
+    /// Install or refresh the permission of `peer` to exchange data with the client through the
+    /// relay, see https://datatracker.ietf.org/doc/html/rfc5766#section-8
+    pub fn permit(&self, peer: IpAddr) {
+        self.permissions.install(peer);
+    }
+
+    /// Data relayed by the allocation.
+    pub fn traffic(&self) -> &Arc<Traffic> {
+        &self.traffic
+    }
+
+    /// Whether `peer` may exchange data with the client through the relay.
+    pub fn permits(&self, peer: IpAddr) -> bool {
+        self.permissions.contains(peer)
+    }
+
+    /// Whether the relayed transport address can reach `peer`, being of the same family,
+    /// see https://datatracker.ietf.org/doc/html/rfc6156#section-6
+    pub fn relays_to(&self, peer: SocketAddr) -> bool {
+        self.relayed_addr.is_ipv4() == peer.is_ipv4()
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.relay_task.abort();
+        self.stats.ended(self.created_at.elapsed(), &self.traffic);
+    }
+}
+
+/// Relay the datagrams received from permitted peers to the client, in ChannelData messages
+/// when a channel is bound to the peer or else in Data indications,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-10.3
+async fn relay_to_client(
+    relay: Arc<UdpSocket>,
+    permissions: Permissions,
+    channels: Channels,
+    bandwidth: Option<Arc<Bandwidth>>,
+    traffic: Arc<Traffic>,
+    stats: Arc<RelayStats>,
+    client: watch::Receiver<Source>,
+) {
+    let mut buf = vec![0; MAX_RELAY_DATAGRAM_SIZE];
+    loop {
+        let received = relay.recv_from(&mut buf).await;
+        let client = client.borrow().clone();
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("could not receive on relay for {:?}: {}", client.addr, err);
+                continue;
+            }
+        };
+        if !permissions.contains(peer.ip()) {
+            stats.dropped.add(DropReason::NoPermission);
+            log::trace!(
+                "dropping data from peer {:?} without permission to reach {:?}",
+                peer,
+                client.addr
+            );
+            continue;
+        }
+        if let Some(bandwidth) = &bandwidth {
+            if !bandwidth.allow(len) {
+                stats.dropped.add(DropReason::OverBandwidth);
+                log::trace!(
+                    "dropping data from peer {:?} to {:?} over the bandwidth of the allocation",
+                    peer,
+                    client.addr
+                );
+                continue;
+            }
+        }
+
+        let bytes = match channels.number(peer) {
+            Some(number) => ChannelData {
+                number,
+                data: &buf[..len],
+            }
+            .encode(client.transport),
+            None => Message::with_random_transaction_id(methods::DATA, Class::Indication)
+                .add_xor_address(attributes::XOR_PEER_ADDRESS, peer)
+                .add_attribute(attributes::DATA, buf[..len].to_vec())
+                .encode(),
+        };
+        match client.send(bytes).await {
+            Ok(()) => traffic.add(Direction::ToClient, len),
+            Err(err) => log::debug!(
+                "could not relay data from peer {:?} to {:?}: {}",
+                peer,
+                client.addr,
+                err
+            ),
+        }
+    }
+}
+
+/// Offer the connections of permitted peers to the client with ConnectionAttempt indications,
+/// see https://datatracker.ietf.org/doc/html/rfc6062#section-5.3
+async fn accept_peers(
+    listener: TcpListener,
+    permissions: Permissions,
+    peers: Peers,
+    pending: Pending,
+    client: Source,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::debug!("could not accept on relay for {:?}: {}", client.addr, err);
+                continue;
+            }
+        };
+        if !permissions.contains(peer.ip()) {
+            log::trace!(
+                "closing connection from peer {:?} without permission to reach {:?}",
+                peer,
+                client.addr
+            );
+            continue;
+        }
+        let link = match peers.link(peer) {
+            Some(link) => link,
+            None => {
+                log::debug!(
+                    "closing connection from peer {:?} already connected to {:?}",
+                    peer,
+                    client.addr
+                );
+                continue;
+            }
+        };
+
+        let id = connection::offer(&pending, PeerConnection::new(stream, link));
+        let indication =
+            Message::with_random_transaction_id(methods::CONNECTION_ATTEMPT, Class::Indication)
+                .add_xor_address(attributes::XOR_PEER_ADDRESS, peer)
+                .add_u32(attributes::CONNECTION_ID, id);
+        if let Err(err) = client.send(indication.encode()).await {
+            log::debug!(
+                "could not offer connection from peer {:?} to {:?}: {}",
+                peer,
+                client.addr,
+                err
+            );
+        }
+    }
+}
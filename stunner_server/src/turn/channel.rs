@@ -0,0 +1,182 @@
+//! Channels bound to the peers of an allocation, over which data is relayed in ChannelData
+//! messages rather than in Send and Data indications,
+//! see https://datatracker.ietf.org/doc/html/rfc5766#section-11
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::server::Transport;
+
+/// Channel numbers a client may bind, see https://datatracker.ietf.org/doc/html/rfc5766#section-11
+pub const CHANNEL_NUMBERS: RangeInclusive<u16> = 0x4000..=0x7FFE;
+
+/// Lifetime of a channel binding unless refreshed by another ChannelBind request,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-11
+pub const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Size of the ChannelData header, the channel number and the length of the data.
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+
+/// Data relayed over a channel, see https://datatracker.ietf.org/doc/html/rfc5766#section-11.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelData<'a> {
+    pub number: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> ChannelData<'a> {
+    /// Decode a ChannelData message, `None` if `buf` doesn't hold one. Padding following the
+    /// data over stream transports is ignored.
+    pub fn decode(buf: &'a [u8]) -> Option<Self> {
+        let number = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]);
+        if !CHANNEL_NUMBERS.contains(&number) {
+            return None;
+        }
+        let len = u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+        let data = buf.get(CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + len)?;
+        Some(ChannelData { number, data })
+    }
+
+    /// Encode the message, padding the data to a multiple of 4 bytes as required over the
+    /// stream transports, see https://datatracker.ietf.org/doc/html/rfc5766#section-11.5
+    pub fn encode(&self, transport: Transport) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(CHANNEL_DATA_HEADER_SIZE + self.data.len() + 3);
+        buf.extend_from_slice(&self.number.to_be_bytes());
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.data);
+        if is_stream(transport) {
+            buf.resize(padded_len(buf.len()), 0);
+        }
+        buf
+    }
+}
+
+/// Size of a ChannelData message over stream transports, including its padding, if the 4 bytes
+/// of `header` start one.
+pub fn channel_data_len(header: [u8; CHANNEL_DATA_HEADER_SIZE]) -> Option<usize> {
+    let number = u16::from_be_bytes([header[0], header[1]]);
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    CHANNEL_NUMBERS
+        .contains(&number)
+        .then(|| padded_len(CHANNEL_DATA_HEADER_SIZE + len))
+}
+
+fn padded_len(len: usize) -> usize {
+    len.next_multiple_of(4)
+}
+
+fn is_stream(transport: Transport) -> bool {
+    match transport {
+        Transport::Tcp | Transport::Tls => true,
+        #[cfg(feature = "quic")]
+        Transport::Quic => true,
+        Transport::Udp | Transport::Dtls | Transport::Ws => false,
+    }
+}
+
+/// Peer a channel is bound to, until it expires.
+#[derive(Debug, Clone, Copy)]
+struct Binding {
+    peer: SocketAddr,
+    expires_at: Instant,
+}
+
+/// Channels bound to the peers of an allocation, shared with the task relaying their data.
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+    bindings: Arc<Mutex<HashMap<u16, Binding>>>,
+}
+
+impl Channels {
+    /// Bind `number` to `peer` or refresh the binding, failing when either is bound to another
+    /// channel or peer, see https://datatracker.ietf.org/doc/html/rfc5766#section-11.2
+    pub fn bind(&self, number: u16, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut bindings = self.bindings.lock().unwrap();
+        bindings.retain(|_, binding| binding.expires_at > now);
+        let taken = bindings
+            .iter()
+            .any(|(other, binding)| (*other == number) != (binding.peer == peer));
+        if taken {
+            return false;
+        }
+        bindings.insert(
+            number,
+            Binding {
+                peer,
+                expires_at: now + CHANNEL_LIFETIME,
+            },
+        );
+        true
+    }
+
+    /// Peer `number` is bound to.
+    pub fn peer(&self, number: u16) -> Option<SocketAddr> {
+        match self.bindings.lock().unwrap().get(&number) {
+            Some(binding) if binding.expires_at > Instant::now() => Some(binding.peer),
+            _ => None,
+        }
+    }
+
+    /// Channel bound to `peer`.
+    pub fn number(&self, peer: SocketAddr) -> Option<u16> {
+        let now = Instant::now();
+        self.bindings
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, binding)| binding.peer == peer && binding.expires_at > now)
+            .map(|(number, _)| *number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel_data_len, ChannelData, Channels};
+    use crate::server::Transport;
+
+    #[test]
+    fn encodes_and_decodes_channel_data() {
+        let message = ChannelData {
+            number: 0x4001,
+            data: b"hello",
+        };
+        let datagram = message.encode(Transport::Udp);
+        assert_eq!(datagram, b"\x40\x01\x00\x05hello");
+        assert_eq!(ChannelData::decode(&datagram), Some(message));
+
+        // Padded to a multiple of 4 bytes over stream transports.
+        let framed = message.encode(Transport::Tcp);
+        assert_eq!(framed, b"\x40\x01\x00\x05hello\0\0\0");
+        assert_eq!(channel_data_len(framed[..4].try_into().unwrap()), Some(12));
+        assert_eq!(ChannelData::decode(&framed), Some(message));
+
+        // Truncated, and STUN messages.
+        assert_eq!(ChannelData::decode(&datagram[..8]), None);
+        assert_eq!(ChannelData::decode(b"\x00\x01\x00\x00"), None);
+        assert_eq!(channel_data_len(*b"\x00\x01\x00\x00"), None);
+        assert_eq!(ChannelData::decode(b"\x7f\xff\x00\x00"), None);
+    }
+
+    #[test]
+    fn binds_channels_to_peers() {
+        let channels = Channels::default();
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let other = "192.0.2.2:5000".parse().unwrap();
+
+        assert!(channels.bind(0x4000, peer));
+        // Refreshing the binding.
+        assert!(channels.bind(0x4000, peer));
+        // Neither the channel nor the peer can be bound again.
+        assert!(!channels.bind(0x4000, other));
+        assert!(!channels.bind(0x4001, peer));
+        assert!(channels.bind(0x4001, other));
+
+        assert_eq!(channels.peer(0x4000), Some(peer));
+        assert_eq!(channels.peer(0x4002), None);
+        assert_eq!(channels.number(other), Some(0x4001));
+    }
+}
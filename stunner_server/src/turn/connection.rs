@@ -0,0 +1,170 @@
+//! Peer data connections of TCP allocations, relayed to client data connections once bound,
+//! see https://datatracker.ietf.org/doc/html/rfc6062
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use super::{Direction, Traffic};
+use crate::ratelimit::Bandwidth;
+
+/// Connect requests fail when the peer can't be reached within this long,
+/// see https://datatracker.ietf.org/doc/html/rfc6062#section-5.2
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Peer data connections not bound by the client within this long are closed,
+/// see https://datatracker.ietf.org/doc/html/rfc6062#section-5.3
+pub const BIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the chunks data is relayed in.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Peer data connections waiting for a ConnectionBind request, by connection id.
+pub type Pending = Arc<Mutex<HashMap<u32, (PeerConnection, Instant)>>>;
+
+/// Peers connected to the relayed transport address of a TCP allocation, at most one
+/// connection per peer address.
+#[derive(Debug, Clone)]
+pub struct Peers {
+    addrs: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// Fails once the allocation is deleted, closing its peer data connections.
+    closed: watch::Receiver<()>,
+    /// Limit of the bytes relayed by the allocation, shared by its connections.
+    bandwidth: Option<Arc<Bandwidth>>,
+    /// Data relayed by the allocation, counted by its connections.
+    traffic: Arc<Traffic>,
+}
+
+impl Peers {
+    pub fn new(
+        closed: watch::Receiver<()>,
+        bandwidth: Option<Arc<Bandwidth>>,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        Peers {
+            addrs: Default::default(),
+            closed,
+            bandwidth,
+            traffic,
+        }
+    }
+
+    /// Register a connection with `addr`, unless there is one already.
+    pub fn link(&self, addr: SocketAddr) -> Option<Link> {
+        if !self.addrs.lock().unwrap().insert(addr) {
+            return None;
+        }
+        Some(Link {
+            addr,
+            peers: self.clone(),
+        })
+    }
+}
+
+/// A connection registered with [`Peers::link`], unregistered when dropped.
+#[derive(Debug)]
+pub struct Link {
+    addr: SocketAddr,
+    peers: Peers,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.peers.addrs.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// A TCP connection between the relayed transport address of an allocation and a peer.
+#[derive(Debug)]
+pub struct PeerConnection {
+    stream: TcpStream,
+    link: Link,
+}
+
+impl PeerConnection {
+    pub fn new(stream: TcpStream, link: Link) -> Self {
+        PeerConnection { stream, link }
+    }
+
+    /// Whether the allocation of the connection was deleted.
+    pub fn is_closed(&self) -> bool {
+        self.link.peers.closed.has_changed().is_err()
+    }
+
+    /// Relay the data between the client data connection and the peer, until both are closed
+    /// or the allocation is deleted.
+    pub async fn relay<S>(mut self, client: &mut S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut closed = self.link.peers.closed.clone();
+        let peers = self.link.peers.clone();
+        let relay = async {
+            let (mut client_reader, mut client_writer) = tokio::io::split(client);
+            let (mut peer_reader, mut peer_writer) = self.stream.split();
+            tokio::try_join!(
+                copy(
+                    &mut client_reader,
+                    &mut peer_writer,
+                    &peers,
+                    Direction::ToPeer
+                ),
+                copy(
+                    &mut peer_reader,
+                    &mut client_writer,
+                    &peers,
+                    Direction::ToClient
+                ),
+            )
+            .map(|_| ())
+        };
+        tokio::select! {
+            result = relay => result,
+            _ = closed.changed() => Ok(()),
+        }
+    }
+}
+
+/// Copy from `reader` to `writer` in `direction` until the end of the stream, waiting for the
+/// bandwidth of `peers` to allow each chunk if limited and counting it in their traffic, then
+/// shut `writer` down.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    peers: &Peers,
+    direction: Direction,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return writer.shutdown().await;
+        }
+        if let Some(bandwidth) = &peers.bandwidth {
+            bandwidth.consume(len).await;
+        }
+        writer.write_all(&buf[..len]).await?;
+        peers.traffic.add(direction, len);
+    }
+}
+
+/// Wait for the client to bind the connection, returning the id it is known by.
+pub fn offer(pending: &Pending, connection: PeerConnection) -> u32 {
+    let mut pending = pending.lock().unwrap();
+    let mut id = rand::random();
+    while pending.contains_key(&id) {
+        id = rand::random();
+    }
+    pending.insert(id, (connection, Instant::now() + BIND_TIMEOUT));
+    id
+}
@@ -0,0 +1,2100 @@
+//! TURN relay, see https://datatracker.ietf.org/doc/html/rfc5766 and
+//! https://datatracker.ietf.org/doc/html/rfc6062 for TCP allocations
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tokio::net::UdpSocket;
+
+use crate::acl::Cidr;
+use crate::message::{attributes, methods, Class, Message};
+use crate::net::{self, SocketConfig};
+use crate::server::{Source, Transport};
+use crate::stats::Histogram;
+
+mod allocation;
+mod channel;
+mod connection;
+
+use allocation::Allocation;
+pub use channel::{channel_data_len, ChannelData};
+use channel::{CHANNEL_LIFETIME, CHANNEL_NUMBERS};
+pub use connection::PeerConnection;
+use connection::{Pending, CONNECT_TIMEOUT};
+
+/// Lifetime of an allocation when the client doesn't request one,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-2.2
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Longest lifetime granted to an allocation.
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Upper bounds in seconds of the allocation lifetime histogram buckets.
+const LIFETIME_BUCKETS: [f64; 8] = [60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0];
+
+/// Protocol number of UDP in the REQUESTED-TRANSPORT attribute.
+const TRANSPORT_UDP: u8 = 17;
+
+/// Protocol number of TCP in the REQUESTED-TRANSPORT attribute.
+const TRANSPORT_TCP: u8 = 6;
+
+/// Address families of the REQUESTED-ADDRESS-FAMILY attribute,
+/// see https://datatracker.ietf.org/doc/html/rfc6156#section-4.1.1
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Flag of the EVEN-PORT attribute asking to reserve the next port as well,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-14.6
+const EVEN_PORT_RESERVE: u8 = 0x80;
+
+/// Size of the value of the RESERVATION-TOKEN attribute.
+const RESERVATION_TOKEN_SIZE: usize = 8;
+
+/// How long a reserved port waits to be allocated with its RESERVATION-TOKEN,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-6.2
+const RESERVATION_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Size of the MOBILITY-TICKET values handed out.
+const MOBILITY_TICKET_SIZE: usize = 16;
+
+/// Number of ports tried before giving up on binding an even one, followed by a free one when
+/// reserving it.
+const EVEN_PORT_ATTEMPTS: usize = 32;
+
+/// Ranges of the peers refused unless allowed, so that the relay can't be used to reach the
+/// network of the server: this network, private, shared, loopback, link-local, benchmarking,
+/// multicast and reserved IPv4 ranges, and their IPv6 counterparts, see
+/// https://datatracker.ietf.org/doc/html/rfc8656#section-21.3. IPv4-mapped IPv6 peers are
+/// matched against the IPv4 ranges.
+const RESERVED_PEERS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b:1::/48",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Peers the relay may exchange data with.
+#[derive(Debug, Clone)]
+pub struct PeerPolicy {
+    /// Ranges permitted even when reserved.
+    allow: Vec<Cidr>,
+    /// Ranges refused on top of the reserved ones.
+    deny: Vec<Cidr>,
+    reserved: Vec<Cidr>,
+}
+
+impl PeerPolicy {
+    /// Refuse the peers in `deny` and in the reserved ranges but those in `allow`, deny entries
+    /// take precedence.
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        let reserved = RESERVED_PEERS
+            .iter()
+            .map(|cidr| cidr.parse().unwrap())
+            .collect();
+        PeerPolicy {
+            allow,
+            deny,
+            reserved,
+        }
+    }
+
+    /// Whether the relay may exchange data with `ip`.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let listed = |cidrs: &[Cidr]| cidrs.iter().any(|cidr| cidr.contains(ip));
+        !listed(&self.deny) && (listed(&self.allow) || !listed(&self.reserved))
+    }
+}
+
+impl Default for PeerPolicy {
+    fn default() -> Self {
+        PeerPolicy::new(Vec::new(), Vec::new())
+    }
+}
+
+/// Ports relayed transport addresses are allocated on, e.g. those a firewall lets through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    fn len(&self) -> u32 {
+        (self.last - self.first) as u32 + 1
+    }
+
+    fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    /// Parse a range of the form first-last, e.g. `49152-65535`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (first, last) = match s.split_once('-') {
+            Some(range) => range,
+            None => bail!("expected first-last, got {:?}", s),
+        };
+        let first: u16 = first
+            .parse()
+            .with_context(|| format!("invalid first port in {:?}", s))?;
+        let last: u16 = last
+            .parse()
+            .with_context(|| format!("invalid last port in {:?}", s))?;
+        if first == 0 || first > last {
+            bail!("invalid port range {:?}", s);
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Transport 5-tuple identifying a client allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub transport: Transport,
+}
+
+/// Limits of the number of simultaneous allocations, none when not set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quotas {
+    /// Allocations of the clients authenticated as the same user.
+    pub per_user: Option<usize>,
+    /// Allocations of the clients with the same IP address.
+    pub per_ip: Option<usize>,
+}
+
+/// Limits of the bytes per second relayed by an allocation in both directions, none when not
+/// set.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimits {
+    /// Limit of the allocations of every client.
+    pub default: Option<u64>,
+    /// Limits of the allocations of the clients authenticated as a user, by username.
+    pub per_user: HashMap<String, u64>,
+}
+
+impl BandwidthLimits {
+    /// Limit of the allocations of a client authenticated as `username` if given.
+    pub fn get(&self, username: Option<&str>) -> Option<u64> {
+        username
+            .and_then(|username| self.per_user.get(username).copied())
+            .or(self.default)
+    }
+}
+
+/// Parse a limit of bytes per second, which can't be zero.
+pub fn parse_bandwidth(value: &str) -> Result<u64> {
+    match value.parse()? {
+        0 => bail!("a bandwidth of 0 bytes per second relays nothing"),
+        bandwidth => Ok(bandwidth),
+    }
+}
+
+/// Parse the limit of bytes per second of a user, given as user=bytes.
+pub fn parse_user_bandwidth(value: &str) -> Result<(String, u64)> {
+    match value.split_once('=') {
+        Some((user, bandwidth)) if !user.is_empty() => {
+            Ok((user.into(), parse_bandwidth(bandwidth)?))
+        }
+        _ => bail!("expected user=bytes, got {:?}", value),
+    }
+}
+
+/// Why relayed data was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The peer has no permission to exchange data with the client.
+    NoPermission,
+    /// The allocation relayed as much data as its bandwidth allows.
+    OverBandwidth,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 2] = [DropReason::NoPermission, DropReason::OverBandwidth];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::NoPermission => "no_permission",
+            DropReason::OverBandwidth => "over_bandwidth",
+        }
+    }
+}
+
+/// Numbers of datagrams of relayed data dropped, by reason.
+#[derive(Debug, Default)]
+pub struct DroppedPackets([AtomicU64; DropReason::ALL.len()]);
+
+impl DroppedPackets {
+    fn add(&self, reason: DropReason) {
+        self.0[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.0[reason as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Direction data is relayed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to a peer.
+    ToPeer,
+    /// From a peer to the client.
+    ToClient,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 2] = [Direction::ToPeer, Direction::ToClient];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::ToPeer => "to_peer",
+            Direction::ToClient => "to_client",
+        }
+    }
+}
+
+/// Bytes and packets of data relayed in each direction. The data of TCP allocations is counted
+/// in the chunks it is read in.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    bytes: [AtomicU64; Direction::ALL.len()],
+    packets: [AtomicU64; Direction::ALL.len()],
+}
+
+impl Traffic {
+    fn add(&self, direction: Direction, len: usize) {
+        self.bytes[direction as usize].fetch_add(len as u64, Ordering::Relaxed);
+        self.packets[direction as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add the data counted by `other`.
+    fn absorb(&self, other: &Traffic) {
+        for direction in Direction::ALL {
+            self.bytes[direction as usize].fetch_add(other.bytes(direction), Ordering::Relaxed);
+            self.packets[direction as usize].fetch_add(other.packets(direction), Ordering::Relaxed);
+        }
+    }
+
+    pub fn bytes(&self, direction: Direction) -> u64 {
+        self.bytes[direction as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn packets(&self, direction: Direction) -> u64 {
+        self.packets[direction as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Statistics of the relay, shared with the allocations and the tasks relaying the data of
+/// peers.
+#[derive(Debug)]
+struct RelayStats {
+    dropped: DroppedPackets,
+    /// Data relayed by the deleted allocations.
+    ended: Traffic,
+    /// How long the deleted allocations lasted.
+    lifetimes: Histogram,
+}
+
+impl Default for RelayStats {
+    fn default() -> Self {
+        RelayStats {
+            dropped: Default::default(),
+            ended: Default::default(),
+            lifetimes: Histogram::new(&LIFETIME_BUCKETS),
+        }
+    }
+}
+
+impl RelayStats {
+    /// Count an allocation deleted after `lifetime`, having relayed `traffic`.
+    fn ended(&self, lifetime: Duration, traffic: &Traffic) {
+        self.ended.absorb(traffic);
+        self.lifetimes.observe(lifetime);
+    }
+}
+
+/// Summary of an active allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationInfo {
+    pub five_tuple: FiveTuple,
+    pub relayed_addr: SocketAddr,
+    /// Whether data is relayed with peers over TCP rather than UDP.
+    pub tcp: bool,
+    /// Time left before the allocation expires, unless refreshed.
+    pub expires_in: Duration,
+    /// Bytes relayed, by direction.
+    pub bytes: [u64; Direction::ALL.len()],
+    /// Packets relayed, by direction.
+    pub packets: [u64; Direction::ALL.len()],
+}
+
+/// TURN server state, the allocations of every client.
+pub struct Turn {
+    /// Addresses relayed transport addresses are allocated on, by family.
+    relay_ipv4: Option<IpAddr>,
+    relay_ipv6: Option<IpAddr>,
+    allocations: Mutex<HashMap<FiveTuple, Allocation>>,
+    /// Peer data connections of TCP allocations waiting for a ConnectionBind request.
+    pending: Pending,
+    /// Peer data connections bound to the client data connection they are received on, until
+    /// the connection handler takes them over.
+    bound: Mutex<HashMap<FiveTuple, PeerConnection>>,
+    /// Ports reserved by an Allocate request with EVEN-PORT, by RESERVATION-TOKEN.
+    reservations: Mutex<HashMap<[u8; RESERVATION_TOKEN_SIZE], (UdpSocket, Instant)>>,
+    /// Number of messages from clients without an allocation that require one.
+    unknown_sources: AtomicU64,
+    peers: PeerPolicy,
+    /// Number of requests refused as their peer isn't permitted.
+    forbidden_peers: AtomicU64,
+    stats: Arc<RelayStats>,
+    quotas: Quotas,
+    bandwidth: BandwidthLimits,
+    /// Whether clients may move their allocations to another address.
+    mobility: bool,
+    /// When the server stops while draining, refreshes don't extend the allocations beyond.
+    drain_deadline: Mutex<Option<Instant>>,
+    /// Ports relayed transport addresses are allocated on, any free one when not set.
+    ports: Option<PortRange>,
+    /// Number of allocations refused as every port of the range was in use.
+    ports_exhausted: AtomicU64,
+    /// Options of the relay sockets and of the connections to peers.
+    socket_config: SocketConfig,
+}
+
+impl Turn {
+    pub fn new(relay_ip: IpAddr) -> Self {
+        Turn {
+            relay_ipv4: None,
+            relay_ipv6: None,
+            allocations: Default::default(),
+            pending: Default::default(),
+            bound: Default::default(),
+            reservations: Default::default(),
+            unknown_sources: AtomicU64::new(0),
+            peers: PeerPolicy::default(),
+            forbidden_peers: AtomicU64::new(0),
+            stats: Default::default(),
+            quotas: Quotas::default(),
+            bandwidth: BandwidthLimits::default(),
+            mobility: false,
+            drain_deadline: Mutex::new(None),
+            ports: None,
+            ports_exhausted: AtomicU64::new(0),
+            socket_config: SocketConfig::default(),
+        }
+        .with_relay_ip(relay_ip)
+    }
+
+    /// Allocate the relayed transport addresses of the family of `relay_ip` on it, letting
+    /// clients relay over both IPv4 and IPv6 when given one of each,
+    /// see https://datatracker.ietf.org/doc/html/rfc6156
+    pub fn with_relay_ip(mut self, relay_ip: IpAddr) -> Self {
+        match relay_ip {
+            IpAddr::V4(_) => self.relay_ipv4 = Some(relay_ip),
+            IpAddr::V6(_) => self.relay_ipv6 = Some(relay_ip),
+        }
+        self
+    }
+
+    /// Refuse the allocations exceeding `quotas` with 486 Allocation Quota Reached.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Limit the bytes relayed by the allocations to `bandwidth`.
+    pub fn with_bandwidth_limits(mut self, bandwidth: BandwidthLimits) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Refuse the permissions, channels and connections to the peers `peers` doesn't permit
+    /// with 403 Forbidden.
+    pub fn with_peer_policy(mut self, peers: PeerPolicy) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    /// Allocate the relayed transport addresses on the ports of `ports` only, refusing the
+    /// allocations with 508 Insufficient Capacity once they are all in use.
+    pub fn with_port_range(mut self, ports: Option<PortRange>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    /// Apply `config` to the relay sockets and to the connections to peers.
+    pub fn with_socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    /// Let the clients asking for it keep their allocations when their address changes,
+    /// see https://datatracker.ietf.org/doc/html/rfc8016
+    pub fn with_mobility(mut self, mobility: bool) -> Self {
+        self.mobility = mobility;
+        self
+    }
+
+    /// Handle a TURN message from a client authenticated as `username` in `realm` if given,
+    /// returning the response to send back if any. The bytes relayed by the allocation it creates
+    /// are limited to `bandwidth` if given, rather than to the limits of the server.
+    pub async fn handle(
+        &self,
+        buf: &[u8],
+        source: &Source,
+        realm: Option<&str>,
+        username: Option<&str>,
+        bandwidth: Option<u64>,
+    ) -> Option<Message> {
+        let message = match Message::decode(buf) {
+            Ok(message) => message,
+            Err(err) => {
+                log::debug!(
+                    "could not parse packet from {:?} : {:?} as a TURN message",
+                    source.addr,
+                    err
+                );
+                return None;
+            }
+        };
+        log::debug!(
+            "TURN message received {:?} from source address: {:?}",
+            message,
+            source.addr
+        );
+
+        match (message.method, message.class) {
+            (methods::ALLOCATE, Class::Request) => {
+                let bandwidth = bandwidth.or_else(|| self.bandwidth.get(username));
+                Some(self.allocate(&message, source, realm, username, bandwidth))
+            }
+            (methods::REFRESH, Class::Request) => Some(self.refresh(&message, source, username)),
+            (methods::CREATE_PERMISSION, Class::Request) => {
+                Some(self.create_permission(&message, source))
+            }
+            (methods::CHANNEL_BIND, Class::Request) => Some(self.channel_bind(&message, source)),
+            (methods::CONNECT, Class::Request) => Some(self.connect(&message, source).await),
+            (methods::CONNECTION_BIND, Class::Request) => {
+                Some(self.connection_bind(&message, source))
+            }
+            (methods::SEND, Class::Indication) => {
+                self.send(&message, source).await;
+                None
+            }
+            (_, Class::Request) => Some(message.error_response(400, "Unsupported method")),
+            // Indications and responses of other methods are silently discarded.
+            _ => None,
+        }
+    }
+
+    /// Handle an Allocate request, see https://datatracker.ietf.org/doc/html/rfc5766#section-6.2
+    fn allocate(
+        &self,
+        request: &Message,
+        source: &Source,
+        realm: Option<&str>,
+        username: Option<&str>,
+        bandwidth: Option<u64>,
+    ) -> Message {
+        // The relay is bound without holding the lock on the allocations, which is only taken to
+        // check the allocation may be made, before binding and again when inserting it.
+        let in_use = {
+            let allocations = self.allocations.lock().unwrap();
+            if let Err(response) = self.check_allocation(&allocations, request, source, username) {
+                return response;
+            }
+            self.ports_in_use(&allocations)
+        };
+
+        let mobility = request.get(attributes::MOBILITY_TICKET).is_some();
+        if mobility && !self.mobility {
+            return request.error_response(405, "Mobility Forbidden");
+        }
+
+        let even_port = request.get(attributes::EVEN_PORT);
+        let reservation = request.get(attributes::RESERVATION_TOKEN);
+        // The reserved port already has a family, see
+        // https://datatracker.ietf.org/doc/html/rfc6156#section-4.2
+        let family = request.get(attributes::REQUESTED_ADDRESS_FAMILY);
+        if family.is_some() && reservation.is_some() {
+            return request.error_response(400, "Bad Request");
+        }
+        let relay_ip = match family {
+            None => self.relay_ipv4.or(self.relay_ipv6),
+            Some([FAMILY_IPV4, ..]) => self.relay_ipv4,
+            Some([FAMILY_IPV6, ..]) => self.relay_ipv6,
+            Some(_) => None,
+        };
+        let relay_ip = match relay_ip {
+            Some(relay_ip) => relay_ip,
+            None => return request.error_response(440, "Address Family not Supported"),
+        };
+
+        let dont_fragment = request.get(attributes::DONT_FRAGMENT).is_some();
+        let expires_at = Instant::now() + lifetime(request);
+        let mut reservation_token = None;
+        let allocation = match request.get(attributes::REQUESTED_TRANSPORT) {
+            Some([TRANSPORT_UDP, ..]) => {
+                let relay = match (even_port, reservation) {
+                    (Some(_), Some(_)) | (Some([]), None) => {
+                        return request.error_response(400, "Bad Request")
+                    }
+                    (Some([flags, ..]), None) => self
+                        .bind_even_port(relay_ip, &in_use, flags & EVEN_PORT_RESERVE != 0)
+                        .map(|(relay, reserved)| {
+                            reservation_token = reserved.map(|reserved| self.reserve(reserved));
+                            relay
+                        }),
+                    (None, Some(token)) => match self.take_reservation(token) {
+                        Some(relay) => Ok(relay),
+                        None => return request.error_response(508, "Insufficient Capacity"),
+                    },
+                    (None, None) => self.bind_relay(relay_ip, &in_use, false, |addr| {
+                        net::bind_udp(addr, &self.socket_config)
+                    }),
+                };
+                if let (Ok(relay), true) = (&relay, dont_fragment) {
+                    if let Err(err) = net::set_dont_fragment(relay, true) {
+                        log::debug!(
+                            "could not set DF on the relay of {:?}: {}",
+                            source.addr,
+                            err
+                        );
+                        return request
+                            .error_response(420, "Unknown Attribute")
+                            .add_attribute(
+                                attributes::UNKNOWN_ATTRIBUTES,
+                                attributes::DONT_FRAGMENT.to_be_bytes().to_vec(),
+                            );
+                    }
+                }
+                relay.and_then(|relay| {
+                    Ok(Allocation::new(
+                        request.transaction_id,
+                        relay,
+                        source.clone(),
+                        expires_at,
+                        bandwidth,
+                        self.stats.clone(),
+                    )?)
+                })
+            }
+            // Ports can't be reserved for TCP allocations, see
+            // https://datatracker.ietf.org/doc/html/rfc6062#section-5.1, and they are bound to
+            // their control connection.
+            Some([TRANSPORT_TCP, ..])
+                if even_port.is_some() || reservation.is_some() || mobility =>
+            {
+                return request.error_response(400, "Bad Request")
+            }
+            // TCP allocations are controlled over a TCP or TLS connection,
+            // see https://datatracker.ietf.org/doc/html/rfc6062#section-5.1
+            Some([TRANSPORT_TCP, ..])
+                if matches!(source.transport, Transport::Tcp | Transport::Tls) =>
+            {
+                self.bind_relay(relay_ip, &in_use, false, |addr| {
+                    Ok(net::bind_relay_tcp(addr, &self.socket_config)?)
+                })
+                .and_then(|listener| {
+                    Ok(Allocation::new_tcp(
+                        request.transaction_id,
+                        listener,
+                        source.clone(),
+                        expires_at,
+                        self.pending.clone(),
+                        bandwidth,
+                        self.stats.clone(),
+                    )?)
+                })
+            }
+            Some([TRANSPORT_TCP, ..]) => return request.error_response(400, "Bad Request"),
+            Some(_) => return request.error_response(442, "Unsupported Transport Protocol"),
+            None => return request.error_response(400, "Bad Request"),
+        };
+        let mut allocation = match allocation {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                log::error!("could not allocate a relay for {:?}: {}", source.addr, err);
+                return request.error_response(508, "Insufficient Capacity");
+            }
+        };
+        let mut allocations = self.allocations.lock().unwrap();
+        // The 5-tuple may have been allocated for, or a quota reached, while binding.
+        if let Err(response) = self.check_allocation(&allocations, request, source, username) {
+            return response;
+        }
+        log::info!(
+            "allocated relay {:?} for {:?}",
+            allocation.relayed_addr,
+            source.addr
+        );
+        allocation.realm = realm.map(str::to_string);
+        allocation.username = username.map(str::to_string);
+        allocation.reservation_token = reservation_token;
+        allocation.mobility_ticket = mobility.then(rand::random);
+        allocation.dont_fragment = dont_fragment;
+        let response = allocate_response(request, &allocation, source);
+        allocations.insert(source.five_tuple(), allocation);
+        response
+    }
+
+    /// Check an allocation may be made for `request` of `source`, authenticated as `username`
+    /// if given, along `allocations`: there is none for its 5-tuple yet and the quotas aren't
+    /// reached. Returns the response to send back otherwise.
+    fn check_allocation(
+        &self,
+        allocations: &HashMap<FiveTuple, Allocation>,
+        request: &Message,
+        source: &Source,
+        username: Option<&str>,
+    ) -> Result<(), Message> {
+        if let Some(allocation) = allocations.get(&source.five_tuple()) {
+            // A retransmission of the request that created the allocation gets the same answer.
+            if allocation.transaction_id == request.transaction_id {
+                return Err(allocate_response(request, allocation, source));
+            }
+            return Err(request.error_response(437, "Allocation Mismatch"));
+        }
+        let ip_quota_reached = self.quotas.per_ip.is_some_and(|quota| {
+            let ip = source.addr.ip();
+            allocations
+                .keys()
+                .filter(|other| other.client.ip() == ip)
+                .count()
+                >= quota
+        });
+        let user_quota_reached = match (self.quotas.per_user, username) {
+            (Some(quota), Some(username)) => {
+                allocations
+                    .values()
+                    .filter(|other| other.username.as_deref() == Some(username))
+                    .count()
+                    >= quota
+            }
+            _ => false,
+        };
+        if ip_quota_reached || user_quota_reached {
+            log::info!(
+                "refused allocation of {:?} as {:?}, quota reached",
+                source.addr,
+                username
+            );
+            return Err(request.error_response(486, "Allocation Quota Reached"));
+        }
+        Ok(())
+    }
+
+    /// Ports of the range the relayed transport addresses and the reserved ports are bound to,
+    /// none when there is no range.
+    fn ports_in_use(&self, allocations: &HashMap<FiveTuple, Allocation>) -> HashSet<u16> {
+        let ports = match self.ports {
+            Some(ports) => ports,
+            None => return HashSet::new(),
+        };
+        let reservations = self.reservations.lock().unwrap();
+        let reserved = reservations
+            .values()
+            .filter_map(|(relay, _)| relay.local_addr().ok());
+        allocations
+            .values()
+            .map(|allocation| allocation.relayed_addr)
+            .chain(reserved)
+            .map(|addr| addr.port())
+            .filter(|port| ports.contains(*port))
+            .collect()
+    }
+
+    /// Bind a relay on `ip` with `bind`, on a port of the range if configured or else on one
+    /// picked by the system. The ports of the range are tried from a random one on, skipping
+    /// those `in_use` and those already bound, and the odd ones when `even` is set.
+    fn bind_relay<T>(
+        &self,
+        ip: IpAddr,
+        in_use: &HashSet<u16>,
+        even: bool,
+        mut bind: impl FnMut(SocketAddr) -> Result<T>,
+    ) -> Result<T> {
+        let ports = match self.ports {
+            Some(ports) => ports,
+            None => return bind(SocketAddr::new(ip, 0)),
+        };
+        let start = rand::random::<u32>() % ports.len();
+        for offset in 0..ports.len() {
+            let port = ports.first + ((start + offset) % ports.len()) as u16;
+            if (even && !port.is_multiple_of(2)) || in_use.contains(&port) {
+                continue;
+            }
+            match bind(SocketAddr::new(ip, port)) {
+                Ok(relay) => return Ok(relay),
+                Err(err) if is_in_use(&err) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        self.ports_exhausted.fetch_add(1, Ordering::Relaxed);
+        bail!("every port of the relay port range {} is in use", ports)
+    }
+
+    /// Bind a relay socket on an even port of `ip`, along with a socket on the next port when
+    /// `reserve` is set, see https://datatracker.ietf.org/doc/html/rfc5766#section-6.2
+    fn bind_even_port(
+        &self,
+        ip: IpAddr,
+        in_use: &HashSet<u16>,
+        reserve: bool,
+    ) -> Result<(UdpSocket, Option<UdpSocket>)> {
+        if let Some(ports) = self.ports {
+            return self.bind_relay(ip, in_use, true, |addr| {
+                let relay = net::bind_udp(addr, &self.socket_config)?;
+                if !reserve {
+                    return Ok((relay, None));
+                }
+                let next = addr.port() + 1;
+                if !ports.contains(next) || in_use.contains(&next) {
+                    return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+                }
+                Ok((
+                    relay,
+                    Some(net::bind_udp(
+                        SocketAddr::new(ip, next),
+                        &self.socket_config,
+                    )?),
+                ))
+            });
+        }
+        let addr = SocketAddr::new(ip, 0);
+        for _ in 0..EVEN_PORT_ATTEMPTS {
+            let relay = net::bind_udp(addr, &self.socket_config)?;
+            let port = relay.local_addr()?.port();
+            if !port.is_multiple_of(2) {
+                continue;
+            }
+            if !reserve {
+                return Ok((relay, None));
+            }
+            if let Ok(next) = net::bind_udp(SocketAddr::new(ip, port + 1), &self.socket_config) {
+                return Ok((relay, Some(next)));
+            }
+        }
+        bail!("no even port found in {} attempts", EVEN_PORT_ATTEMPTS)
+    }
+
+    /// Reserve the port `relay` is bound to, returning the token to allocate it with.
+    fn reserve(&self, relay: UdpSocket) -> [u8; RESERVATION_TOKEN_SIZE] {
+        let mut reservations = self.reservations.lock().unwrap();
+        let mut token = rand::random();
+        while reservations.contains_key(&token) {
+            token = rand::random();
+        }
+        reservations.insert(token, (relay, Instant::now() + RESERVATION_LIFETIME));
+        token
+    }
+
+    /// Take the port reserved with `token`, unless it expired.
+    fn take_reservation(&self, token: &[u8]) -> Option<UdpSocket> {
+        let token: [u8; RESERVATION_TOKEN_SIZE] = token.try_into().ok()?;
+        match self.reservations.lock().unwrap().remove(&token) {
+            Some((relay, expires_at)) if expires_at > Instant::now() => Some(relay),
+            _ => None,
+        }
+    }
+
+    /// Handle a Refresh request, see https://datatracker.ietf.org/doc/html/rfc5766#section-7.2
+    /// With a MOBILITY-TICKET, the allocation moves to the 5-tuple of the request,
+    /// see https://datatracker.ietf.org/doc/html/rfc8016#section-3.2
+    fn refresh(&self, request: &Message, source: &Source, username: Option<&str>) -> Message {
+        let five_tuple = source.five_tuple();
+        let mut allocations = self.allocations.lock().unwrap();
+        if let Some(ticket) = request.get(attributes::MOBILITY_TICKET) {
+            if !allocations.contains_key(&five_tuple) {
+                let moved = allocations
+                    .iter()
+                    .find(|(_, allocation)| {
+                        allocation
+                            .mobility_ticket
+                            .is_some_and(|other| other[..] == *ticket)
+                    })
+                    .map(|(from, allocation)| {
+                        // Only an authenticated user can prove the allocation is theirs.
+                        let owner =
+                            username.is_some() && allocation.username.as_deref() == username;
+                        (*from, owner)
+                    });
+                match moved {
+                    Some((from, true)) => {
+                        let allocation = allocations.remove(&from).unwrap();
+                        log::info!(
+                            "moved relay {:?} from {:?} to {:?}",
+                            allocation.relayed_addr,
+                            from.client,
+                            source.addr
+                        );
+                        allocation.move_to(source.clone());
+                        allocations.insert(five_tuple, allocation);
+                    }
+                    Some((_, false)) => return request.error_response(441, "Wrong Credentials"),
+                    None => return request.error_response(400, "Bad Request"),
+                }
+            }
+        }
+        let allocation = match allocations.get_mut(&five_tuple) {
+            Some(allocation) => allocation,
+            None => {
+                self.unknown_source(source);
+                return request.error_response(437, "Allocation Mismatch");
+            }
+        };
+        // The family of an allocation can't change,
+        // see https://datatracker.ietf.org/doc/html/rfc6156#section-5.2
+        let family = match allocation.relayed_addr {
+            SocketAddr::V4(_) => FAMILY_IPV4,
+            SocketAddr::V6(_) => FAMILY_IPV6,
+        };
+        if request
+            .get(attributes::REQUESTED_ADDRESS_FAMILY)
+            .is_some_and(|requested| requested.first() != Some(&family))
+        {
+            return request.error_response(443, "Peer Address Family Mismatch");
+        }
+
+        // A lifetime of zero deletes the allocation.
+        if request.get_u32(attributes::LIFETIME) == Some(0) {
+            log::info!(
+                "released relay {:?} of {:?}",
+                allocation.relayed_addr,
+                source.addr
+            );
+            allocations.remove(&five_tuple);
+            return request.success_response().add_u32(attributes::LIFETIME, 0);
+        }
+
+        let mut lifetime = lifetime(request);
+        let now = Instant::now();
+        // While draining, clients learn when their allocation ends at the latest.
+        if let Some(deadline) = *self.drain_deadline.lock().unwrap() {
+            lifetime = lifetime.min(deadline.saturating_duration_since(now));
+        }
+        allocation.expires_at = now + lifetime;
+        let response = request
+            .success_response()
+            .add_u32(attributes::LIFETIME, lifetime.as_secs() as u32);
+        // Each ticket is only good for one move.
+        match &mut allocation.mobility_ticket {
+            Some(ticket) => {
+                *ticket = rand::random();
+                response.add_attribute(attributes::MOBILITY_TICKET, ticket.to_vec())
+            }
+            None => response,
+        }
+    }
+
+    /// Handle a Send indication, see https://datatracker.ietf.org/doc/html/rfc5766#section-10.2
+    /// With DONT-FRAGMENT, the DF bit is set on the datagram relayed, or it is dropped if it
+    /// can't be.
+    async fn send(&self, indication: &Message, source: &Source) {
+        let (peer, data) = match (
+            indication.get_xor_address(attributes::XOR_PEER_ADDRESS),
+            indication.get(attributes::DATA),
+        ) {
+            (Some(peer), Some(data)) => (peer, data),
+            _ => {
+                log::debug!("dropping malformed Send indication from {:?}", source.addr);
+                return;
+            }
+        };
+        let (relay, traffic, dont_fragment) = {
+            let allocations = self.allocations.lock().unwrap();
+            let allocation = match allocations.get(&source.five_tuple()) {
+                Some(allocation) => allocation,
+                None => {
+                    self.unknown_source(source);
+                    return;
+                }
+            };
+            let relay = match allocation.relay_to() {
+                Some(relay) => relay,
+                None => {
+                    log::debug!(
+                        "dropping Send indication from {:?} for a TCP allocation",
+                        source.addr
+                    );
+                    return;
+                }
+            };
+            if !self.admits(allocation, source, peer, data.len()) {
+                return;
+            }
+            // The relay of allocations asking for DONT-FRAGMENT always sets it.
+            let dont_fragment =
+                indication.get(attributes::DONT_FRAGMENT).is_some() && !allocation.dont_fragment;
+            (relay, allocation.traffic().clone(), dont_fragment)
+        };
+        if dont_fragment {
+            if let Err(err) = net::set_dont_fragment(&relay, true) {
+                log::debug!(
+                    "dropping Send indication from {:?}, could not set DF: {}",
+                    source.addr,
+                    err
+                );
+                return;
+            }
+        }
+        match relay.send_to(data, peer).await {
+            Ok(_) => traffic.add(Direction::ToPeer, data.len()),
+            Err(err) => log::debug!(
+                "could not relay data from {:?} to peer {:?}: {}",
+                source.addr,
+                peer,
+                err
+            ),
+        }
+        if dont_fragment {
+            if let Err(err) = net::set_dont_fragment(&relay, false) {
+                log::debug!(
+                    "could not clear DF on the relay of {:?}: {}",
+                    source.addr,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Whether `len` bytes of data from the client may be relayed to `peer` by `allocation`,
+    /// counting them as dropped otherwise.
+    fn admits(
+        &self,
+        allocation: &Allocation,
+        source: &Source,
+        peer: SocketAddr,
+        len: usize,
+    ) -> bool {
+        if !allocation.permits(peer.ip()) {
+            self.stats.dropped.add(DropReason::NoPermission);
+            log::trace!(
+                "dropping data from {:?} to peer {:?} without permission",
+                source.addr,
+                peer
+            );
+            return false;
+        }
+        if !allocation.allows(len) {
+            self.stats.dropped.add(DropReason::OverBandwidth);
+            log::trace!(
+                "dropping data from {:?} to peer {:?} over the bandwidth of the allocation",
+                source.addr,
+                peer
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Handle a CreatePermission request, installing or refreshing the permission of each
+    /// peer, see https://datatracker.ietf.org/doc/html/rfc5766#section-9.2
+    fn create_permission(&self, request: &Message, source: &Source) -> Message {
+        let peers = match request.get_xor_addresses(attributes::XOR_PEER_ADDRESS) {
+            Some(peers) if !peers.is_empty() => peers,
+            _ => return request.error_response(400, "Bad Request"),
+        };
+        if let Some(peer) = peers.iter().find(|peer| !self.peers.permits(peer.ip())) {
+            return self.forbidden(request, source, *peer);
+        }
+        let allocations = self.allocations.lock().unwrap();
+        let allocation = match allocations.get(&source.five_tuple()) {
+            Some(allocation) => allocation,
+            None => {
+                self.unknown_source(source);
+                return request.error_response(437, "Allocation Mismatch");
+            }
+        };
+        if !peers.iter().all(|peer| allocation.relays_to(*peer)) {
+            return request.error_response(443, "Peer Address Family Mismatch");
+        }
+        for peer in peers {
+            allocation.permit(peer.ip());
+        }
+        request.success_response()
+    }
+
+    /// Handle a ChannelBind request, see https://datatracker.ietf.org/doc/html/rfc5766#section-11.2
+    fn channel_bind(&self, request: &Message, source: &Source) -> Message {
+        let number = request
+            .get(attributes::CHANNEL_NUMBER)
+            .and_then(|value| Some(u16::from_be_bytes([*value.first()?, *value.get(1)?])));
+        let (number, peer) = match (
+            number,
+            request.get_xor_address(attributes::XOR_PEER_ADDRESS),
+        ) {
+            (Some(number), Some(peer)) if CHANNEL_NUMBERS.contains(&number) => (number, peer),
+            _ => return request.error_response(400, "Bad Request"),
+        };
+        if !self.peers.permits(peer.ip()) {
+            return self.forbidden(request, source, peer);
+        }
+        let allocations = self.allocations.lock().unwrap();
+        let allocation = match allocations.get(&source.five_tuple()) {
+            Some(allocation) => allocation,
+            None => {
+                self.unknown_source(source);
+                return request.error_response(437, "Allocation Mismatch");
+            }
+        };
+        if !allocation.relays_to(peer) {
+            return request.error_response(443, "Peer Address Family Mismatch");
+        }
+        if !allocation.bind_channel(number, peer) {
+            return request.error_response(400, "Bad Request");
+        }
+        log::debug!(
+            "bound channel {:#06x} of {:?} to peer {:?} for {:?}",
+            number,
+            source.addr,
+            peer,
+            CHANNEL_LIFETIME
+        );
+        request.success_response()
+    }
+
+    /// Handle a ChannelData message, relaying its data to the peer the channel is bound to,
+    /// see https://datatracker.ietf.org/doc/html/rfc5766#section-11.6
+    pub async fn channel_data(&self, message: &ChannelData<'_>, source: &Source) {
+        let (relay, peer, traffic) = {
+            let allocations = self.allocations.lock().unwrap();
+            let allocation = match allocations.get(&source.five_tuple()) {
+                Some(allocation) => allocation,
+                None => {
+                    self.unknown_source(source);
+                    return;
+                }
+            };
+            let (relay, peer) = match (
+                allocation.relay_to(),
+                allocation.channel_peer(message.number),
+            ) {
+                (Some(relay), Some(peer)) => (relay, peer),
+                _ => {
+                    log::debug!(
+                        "dropping data from {:?} on unbound channel {:#06x}",
+                        source.addr,
+                        message.number
+                    );
+                    return;
+                }
+            };
+            if !self.admits(allocation, source, peer, message.data.len()) {
+                return;
+            }
+            (relay, peer, allocation.traffic().clone())
+        };
+        match relay.send_to(message.data, peer).await {
+            Ok(_) => traffic.add(Direction::ToPeer, message.data.len()),
+            Err(err) => log::debug!(
+                "could not relay data from {:?} to peer {:?}: {}",
+                source.addr,
+                peer,
+                err
+            ),
+        }
+    }
+
+    /// Handle a Connect request, opening a connection from the relayed transport address of a
+    /// TCP allocation to a peer, see https://datatracker.ietf.org/doc/html/rfc6062#section-5.2
+    async fn connect(&self, request: &Message, source: &Source) -> Message {
+        let peer = match request.get_xor_address(attributes::XOR_PEER_ADDRESS) {
+            Some(peer) => peer,
+            None => return request.error_response(400, "Bad Request"),
+        };
+        if !self.peers.permits(peer.ip()) {
+            return self.forbidden(request, source, peer);
+        }
+        let (relayed_addr, link) = {
+            let allocations = self.allocations.lock().unwrap();
+            let allocation = match allocations.get(&source.five_tuple()) {
+                Some(allocation) => allocation,
+                None => {
+                    self.unknown_source(source);
+                    return request.error_response(437, "Allocation Mismatch");
+                }
+            };
+            let peers = match allocation.peers() {
+                Some(peers) => peers,
+                None => return request.error_response(400, "Not a TCP allocation"),
+            };
+            if !allocation.relays_to(peer) {
+                return request.error_response(443, "Peer Address Family Mismatch");
+            }
+            let link = match peers.link(peer) {
+                Some(link) => link,
+                None => return request.error_response(446, "Connection Already Exists"),
+            };
+            allocation.permit(peer.ip());
+            (allocation.relayed_addr, link)
+        };
+
+        let stream = match tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            net::connect_from(relayed_addr, peer, &self.socket_config),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                log::debug!(
+                    "could not connect {:?} to peer {:?}: {}",
+                    relayed_addr,
+                    peer,
+                    err
+                );
+                return request.error_response(447, "Connection Timeout or Failure");
+            }
+            Err(_) => {
+                log::debug!("timed out connecting {:?} to peer {:?}", relayed_addr, peer);
+                return request.error_response(447, "Connection Timeout or Failure");
+            }
+        };
+        let id = connection::offer(&self.pending, PeerConnection::new(stream, link));
+        request
+            .success_response()
+            .add_u32(attributes::CONNECTION_ID, id)
+    }
+
+    /// Handle a ConnectionBind request, received on a new client data connection to relay the
+    /// data of a peer connection, see https://datatracker.ietf.org/doc/html/rfc6062#section-5.4
+    fn connection_bind(&self, request: &Message, source: &Source) -> Message {
+        let five_tuple = source.five_tuple();
+        if !matches!(source.transport, Transport::Tcp | Transport::Tls)
+            || self.allocations.lock().unwrap().contains_key(&five_tuple)
+        {
+            return request.error_response(400, "Bad Request");
+        }
+        let connection = request
+            .get_u32(attributes::CONNECTION_ID)
+            .and_then(|id| self.pending.lock().unwrap().remove(&id));
+        let connection = match connection {
+            Some((connection, _)) if !connection.is_closed() => connection,
+            _ => return request.error_response(400, "Bad Request"),
+        };
+        self.bound.lock().unwrap().insert(five_tuple, connection);
+        request.success_response()
+    }
+
+    /// Stop extending the allocations beyond `deadline` when they are refreshed, as the server
+    /// stops then.
+    pub fn drain(&self, deadline: Instant) {
+        *self.drain_deadline.lock().unwrap() = Some(deadline);
+    }
+
+    /// Take the peer data connection bound to a client data connection, if any.
+    pub fn take_bound(&self, five_tuple: &FiveTuple) -> Option<PeerConnection> {
+        self.bound.lock().unwrap().remove(five_tuple)
+    }
+
+    /// Count a message from a client without allocation that requires one.
+    fn unknown_source(&self, source: &Source) {
+        self.unknown_sources.fetch_add(1, Ordering::Relaxed);
+        log::debug!("message from {:?} without allocation", source.addr);
+    }
+
+    /// Refuse `request` as the relay may not exchange data with `peer`.
+    fn forbidden(&self, request: &Message, source: &Source, peer: SocketAddr) -> Message {
+        self.forbidden_peers.fetch_add(1, Ordering::Relaxed);
+        log::debug!(
+            "refused {:?} from {:?} to peer {:?}, which isn't permitted",
+            request.method,
+            source.addr,
+            peer
+        );
+        request.error_response(403, "Forbidden")
+    }
+
+    /// Number of allocations refused as every port of the relay port range was in use.
+    pub fn ports_exhausted(&self) -> u64 {
+        self.ports_exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests refused as their peer isn't permitted.
+    pub fn forbidden_peers(&self) -> u64 {
+        self.forbidden_peers.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages from clients without an allocation that require one.
+    pub fn unknown_sources(&self) -> u64 {
+        self.unknown_sources.load(Ordering::Relaxed)
+    }
+
+    /// Numbers of datagrams of relayed data dropped, by reason.
+    pub fn dropped(&self) -> &DroppedPackets {
+        &self.stats.dropped
+    }
+
+    /// Data relayed by the active and deleted allocations.
+    pub fn relayed(&self) -> Traffic {
+        let allocations = self.allocations.lock().unwrap();
+        let relayed = Traffic::default();
+        // The allocations are deleted with the lock held, moving their data to the totals.
+        relayed.absorb(&self.stats.ended);
+        for allocation in allocations.values() {
+            relayed.absorb(allocation.traffic());
+        }
+        relayed
+    }
+
+    /// How long the deleted allocations lasted.
+    pub fn lifetimes(&self) -> &Histogram {
+        &self.stats.lifetimes
+    }
+
+    /// Numbers of active allocations of the clients that authenticated, by realm and by
+    /// username. Users of the same name in several realms are counted together.
+    pub fn allocations_by_owner(&self) -> (BTreeMap<String, u64>, BTreeMap<String, u64>) {
+        let mut realms = BTreeMap::new();
+        let mut users = BTreeMap::new();
+        for allocation in self.allocations.lock().unwrap().values() {
+            if let Some(realm) = &allocation.realm {
+                *realms.entry(realm.clone()).or_default() += 1;
+            }
+            if let Some(username) = &allocation.username {
+                *users.entry(username.clone()).or_default() += 1;
+            }
+        }
+        (realms, users)
+    }
+
+    /// Number of active allocations.
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.lock().unwrap().len()
+    }
+
+    /// Summaries of the active allocations.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        let now = Instant::now();
+        self.allocations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(five_tuple, allocation)| AllocationInfo {
+                five_tuple: *five_tuple,
+                relayed_addr: allocation.relayed_addr,
+                tcp: allocation.peers().is_some(),
+                expires_in: allocation.expires_at.saturating_duration_since(now),
+                bytes: Direction::ALL.map(|direction| allocation.traffic().bytes(direction)),
+                packets: Direction::ALL.map(|direction| allocation.traffic().packets(direction)),
+            })
+            .collect()
+    }
+
+    /// Delete the allocation with the relayed transport address `relayed_addr`, returning
+    /// whether there was one.
+    pub fn release_relayed(&self, relayed_addr: SocketAddr) -> bool {
+        let mut allocations = self.allocations.lock().unwrap();
+        let five_tuple = match allocations
+            .iter()
+            .find(|(_, allocation)| allocation.relayed_addr == relayed_addr)
+        {
+            Some((five_tuple, _)) => *five_tuple,
+            None => return false,
+        };
+        allocations.remove(&five_tuple);
+        log::info!(
+            "released relay {:?} of {:?} on request",
+            relayed_addr,
+            five_tuple.client
+        );
+        true
+    }
+
+    /// Delete the allocation of a client, if any.
+    pub fn release(&self, five_tuple: &FiveTuple) {
+        if let Some(allocation) = self.allocations.lock().unwrap().remove(five_tuple) {
+            log::info!(
+                "released relay {:?} of {:?}",
+                allocation.relayed_addr,
+                five_tuple.client
+            );
+        }
+    }
+
+    /// Delete the allocations whose lifetime has expired.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.allocations
+            .lock()
+            .unwrap()
+            .retain(|five_tuple, allocation| {
+                let alive = allocation.expires_at > now;
+                if !alive {
+                    log::info!(
+                        "relay {:?} of {:?} expired",
+                        allocation.relayed_addr,
+                        five_tuple.client
+                    );
+                }
+                alive
+            });
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, (connection, expires_at)| *expires_at > now && !connection.is_closed());
+        self.reservations
+            .lock()
+            .unwrap()
+            .retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
+
+/// Whether `err` is caused by the address being in use already.
+fn is_in_use(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::AddrInUse)
+    })
+}
+
+/// Lifetime requested by the client, bounded by the server limits,
+/// see https://datatracker.ietf.org/doc/html/rfc5766#section-6.2
+fn lifetime(request: &Message) -> Duration {
+    match request.get_u32(attributes::LIFETIME) {
+        Some(secs) => Duration::from_secs(secs as u64).clamp(DEFAULT_LIFETIME, MAX_LIFETIME),
+        None => DEFAULT_LIFETIME,
+    }
+}
+
+/// Success response to an Allocate request.
+fn allocate_response(request: &Message, allocation: &Allocation, source: &Source) -> Message {
+    let lifetime = allocation
+        .expires_at
+        .saturating_duration_since(Instant::now());
+    let response = request
+        .success_response()
+        .add_xor_address(attributes::XOR_RELAYED_ADDRESS, allocation.relayed_addr)
+        .add_u32(attributes::LIFETIME, lifetime.as_secs_f64().ceil() as u32)
+        .add_xor_address(attributes::XOR_MAPPED_ADDRESS, source.addr);
+    let response = match allocation.reservation_token {
+        Some(token) => response.add_attribute(attributes::RESERVATION_TOKEN, token.to_vec()),
+        None => response,
+    };
+    match allocation.mobility_ticket {
+        Some(ticket) => response.add_attribute(attributes::MOBILITY_TICKET, ticket.to_vec()),
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::UdpSocket;
+
+    use super::{
+        BandwidthLimits, ChannelData, Direction, DropReason, PeerPolicy, PortRange, Quotas, Turn,
+    };
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::server::{Sink, Source, Transport};
+
+    /// Permit the peers on the loopback interface, refused by default.
+    fn loopback_peers() -> PeerPolicy {
+        PeerPolicy::new(vec!["127.0.0.0/8".parse().unwrap()], Vec::new())
+    }
+
+    async fn client() -> (UdpSocket, Source) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let source = Source {
+            addr: client.local_addr().unwrap(),
+            local_addr: server.local_addr().unwrap(),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(server),
+        };
+        (client, source)
+    }
+
+    async fn allocate(turn: &Turn, source: &Source) -> Message {
+        allocate_as(turn, source, None).await
+    }
+
+    async fn allocate_as(turn: &Turn, source: &Source, username: Option<&str>) -> Message {
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+        turn.handle(&request.encode(), source, None, username, None)
+            .await
+            .unwrap()
+    }
+
+    async fn create_permission(turn: &Turn, source: &Source, peer: SocketAddr) -> Message {
+        let request =
+            Message::with_random_transaction_id(methods::CREATE_PERMISSION, Class::Request)
+                .add_xor_address(attributes::XOR_PEER_ADDRESS, peer);
+        turn.handle(&request.encode(), source, None, None, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn allocates_and_refreshes_a_relay() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+
+        let response = allocate(&turn, &source).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert!(response
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .is_some());
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(source.addr)
+        );
+        assert_eq!(response.get_u32(attributes::LIFETIME), Some(600));
+
+        // A second allocation on the same 5-tuple is rejected.
+        assert_eq!(allocate(&turn, &source).await.error_code(), Some(437));
+
+        let refresh = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_u32(attributes::LIFETIME, 0);
+        let response = turn
+            .handle(&refresh.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert_eq!(response.get_u32(attributes::LIFETIME), Some(0));
+
+        // The allocation is gone after being refreshed with a zero lifetime.
+        let response = turn
+            .handle(&refresh.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(437));
+        assert_eq!(turn.unknown_sources(), 1);
+    }
+
+    #[tokio::test]
+    async fn caps_refreshes_while_draining() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+        allocate(&turn, &source).await;
+        turn.drain(Instant::now() + Duration::from_secs(30));
+
+        let refresh = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_u32(attributes::LIFETIME, 3600);
+        let response = turn
+            .handle(&refresh.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert!(response.get_u32(attributes::LIFETIME).unwrap() <= 30);
+        assert!(turn.allocations()[0].expires_in <= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn enforces_allocation_quotas() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_quotas(Quotas {
+            per_user: Some(1),
+            per_ip: None,
+        });
+        let (_first, first) = client().await;
+        let (_second, second) = client().await;
+        let response = allocate_as(&turn, &first, Some("alice")).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+        let response = allocate_as(&turn, &second, Some("alice")).await;
+        assert_eq!(response.error_code(), Some(486));
+        let response = allocate_as(&turn, &second, Some("bob")).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_quotas(Quotas {
+            per_user: None,
+            per_ip: Some(1),
+        });
+        assert_eq!(allocate(&turn, &first).await.class, Class::SuccessResponse);
+        // The clients share 127.0.0.1.
+        assert_eq!(allocate(&turn, &second).await.error_code(), Some(486));
+        turn.release(&first.five_tuple());
+        assert_eq!(allocate(&turn, &second).await.class, Class::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn reserves_the_port_following_an_even_one() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_first, first) = client().await;
+        let (_second, second) = client().await;
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+
+        let even_port = request
+            .clone()
+            .add_attribute(attributes::EVEN_PORT, vec![0x80]);
+        let response = turn
+            .handle(&even_port.encode(), &first, None, None, None)
+            .await
+            .unwrap();
+        let relayed_addr = response
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        assert_eq!(relayed_addr.port() % 2, 0);
+        let token = response
+            .get(attributes::RESERVATION_TOKEN)
+            .unwrap()
+            .to_vec();
+        assert_eq!(token.len(), 8);
+
+        // Both attributes can't be given together.
+        let both = even_port.add_attribute(attributes::RESERVATION_TOKEN, token.clone());
+        let response = turn
+            .handle(&both.encode(), &second, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+
+        let reserved = request.add_attribute(attributes::RESERVATION_TOKEN, token);
+        let response = turn
+            .handle(&reserved.encode(), &second, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_RELAYED_ADDRESS),
+            Some(SocketAddr::new(relayed_addr.ip(), relayed_addr.port() + 1))
+        );
+        // A reservation is only used once.
+        turn.release(&second.five_tuple());
+        let response = turn
+            .handle(&reserved.encode(), &second, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(508));
+    }
+
+    #[tokio::test]
+    async fn moves_allocations_with_mobility_tickets() {
+        let (_first, first) = client().await;
+        let (second, moved) = client().await;
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0])
+            .add_attribute(attributes::MOBILITY_TICKET, Vec::new());
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let response = turn
+            .handle(&request.encode(), &first, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(405));
+
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into())
+            .with_mobility(true)
+            .with_peer_policy(loopback_peers());
+        let response = turn
+            .handle(&request.encode(), &first, None, Some("alice"), None)
+            .await
+            .unwrap();
+        let relayed_addr = response
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let ticket = response.get(attributes::MOBILITY_TICKET).unwrap().to_vec();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        create_permission(&turn, &first, peer.local_addr().unwrap()).await;
+
+        let refresh = |ticket: Vec<u8>| {
+            Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+                .add_attribute(attributes::MOBILITY_TICKET, ticket)
+                .encode()
+        };
+        // Only the user of the allocation can move it, once authenticated.
+        for username in [Some("bob"), None] {
+            let response = turn
+                .handle(&refresh(ticket.clone()), &moved, None, username, None)
+                .await
+                .unwrap();
+            assert_eq!(response.error_code(), Some(441));
+        }
+        let response = turn
+            .handle(&refresh(ticket.clone()), &moved, None, Some("alice"), None)
+            .await
+            .unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        let new_ticket = response.get(attributes::MOBILITY_TICKET).unwrap();
+        assert_ne!(new_ticket, ticket);
+        assert_eq!(turn.allocations()[0].five_tuple, moved.five_tuple());
+
+        // The data of peers follows the client, and tickets can't be used twice.
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        let len = second.recv(&mut buf).await.unwrap();
+        let data = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(data.get(attributes::DATA), Some(&b"pong"[..]));
+        let response = turn
+            .handle(&refresh(ticket), &first, None, Some("alice"), None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+
+        // Nobody can move the allocations of unauthenticated clients.
+        let response = turn
+            .handle(&request.encode(), &first, None, None, None)
+            .await
+            .unwrap();
+        let ticket = response.get(attributes::MOBILITY_TICKET).unwrap().to_vec();
+        let (_third, third) = client().await;
+        let response = turn
+            .handle(&refresh(ticket), &third, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(441));
+    }
+
+    #[tokio::test]
+    async fn lists_and_releases_allocations() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+        let response = allocate(&turn, &source).await;
+        let relayed_addr = response
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+
+        let allocations = turn.allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].five_tuple, source.five_tuple());
+        assert_eq!(allocations[0].relayed_addr, relayed_addr);
+        assert!(!allocations[0].tcp);
+
+        assert!(turn.release_relayed(relayed_addr));
+        assert!(!turn.release_relayed(relayed_addr));
+        assert_eq!(turn.allocation_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_peers_in_reserved_ranges() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+        allocate(&turn, &source).await;
+
+        for peer in [
+            "127.0.0.1:5000",
+            "10.1.2.3:5000",
+            "169.254.169.254:80",
+            "[::1]:5000",
+            "[::ffff:192.168.1.1]:5000",
+        ] {
+            let response = create_permission(&turn, &source, peer.parse().unwrap()).await;
+            assert_eq!(response.error_code(), Some(403), "{}", peer);
+        }
+        let public = "203.0.113.1:5000".parse().unwrap();
+        let response = create_permission(&turn, &source, public).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert_eq!(turn.forbidden_peers(), 5);
+
+        // Reserved ranges can be allowed, and public ones denied.
+        let peers = PeerPolicy::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["203.0.113.0/24".parse().unwrap()],
+        );
+        assert!(peers.permits("10.1.2.3".parse().unwrap()));
+        assert!(!peers.permits("203.0.113.1".parse().unwrap()));
+        assert!(!peers.permits("192.168.1.1".parse().unwrap()));
+        assert!(peers.permits("198.51.100.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn allocates_relays_of_the_requested_family() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+        let request = |family: u8| {
+            Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+                .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0])
+                .add_attribute(attributes::REQUESTED_ADDRESS_FAMILY, vec![family, 0, 0, 0])
+        };
+        for family in [0x02, 0x03] {
+            let response = turn
+                .handle(&request(family).encode(), &source, None, None, None)
+                .await
+                .unwrap();
+            assert_eq!(response.error_code(), Some(440));
+        }
+        let reserved = request(0x01).add_attribute(attributes::RESERVATION_TOKEN, vec![0; 8]);
+        let response = turn
+            .handle(&reserved.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+
+        let turn = turn.with_relay_ip(Ipv6Addr::LOCALHOST.into());
+        let response = turn
+            .handle(&request(0x02).encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        let relayed = response
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        assert_eq!(relayed.ip(), Ipv6Addr::LOCALHOST);
+
+        // Neither the allocation nor its peers can change family.
+        let refresh = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_attribute(attributes::REQUESTED_ADDRESS_FAMILY, vec![0x01, 0, 0, 0]);
+        let response = turn
+            .handle(&refresh.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(443));
+        let ipv4 = "203.0.113.1:5000".parse().unwrap();
+        let response = create_permission(&turn, &source, ipv4).await;
+        assert_eq!(response.error_code(), Some(443));
+        let ipv6 = "[2001:db8::1]:5000".parse().unwrap();
+        let response = create_permission(&turn, &source, ipv6).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn allocates_on_the_relay_port_range() {
+        let blocker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = blocker.local_addr().unwrap().port();
+        let range = format!("{}-{}", port, port).parse().unwrap();
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_port_range(Some(range));
+        let (_client, source) = client().await;
+
+        // The port is used by another socket.
+        assert_eq!(allocate(&turn, &source).await.error_code(), Some(508));
+        assert_eq!(turn.ports_exhausted(), 1);
+        drop(blocker);
+        let relayed_addr = allocate(&turn, &source)
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        assert_eq!(relayed_addr.port(), port);
+        // And then by the allocation.
+        let (_client, other) = client().await;
+        assert_eq!(allocate(&turn, &other).await.error_code(), Some(508));
+        assert_eq!(turn.ports_exhausted(), 2);
+
+        assert!("49152-65535".parse::<PortRange>().is_ok());
+        for invalid in ["0-10", "10-5", "49152", "a-b"] {
+            assert!(invalid.parse::<PortRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_allocations_without_supported_transport() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into());
+        let (_client, source) = client().await;
+
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request);
+        let response = turn
+            .handle(&request.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+
+        let sctp = request
+            .clone()
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![132, 0, 0, 0]);
+        let response = turn
+            .handle(&sctp.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(442));
+
+        // TCP allocations can't be requested over UDP.
+        let tcp = request.add_attribute(attributes::REQUESTED_TRANSPORT, vec![6, 0, 0, 0]);
+        let response = turn
+            .handle(&tcp.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+    }
+
+    #[tokio::test]
+    async fn relays_data_between_client_and_peer() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(loopback_peers());
+        let (client, source) = client().await;
+        let relayed_addr = allocate(&turn, &source)
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let response = create_permission(&turn, &source, peer_addr).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+
+        let send = Message::with_random_transaction_id(methods::SEND, Class::Indication)
+            .add_xor_address(attributes::XOR_PEER_ADDRESS, peer_addr)
+            .add_attribute(attributes::DATA, b"ping".to_vec());
+        assert!(turn
+            .handle(&send.encode(), &source, None, None, None)
+            .await
+            .is_none());
+
+        let mut buf = [0; 1024];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, relayed_addr);
+
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        let data = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(data.method, methods::DATA);
+        assert_eq!(data.class, Class::Indication);
+        assert_eq!(
+            data.get_xor_address(attributes::XOR_PEER_ADDRESS),
+            Some(peer_addr)
+        );
+        assert_eq!(data.get(attributes::DATA), Some(&b"pong"[..]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn honors_dont_fragment() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(loopback_peers());
+        let (_client, source) = client().await;
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0])
+            .add_attribute(attributes::DONT_FRAGMENT, Vec::new());
+        let response = turn
+            .handle(&request.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert!(turn.allocations.lock().unwrap()[&source.five_tuple()].dont_fragment);
+
+        // Send indications asking for it are relayed with DF too.
+        let (_client, source) = client().await;
+        allocate(&turn, &source).await;
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        create_permission(&turn, &source, peer_addr).await;
+        let send = Message::with_random_transaction_id(methods::SEND, Class::Indication)
+            .add_xor_address(attributes::XOR_PEER_ADDRESS, peer_addr)
+            .add_attribute(attributes::DONT_FRAGMENT, Vec::new())
+            .add_attribute(attributes::DATA, b"ping".to_vec());
+        turn.handle(&send.encode(), &source, None, None, None).await;
+        let mut buf = [0; 16];
+        let len = peer.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+    }
+
+    #[tokio::test]
+    async fn counts_the_data_relayed_and_allocations() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(loopback_peers());
+        let (client, source) = client().await;
+        let request = Message::with_random_transaction_id(methods::ALLOCATE, Class::Request)
+            .add_attribute(attributes::REQUESTED_TRANSPORT, vec![17, 0, 0, 0]);
+        let relayed_addr = turn
+            .handle(
+                &request.encode(),
+                &source,
+                Some("acme"),
+                Some("alice"),
+                None,
+            )
+            .await
+            .unwrap()
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let (realms, users) = turn.allocations_by_owner();
+        assert_eq!(realms.into_iter().collect::<Vec<_>>(), [("acme".into(), 1)]);
+        assert_eq!(users.into_iter().collect::<Vec<_>>(), [("alice".into(), 1)]);
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        create_permission(&turn, &source, peer_addr).await;
+        let send = Message::with_random_transaction_id(methods::SEND, Class::Indication)
+            .add_xor_address(attributes::XOR_PEER_ADDRESS, peer_addr)
+            .add_attribute(attributes::DATA, b"ping".to_vec());
+        turn.handle(&send.encode(), &source, None, None, None).await;
+        let mut buf = [0; 1024];
+        peer.recv(&mut buf).await.unwrap();
+        peer.send_to(b"hello", relayed_addr).await.unwrap();
+        client.recv(&mut buf).await.unwrap();
+
+        let allocation = turn.allocations().remove(0);
+        assert_eq!(allocation.bytes, [4, 5]);
+        assert_eq!(allocation.packets, [1, 1]);
+        let relayed = turn.relayed();
+        assert_eq!(relayed.bytes(Direction::ToPeer), 4);
+        assert_eq!(relayed.bytes(Direction::ToClient), 5);
+
+        // The totals and lifetimes of deleted allocations are kept.
+        let refresh = Message::with_random_transaction_id(methods::REFRESH, Class::Request)
+            .add_u32(attributes::LIFETIME, 0);
+        turn.handle(&refresh.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        let relayed = turn.relayed();
+        assert_eq!(relayed.packets(Direction::ToPeer), 1);
+        assert_eq!(relayed.packets(Direction::ToClient), 1);
+        let mut out = String::new();
+        turn.lifetimes().render(&mut out, "lifetime", "help");
+        assert!(out.contains("lifetime_bucket{le=\"60\"} 1\n"), "{}", out);
+        assert!(turn.allocations_by_owner().0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relays_data_over_channels() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(loopback_peers());
+        let (client, source) = client().await;
+        let relayed_addr = allocate(&turn, &source)
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let channel_bind = |number: u16, peer: SocketAddr| {
+            Message::with_random_transaction_id(methods::CHANNEL_BIND, Class::Request)
+                .add_u32(attributes::CHANNEL_NUMBER, (number as u32) << 16)
+                .add_xor_address(attributes::XOR_PEER_ADDRESS, peer)
+                .encode()
+        };
+
+        let bind = channel_bind(0x4000, peer_addr);
+        let response = turn.handle(&bind, &source, None, None, None).await.unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        // Invalid channel numbers, and peers bound to another channel, are rejected.
+        let bind = channel_bind(0x3FFF, peer_addr);
+        let response = turn.handle(&bind, &source, None, None, None).await.unwrap();
+        assert_eq!(response.error_code(), Some(400));
+        let bind = channel_bind(0x4001, peer_addr);
+        let response = turn.handle(&bind, &source, None, None, None).await.unwrap();
+        assert_eq!(response.error_code(), Some(400));
+
+        let message = ChannelData {
+            number: 0x4000,
+            data: b"ping",
+        };
+        turn.channel_data(&message, &source).await;
+        let mut buf = [0; 1024];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, relayed_addr);
+
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            ChannelData::decode(&buf[..len]),
+            Some(ChannelData {
+                number: 0x4000,
+                data: b"pong"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_the_bandwidth_of_allocations() {
+        let limits = BandwidthLimits {
+            default: Some(1_000_000),
+            per_user: [("alice".to_string(), 6)].into(),
+        };
+        assert_eq!(limits.get(None), Some(1_000_000));
+        assert_eq!(limits.get(Some("bob")), Some(1_000_000));
+        assert_eq!(limits.get(Some("alice")), Some(6));
+
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into())
+            .with_bandwidth_limits(limits)
+            .with_peer_policy(loopback_peers());
+        let (client, source) = client().await;
+        let relayed_addr = allocate_as(&turn, &source, Some("alice"))
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        create_permission(&turn, &source, peer.local_addr().unwrap()).await;
+        let send = Message::with_random_transaction_id(methods::SEND, Class::Indication)
+            .add_xor_address(attributes::XOR_PEER_ADDRESS, peer.local_addr().unwrap())
+            .add_attribute(attributes::DATA, b"ping".to_vec());
+        turn.handle(&send.encode(), &source, None, None, None).await;
+        turn.handle(&send.encode(), &source, None, None, None).await;
+
+        let mut buf = [0; 1024];
+        let len = peer.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        // Both directions are counted, only 2 of the 6 bytes per second of alice are left.
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        peer.send_to(b"po", relayed_addr).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        let data = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(data.get(attributes::DATA), Some(&b"po"[..]));
+
+        let timeout = std::time::Duration::from_millis(100);
+        assert!(tokio::time::timeout(timeout, peer.recv(&mut buf))
+            .await
+            .is_err());
+        assert!(tokio::time::timeout(timeout, client.recv(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_data_without_permission() {
+        let turn = Turn::new(Ipv4Addr::LOCALHOST.into()).with_peer_policy(loopback_peers());
+        let (_other, other) = client().await;
+        let (client, source) = client().await;
+        let relayed_addr = allocate(&turn, &source)
+            .await
+            .get_xor_address(attributes::XOR_RELAYED_ADDRESS)
+            .unwrap();
+        let peer = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let timeout = std::time::Duration::from_millis(100);
+        let mut buf = [0; 1024];
+
+        // Neither the client nor the peer can send data before a permission is installed.
+        let send = Message::with_random_transaction_id(methods::SEND, Class::Indication)
+            .add_xor_address(attributes::XOR_PEER_ADDRESS, peer.local_addr().unwrap())
+            .add_attribute(attributes::DATA, b"ping".to_vec());
+        turn.handle(&send.encode(), &source, None, None, None).await;
+        assert!(tokio::time::timeout(timeout, peer.recv(&mut buf))
+            .await
+            .is_err());
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        assert!(tokio::time::timeout(timeout, client.recv(&mut buf))
+            .await
+            .is_err());
+        assert_eq!(turn.dropped().get(DropReason::NoPermission), 2);
+
+        // A permission is for an IP address, whatever the port.
+        let other_port = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let response = create_permission(&turn, &source, other_port).await;
+        assert_eq!(response.class, Class::SuccessResponse);
+        peer.send_to(b"pong", relayed_addr).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        let data = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(data.get(attributes::DATA), Some(&b"pong"[..]));
+
+        // Without allocation or peer address.
+        let response = create_permission(&turn, &other, other_port).await;
+        assert_eq!(response.error_code(), Some(437));
+        let request =
+            Message::with_random_transaction_id(methods::CREATE_PERMISSION, Class::Request);
+        let response = turn
+            .handle(&request.encode(), &source, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.error_code(), Some(400));
+    }
+}
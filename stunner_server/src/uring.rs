@@ -0,0 +1,385 @@
+//! UDP served through io_uring on Linux: receives are kept queued in the ring and the responses
+//! queued along with them, so that a single system call submits the responses of the datagrams
+//! handled and receives the next ones.
+
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use anyhow::Result;
+use io_uring::{opcode, squeue, types, IoUring};
+use socket2::{SockAddr, SockAddrStorage};
+use tokio::io::unix::AsyncFd;
+use tokio::net::UdpSocket;
+use tracing::Instrument;
+
+use crate::net;
+use crate::pktinfo::{self, msghdr, Control};
+use crate::server::{Server, Sink, Source, Transport};
+
+/// Number of datagrams queued to be received at once, and of responses queued to be sent.
+const QUEUED: usize = 64;
+
+/// Tag of the user data of the send operations, their slot index is in the low bits.
+const SEND: u64 = 1 << 32;
+
+/// User data of the operations cancelling the receives on shutdown.
+const CANCEL: u64 = 1 << 33;
+
+/// Buffer and message header of a datagram being received, at a stable address for as long as
+/// the kernel may write to them.
+struct RecvSlot {
+    buf: Vec<u8>,
+    addr: SockAddrStorage,
+    control: Control,
+    iovec: libc::iovec,
+    msg: libc::msghdr,
+}
+
+/// Response being sent, at a stable address for as long as the kernel may read it.
+struct SendSlot {
+    buf: Vec<u8>,
+    addr: SockAddr,
+    control: Control,
+    iovec: libc::iovec,
+    msg: libc::msghdr,
+}
+
+// SAFETY: the pointers of the message headers only point into the slots themselves.
+unsafe impl Send for RecvSlot {}
+unsafe impl Send for SendSlot {}
+
+/// Ring receiving and sending the datagrams of a UDP socket.
+pub struct Ring {
+    ring: IoUring,
+    /// Readable when operations completed.
+    completed: AsyncFd<OwnedFd>,
+    /// The slot vectors are never resized, so that the slots don't move.
+    receives: Vec<RecvSlot>,
+    sends: Vec<SendSlot>,
+    /// Indexes of the send slots not in use.
+    free_sends: Vec<usize>,
+}
+
+impl Ring {
+    /// Set up a ring, failing when io_uring isn't supported or allowed.
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::new(2 * QUEUED as u32)?;
+        // SAFETY: eventfd has no memory effect, the descriptor returned is owned.
+        let completed = unsafe {
+            let fd = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        ring.submitter().register_eventfd(completed.as_raw_fd())?;
+        let receives = (0..QUEUED)
+            .map(|_| {
+                RecvSlot {
+                    buf: Vec::new(),
+                    addr: SockAddrStorage::zeroed(),
+                    control: Control::default(),
+                    // SAFETY: all zeros is a valid iovec and msghdr, they are set when queued.
+                    iovec: unsafe { mem::zeroed() },
+                    msg: unsafe { mem::zeroed() },
+                }
+            })
+            .collect();
+        let sends = (0..QUEUED)
+            .map(|_| {
+                SendSlot {
+                    buf: Vec::new(),
+                    addr: SockAddr::from(SocketAddr::from(([0; 4], 0))),
+                    control: Control::default(),
+                    // SAFETY: as above.
+                    iovec: unsafe { mem::zeroed() },
+                    msg: unsafe { mem::zeroed() },
+                }
+            })
+            .collect();
+        Ok(Ring {
+            ring,
+            completed: AsyncFd::new(completed)?,
+            receives,
+            sends,
+            free_sends: (0..QUEUED).collect(),
+        })
+    }
+
+    /// Reply to STUN requests received on the UDP socket, until the server shuts down. The
+    /// datagrams received are handled in turn, like batches are.
+    pub async fn serve(mut self, sock: Arc<UdpSocket>, server: Arc<Server>) -> Result<()> {
+        let fd = types::Fd(sock.as_raw_fd());
+        let local_addr = sock.local_addr()?;
+        // One extra byte tells apart the datagrams that don't fit the buffer.
+        let buf_size = server.recv_buffer_size() + 1;
+        for index in 0..QUEUED {
+            self.receives[index].buf.resize(buf_size, 0);
+            self.queue_recv(fd, index)?;
+        }
+        let mut completions = Vec::with_capacity(2 * QUEUED);
+        loop {
+            self.ring.submit()?;
+            completions.extend(
+                self.ring
+                    .completion()
+                    .map(|entry| (entry.user_data(), entry.result())),
+            );
+            if completions.is_empty() {
+                tokio::select! {
+                    ready = self.completed.readable() => {
+                        let mut guard = ready?;
+                        reset(guard.get_inner());
+                        guard.clear_ready();
+                    }
+                    _ = server.stopped() => break,
+                }
+                continue;
+            }
+            for (user_data, result) in completions.drain(..) {
+                if user_data & SEND != 0 {
+                    let index = (user_data & !SEND) as usize;
+                    let slot = &mut self.sends[index];
+                    if result < 0 {
+                        log::error!(
+                            "could not send response to address {:?}, reason: {}",
+                            slot.addr.as_socket(),
+                            io::Error::from_raw_os_error(-result)
+                        );
+                    }
+                    server.buffers().put(mem::take(&mut slot.buf));
+                    self.free_sends.push(index);
+                    continue;
+                }
+                let index = user_data as usize;
+                if result < 0 {
+                    log::error!(
+                        "could not receive datagram, reason: {}",
+                        io::Error::from_raw_os_error(-result)
+                    );
+                } else {
+                    self.handle(fd, index, result as usize, local_addr, &sock, &server)
+                        .await?;
+                }
+                self.queue_recv(fd, index)?;
+            }
+        }
+        self.cancel()?;
+        Ok(())
+    }
+
+    /// Handle the datagram of `len` bytes received in a slot, queuing its response if any.
+    async fn handle(
+        &mut self,
+        fd: types::Fd,
+        index: usize,
+        len: usize,
+        local_addr: SocketAddr,
+        sock: &Arc<UdpSocket>,
+        server: &Server,
+    ) -> Result<()> {
+        let slot = &mut self.receives[index];
+        let addr = mem::replace(&mut slot.addr, SockAddrStorage::zeroed());
+        // SAFETY: the kernel initialized the address and set its length.
+        let src_addr = unsafe { SockAddr::new(addr, slot.msg.msg_namelen) };
+        let src_addr = src_addr.as_socket().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, "received from a non IP address")
+        })?;
+        if len > server.recv_buffer_size() {
+            server.truncated(src_addr);
+            return Ok(());
+        }
+        let source = Source {
+            addr: src_addr,
+            local_addr: pktinfo::destination(&slot.msg, local_addr),
+            transport: Transport::Udp,
+            sink: Sink::Datagram(sock.clone()),
+        };
+        let span = source.span();
+        let response = match server
+            .handle(&slot.buf[..len], &source)
+            .instrument(span)
+            .await
+        {
+            Some(response) => response,
+            None => return Ok(()),
+        };
+        match self.free_sends.pop() {
+            Some(send) => self.queue_send(fd, send, response, src_addr, source.local_addr),
+            None => {
+                // Too many responses are waiting to be sent, this one is sent right away.
+                if let Err(err) = net::send_to(sock, &response, src_addr, source.local_addr).await {
+                    log::error!(
+                        "could not send response to address {:?}, reason: {}",
+                        src_addr,
+                        err
+                    );
+                }
+                server.buffers().put(response);
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue the receive of a datagram in a slot.
+    fn queue_recv(&mut self, fd: types::Fd, index: usize) -> io::Result<()> {
+        let slot = &mut self.receives[index];
+        slot.iovec = libc::iovec {
+            iov_base: slot.buf.as_mut_ptr().cast(),
+            iov_len: slot.buf.len(),
+        };
+        slot.msg = msghdr(
+            &mut slot.iovec,
+            (&mut slot.addr as *mut SockAddrStorage).cast(),
+            slot.addr.size_of(),
+        );
+        pktinfo::recv_control(&mut slot.msg, &mut slot.control);
+        let entry = opcode::RecvMsg::new(fd, &mut slot.msg)
+            .build()
+            .user_data(index as u64);
+        self.push(entry)
+    }
+
+    /// Queue the send of `response` to `addr` from `local_addr` in a slot.
+    fn queue_send(
+        &mut self,
+        fd: types::Fd,
+        index: usize,
+        response: Vec<u8>,
+        addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<()> {
+        let slot = &mut self.sends[index];
+        slot.buf = response;
+        slot.addr = SockAddr::from(addr);
+        slot.iovec = libc::iovec {
+            iov_base: slot.buf.as_mut_ptr().cast(),
+            iov_len: slot.buf.len(),
+        };
+        slot.msg = msghdr(
+            &mut slot.iovec,
+            slot.addr.as_ptr() as *mut libc::c_void,
+            slot.addr.len(),
+        );
+        pktinfo::set_source(&mut slot.msg, &mut slot.control, local_addr);
+        let entry = opcode::SendMsg::new(fd, &slot.msg)
+            .build()
+            .user_data(SEND | index as u64);
+        Ok(self.push(entry)?)
+    }
+
+    /// Push an operation to the submission queue, submitting the queued ones when it's full.
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: the buffers and headers of the operations live in the slots, which
+            // outlive them as the ring is only dropped once every operation completed.
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+
+    /// Cancel the queued receives and wait for every operation to complete, so that the
+    /// kernel is done with the slots.
+    fn cancel(&mut self) -> io::Result<()> {
+        for index in 0..QUEUED {
+            self.push(
+                opcode::AsyncCancel::new(index as u64)
+                    .build()
+                    .user_data(CANCEL),
+            )?;
+        }
+        let mut pending = QUEUED + QUEUED - self.free_sends.len();
+        while pending > 0 {
+            // Blocking, but only until the cancellations and sends complete.
+            self.ring.submit_and_wait(1)?;
+            for entry in self.ring.completion() {
+                if entry.user_data() != CANCEL {
+                    pending -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reset the counter of the eventfd notifying completions.
+fn reset(fd: &OwnedFd) {
+    let mut count = 0u64;
+    // SAFETY: the counter is read into a u64, of the size given.
+    unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            (&mut count as *mut u64).cast(),
+            mem::size_of::<u64>(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::Ring;
+    use crate::message::{attributes, methods, Class, Message};
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn replies_to_requests() {
+        let server = Arc::new(Server::default().with_recv_buffer_size(64));
+        let ring = match Ring::new() {
+            Ok(ring) => ring,
+            // io_uring can be disabled, e.g. in containers.
+            Err(err) => return eprintln!("skipping, io_uring is unavailable: {}", err),
+        };
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = sock.local_addr().unwrap();
+        let serving = tokio::spawn(ring.serve(sock, server.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let large = Message::with_random_transaction_id(methods::BINDING, Class::Request)
+            .add_attribute(0x8022, vec![0; 64]);
+        client.send_to(&large.encode(), server_addr).await.unwrap();
+        let mut requests = Vec::new();
+        for _ in 0..100 {
+            let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+            client
+                .send_to(&request.encode(), server_addr)
+                .await
+                .unwrap();
+            requests.push(request);
+        }
+        // The datagrams received at once are handled in the order their receives complete.
+        let mut responses = Vec::new();
+        for _ in &requests {
+            let mut buf = [0; 1500];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = Message::decode(&buf[..len]).unwrap();
+            assert_eq!(
+                response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+                Some(client.local_addr().unwrap())
+            );
+            responses.push(response.transaction_id);
+        }
+        for request in &requests {
+            assert!(responses.contains(&request.transaction_id));
+        }
+        server.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(server.metrics().contains("stunner_truncated_total 1\n"));
+    }
+}
@@ -0,0 +1,125 @@
+//! Users stored in a SQLite or PostgreSQL database, for deployments with too many of them to
+//! pass on the command line. They are looked up as requests are authenticated.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use sqlx::any::AnyPoolOptions;
+use sqlx::AnyPool;
+
+use crate::auth::PasswordAlgorithm;
+use crate::secret::{parse_ha1, Secret, SecretCache};
+
+/// Query returning the password of a user, or its HA1 hash MD5(username:realm:password) in hex.
+/// Users stored by their HA1 hash only authenticate with the MD5 password algorithm.
+const QUERY: &str = "SELECT password, ha1 FROM users WHERE username = $1";
+
+/// Connections kept open to the database.
+const MAX_CONNECTIONS: u32 = 8;
+
+/// How long the secret looked up for a username is reused, including when there is no such user.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Looks up the long-term credential keys of users in a table created with:
+/// `CREATE TABLE users (username TEXT PRIMARY KEY, password TEXT, ha1 TEXT)`,
+/// where one of password and ha1 is set.
+pub struct UserDatabase {
+    pool: AnyPool,
+    cache: SecretCache,
+}
+
+impl UserDatabase {
+    /// Connect to the database at `url`, e.g. sqlite://users.db or postgres://host/stunner.
+    /// Connections are opened when first needed.
+    pub fn connect(url: &str) -> Result<Self> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect_lazy(url)?;
+        Ok(UserDatabase {
+            pool,
+            cache: SecretCache::new(CACHE_TTL),
+        })
+    }
+
+    /// Long-term credential key of `username` in `realm` derived with `algorithm`, if there is
+    /// such a user.
+    pub async fn key(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Result<Option<Vec<u8>>> {
+        let secret = self.secret(username).await?;
+        Ok(secret.and_then(|secret| secret.key(username, realm, algorithm)))
+    }
+
+    async fn secret(&self, username: &str) -> Result<Option<Secret>> {
+        if let Some(secret) = self.cache.get(username) {
+            return Ok(secret);
+        }
+
+        let row: Option<(Option<String>, Option<String>)> = sqlx::query_as(QUERY)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        let secret = match row {
+            Some((Some(password), _)) => Some(Secret::Password(password)),
+            Some((None, Some(ha1))) => Some(Secret::Ha1(parse_ha1(&ha1)?)),
+            Some((None, None)) | None => None,
+        };
+        self.cache.insert(username, secret.clone());
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserDatabase;
+    use crate::auth::{key, PasswordAlgorithm};
+    use crate::message::hex;
+
+    #[tokio::test]
+    async fn looks_up_passwords_and_ha1_hashes() {
+        // Every connection to an in-memory database has its own, use a file.
+        let path = std::env::temp_dir().join(format!("stunner-users-{}.db", rand::random::<u64>()));
+        let db = UserDatabase::connect(&format!("sqlite://{}?mode=rwc", path.display())).unwrap();
+        let ha1 = hex(&key("bob", "stunner", "secret"));
+        for statement in [
+            "CREATE TABLE users (username TEXT PRIMARY KEY, password TEXT, ha1 TEXT)",
+            "INSERT INTO users (username, password) VALUES ('alice', 'pass')",
+            &format!(
+                "INSERT INTO users (username, ha1) VALUES ('bob', '{}')",
+                ha1
+            ),
+        ] {
+            sqlx::query(statement).execute(&db.pool).await.unwrap();
+        }
+
+        let md5 = PasswordAlgorithm::Md5;
+        let sha256 = PasswordAlgorithm::Sha256;
+        assert_eq!(
+            db.key("alice", "stunner", md5).await.unwrap(),
+            Some(key("alice", "stunner", "pass"))
+        );
+        assert_eq!(
+            db.key("alice", "stunner", sha256).await.unwrap(),
+            Some(sha256.key("alice", "stunner", "pass"))
+        );
+        assert_eq!(
+            db.key("bob", "stunner", md5).await.unwrap(),
+            Some(key("bob", "stunner", "secret"))
+        );
+        // The password is needed to derive the SHA-256 key.
+        assert_eq!(db.key("bob", "stunner", sha256).await.unwrap(), None);
+        assert_eq!(db.key("carol", "stunner", md5).await.unwrap(), None);
+
+        // Lookups are cached.
+        sqlx::query("DELETE FROM users")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(db.key("alice", "stunner", md5).await.unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
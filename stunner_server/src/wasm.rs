@@ -0,0 +1,292 @@
+//! Handlers loaded from WebAssembly modules, so that operators can add their own admission
+//! logic, e.g. checking usernames against a pattern, without rebuilding the server. Modules,
+//! binary or text, are run with wasmtime without access to the host, in a fresh instance for
+//! each message whose fuel and memory are bounded.
+//!
+//! A module exports its memory, a function allocating the inputs of the hooks in it and either
+//! hook or both:
+//!
+//! ```text
+//! (memory (export "memory") 1)
+//! ;; Address of `len` bytes the inputs of a hook are written to.
+//! (func (export "alloc") (param $len i32) (result i32))
+//! ;; The message as received and the source address, as text.
+//! (func (export "pre_decode")
+//!   (param $msg i32) (param $msg_len i32) (param $src i32) (param $src_len i32) (result i32))
+//! ;; Also the username the request was authenticated with, empty if it wasn't.
+//! (func (export "post_decode")
+//!   (param $msg i32) (param $msg_len i32) (param $src i32) (param $src_len i32)
+//!   (param $user i32) (param $user_len i32) (result i32))
+//! ```
+//!
+//! The hooks return 0 to hand the message to the next handler, 1 to drop it, or the error code,
+//! from 300 to 699, to answer a request with. Messages are dropped when a hook traps.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use wasmtime::{
+    Config, Engine, Instance, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::handler::{Handler, HookFuture, Transaction, Verdict};
+use crate::message::{Class, Message};
+use crate::server::{Server, Source};
+
+/// Fuel of the instance handling a message, about as many instructions.
+const FUEL: u64 = 10_000_000;
+
+/// Size the memory of an instance can grow to.
+const MAX_MEMORY_SIZE: usize = 16 << 20;
+
+/// Returned by a hook to hand the message to the next handler.
+const CONTINUE: i32 = 0;
+
+/// Returned by a hook to drop the message.
+const DROP: i32 = 1;
+
+/// Compiles the modules, with fuel consumption enabled.
+pub fn engine() -> Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Ok(Engine::new(&config)?)
+}
+
+/// A handler implemented by a WebAssembly module.
+pub struct WasmHandler {
+    /// Path of the module, for logs.
+    name: String,
+    instance_pre: InstancePre<StoreLimits>,
+    pre_decode: bool,
+    post_decode: bool,
+}
+
+impl WasmHandler {
+    /// Compile the module at `path` and check its exports.
+    pub fn load(engine: &Engine, path: &Path) -> Result<Self> {
+        let module = Module::from_file(engine, path)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("could not load WebAssembly module {:?}", path))?;
+        let instance_pre = Linker::new(engine)
+            .instantiate_pre(&module)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("{:?} imports from the host", path))?;
+        let handler = WasmHandler {
+            name: path.display().to_string(),
+            instance_pre,
+            pre_decode: module.get_export("pre_decode").is_some(),
+            post_decode: module.get_export("post_decode").is_some(),
+        };
+        if !handler.pre_decode && !handler.post_decode {
+            bail!("{:?} exports neither pre_decode nor post_decode", path);
+        }
+        // Report missing or mistyped exports now rather than for each message.
+        let (mut store, instance) = handler.instantiate()?;
+        instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        if handler.pre_decode {
+            instance.get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "pre_decode")?;
+        }
+        if handler.post_decode {
+            instance
+                .get_typed_func::<(i32, i32, i32, i32, i32, i32), i32>(&mut store, "post_decode")?;
+        }
+        instance
+            .get_memory(&mut store, "memory")
+            .with_context(|| format!("{:?} doesn't export its memory", path))?;
+        Ok(handler)
+    }
+
+    /// A fresh instance of the module.
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, Instance)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_SIZE)
+            .build();
+        let mut store = Store::new(self.instance_pre.module().engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL)?;
+        let instance = self.instance_pre.instantiate(&mut store)?;
+        Ok((store, instance))
+    }
+
+    /// Call the hook `name` with `inputs` written to the memory of a fresh instance.
+    fn call(&self, name: &str, inputs: &[&[u8]]) -> Result<i32> {
+        let (mut store, instance) = self.instantiate()?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let len = inputs.iter().map(|input| input.len()).sum::<usize>();
+        let mut ptr = alloc.call(&mut store, i32::try_from(len)?)? as u32 as usize;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("missing memory")?;
+        let mut params = Vec::with_capacity(2 * inputs.len());
+        for input in inputs {
+            memory.write(&mut store, ptr, input)?;
+            params.push(ptr as i32);
+            params.push(input.len() as i32);
+            ptr += input.len();
+        }
+        let result = match *params {
+            [msg, msg_len, src, src_len] => instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, name)?
+                .call(&mut store, (msg, msg_len, src, src_len))?,
+            [msg, msg_len, src, src_len, user, user_len] => instance
+                .get_typed_func::<(i32, i32, i32, i32, i32, i32), i32>(&mut store, name)?
+                .call(&mut store, (msg, msg_len, src, src_len, user, user_len))?,
+            _ => unreachable!("hooks take two or three inputs"),
+        };
+        Ok(result)
+    }
+
+    /// Verdict of a hook returning `result`, for `message` if it was decoded.
+    fn verdict(&self, result: Result<i32>, message: Option<&Message>) -> Verdict {
+        match result {
+            Ok(CONTINUE) => Verdict::Continue,
+            Ok(DROP) => Verdict::Drop("filtered"),
+            Ok(code @ 300..=699) => match message {
+                Some(message) if message.class == Class::Request => Verdict::Respond(
+                    message.error_response(code as u16, reason(code as u16)),
+                    "filtered",
+                ),
+                _ => Verdict::Drop("filtered"),
+            },
+            Ok(result) => {
+                log::warn!("{} filter returned {}, dropping", self.name, result);
+                Verdict::Drop("filter_error")
+            }
+            Err(err) => {
+                log::warn!("{} filter failed, dropping: {:#}", self.name, err);
+                Verdict::Drop("filter_error")
+            }
+        }
+    }
+}
+
+impl Handler for WasmHandler {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn sees_responses(&self) -> bool {
+        false
+    }
+
+    fn pre_decode(&self, _server: &Server, buf: &[u8], source: &Source) -> Verdict {
+        if !self.pre_decode {
+            return Verdict::Continue;
+        }
+        let src = source.addr.to_string();
+        let result = self.call("pre_decode", &[buf, src.as_bytes()]);
+        self.verdict(result, None)
+    }
+
+    fn post_decode<'a>(
+        &'a self,
+        _server: &'a Server,
+        message: &'a mut Message,
+        transaction: &'a mut Transaction<'_>,
+    ) -> HookFuture<'a> {
+        let verdict = match self.post_decode {
+            true => {
+                let src = transaction.source.addr.to_string();
+                let user = transaction
+                    .credentials
+                    .as_ref()
+                    .map_or("", |credentials| credentials.username.as_str());
+                let inputs = [transaction.buf, src.as_bytes(), user.as_bytes()];
+                self.verdict(self.call("post_decode", &inputs), Some(message))
+            }
+            false => Verdict::Continue,
+        };
+        Box::pin(async { verdict })
+    }
+}
+
+/// Reason phrase of the error responses with `code`.
+fn reason(code: u16) -> &'static str {
+    match code {
+        300 => "Try Alternate",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        420 => "Unknown Attribute",
+        437 => "Allocation Mismatch",
+        441 => "Wrong Credentials",
+        486 => "Allocation Quota Reached",
+        500 => "Server Error",
+        508 => "Insufficient Capacity",
+        _ => "Rejected",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::{engine, WasmHandler};
+    use crate::handler::Builtin;
+    use crate::message::{methods, Class, Message};
+    use crate::server::{Server, Sink, Source, Transport};
+
+    /// Answers 403 to the messages from port 2, drops the ones from port 3 and traps on the
+    /// ones from port 4, going by the last character of the source address.
+    const FILTER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "post_decode")
+            (param $msg i32) (param $msg_len i32) (param $src i32) (param $src_len i32)
+            (param $user i32) (param $user_len i32) (result i32)
+            (local $port i32)
+            (local.set $port
+              (i32.load8_u (i32.sub (i32.add (local.get $src) (local.get $src_len)) (i32.const 1))))
+            (if (i32.eq (local.get $port) (i32.const 52)) (then unreachable))
+            (if (i32.eq (local.get $port) (i32.const 50)) (then (return (i32.const 403))))
+            (i32.eq (local.get $port) (i32.const 51))))
+    "#;
+
+    #[tokio::test]
+    async fn filters_requests_with_a_module() {
+        let path =
+            std::env::temp_dir().join(format!("stunner-filter-{}.wat", rand::random::<u64>()));
+        std::fs::write(&path, FILTER).unwrap();
+        let handler = WasmHandler::load(&engine().unwrap(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let server =
+            Server::default().with_handlers(vec![Builtin::Acl.handler(), Box::new(handler)]);
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let request = Message::with_random_transaction_id(methods::BINDING, Class::Request);
+        let handle = |addr: &str| {
+            let source = Source {
+                addr: addr.parse().unwrap(),
+                local_addr: sock.local_addr().unwrap(),
+                transport: Transport::Udp,
+                sink: Sink::Datagram(sock.clone()),
+            };
+            let (server, request) = (&server, &request);
+            async move {
+                let response = server.handle(&request.encode(), &source).await?;
+                Some(Message::decode(&response).unwrap())
+            }
+        };
+
+        let response = handle("127.0.0.1:1").await.unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        let response = handle("127.0.0.1:2").await.unwrap();
+        assert_eq!(response.error_code(), Some(403));
+        assert!(handle("127.0.0.1:3").await.is_none());
+        assert!(handle("127.0.0.1:4").await.is_none());
+    }
+
+    #[test]
+    fn rejects_modules_without_hooks() {
+        let path =
+            std::env::temp_dir().join(format!("stunner-filter-{}.wat", rand::random::<u64>()));
+        std::fs::write(&path, r#"(module (memory (export "memory") 1))"#).unwrap();
+        let err = WasmHandler::load(&engine().unwrap(), &path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err
+            .to_string()
+            .contains("neither pre_decode nor post_decode"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Users looked up from an HTTP endpoint as requests are authenticated, so that the credentials
+//! come from an existing identity system rather than a local user store.
+//!
+//! The server POSTs `{"username": "alice", "realm": "stunner"}` to the endpoint, which answers
+//! 200 OK with `{"password": "..."}` or `{"ha1": "<MD5(username:realm:password) in hex>"}`, or
+//! 404 Not Found when there is no such user.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+use crate::auth::PasswordAlgorithm;
+use crate::secret::{parse_ha1, Secret, SecretCache};
+
+/// Looks up the long-term credential keys of users from an HTTP endpoint.
+pub struct AuthWebhook {
+    client: Client,
+    url: String,
+    cache: SecretCache,
+}
+
+impl AuthWebhook {
+    /// Look up users from `url`, giving up on requests taking longer than `timeout`. The answer
+    /// for a username is reused for `cache_ttl`, including when there is no such user.
+    pub fn new(url: String, timeout: Duration, cache_ttl: Duration) -> Result<Self> {
+        let client = Client::builder().timeout(timeout).build()?;
+        Ok(AuthWebhook {
+            client,
+            url,
+            cache: SecretCache::new(cache_ttl),
+        })
+    }
+
+    /// Long-term credential key of `username` in `realm` derived with `algorithm`, if there is
+    /// such a user.
+    pub async fn key(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Result<Option<Vec<u8>>> {
+        let secret = match self.cache.get(username) {
+            Some(secret) => secret,
+            None => {
+                let secret = self.fetch(username, realm).await?;
+                self.cache.insert(username, secret.clone());
+                secret
+            }
+        };
+        Ok(secret.and_then(|secret| secret.key(username, realm, algorithm)))
+    }
+
+    async fn fetch(&self, username: &str, realm: &str) -> Result<Option<Secret>> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&json!({ "username": username, "realm": realm }))
+            .send()
+            .await
+            .with_context(|| format!("could not reach {}", self.url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body: Value = response.error_for_status()?.json().await?;
+        match (body["password"].as_str(), body["ha1"].as_str()) {
+            (Some(password), _) => Ok(Some(Secret::Password(password.into()))),
+            (None, Some(ha1)) => Ok(Some(Secret::Ha1(parse_ha1(ha1)?))),
+            (None, None) => bail!("no password nor ha1 in the answer of {}", self.url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::AuthWebhook;
+    use crate::auth::{key, PasswordAlgorithm};
+    use crate::http::response;
+
+    /// Answer the requests of the webhook, the password of alice being pass, until `count` of
+    /// them were answered.
+    async fn endpoint(listener: TcpListener, count: usize) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for _ in 0..count {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.ends_with(b"}") {
+                let len = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..len]);
+            }
+            let request = String::from_utf8(buf).unwrap();
+            let body = request.split("\r\n\r\n").nth(1).unwrap().to_string();
+            let answer = if body.contains("\"alice\"") {
+                response("200 OK", "application/json", r#"{"password":"pass"}"#)
+            } else {
+                response("404 Not Found", "application/json", "{}")
+            };
+            stream.write_all(answer.as_bytes()).await.unwrap();
+            bodies.push(body);
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn looks_up_and_caches_passwords() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/users", listener.local_addr().unwrap());
+        let answering = tokio::spawn(endpoint(listener, 2));
+        let webhook =
+            AuthWebhook::new(url, Duration::from_secs(5), Duration::from_secs(60)).unwrap();
+
+        let md5 = PasswordAlgorithm::Md5;
+        assert_eq!(
+            webhook.key("alice", "stunner", md5).await.unwrap(),
+            Some(key("alice", "stunner", "pass"))
+        );
+        assert_eq!(webhook.key("bob", "stunner", md5).await.unwrap(), None);
+        // Answers are cached, the endpoint is only asked twice.
+        assert!(webhook
+            .key("alice", "stunner", md5)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(webhook.key("bob", "stunner", md5).await.unwrap(), None);
+        let bodies = answering.await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&bodies[0]).unwrap(),
+            serde_json::json!({ "username": "alice", "realm": "stunner" })
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/users", listener.local_addr().unwrap());
+        let webhook =
+            AuthWebhook::new(url, Duration::from_millis(100), Duration::from_secs(60)).unwrap();
+        let lookup = webhook.key("alice", "stunner", PasswordAlgorithm::Md5);
+        assert!(tokio::time::timeout(Duration::from_secs(5), lookup)
+            .await
+            .unwrap()
+            .is_err());
+        drop(listener);
+    }
+}
@@ -0,0 +1,408 @@
+//! STUN over WebSocket, so that web pages can query the server without a native client. Each
+//! binary message carries a single STUN message, see https://datatracker.ietf.org/doc/html/rfc6455
+
+use std::io::{Cursor, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::http;
+use crate::server::{Server, Sink, Source, Transport};
+
+/// Appended to the key of the client to compute the accept value of the handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Number of messages queued to be written to a connection before senders wait.
+const CONNECTION_QUEUE_SIZE: usize = 32;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Status of the close frame sent when closing the connection normally.
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Accept WebSocket connections and serve STUN requests on each of them, until the server shuts
+/// down and every connection is closed.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) -> Result<()> {
+    let local_addr = listener.local_addr()?;
+    log::info!("serving WebSocket on addr: {}", local_addr);
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = server.stopped() => break,
+            // Reap the finished connections.
+            Some(_) = connections.join_next() => continue,
+        };
+        let permit = match server.admit_connection(peer_addr) {
+            Some(permit) => permit,
+            None => continue,
+        };
+        log::debug!("accepted WebSocket connection from {:?}", peer_addr);
+        let server = server.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_connection(stream, peer_addr, local_addr, server).await {
+                log::debug!("WebSocket connection with {:?} closed: {}", peer_addr, err);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Complete the opening handshake, then reply to every STUN request received until the client
+/// closes the connection, stays idle for too long or the server shuts down.
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    server: Arc<Server>,
+) -> Result<()> {
+    let limits = server.connection_limits();
+    let head = tokio::time::timeout(limits.idle_timeout, http::read_head(&mut stream))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "handshake timed out"))??;
+    let mut head = match head {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    // Frames sent right after the request are read before the ones still in the stream.
+    let end = head
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(head.len(), |end| end + 4);
+    let early = head.split_off(end);
+    let key = match accept_key(&String::from_utf8_lossy(&head)) {
+        Some(key) => key,
+        None => {
+            let response = http::response("400 Bad Request", "text/plain", "");
+            stream.write_all(response.as_bytes()).await?;
+            bail!("not a WebSocket handshake");
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        key
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = Cursor::new(early).chain(reader);
+    // Responses and messages sent outside of a request, such as TURN Data indications, are
+    // written in order by a single writer, along with the pongs.
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(CONNECTION_QUEUE_SIZE);
+    let (pong_tx, mut pong_rx) = mpsc::channel::<Vec<u8>>(CONNECTION_QUEUE_SIZE);
+    let source = Source {
+        addr: peer_addr,
+        local_addr,
+        transport: Transport::Ws,
+        sink: Sink::Stream(tx),
+    };
+
+    let read = async {
+        loop {
+            let message = tokio::time::timeout(
+                limits.idle_timeout,
+                read_message(&mut reader, limits.max_message_size, &pong_tx),
+            )
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "connection idle for too long"))??;
+            let buf = match message {
+                Some(buf) => buf,
+                None => return Ok(()),
+            };
+            let span = source.span();
+            let response = server.handle(&buf, &source).instrument(span.clone()).await;
+            if let Some(response) = response {
+                source
+                    .send(response)
+                    .instrument(tracing::info_span!(parent: &span, "send"))
+                    .await?;
+            }
+        }
+    };
+    let write = async {
+        loop {
+            tokio::select! {
+                Some(bytes) = rx.recv() => {
+                    writer.write_all(&encode_frame(OPCODE_BINARY, &bytes)).await?;
+                    server.buffers().put(bytes);
+                }
+                Some(payload) = pong_rx.recv() => {
+                    writer.write_all(&encode_frame(OPCODE_PONG, &payload)).await?;
+                }
+                else => return Ok::<_, anyhow::Error>(()),
+            }
+        }
+    };
+
+    let result = tokio::select! {
+        result = read => result,
+        result = write => result,
+        _ = server.stopped() => Ok(()),
+    };
+    server.disconnected(&source);
+
+    // Flush what was queued, then close the connection, answering the close frame of the client
+    // if it sent one.
+    rx.close();
+    while let Ok(bytes) = rx.try_recv() {
+        if writer
+            .write_all(&encode_frame(OPCODE_BINARY, &bytes))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    let close = encode_frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+    let _ = writer.write_all(&close).await;
+    let _ = writer.shutdown().await;
+    result
+}
+
+/// Value of the Sec-WebSocket-Accept header answering the opening handshake `head`, or `None`
+/// if it isn't one.
+fn accept_key(head: &str) -> Option<String> {
+    if !head.starts_with("GET ") {
+        return None;
+    }
+    let header = |name: &str| {
+        head.split("\r\n").skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+    };
+    let upgrade = header("Upgrade")?.eq_ignore_ascii_case("websocket");
+    let connection = header("Connection")?
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade || !connection || header("Sec-WebSocket-Version")? != "13" {
+        return None;
+    }
+    let mut sha1 = Sha1::new();
+    sha1.update(header("Sec-WebSocket-Key")?.as_bytes());
+    sha1.update(GUID.as_bytes());
+    Some(BASE64.encode(sha1.finalize()))
+}
+
+/// Read a binary message from the client, possibly fragmented in several frames, answering the
+/// pings received meanwhile through `pongs`. Returns `None` once the client closes the
+/// connection, and an error if the message is larger than `max_size`.
+async fn read_message<S>(
+    stream: &mut S,
+    max_size: usize,
+    pongs: &mpsc::Sender<Vec<u8>>,
+) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut message = Vec::new();
+    loop {
+        let (fin, opcode, payload) = match read_frame(stream, max_size).await? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        match opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => {
+                if message.len() + payload.len() > max_size {
+                    bail!("message exceeds the limit of {} bytes", max_size);
+                }
+                message.extend_from_slice(&payload);
+                if fin {
+                    return Ok(Some(message));
+                }
+            }
+            OPCODE_TEXT => bail!("received a text message"),
+            OPCODE_CLOSE => return Ok(None),
+            OPCODE_PING => {
+                let _ = pongs.send(payload).await;
+            }
+            OPCODE_PONG => {}
+            _ => bail!("received a frame with the unknown opcode {:#x}", opcode),
+        }
+    }
+}
+
+/// Read a frame from the client, returning whether it's the final fragment of its message, its
+/// opcode and unmasked payload. Returns `None` if the stream was closed before a new frame
+/// started.
+async fn read_frame<S>(stream: &mut S, max_size: usize) -> Result<Option<(bool, u8, Vec<u8>)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0; 2];
+    match stream.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        bail!("received a frame with reserved bits set");
+    }
+    // Frames sent by clients MUST be masked.
+    if head[1] & 0x80 == 0 {
+        bail!("received an unmasked frame");
+    }
+    let len = match head[1] & 0x7f {
+        126 => stream.read_u16().await? as u64,
+        127 => stream.read_u64().await?,
+        len => len as u64,
+    };
+    if len > max_size as u64 {
+        bail!("frame of {} bytes exceeds the limit", len);
+    }
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// Frame sent to the client, unmasked and unfragmented.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use stun_coder::{StunAttribute, StunMessage, StunMessageClass, StunMessageMethod};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{accept_key, encode_frame, read_frame, serve, OPCODE_BINARY, OPCODE_CLOSE};
+    use crate::server::Server;
+
+    /// Frame sent by a client, masked.
+    fn client_frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![
+            if fin { 0x80 } else { 0 } | opcode,
+            0x80 | payload.len() as u8,
+        ];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // Example of https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+        let head = "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                    Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(
+            accept_key(head).as_deref(),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(
+            accept_key("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_fragmented_frames() {
+        let mut bytes = client_frame(OPCODE_BINARY, false, b"first");
+        bytes.extend(client_frame(0, true, b"second"));
+        let mut stream = &bytes[..];
+        assert_eq!(
+            read_frame(&mut stream, 8192).await.unwrap(),
+            Some((false, OPCODE_BINARY, b"first".to_vec()))
+        );
+        assert_eq!(
+            read_frame(&mut stream, 8192).await.unwrap(),
+            Some((true, 0, b"second".to_vec()))
+        );
+        assert_eq!(read_frame(&mut stream, 8192).await.unwrap(), None);
+        assert!(
+            read_frame(&mut &encode_frame(OPCODE_BINARY, b"unmasked")[..], 8192)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_to_binding_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(Server::default())));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let client_addr: SocketAddr = stream.local_addr().unwrap();
+        let request =
+            StunMessage::new(StunMessageMethod::BindingRequest, StunMessageClass::Request)
+                .encode(None)
+                .unwrap();
+        let mut bytes = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                          Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                          Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        // Fragmented, and sent along with the handshake.
+        bytes.extend(client_frame(OPCODE_BINARY, false, &request[..8]));
+        bytes.extend(client_frame(0, true, &request[8..]));
+        bytes.extend(client_frame(OPCODE_CLOSE, true, &[]));
+        stream.write_all(&bytes).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let head = b"HTTP/1.1 101 Switching Protocols\r\n";
+        assert!(response.starts_with(head));
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let mut frames = &response[end..];
+        let (fin, opcode, len) = (frames[0] & 0x80 != 0, frames[0] & 0x0f, frames[1] as usize);
+        assert!(fin);
+        assert_eq!(opcode, OPCODE_BINARY);
+        let message = StunMessage::decode(&frames[2..2 + len], None).unwrap();
+        assert!(matches!(
+            message.get_attributes()[0],
+            StunAttribute::XorMappedAddress { socket_addr } if socket_addr == client_addr
+        ));
+        frames = &frames[2 + len..];
+        assert_eq!(frames, [0x88, 2, 0x03, 0xe8]);
+    }
+}
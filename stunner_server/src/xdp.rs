@@ -0,0 +1,655 @@
+//! Answers to Binding requests sent in the kernel by an XDP program attached to a network
+//! interface, before the packets reach the network stack, for the public servers flooded with
+//! them. The program only answers the requests received over IPv4 on the UDP ports served
+//! carrying no attribute, the others are passed on to the server as usual.
+//!
+//! The program is assembled here rather than compiled, so that it doesn't need a toolchain for
+//! BPF. The answers it sent are counted in an array map mapped in memory, which is read without
+//! any system call, so that the metrics can still be rendered once sandboxed.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context, Result};
+
+/// Commands of the `bpf` system call.
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_PROG_LOAD: libc::c_long = 5;
+#[cfg(test)]
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+const BPF_LINK_CREATE: libc::c_long = 28;
+
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+
+/// Verdicts of XDP programs.
+const XDP_PASS: i32 = 2;
+const XDP_TX: i32 = 3;
+
+/// Helpers called by the program.
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_XDP_ADJUST_TAIL: i32 = 65;
+
+/// Instruction classes, sizes, modes and operations.
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_STX: u8 = 0x03;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_B: u8 = 0x10;
+const BPF_DW: u8 = 0x18;
+const BPF_IMM: u8 = 0x00;
+const BPF_MEM: u8 = 0x60;
+const BPF_ATOMIC: u8 = 0xc0;
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_ADD: u8 = 0x00;
+const BPF_AND: u8 = 0x50;
+const BPF_RSH: u8 = 0x70;
+const BPF_XOR: u8 = 0xa0;
+const BPF_MOV: u8 = 0xb0;
+const BPF_JA: u8 = 0x00;
+const BPF_JEQ: u8 = 0x10;
+const BPF_JGT: u8 = 0x20;
+const BPF_JNE: u8 = 0x50;
+const BPF_JLT: u8 = 0xa0;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+/// Registers, `R1` holding the context of the program on entry and `R10` the frame pointer.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R5: u8 = 5;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R8: u8 = 8;
+const R10: u8 = 10;
+
+/// Offsets in the frames answered: an Ethernet header, an IPv4 header without options, a UDP
+/// header and a STUN header, followed in the answers by an XOR-MAPPED-ADDRESS.
+const ETHER_TYPE: i16 = 12;
+const IP: i16 = 14;
+const IP_TOTAL_LENGTH: i16 = IP + 2;
+const IP_FRAGMENT: i16 = IP + 6;
+const IP_TTL: i16 = IP + 8;
+const IP_PROTOCOL: i16 = IP + 9;
+const IP_CHECKSUM: i16 = IP + 10;
+const IP_SOURCE: i16 = IP + 12;
+const IP_DESTINATION: i16 = IP + 16;
+const UDP: i16 = IP + 20;
+const UDP_LENGTH: i16 = UDP + 4;
+const UDP_CHECKSUM: i16 = UDP + 6;
+const STUN: i16 = UDP + 8;
+const STUN_LENGTH: i16 = STUN + 2;
+const STUN_COOKIE: i16 = STUN + 4;
+const ATTRIBUTE: i16 = STUN + 20;
+const REQUEST_LEN: i32 = ATTRIBUTE as i32;
+const ADDRESS_ATTRIBUTE_LEN: i32 = 12;
+
+/// Values of the fields compared or written, as loaded from the packet in the byte order of the
+/// host, which BPF shares.
+fn field16(value: u16) -> i32 {
+    i32::from(u16::from_ne_bytes(value.to_be_bytes()))
+}
+
+fn field32(value: u32) -> i32 {
+    u32::from_ne_bytes(value.to_be_bytes()) as i32
+}
+
+/// An instruction, as `struct bpf_insn`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+/// Assembles a program, the conditional jumps of which all go to the end, where the packet is
+/// passed on to the network stack.
+#[derive(Default)]
+struct Assembler {
+    insns: Vec<Insn>,
+    /// Instructions jumping to the end.
+    passes: Vec<usize>,
+}
+
+impl Assembler {
+    fn push(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.insns.push(Insn {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        });
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: i32) {
+        self.push(BPF_ALU64 | BPF_MOV | BPF_K, dst, 0, 0, imm);
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.push(BPF_ALU64 | BPF_MOV | BPF_X, dst, src, 0, 0);
+    }
+
+    fn alu_imm(&mut self, op: u8, dst: u8, imm: i32) {
+        self.push(BPF_ALU64 | op | BPF_K, dst, 0, 0, imm);
+    }
+
+    fn alu(&mut self, op: u8, dst: u8, src: u8) {
+        self.push(BPF_ALU64 | op | BPF_X, dst, src, 0, 0);
+    }
+
+    fn load(&mut self, size: u8, dst: u8, src: u8, off: i16) {
+        self.push(BPF_LDX | BPF_MEM | size, dst, src, off, 0);
+    }
+
+    fn store(&mut self, size: u8, dst: u8, off: i16, src: u8) {
+        self.push(BPF_STX | BPF_MEM | size, dst, src, off, 0);
+    }
+
+    /// Store `imm` through the scratch register `R5`.
+    fn store_imm(&mut self, size: u8, dst: u8, off: i16, imm: i32) {
+        self.mov_imm(R5, imm);
+        self.store(size, dst, off, R5);
+    }
+
+    /// Jump `off` instructions ahead when `dst` compares to `imm`.
+    fn jump_imm(&mut self, op: u8, dst: u8, imm: i32, off: i16) {
+        self.push(BPF_JMP | op | BPF_K, dst, 0, off, imm);
+    }
+
+    /// Pass the packet on when `dst` compares to `imm`.
+    fn pass_if_imm(&mut self, op: u8, dst: u8, imm: i32) {
+        self.passes.push(self.insns.len());
+        self.jump_imm(op, dst, imm, 0);
+    }
+
+    /// Pass the packet on.
+    fn pass(&mut self) {
+        self.passes.push(self.insns.len());
+        self.push(BPF_JMP | BPF_JA, 0, 0, 0, 0);
+    }
+
+    /// Pass the packet on when `dst` compares to `src`.
+    fn pass_if(&mut self, op: u8, dst: u8, src: u8) {
+        self.passes.push(self.insns.len());
+        self.push(BPF_JMP | op | BPF_X, dst, src, 0, 0);
+    }
+
+    /// Pass the packet on unless the field of `size` at `off` in the packet pointed to by `R2`
+    /// is `value`.
+    fn expect(&mut self, size: u8, off: i16, value: i32) {
+        self.load(size, R5, R2, off);
+        self.pass_if_imm(BPF_JNE, R5, value);
+    }
+
+    fn load_map_fd(&mut self, dst: u8, fd: i32) {
+        self.push(BPF_LD | BPF_IMM | BPF_DW, dst, BPF_PSEUDO_MAP_FD, 0, fd);
+        self.push(0, 0, 0, 0, 0);
+    }
+
+    fn call(&mut self, helper: i32) {
+        self.push(BPF_JMP | BPF_CALL, 0, 0, 0, helper);
+    }
+
+    fn exit(&mut self) {
+        self.push(BPF_JMP | BPF_EXIT, 0, 0, 0, 0);
+    }
+
+    /// End the program with the instructions passing the packet on.
+    fn finish(mut self) -> Vec<Insn> {
+        let end = self.insns.len();
+        for pass in std::mem::take(&mut self.passes) {
+            self.insns[pass].off = (end - pass - 1) as i16;
+        }
+        self.mov_imm(R0, XDP_PASS);
+        self.exit();
+        self.insns
+    }
+}
+
+/// Assemble the program answering the Binding requests received on `ports`, counting the
+/// answers in the first element of the array map `counter`.
+fn program(ports: &[u16], counter: i32) -> Vec<Insn> {
+    let mut asm = Assembler::default();
+    asm.mov(R6, R1);
+    // The frame must be exactly a request without attributes, so that the answer written past
+    // it is where its lengths say.
+    asm.load(BPF_W, R2, R6, 0);
+    asm.load(BPF_W, R3, R6, 4);
+    asm.mov(R4, R2);
+    asm.alu_imm(BPF_ADD, R4, REQUEST_LEN);
+    asm.pass_if(BPF_JGT, R4, R3);
+    asm.pass_if(BPF_JLT, R4, R3);
+    asm.expect(BPF_H, ETHER_TYPE, field16(0x0800));
+    asm.expect(BPF_B, IP, 0x45);
+    asm.expect(
+        BPF_H,
+        IP_TOTAL_LENGTH,
+        field16(REQUEST_LEN as u16 - IP as u16),
+    );
+    asm.load(BPF_H, R5, R2, IP_FRAGMENT);
+    asm.alu_imm(BPF_AND, R5, field16(0x3fff));
+    asm.pass_if_imm(BPF_JNE, R5, 0);
+    asm.expect(BPF_B, IP_PROTOCOL, libc::IPPROTO_UDP);
+    asm.load(BPF_H, R5, R2, UDP + 2);
+    for (i, port) in ports.iter().enumerate() {
+        // Skip over the comparisons left and the jump passing the packet on.
+        let skip = (ports.len() - i) as i16;
+        asm.jump_imm(BPF_JEQ, R5, field16(*port), skip);
+    }
+    asm.pass();
+    asm.expect(BPF_H, UDP_LENGTH, field16(REQUEST_LEN as u16 - UDP as u16));
+    asm.expect(BPF_H, STUN, field16(0x0001));
+    asm.expect(BPF_H, STUN_LENGTH, 0);
+    asm.expect(BPF_W, STUN_COOKIE, field32(crate::message::MAGIC_COOKIE));
+
+    // Room for the XOR-MAPPED-ADDRESS.
+    asm.mov(R1, R6);
+    asm.mov_imm(R2, ADDRESS_ATTRIBUTE_LEN);
+    asm.call(BPF_FUNC_XDP_ADJUST_TAIL);
+    asm.pass_if_imm(BPF_JNE, R0, 0);
+    asm.load(BPF_W, R2, R6, 0);
+    asm.load(BPF_W, R3, R6, 4);
+    asm.mov(R4, R2);
+    asm.alu_imm(BPF_ADD, R4, REQUEST_LEN + ADDRESS_ATTRIBUTE_LEN);
+    asm.pass_if(BPF_JGT, R4, R3);
+
+    // Send the frame back where it came from.
+    for (off, size, other) in [(0, BPF_W, 6), (4, BPF_H, 10)] {
+        asm.load(size, R4, R2, off);
+        asm.load(size, R5, R2, other);
+        asm.store(size, R2, off, R5);
+        asm.store(size, R2, other, R4);
+    }
+    asm.load(BPF_W, R7, R2, IP_SOURCE);
+    asm.load(BPF_W, R5, R2, IP_DESTINATION);
+    asm.store(BPF_W, R2, IP_SOURCE, R5);
+    asm.store(BPF_W, R2, IP_DESTINATION, R7);
+    asm.load(BPF_H, R8, R2, UDP);
+    asm.load(BPF_H, R5, R2, UDP + 2);
+    asm.store(BPF_H, R2, UDP, R5);
+    asm.store(BPF_H, R2, UDP + 2, R8);
+    let len = REQUEST_LEN + ADDRESS_ATTRIBUTE_LEN;
+    asm.store_imm(
+        BPF_H,
+        R2,
+        IP_TOTAL_LENGTH,
+        field16((len - IP as i32) as u16),
+    );
+    asm.store_imm(BPF_B, R2, IP_TTL, 64);
+    asm.store_imm(BPF_H, R2, UDP_LENGTH, field16((len - UDP as i32) as u16));
+    // The checksum is optional over IPv4.
+    asm.store_imm(BPF_H, R2, UDP_CHECKSUM, 0);
+
+    // Turn the request into a success response, the transaction ID is left as it is.
+    asm.store_imm(BPF_H, R2, STUN, field16(0x0101));
+    asm.store_imm(
+        BPF_H,
+        R2,
+        STUN_LENGTH,
+        field16(ADDRESS_ATTRIBUTE_LEN as u16),
+    );
+    asm.store_imm(
+        BPF_H,
+        R2,
+        ATTRIBUTE,
+        field16(crate::message::attributes::XOR_MAPPED_ADDRESS),
+    );
+    asm.store_imm(BPF_H, R2, ATTRIBUTE + 2, field16(8));
+    asm.store_imm(BPF_H, R2, ATTRIBUTE + 4, field16(0x0001));
+    asm.alu_imm(
+        BPF_XOR,
+        R8,
+        field16((crate::message::MAGIC_COOKIE >> 16) as u16),
+    );
+    asm.store(BPF_H, R2, ATTRIBUTE + 6, R8);
+    asm.alu_imm(BPF_XOR, R7, field32(crate::message::MAGIC_COOKIE));
+    asm.store(BPF_W, R2, ATTRIBUTE + 8, R7);
+
+    // The checksum of the IP header, in which only the addresses were swapped, but the length
+    // and TTL changed.
+    asm.store_imm(BPF_H, R2, IP_CHECKSUM, 0);
+    asm.mov_imm(R0, 0);
+    for off in (IP..UDP).step_by(2) {
+        asm.load(BPF_H, R5, R2, off);
+        asm.alu(BPF_ADD, R0, R5);
+    }
+    for _ in 0..2 {
+        asm.mov(R5, R0);
+        asm.alu_imm(BPF_RSH, R5, 16);
+        asm.alu_imm(BPF_AND, R0, 0xffff);
+        asm.alu(BPF_ADD, R0, R5);
+    }
+    asm.alu_imm(BPF_XOR, R0, 0xffff);
+    asm.store(BPF_H, R2, IP_CHECKSUM, R0);
+
+    // Count the answer.
+    asm.store_imm(BPF_W, R10, -4, 0);
+    asm.mov(R2, R10);
+    asm.alu_imm(BPF_ADD, R2, -4);
+    asm.load_map_fd(R1, counter);
+    asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    asm.jump_imm(BPF_JEQ, R0, 0, 2);
+    asm.mov_imm(R1, 1);
+    asm.push(BPF_STX | BPF_ATOMIC | BPF_DW, R0, R1, 0, BPF_ADD.into());
+    asm.mov_imm(R0, XDP_TX);
+    asm.exit();
+    asm.finish()
+}
+
+/// Call `bpf` with `cmd` and `attr`, returning the file descriptor created if any.
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    // SAFETY: `attr` is the union member expected by `cmd`, padded with zeros, and outlives the
+    // call.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            (attr as *mut T).cast::<libc::c_void>(),
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+/// `bpf` creating a file descriptor.
+fn bpf_fd<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<OwnedFd> {
+    let fd = bpf(cmd, attr)?;
+    // SAFETY: the command created this file descriptor, owned by no one else.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// `union bpf_attr` for BPF_MAP_CREATE.
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    padding: [u32; 27],
+}
+
+/// `union bpf_attr` for BPF_PROG_LOAD.
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    padding: [u64; 12],
+}
+
+/// `union bpf_attr` for BPF_LINK_CREATE.
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+    padding: [u64; 14],
+}
+
+/// Counter of the answers in a map mapped in memory.
+struct Counter {
+    map: OwnedFd,
+    value: NonNull<AtomicU64>,
+}
+
+// SAFETY: the value is only accessed atomically.
+unsafe impl Send for Counter {}
+unsafe impl Sync for Counter {}
+
+impl Counter {
+    fn new() -> io::Result<Self> {
+        let mut attr = MapCreateAttr {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1,
+            map_flags: BPF_F_MMAPABLE,
+            ..Default::default()
+        };
+        let map = bpf_fd(BPF_MAP_CREATE, &mut attr)?;
+        // SAFETY: the map is an array of a single aligned u64, mapped shared with the kernel.
+        let value = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                map.as_raw_fd(),
+                0,
+            )
+        };
+        if value == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Counter {
+            map,
+            value: NonNull::new(value.cast()).unwrap(),
+        })
+    }
+
+    fn get(&self) -> u64 {
+        // SAFETY: the mapping lives as long as the counter.
+        unsafe { self.value.as_ref() }.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created with this size and isn't referenced anymore.
+        unsafe { libc::munmap(self.value.as_ptr().cast(), page_size()) };
+    }
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The loaded program, detached once dropped.
+struct Program {
+    fd: OwnedFd,
+    counter: Counter,
+}
+
+impl Program {
+    fn load(ports: &[u16]) -> Result<Self> {
+        let counter = Counter::new().context("could not create the counter of the answers")?;
+        let insns = program(ports, counter.map.as_raw_fd());
+        let mut log = vec![0u8; 1 << 16];
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: c"GPL".as_ptr() as u64,
+            log_level: 1,
+            log_size: log.len() as u32,
+            log_buf: log.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        match bpf_fd(BPF_PROG_LOAD, &mut attr) {
+            Ok(fd) => Ok(Program { fd, counter }),
+            Err(err) => {
+                let len = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+                let log = String::from_utf8_lossy(&log[..len]);
+                Err(err).with_context(|| format!("could not load the XDP program: {}", log.trim()))
+            }
+        }
+    }
+}
+
+/// Answers Binding requests in the kernel as long as it lives.
+pub struct Xdp {
+    interface: String,
+    _link: OwnedFd,
+    program: Program,
+}
+
+impl Xdp {
+    /// Attach the program answering the Binding requests received on `ports` to `interface`.
+    pub fn attach(interface: &str, ports: &[u16]) -> Result<Self> {
+        if ports.is_empty() {
+            bail!("no UDP port to answer Binding requests on in the kernel");
+        }
+        let name = std::ffi::CString::new(interface)?;
+        // SAFETY: the name is a NUL terminated string.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("no interface named {}", interface));
+        }
+        let program = Program::load(ports)?;
+        let mut attr = LinkCreateAttr {
+            prog_fd: program.fd.as_raw_fd() as u32,
+            target_ifindex: index,
+            attach_type: BPF_XDP,
+            ..Default::default()
+        };
+        let link = bpf_fd(BPF_LINK_CREATE, &mut attr)
+            .with_context(|| format!("could not attach the XDP program to {}", interface))?;
+        Ok(Xdp {
+            interface: interface.to_string(),
+            _link: link,
+            program,
+        })
+    }
+
+    /// Interface the program is attached to.
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Number of Binding requests answered in the kernel.
+    pub fn answered(&self) -> u64 {
+        self.program.counter.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{bpf, Program, BPF_PROG_TEST_RUN, XDP_PASS, XDP_TX};
+    use crate::message::{attributes, methods, Attribute, Class, Message};
+
+    /// `union bpf_attr` for BPF_PROG_TEST_RUN.
+    #[repr(C)]
+    #[derive(Default)]
+    struct TestRunAttr {
+        prog_fd: u32,
+        retval: u32,
+        data_size_in: u32,
+        data_size_out: u32,
+        data_in: u64,
+        data_out: u64,
+        padding: [u64; 10],
+    }
+
+    /// Run `program` on `frame`, returning its verdict and the frame once processed.
+    fn run(program: &Program, frame: &[u8]) -> (i32, Vec<u8>) {
+        let mut out = vec![0; 256];
+        let mut attr = TestRunAttr {
+            prog_fd: std::os::unix::io::AsRawFd::as_raw_fd(&program.fd) as u32,
+            data_size_in: frame.len() as u32,
+            data_size_out: out.len() as u32,
+            data_in: frame.as_ptr() as u64,
+            data_out: out.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        bpf(BPF_PROG_TEST_RUN, &mut attr).unwrap();
+        out.truncate(attr.data_size_out as usize);
+        (attr.retval as i32, out)
+    }
+
+    /// An Ethernet frame carrying `payload` from 192.0.2.1:40000 to 198.51.100.1:`port`.
+    fn frame(port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2, 8, 0];
+        let len = (20 + 8 + payload.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0]);
+        frame[16..18].copy_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        let checksum = checksum(&frame[14..34]);
+        frame[24..26].copy_from_slice(&checksum.to_be_bytes());
+        frame.extend_from_slice(&40000u16.to_be_bytes());
+        frame.extend_from_slice(&port.to_be_bytes());
+        frame.extend_from_slice(&(len - 20).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn checksum(header: &[u8]) -> u16 {
+        let mut sum: u32 = header
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn answers_binding_requests() {
+        let program = match Program::load(&[3478, 19302]) {
+            Ok(program) => program,
+            // Loading BPF programs requires privileges.
+            Err(err) => return eprintln!("skipped: {:#}", err),
+        };
+        let request = Message::new(methods::BINDING, Class::Request, [7; 12]);
+        let (verdict, answer) = run(&program, &frame(19302, &request.encode()));
+        assert_eq!(verdict, XDP_TX);
+        assert_eq!(program.counter.get(), 1);
+        assert_eq!(&answer[..6], &[2, 0, 0, 0, 0, 2]);
+        assert_eq!(&answer[6..12], &[2, 0, 0, 0, 0, 1]);
+        assert_eq!(&answer[26..34], &[198, 51, 100, 1, 192, 0, 2, 1]);
+        assert_eq!(checksum(&answer[14..34]), 0);
+        assert_eq!(&answer[34..38], &[0x4b, 0x66, 0x9c, 0x40]);
+        let response = Message::decode(&answer[42..]).unwrap();
+        assert_eq!(response.class, Class::SuccessResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(
+            response.get_xor_address(attributes::XOR_MAPPED_ADDRESS),
+            Some(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 40000)))
+        );
+
+        // Other ports, requests carrying attributes and anything else are passed on.
+        let (verdict, _) = run(&program, &frame(3479, &request.encode()));
+        assert_eq!(verdict, XDP_PASS);
+        let mut software = Message::new(methods::BINDING, Class::Request, [7; 12]);
+        software.attributes.push(Attribute {
+            kind: 0x8022,
+            value: b"test".to_vec(),
+        });
+        let (verdict, _) = run(&program, &frame(3478, &software.encode()));
+        assert_eq!(verdict, XDP_PASS);
+        let (verdict, _) = run(&program, &frame(3478, &[0; 20]));
+        assert_eq!(verdict, XDP_PASS);
+        assert_eq!(program.counter.get(), 1);
+    }
+}